@@ -0,0 +1,98 @@
+//! Compares the blocked matrix products against the loops the dense layer used before them.
+//!
+//! Run with `cargo bench --bench gemm`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use neurox::utils::gemm::{gemm, Matrix};
+
+/// The forward pass of a dense layer as the nested loops over every output
+fn loop_forward(inputs: &[f32], weights: &[f32], biases: &[f32], (batch, input_len, size): (usize, usize, usize), outputs: &mut [f32]) {
+    for b in 0..batch {
+        for x in 0..size {
+            let mut sum = biases[x];
+            for y in 0..input_len {
+                sum += weights[input_len * x + y] * inputs[b * input_len + y];
+            }
+            outputs[b * size + x] = sum;
+        }
+    }
+}
+
+/// The modifications and sensitivities of the backward pass of a dense layer as nested loops
+fn loop_backward(inputs: &[f32], weights: &[f32], gradients: &[f32], (batch, input_len, size): (usize, usize, usize), weight_mods: &mut [f32], sensitivities: &mut [f32]) {
+    sensitivities.fill(0.);
+    for b in 0..batch {
+        for x in 0..size {
+            let gradient = gradients[b * size + x];
+            for y in 0..input_len {
+                weight_mods[input_len * x + y] -= 0.01 * inputs[b * input_len + y] * gradient;
+                sensitivities[b * input_len + y] += gradient * weights[input_len * x + y];
+            }
+        }
+    }
+}
+
+/// The average time of `f`, running it for roughly half a second
+fn time<F: FnMut()>(mut f: F) -> Duration {
+    f();
+    let start = Instant::now();
+    let mut runs = 0;
+    while start.elapsed() < Duration::from_millis(500) {
+        f();
+        runs += 1;
+    }
+    start.elapsed() / runs
+}
+
+fn report(name: &str, flops: usize, duration: Duration) {
+    println!("{:<28} {:>10.1?} {:>8.2} GFLOP/s", name, duration, flops as f64 / duration.as_secs_f64() / 1e9);
+}
+
+fn main() {
+    let mut thread_counts = vec![1, std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1)];
+    thread_counts.dedup();
+
+    for (batch, input_len, size) in [(32, 64, 64), (64, 256, 256), (128, 784, 512)] {
+        println!("batch {}, inputs {}, outputs {}", batch, input_len, size);
+        let inputs: Vec<f32> = (0..batch * input_len).map(|_| rand::random::<f32>() - 0.5).collect();
+        let weights: Vec<f32> = (0..size * input_len).map(|_| rand::random::<f32>() - 0.5).collect();
+        let biases: Vec<f32> = (0..size).map(|_| rand::random::<f32>() - 0.5).collect();
+        let gradients: Vec<f32> = (0..batch * size).map(|_| rand::random::<f32>() - 0.5).collect();
+        let mut outputs = vec![0.; batch * size];
+        let mut weight_mods = vec![0.; size * input_len];
+        let mut sensitivities = vec![0.; batch * input_len];
+
+        let forward_flops = 2 * batch * input_len * size;
+        let backward_flops = 2 * forward_flops;
+
+        report("forward loops", forward_flops, time(|| {
+            loop_forward(&inputs, &weights, &biases, (batch, input_len, size), &mut outputs);
+            black_box(&outputs);
+        }));
+        for &threads in &thread_counts {
+            report(&format!("forward gemm ({} threads)", threads), forward_flops, time(|| {
+                for b in 0..batch {
+                    outputs[b * size..(b + 1) * size].copy_from_slice(&biases);
+                }
+                gemm(threads, 1., Matrix::new(&inputs, batch, input_len), Matrix::new(&weights, size, input_len).t(), 1., &mut outputs);
+                black_box(&outputs);
+            }));
+        }
+
+        report("backward loops", backward_flops, time(|| {
+            loop_backward(&inputs, &weights, &gradients, (batch, input_len, size), &mut weight_mods, &mut sensitivities);
+            black_box((&weight_mods, &sensitivities));
+        }));
+        for &threads in &thread_counts {
+            report(&format!("backward gemm ({} threads)", threads), backward_flops, time(|| {
+                let g = Matrix::new(&gradients, batch, size);
+                gemm(threads, -0.01, g.t(), Matrix::new(&inputs, batch, input_len), 1., &mut weight_mods);
+                gemm(threads, 1., g, Matrix::new(&weights, size, input_len), 0., &mut sensitivities);
+                black_box((&weight_mods, &sensitivities));
+            }));
+        }
+        println!();
+    }
+}
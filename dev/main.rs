@@ -2,29 +2,31 @@
 
 use std::f32::consts::PI;
 use std::fs;
+use std::sync::Arc;
 use std::time::Instant;
 use neurox::{Executor, Optimizer};
 use neurox::activation::Activation::{Linear, PNSigmoid, ReLU, Sigmoid, TanH};
 use neurox::dual_vec::DualVec;
 use neurox::Executor::{CPU, GPU};
+use neurox::builder::NetworkBuilder;
 use neurox::layer::LayerType::Dense;
 use neurox::loss::Loss;
 use neurox::network::Network;
 
 pub fn main() {
-    let ex = &Executor::gpu();
-    // let ex = &CPU;
-    let layers = vec![
-        (ex, Dense(256, TanH)),
-        (ex, Dense(256, TanH)),
-        (ex, Dense(64, TanH)),
-        (ex, Dense(64, TanH)),
-        (ex, Dense(16, TanH)),
-        (ex, Dense(1, Linear)),
-    ];
-    let mut network = Network::new(1, &layers).unwrap();
+    let ex = Arc::new(Executor::gpu());
+    // let ex = Arc::new(CPU);
+    let mut network = NetworkBuilder::new(1)
+        .dense(256, TanH).on(&ex)
+        .dense(256, TanH)
+        .dense(64, TanH)
+        .dense(64, TanH)
+        .dense(16, TanH)
+        .dense(1, Linear)
+        .build()
+        .unwrap();
     // let bytes = fs::read("test.neurox").unwrap();
-    // let mut network = Network::from_bytes(Some(gpu), bytes);
+    // let mut network = Network::from_bytes(Some(ex.clone()), bytes).unwrap();
 
     let mut inputs = vec![];
     let mut targets = vec![];
@@ -35,8 +37,8 @@ pub fn main() {
         targets.push((v* 2. * PI).sin());
     }
 
-    let mut inputs = DualVec::from_vec((&CPU, ex), inputs);
-    let mut targets = DualVec::from_vec((&CPU, ex), targets);
+    let mut inputs = DualVec::from_vec((&CPU, &ex), inputs);
+    let mut targets = DualVec::from_vec((&CPU, &ex), targets);
 
     let res = network.train(&mut inputs, &mut targets, Optimizer::GradientDecent(0.02), Loss::MeanSquared, 400, 4)
         .inspect_err(|e| {
@@ -45,7 +47,7 @@ pub fn main() {
         );
 
     let st = Instant::now();
-    let mut outputs = network.predict(&mut inputs);
+    let mut outputs = network.predict(&mut inputs).unwrap();
     let d = st.elapsed();
     if let (Some(inputs), Some(targets), Some(outputs)) = (inputs.cpu_borrow(), targets.cpu_borrow(), outputs.cpu_borrow()) {
         for i in 0..samples {
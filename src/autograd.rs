@@ -0,0 +1,337 @@
+use crate::dual_vec::DualVec;
+use crate::error::{Error, TensorError};
+use crate::Executor;
+use crate::ops;
+use crate::tensor::Tensor;
+
+/// A value recorded on a [Tape]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Var(usize);
+
+/// How a value of a [Tape] was computed from the values before it
+#[derive(Clone, Copy, Debug)]
+enum Op {
+    /// An input of the computation, such as the inputs or parameters of a layer
+    Leaf,
+    Add(Var, Var),
+    Sub(Var, Var),
+    Mul(Var, Var),
+    Div(Var, Var),
+    Scale(Var, f32),
+    Exp(Var),
+    Log(Var),
+    Sum(Var, usize),
+    Mean(Var, usize),
+    MatMul(Var, Var),
+    Transpose(Var, usize, usize),
+    Reshape(Var),
+}
+
+/// Records the [ops] run on tensors, so the gradients of a result with respect to every value it
+/// was computed from can be found by walking the records backwards.
+///
+/// Only the forward computation has to be written, such as by the [Definition] of a layer or a
+/// custom [Loss], and [Tape::backward] applies the chain rule through every recorded op on the
+/// executor of the tape.
+///
+/// [Definition]: crate::layer::autograd::Definition
+/// [Loss]: crate::loss::Loss::Custom
+pub struct Tape<'e> {
+    exec: &'e Executor,
+    values: Vec<Tensor>,
+    ops: Vec<Op>,
+}
+
+/// The gradients of the values of a [Tape], `None` for the values the result doesn't depend on
+#[derive(Debug)]
+pub struct Gradients(Vec<Option<Tensor>>);
+
+impl Gradients {
+    /// The gradient of `var`, which has the shape of its value and is packed in row major order
+    pub fn get(&self, var: Var) -> Option<&Tensor> {
+        self.0[var.0].as_ref()
+    }
+
+    pub fn take(&mut self, var: Var) -> Option<Tensor> {
+        self.0[var.0].take()
+    }
+}
+
+impl<'e> Tape<'e> {
+    pub fn new(exec: &'e Executor) -> Self {
+        Tape {
+            exec,
+            values: vec![],
+            ops: vec![],
+        }
+    }
+
+    pub fn exec(&self) -> &'e Executor {
+        self.exec
+    }
+
+    /// Records an input of the computation
+    pub fn var(&mut self, value: Tensor) -> Var {
+        self.push(value, Op::Leaf)
+    }
+
+    pub fn value(&self, var: Var) -> &Tensor {
+        &self.values[var.0]
+    }
+
+    /// See [ops::add], the shapes are broadcast together
+    pub fn add(&mut self, a: Var, b: Var) -> Result<Var, Error> {
+        let value = ops::add(self.exec, &mut self.values[a.0].clone(), &mut self.values[b.0].clone())?;
+        Ok(self.push(value, Op::Add(a, b)))
+    }
+
+    pub fn sub(&mut self, a: Var, b: Var) -> Result<Var, Error> {
+        let value = ops::sub(self.exec, &mut self.values[a.0].clone(), &mut self.values[b.0].clone())?;
+        Ok(self.push(value, Op::Sub(a, b)))
+    }
+
+    pub fn mul(&mut self, a: Var, b: Var) -> Result<Var, Error> {
+        let value = ops::mul(self.exec, &mut self.values[a.0].clone(), &mut self.values[b.0].clone())?;
+        Ok(self.push(value, Op::Mul(a, b)))
+    }
+
+    pub fn div(&mut self, a: Var, b: Var) -> Result<Var, Error> {
+        let value = ops::div(self.exec, &mut self.values[a.0].clone(), &mut self.values[b.0].clone())?;
+        Ok(self.push(value, Op::Div(a, b)))
+    }
+
+    pub fn scale(&mut self, a: Var, factor: f32) -> Result<Var, Error> {
+        let value = ops::scale(self.exec, &mut self.values[a.0].clone(), factor)?;
+        Ok(self.push(value, Op::Scale(a, factor)))
+    }
+
+    pub fn exp(&mut self, a: Var) -> Result<Var, Error> {
+        let value = ops::exp(self.exec, &mut self.values[a.0].clone())?;
+        Ok(self.push(value, Op::Exp(a)))
+    }
+
+    pub fn log(&mut self, a: Var) -> Result<Var, Error> {
+        let value = ops::log(self.exec, &mut self.values[a.0].clone())?;
+        Ok(self.push(value, Op::Log(a)))
+    }
+
+    pub fn sum(&mut self, a: Var, axis: usize) -> Result<Var, Error> {
+        let value = ops::sum(self.exec, &mut self.values[a.0].clone(), axis)?;
+        Ok(self.push(value, Op::Sum(a, axis)))
+    }
+
+    pub fn mean(&mut self, a: Var, axis: usize) -> Result<Var, Error> {
+        let value = ops::mean(self.exec, &mut self.values[a.0].clone(), axis)?;
+        Ok(self.push(value, Op::Mean(a, axis)))
+    }
+
+    pub fn matmul(&mut self, a: Var, b: Var) -> Result<Var, Error> {
+        let value = ops::matmul(self.exec, &mut self.values[a.0].clone(), &mut self.values[b.0].clone())?;
+        Ok(self.push(value, Op::MatMul(a, b)))
+    }
+
+    pub fn transpose(&mut self, a: Var, x: usize, y: usize) -> Result<Var, Error> {
+        let value = ops::transpose(self.exec, &mut self.values[a.0].clone(), x, y)?;
+        Ok(self.push(value, Op::Transpose(a, x, y)))
+    }
+
+    /// The same values in another shape, copied first when they aren't contiguous
+    pub fn reshape(&mut self, a: Var, shape: &[usize]) -> Result<Var, Error> {
+        let value = packed(self.exec, self.values[a.0].clone())?.reshape(shape)?;
+        Ok(self.push(value, Op::Reshape(a)))
+    }
+
+    fn push(&mut self, value: Tensor, op: Op) -> Var {
+        self.values.push(value);
+        self.ops.push(op);
+        Var(self.values.len() - 1)
+    }
+
+    /// The gradients of `output` with respect to every value it was computed from, starting from
+    /// `gradient`, which has the shape of `output`. Without a gradient, the gradients are those of
+    /// the sum of all values of `output`.
+    pub fn backward(&mut self, output: Var, gradient: Option<Tensor>) -> Result<Gradients, Error> {
+        let exec = self.exec;
+        let shape = self.values[output.0].shape().to_vec();
+        let gradient = match gradient {
+            Some(gradient) if gradient.shape() != shape => {
+                return Err(Error::Tensor(TensorError::Shape(gradient.shape().to_vec(), self.values[output.0].len())));
+            }
+            Some(gradient) => gradient,
+            None => filled(exec, &shape, 1.)?,
+        };
+
+        let mut gradients: Vec<Option<Tensor>> = vec![None; self.values.len()];
+        gradients[output.0] = Some(gradient);
+
+        for i in (0..=output.0).rev() {
+            let Some(mut g) = gradients[i].take() else {
+                continue;
+            };
+            let mut out = self.values[i].clone();
+
+            match self.ops[i] {
+                Op::Leaf => {
+                    // kept for the caller, leaves have no inputs to pass it on to
+                    gradients[i] = Some(g);
+                    continue;
+                }
+                Op::Add(a, b) => {
+                    self.accumulate(&mut gradients, a, g.clone())?;
+                    self.accumulate(&mut gradients, b, g)?;
+                }
+                Op::Sub(a, b) => {
+                    let negated = ops::scale(exec, &mut g, -1.)?;
+                    self.accumulate(&mut gradients, a, g)?;
+                    self.accumulate(&mut gradients, b, negated)?;
+                }
+                Op::Mul(a, b) => {
+                    let ga = ops::mul(exec, &mut g, &mut self.values[b.0].clone())?;
+                    let gb = ops::mul(exec, &mut g, &mut self.values[a.0].clone())?;
+                    self.accumulate(&mut gradients, a, ga)?;
+                    self.accumulate(&mut gradients, b, gb)?;
+                }
+                Op::Div(a, b) => {
+                    // d(a / b) / db = -(a / b) / b
+                    let mut b_value = self.values[b.0].clone();
+                    let ga = ops::div(exec, &mut g, &mut b_value)?;
+                    let mut gb = ops::mul(exec, &mut g, &mut out)?;
+                    let gb = ops::div(exec, &mut gb, &mut b_value)?;
+                    let gb = ops::scale(exec, &mut gb.clone(), -1.)?;
+                    self.accumulate(&mut gradients, a, ga)?;
+                    self.accumulate(&mut gradients, b, gb)?;
+                }
+                Op::Scale(a, factor) => {
+                    let ga = ops::scale(exec, &mut g, factor)?;
+                    self.accumulate(&mut gradients, a, ga)?;
+                }
+                Op::Exp(a) => {
+                    let ga = ops::mul(exec, &mut g, &mut out)?;
+                    self.accumulate(&mut gradients, a, ga)?;
+                }
+                Op::Log(a) => {
+                    let ga = ops::div(exec, &mut g, &mut self.values[a.0].clone())?;
+                    self.accumulate(&mut gradients, a, ga)?;
+                }
+                Op::Sum(a, axis) | Op::Mean(a, axis) => {
+                    // every value along the axis gets the gradient of its sum
+                    let shape = self.values[a.0].shape().to_vec();
+                    let mut kept = shape.clone();
+                    kept[axis] = 1;
+                    let mut g = packed(exec, g)?.reshape(&kept)?;
+                    let mut ga = ops::add(exec, &mut filled(exec, &shape, 0.)?, &mut g)?;
+                    if let Op::Mean(..) = self.ops[i] {
+                        ga = ops::scale(exec, &mut ga, 1. / shape[axis] as f32)?;
+                    }
+                    self.accumulate(&mut gradients, a, ga)?;
+                }
+                Op::MatMul(a, b) => {
+                    let mut a_t = ops::transpose(exec, &mut self.values[a.0].clone(), 0, 1)?;
+                    let mut b_t = ops::transpose(exec, &mut self.values[b.0].clone(), 0, 1)?;
+                    let ga = ops::matmul(exec, &mut g, &mut b_t)?;
+                    let gb = ops::matmul(exec, &mut a_t, &mut g)?;
+                    self.accumulate(&mut gradients, a, ga)?;
+                    self.accumulate(&mut gradients, b, gb)?;
+                }
+                Op::Transpose(a, x, y) => {
+                    let ga = ops::transpose(exec, &mut g, x, y)?;
+                    self.accumulate(&mut gradients, a, ga)?;
+                }
+                Op::Reshape(a) => {
+                    let ga = packed(exec, g)?.reshape(self.values[a.0].shape())?;
+                    self.accumulate(&mut gradients, a, ga)?;
+                }
+            }
+        }
+
+        Ok(Gradients(gradients))
+    }
+
+    /// Adds the gradient `g` of a value computed from `var` to the gradient of `var`, summing the
+    /// dimensions the value of `var` was broadcast along
+    fn accumulate(&self, gradients: &mut [Option<Tensor>], var: Var, g: Tensor) -> Result<(), Error> {
+        let mut g = unbroadcast(self.exec, g, self.values[var.0].shape())?;
+        gradients[var.0] = Some(match gradients[var.0].take() {
+            Some(mut sum) => ops::add(self.exec, &mut sum, &mut g)?,
+            None => g,
+        });
+        Ok(())
+    }
+}
+
+/// Sums `g` over the dimensions a value of `shape` was broadcast along to get to the shape of `g`
+fn unbroadcast(exec: &Executor, mut g: Tensor, shape: &[usize]) -> Result<Tensor, Error> {
+    if g.shape() == shape {
+        return packed(exec, g);
+    }
+
+    for _ in shape.len()..g.shape().len() {
+        g = ops::sum(exec, &mut g, 0)?;
+    }
+    // from the last dimension, so removing one doesn't move the ones left to check
+    for d in (0..shape.len()).rev() {
+        if shape[d] == 1 && g.shape()[d] != 1 {
+            g = ops::sum(exec, &mut g, d)?;
+        }
+    }
+    packed(exec, g)?.reshape(shape)
+}
+
+/// `tensor` when its values are packed from the start of its vector, otherwise a packed copy
+fn packed(exec: &Executor, mut tensor: Tensor) -> Result<Tensor, Error> {
+    if tensor.is_packed() {
+        return Ok(tensor);
+    }
+    ops::contiguous(exec, &mut tensor)
+}
+
+/// A tensor of `shape` where every value is `value`
+fn filled(exec: &Executor, shape: &[usize], value: f32) -> Result<Tensor, Error> {
+    let len = shape.iter().product();
+    Tensor::new(DualVec::from_vec((exec, exec), vec![value; len]), shape)
+}
+
+/// Compares the gradients a [Tape] finds for the sum of the result of `f` against central finite
+/// differences, changing every value of every input by `epsilon` in turn.
+///
+/// Returns the largest difference between them, relative to the larger of the two gradients, or
+/// absolute where both are below 1.
+pub fn check_gradients<F>(exec: &Executor, inputs: &[Tensor], epsilon: f32, f: F) -> Result<f32, Error>
+    where F: Fn(&mut Tape, &[Var]) -> Result<Var, Error> {
+    let sum_of = |inputs: &[Tensor]| -> Result<f32, Error> {
+        let mut tape = Tape::new(exec);
+        let vars: Vec<Var> = inputs.iter().map(|input| tape.var(input.clone())).collect();
+        let output = f(&mut tape, &vars)?;
+        Ok(tape.value(output).clone().to_vec().iter().sum())
+    };
+
+    let mut tape = Tape::new(exec);
+    let vars: Vec<Var> = inputs.iter().map(|input| tape.var(input.clone())).collect();
+    let output = f(&mut tape, &vars)?;
+    let mut gradients = tape.backward(output, None)?;
+
+    let mut worst: f32 = 0.;
+    for (i, input) in inputs.iter().enumerate() {
+        let values = input.clone().to_vec();
+        let analytic = match gradients.take(vars[i]) {
+            Some(mut gradient) => gradient.to_vec(),
+            None => vec![0.; values.len()],
+        };
+
+        for j in 0..values.len() {
+            let mut moved = inputs.to_vec();
+            let mut numeric = 0.;
+            for (sign, direction) in [(1., epsilon), (-1., -epsilon)] {
+                let mut changed = values.clone();
+                changed[j] += direction;
+                moved[i] = Tensor::new(DualVec::from_vec((exec, exec), changed), input.shape())?;
+                numeric += sign * sum_of(&moved)?;
+            }
+            numeric /= 2. * epsilon;
+
+            let difference = (analytic[j] - numeric).abs() / analytic[j].abs().max(numeric.abs()).max(1.);
+            worst = worst.max(difference);
+        }
+    }
+    Ok(worst)
+}
@@ -0,0 +1,156 @@
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::activation::Activation;
+use crate::error::{Error, NetworkError};
+use crate::Executor;
+use crate::Executor::CPU;
+use crate::layer::LayerType;
+use crate::layer::autograd::Definition;
+use crate::layer::positional::Encoding;
+use crate::layer::recurrent::Cell;
+use crate::network::Network;
+use crate::shape::Shape;
+use crate::tensor::DType;
+
+/// Builds a sequential [Network] one layer at a time, keeping track of the shape of the
+/// samples between the layers so sizes such as the characteristics of attention or the
+/// timesteps of recurrent layers don't have to be repeated.
+///
+/// Layers run on the CPU until [NetworkBuilder::on] chooses another executor, which then
+/// applies to the last added layer and every layer after it.
+pub struct NetworkBuilder {
+    input_size: usize,
+    shape: Shape,
+    exec: Arc<Executor>,
+    layers: Vec<(Arc<Executor>, LayerType)>,
+    precisions: Vec<DType>,
+    /// The first shape with a dimension of 0, which fails the build
+    empty: Option<Shape>,
+}
+
+impl NetworkBuilder {
+    pub fn new(input_shape: impl Into<Shape>) -> Self {
+        let shape = input_shape.into();
+        NetworkBuilder {
+            input_size: shape.size(),
+            empty: Self::empty(&shape),
+            shape,
+            exec: Arc::new(CPU),
+            layers: vec![],
+            precisions: vec![],
+        }
+    }
+
+    /// Runs the last added layer and all following layers on `exec`
+    pub fn on(mut self, exec: &Arc<Executor>) -> Self {
+        self.exec = exec.clone();
+        if let Some((layer_exec, _)) = self.layers.last_mut() {
+            *layer_exec = exec.clone();
+        }
+        self
+    }
+
+    /// Keeps the values of the last added layer in `dtype`, see [Layer::set_precision]. The network
+    /// fails to build when the layer doesn't support it on its executor.
+    ///
+    /// [Layer::set_precision]: crate::layer::Layer::set_precision
+    pub fn precision(mut self, dtype: DType) -> Self {
+        if let Some(precision) = self.precisions.last_mut() {
+            *precision = dtype;
+        }
+        self
+    }
+
+    /// Adds a layer without knowledge of its output shape, which is then treated as flat
+    pub fn layer(self, layer: LayerType) -> Self {
+        let size = layer.output_size(self.shape.size());
+        self.push(layer, Shape::from(size))
+    }
+
+    pub fn dense(self, size: usize, activation: Activation) -> Self {
+        self.layer(LayerType::Dense(size, activation))
+    }
+
+    /// Self attention over the positions of the current shape
+    pub fn attention(self, heads: usize, internal: usize, output: usize, causal: bool) -> Self {
+        let shape = Shape::from([self.shape.positions(), output]);
+        let characteristics = self.shape.last();
+        self.push(LayerType::Attention(heads, internal, characteristics, output, causal), shape)
+    }
+
+    pub fn embedding(self, vocabulary: usize, size: usize) -> Self {
+        let shape = Shape::from([self.shape.size(), size]);
+        self.push(LayerType::Embedding(vocabulary, size), shape)
+    }
+
+    pub fn recurrent(self, cell: Cell, units: usize, return_sequences: bool, stateful: bool) -> Self {
+        let timesteps = self.shape.positions();
+        let shape = if return_sequences { Shape::from([timesteps, units]) } else { Shape::from(units) };
+        self.push(LayerType::Recurrent { cell, units, timesteps, return_sequences, stateful }, shape)
+    }
+
+    pub fn bidirectional(self, cell: Cell, units: usize, return_sequences: bool) -> Self {
+        let timesteps = self.shape.positions();
+        let shape = if return_sequences { Shape::from([timesteps, units * 2]) } else { Shape::from(units * 2) };
+        self.push(LayerType::Bidirectional { cell, units, timesteps, return_sequences }, shape)
+    }
+
+    /// Normalizes every position over its characteristics
+    pub fn layer_norm(self) -> Self {
+        let shape = self.shape.clone();
+        self.push(LayerType::LayerNorm(shape.last()), shape)
+    }
+
+    pub fn dropout(self, rate: f32) -> Self {
+        let shape = self.shape.clone();
+        self.push(LayerType::Dropout(rate), shape)
+    }
+
+    pub fn positional_encoding(self, encoding: Encoding) -> Self {
+        let shape = self.shape.clone();
+        self.push(LayerType::PositionalEncoding(encoding, shape.last()), shape)
+    }
+
+    pub fn transformer_encoder(self, heads: usize, d_ff: usize, dropout: f32, causal: bool) -> Self {
+        let shape = self.shape.clone();
+        self.push(LayerType::TransformerEncoder { heads, d_model: shape.last(), d_ff, dropout, causal }, shape)
+    }
+
+    /// A layer computing `definition`, whose gradients are found automatically. Its outputs are flat.
+    pub fn autograd(self, definition: impl Definition + 'static) -> Self {
+        self.layer(LayerType::Autograd(Rc::new(definition)))
+    }
+
+    /// The shape of the samples output by the last added layer
+    pub fn shape(&self) -> &Shape {
+        &self.shape
+    }
+
+    /// Builds the network, failing when a shape has a dimension of 0 or a layer doesn't fit the
+    /// outputs of the layer before it
+    pub fn build(self) -> Result<Network, Error> {
+        if let Some(shape) = self.empty {
+            return Err(Error::Network(NetworkError::ZeroDimension(shape.dims().to_vec())));
+        }
+        let mut network = Network::new(self.input_size, &self.layers)?;
+        for (i, dtype) in self.precisions.into_iter().enumerate() {
+            if dtype != DType::F32 {
+                network.set_precision(i, dtype)?;
+            }
+        }
+        Ok(network)
+    }
+
+    fn push(mut self, layer: LayerType, shape: Shape) -> Self {
+        self.layers.push((self.exec.clone(), layer));
+        self.precisions.push(DType::F32);
+        self.empty = self.empty.or_else(|| Self::empty(&shape));
+        self.shape = shape;
+        self
+    }
+
+    fn empty(shape: &Shape) -> Option<Shape> {
+        shape.dims().contains(&0).then(|| shape.clone())
+    }
+}
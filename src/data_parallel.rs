@@ -0,0 +1,204 @@
+use std::cell::RefMut;
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::{Executor, Optimizer};
+use crate::Executor::CPU;
+use crate::dual_vec::DualVec;
+use crate::error::{Error, MismatchError, NetworkError};
+use crate::layer::Layer;
+use crate::loss::Loss;
+use crate::network::{Batches, Network};
+use crate::profiler;
+use crate::tensor::Tensor;
+
+/// Trains copies of a network on several executors, such as the OpenCL devices of a machine,
+/// splitting every batch between them.
+///
+/// Every replica finds the modifications of its part of the batch, which are summed on the host
+/// into the modifications of the whole batch. All replicas then apply the same summed
+/// modifications, so they take the same step as a single network trained on the whole batch and
+/// their values stay the same as long as their executors compute the same way. [DataParallel::sync]
+/// copies the values of the first replica to the others, for executors which round differently.
+///
+/// The replicas are run one after the other on the calling thread, while the work queued on
+/// separate devices runs at the same time.
+pub struct DataParallel {
+    replicas: Vec<Network>,
+    /// The executor of every replica
+    execs: Vec<Arc<Executor>>,
+}
+
+impl DataParallel {
+    /// Replicates `network` with all of its layers on every executor of `execs`
+    pub fn new(network: &mut Network, execs: &[Arc<Executor>]) -> Result<Self, Error> {
+        if execs.is_empty() {
+            return Err(Error::Network(NetworkError::ZeroReplicas));
+        }
+
+        let mut replicas = Vec::with_capacity(execs.len());
+        for exec in execs {
+            replicas.push(network.replicate(exec)?);
+        }
+        // fail before training when a layer can't share its modifications
+        for layer in replicas[0].layers() {
+            layer.borrow_mut().mods()?;
+        }
+
+        Ok(DataParallel {
+            replicas,
+            execs: execs.to_vec(),
+        })
+    }
+
+    /// The amount of replicas the batches are split between
+    pub fn replicas(&self) -> usize {
+        self.replicas.len()
+    }
+
+    /// The replica `replica`, whose values are those of every replica
+    pub fn replica(&mut self, replica: usize) -> &mut Network {
+        &mut self.replicas[replica]
+    }
+
+    /// The first replica, such as for predicting or saving the trained network
+    pub fn network(&mut self) -> &mut Network {
+        &mut self.replicas[0]
+    }
+
+    pub fn into_network(mut self) -> Network {
+        self.replicas.swap_remove(0)
+    }
+
+    /// Copies the values of the first replica to the others
+    pub fn sync(&mut self) -> Result<(), Error> {
+        for r in 1..self.replicas.len() {
+            self.replicas[r] = self.replicas[0].replicate(&self.execs[r])?;
+        }
+        Ok(())
+    }
+
+    /// Trains every replica on its part of each batch of `batch_size` samples, like [Network::train].
+    /// Returns the loss of the last epoch, the mean over its batches of the summed losses of a batch.
+    pub fn train(&mut self, inputs: &mut DualVec, targets: &mut DualVec, optimizer: Optimizer, loss: Loss, epochs: u32, batch_size: usize) -> Result<f32, Error> {
+        for replica in &mut self.replicas {
+            replica.set_training(true);
+        }
+        let result = self.fit(inputs, targets, &optimizer, &loss, epochs, batch_size);
+        for replica in &mut self.replicas {
+            replica.set_training(false);
+        }
+        result
+    }
+
+    fn fit(&mut self, inputs: &mut DualVec, targets: &mut DualVec, optimizer: &Optimizer, loss: &Loss, epochs: u32, batch_size: usize) -> Result<f32, Error> {
+        let (input_size, output_size) = {
+            let layers = self.replicas[0].layers();
+            (layers[0].borrow().input_size(), layers.last().unwrap().borrow().output_size())
+        };
+
+        let samples = inputs.len() / input_size;
+        if targets.len() / output_size != samples {
+            return Err(Error::Mismatch(MismatchError::Sample(samples, targets.len() / output_size)))
+        }
+        let mut batches = Batches::new(samples, batch_size);
+        let batch_size = batches.batch_size();
+        let count = self.replicas.len();
+        if batch_size < count {
+            return Err(Error::Network(NetworkError::Replicas(batch_size, count)));
+        }
+
+        // the samples of the batch given to every replica
+        let parts: Vec<Range<usize>> = (0..count).map(|r| r * batch_size / count..(r + 1) * batch_size / count).collect();
+        let mut output_sensitivities: Vec<DualVec> = self.replicas.iter().zip(&parts)
+            .map(|(replica, part)| DualVec::from_exec(replica.layers().last().unwrap().borrow().exec(), output_size * part.len()))
+            .collect();
+
+        let input_samples = Tensor::batch(inputs.clone(), input_size);
+        let target_samples = Tensor::batch(targets.clone(), output_size);
+
+        let batch_count = batches.count();
+        let mut last_loss = f32::INFINITY;
+        for epoch in 0..epochs {
+            let mut total = 0.;
+            for batch_samples in batches.epoch() {
+                for ((replica, part), sensitivities) in self.replicas.iter_mut().zip(&parts).zip(&mut output_sensitivities) {
+                    let mut part_inputs = input_samples.gather(&batch_samples[part.clone()])?;
+                    let mut part_targets = target_samples.gather(&batch_samples[part.clone()])?;
+                    let mut part_output = replica.forward_batch(&mut part_inputs, None)?;
+                    let mut losses = loss.part_calculate(&CPU, &mut part_output, &mut part_targets, batch_size)?;
+                    total += losses.cpu_borrow().unwrap().iter().sum::<f32>();
+
+                    // the losses are means over the whole batch, so that the modifications of the parts add up
+                    loss.part_derivative(replica.layers().last().unwrap().borrow().exec(), &mut part_output, &mut part_targets, batch_size, sensitivities)?;
+                    replica.backward_batch(&mut part_inputs, sensitivities, optimizer);
+                }
+
+                self.sum_mods()?;
+                for replica in &mut self.replicas {
+                    replica.apply_gradients(optimizer, batch_size);
+                }
+            }
+            profiler::finish_epoch(&self.execs, epoch);
+            last_loss = total / batch_count as f32;
+        }
+
+        Ok(last_loss)
+    }
+
+    /// Replaces the modifications of every replica, which are sums over the samples of its part of
+    /// the batch, by their sum over the whole batch. Only the rows some replica modified are summed
+    /// for layers which modify some rows of their values, and every replica applies all of them.
+    fn sum_mods(&mut self) -> Result<(), Error> {
+        for i in 0..self.replicas[0].layers().len() {
+            let mut layers: Vec<_> = self.replicas.iter().map(|replica| replica.layers()[i].borrow_mut()).collect();
+
+            let rows = Self::modified_rows(&layers);
+            if let Some((_, rows)) = &rows {
+                for layer in layers.iter_mut() {
+                    layer.mark_modified(rows);
+                }
+            }
+
+            let mut mods = layers.iter_mut().map(|layer| layer.mods()).collect::<Result<Vec<_>, _>>()?;
+            for m in 0..mods[0].len() {
+                // layers which don't modify rows are a single row of all their modifications
+                let (size, modified) = match &rows {
+                    Some((size, rows)) => (*size, &rows[..]),
+                    None => (mods[0][m].len(), &[0][..]),
+                };
+
+                for range in modified.iter().map(|r| r * size..(r + 1) * size) {
+                    let mut sum = vec![0.; range.len()];
+                    for replica in mods.iter_mut() {
+                        for (s, v) in sum.iter_mut().zip(&replica[m].cpu_borrow().unwrap()[range.clone()]) {
+                            *s += v;
+                        }
+                    }
+                    for replica in mods.iter_mut() {
+                        replica[m].cpu_borrow().unwrap()[range.clone()].copy_from_slice(&sum);
+                    }
+                }
+                for replica in mods.iter_mut() {
+                    replica[m].updated_cpu();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The row size and the rows of a layer modified by any replica, sorted, or `None` when the
+    /// layer doesn't modify rows
+    fn modified_rows(layers: &[RefMut<'_, dyn Layer + 'static>]) -> Option<(usize, Vec<usize>)> {
+        let mut rows = vec![];
+        let mut size = 0;
+        for layer in layers {
+            let (row_size, modified) = layer.modified_rows()?;
+            size = row_size;
+            rows.extend_from_slice(modified);
+        }
+        rows.sort_unstable();
+        rows.dedup();
+        Some((size, rows))
+    }
+}
@@ -1,11 +1,17 @@
-use std::cell::{RefCell, RefMut};
+use std::cell::{Cell, OnceCell, RefCell, RefMut};
 use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Instant;
 
-use ocl::Buffer;
+use ocl::{Buffer, Event, Queue};
 use rand::{random, Rng, thread_rng};
 
 use crate::Executor;
 use crate::Executor::GPU;
+use crate::gpu::Gpu;
+use crate::pool;
+use crate::pool::MemoryPool;
+use crate::profiler::{Direction, Profiler};
 use crate::utils::cl_utils;
 
 fn gen_index<R: Rng + ?Sized>(rng: &mut R, ubound: usize) -> usize {
@@ -16,46 +22,114 @@ fn gen_index<R: Rng + ?Sized>(rng: &mut R, ubound: usize) -> usize {
     }
 }
 
+/// A copy between the host and the device which may still be running
 #[derive(Debug)]
+struct Transfer {
+    event: Event,
+    /// Whether the queue of the kernels already waits for the copy, which it doesn't when the
+    /// copy was started on the transfer queue
+    ordered: bool,
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        // the copy uses the host vector, which must not be touched or freed until it is done
+        let _ = self.event.wait_for();
+    }
+}
+
+/// The storage of a [DualVec] and which of its sides is newer, shared between all of its clones
+#[derive(Debug)]
+struct Storage {
+    /// The last copy started between the host and the device
+    pending: RefCell<Option<Transfer>>,
+    /// Allocated on the first use when the vector was only created for the GPU
+    cpu: OnceCell<RefCell<Vec<f32>>>,
+    gpu: Option<RefCell<Buffer<f32>>>,
+    cpu_newer: Cell<bool>,
+    gpu_newer: Cell<bool>,
+    /// Where the storage is allocated from and returned to once the last clone is dropped
+    pool: Arc<MemoryPool>,
+    /// The layer the storage is recorded under in the pool
+    owner: Arc<str>,
+    /// The profiling mode of the GPU the copies between the sides are recorded by
+    profiler: Option<Arc<Profiler>>,
+}
+
+impl Drop for Storage {
+    fn drop(&mut self) {
+        // the copy in flight uses the vector, so it is waited for before the vector is reused
+        self.pending.get_mut().take();
+        if let Some(vec) = self.cpu.take() {
+            self.pool.recycle_vec(&self.owner, vec.into_inner());
+        }
+        if let Some(buf) = self.gpu.take() {
+            self.pool.recycle_buffer(&self.owner, buf.into_inner());
+        }
+    }
+}
+
+/// A vector of values kept on the host, the device or both, which is copied to the side it is
+/// used on whenever the other side is newer.
+///
+/// Clones share the storage along with which side is newer, so an update through one clone is
+/// seen by all of them.
+#[derive(Clone, Debug)]
 pub struct DualVec {
     len: usize,
     capacity: usize,
-    pub cpu: (Option<Rc<RefCell<Vec<f32>>>>, bool),
-    pub gpu: (Option<Rc<RefCell<Buffer<f32>>>>, bool),
+    storage: Rc<Storage>,
 }
 
 
 impl DualVec {
     pub fn from_exec(exec: &Executor, len: usize) -> Self {
-        Self::from_vec((exec, exec), vec![0.; len])
+        Self::from_execs((exec, exec), len)
     }
     pub fn from_execs(exec: (&Executor, &Executor), len: usize) -> Self {
-        Self::from_vec(exec, vec![0.; len])
+        let (pool, owner) = (pool_of(exec), pool::owner());
+        // buffers from the pool are already cleared, so nothing has to be written
+        let gpu = gpu_of(exec).map(|q| pool.buffer(q.queue(), &owner, len));
+        let cpu = on_cpu(exec).then(|| pool.vec(&owner, len));
+        Self::with_storage(len, cpu, gpu, exec, pool, owner)
     }
 
     pub fn from_vec(exec: (&Executor, &Executor), vec: Vec<f32>) -> Self {
         let len = vec.len();
-        let capacity = vec.capacity();
-        let gpu = match exec {
-            (GPU(q), _) | (_, GPU(q)) => {
-                let buf = cl_utils::new_buffer(q, vec.len());
-                cl_utils::buf_write(&buf, &vec);
-                Some(Rc::new(RefCell::new(buf)))
-            },
-            _ => None,
-        };
+        let (pool, owner) = (pool_of(exec), pool::owner());
+        let gpu = gpu_of(exec).map(|q| {
+            let buf = pool.buffer(q.queue(), &owner, len);
+            cl_utils::buf_write(&buf, &vec);
+            buf
+        });
         // Ensures that only a copy on both the GPU and CPU are made only if it will
         // be used on both, otherwise it would waste memory creating unused copies.
-        let cpu = match exec {
-            (Executor::CPU, _) | (_, Executor::CPU) => Some(Rc::new(RefCell::new(vec))),
-            _ => { None },
+        let cpu = on_cpu(exec).then(|| {
+            pool.adopt_vec(&owner, &vec);
+            vec
+        });
+        Self::with_storage(len, cpu, gpu, exec, pool, owner)
+    }
+
+    fn with_storage(len: usize, cpu: Option<Vec<f32>>, gpu: Option<Buffer<f32>>, exec: (&Executor, &Executor), pool: Arc<MemoryPool>, owner: Arc<str>) -> Self {
+        let storage = Storage {
+            pending: RefCell::new(None),
+            cpu: OnceCell::new(),
+            gpu: gpu.map(RefCell::new),
+            cpu_newer: Cell::new(false),
+            gpu_newer: Cell::new(false),
+            pool,
+            owner,
+            profiler: gpu_of(exec).map(|gpu| gpu.profiler().clone()),
         };
+        if let Some(vec) = cpu {
+            let _ = storage.cpu.set(RefCell::new(vec));
+        }
 
         DualVec {
             len,
             capacity: len,
-            cpu: (cpu, false),
-            gpu: (gpu, false),
+            storage: Rc::new(storage),
         }
     }
 
@@ -80,80 +154,197 @@ impl DualVec {
     pub fn randomize(&mut self, exec: &Executor, div: f32) {
         match exec {
             GPU(p) => {
-                if let Some(buf) = &self.gpu.0 {
-                    cl_utils::randomize_buffer(&*buf.borrow(), 256, div, p);
+                self.order_device();
+                if let Some(buf) = &self.storage.gpu {
+                    cl_utils::randomize_buffer(&buf.borrow(), 256, div, p);
                     self.updated_gpu();
                 }
             }
-            Executor::CPU => {
+            Executor::CPU | Executor::Threaded(_) => {
+                let len = self.len;
                 if let Some(vec) = self.cpu() {
-                    for i in 0..self.len {
-                        vec.borrow_mut()[i] = (random::<f32>() * 2.0 - 1.0) / div;
+                    let mut vec = vec.borrow_mut();
+                    for i in 0..len {
+                        vec[i] = (random::<f32>() * 2.0 - 1.0) / div;
                     }
-                    self.updated_cpu();
                 }
+                self.updated_cpu();
             }
         }
     }
 
     pub fn clear(&mut self) {
-        if self.cpu.1 {
-            self.cpu.0.as_mut().unwrap().borrow_mut().fill(0.);
-            self.cpu.1 = false;
+        self.wait_host();
+        self.order_device();
+        if self.storage.cpu_newer.take() {
+            self.storage.cpu.get().unwrap().borrow_mut().fill(0.);
         }
-        if self.gpu.1 {
-            self.gpu.0.as_ref().unwrap().borrow_mut().cmd().fill(0., None).enq().unwrap();
-            self.gpu.1 = false;
+        if self.storage.gpu_newer.take() {
+            self.storage.gpu.as_ref().unwrap().borrow_mut().cmd().fill(0., None).enq().unwrap();
         }
     }
 
-    pub fn gpu(&mut self) -> Option<Rc<RefCell<Buffer<f32>>>> {
-        if self.gpu.0.is_none() {
-            // Can't create a GPU buffer, since a Queue is required and should have been when creating the buffer
-            return None;
+    /// The device buffer, copying the host values to it first when they are newer.
+    ///
+    /// The copy is asynchronous, the kernels enqueued afterwards wait for it on the device and the
+    /// host only waits for it once the host values are touched again.
+    pub fn gpu(&mut self) -> Option<&RefCell<Buffer<f32>>> {
+        // Can't create a GPU buffer, since a Queue is required and should have been when creating the buffer
+        self.storage.gpu.as_ref()?;
+
+        self.order_device();
+        if self.storage.cpu_newer.get() {
+            self.write_gpu(None);
         }
+        self.storage.gpu.as_ref()
+    }
 
-        if self.cpu.1 {
-            cl_utils::buf_write(&*self.gpu.0.clone().unwrap().borrow_mut(), &*self.cpu.0.clone().unwrap().borrow_mut());
-            self.cpu.1 = false;
+    /// The host values, copying the values of the device first when they are newer. This only
+    /// blocks when a copy is needed or one started by [DualVec::prefetch_cpu] is still running.
+    pub fn cpu(&mut self) -> Option<&RefCell<Vec<f32>>> {
+        self.wait_host();
+        let cpu = self.alloc_cpu();
+
+        if self.storage.gpu_newer.take() {
+            let start = Instant::now();
+            cl_utils::read_to(&self.storage.gpu.as_ref().unwrap().borrow(), &mut cpu.borrow_mut());
+            self.record(Direction::ToHost, start);
         }
-        self.gpu.0.clone()
+        Some(cpu)
     }
 
-    pub fn cpu(&mut self) -> Option<Rc<RefCell<Vec<f32>>>> {
-        if self.cpu.0.is_none() {
-            let mut vec = vec![0.; self.len];
-            vec.reserve_exact(self.capacity - self.len);
-            self.cpu.0.replace(Rc::new(RefCell::new(vec)));
+    /// Copies the values to the side used by `exec` when the other side is newer, so the copy is
+    /// made here rather than where they are used next. Copies to the device don't block, like
+    /// those of [DualVec::gpu].
+    pub fn sync_to(&mut self, exec: &Executor) {
+        match exec {
+            GPU(_) => {
+                self.gpu();
+            }
+            Executor::CPU | Executor::Threaded(_) => {
+                self.cpu();
+            }
         }
+    }
 
-        if self.gpu.1 {
-            cl_utils::read_to(&*self.gpu.0.clone().unwrap().borrow_mut(), &mut *self.cpu.0.clone().unwrap().borrow_mut());
-            self.gpu.1 = false;
+    /// Starts copying the host values to the device when they are newer, so they are there by the
+    /// time they are used. The copy runs on the [transfer queue] of the GPU, and isn't ordered
+    /// after the kernels already enqueued, so the buffer must not be in use by those, like the
+    /// inputs of the next batch while the current one is computed.
+    ///
+    /// [transfer queue]: crate::gpu::Gpu::transfer_queue
+    pub fn prefetch_gpu(&mut self, exec: &Executor) {
+        if let GPU(gpu) = exec
+            && self.storage.cpu_newer.get()
+            && self.storage.gpu.is_some() {
+            self.write_gpu(Some(gpu.transfer_queue()));
         }
-        self.cpu.0.clone()
+    }
+
+    /// Starts copying the values of the device to the host when they are newer, once the kernels
+    /// already enqueued are done, so reading them later with [DualVec::cpu] doesn't have to wait.
+    pub fn prefetch_cpu(&mut self, exec: &Executor) {
+        if let GPU(gpu) = exec
+            && self.storage.gpu_newer.get() {
+            self.wait_host();
+            let cpu = self.alloc_cpu();
+
+            let buf = self.storage.gpu.as_ref().unwrap().borrow();
+            let mut vec = cpu.borrow_mut();
+            let start = Instant::now();
+            let computed = gpu.queue().enqueue_marker(None::<&Event>)
+                .expect("Failed to enqueue marker");
+            let mut event = Event::empty();
+            // Safety: the vector is only touched or dropped after waiting for the pending copy
+            unsafe {
+                buf.read(&mut *vec)
+                    .queue(gpu.transfer_queue())
+                    .ewait(&computed)
+                    .enew(&mut event)
+                    .block(false)
+                    .enq()
+                    .expect("Failed to read buffer");
+            }
+            self.record(Direction::ToHost, start);
+
+            self.storage.pending.replace(Some(Transfer { event, ordered: false }));
+            self.storage.gpu_newer.set(false);
+        }
+    }
+
+    /// Writes the host values to the device without blocking, on `queue` or the queue of the
+    /// kernels when it is `None`
+    fn write_gpu(&self, queue: Option<&Queue>) {
+        self.wait_host();
+
+        let buf = self.storage.gpu.as_ref().unwrap().borrow();
+        let vec = self.storage.cpu.get().unwrap().borrow();
+        let start = Instant::now();
+        let mut event = Event::empty();
+        // Safety: the vector is only touched or dropped after waiting for the pending copy
+        unsafe {
+            let mut cmd = buf.write(&vec[..]).enew(&mut event).block(false);
+            if let Some(queue) = queue {
+                cmd = cmd.queue(queue);
+            }
+            cmd.enq().expect("Failed to write buffer");
+        }
+        self.record(Direction::ToDevice, start);
+
+        self.storage.pending.replace(Some(Transfer { event, ordered: queue.is_none() }));
+        self.storage.cpu_newer.set(false);
+    }
+
+    /// Records a copy between the sides started at `start` with the profiler of the GPU
+    fn record(&self, direction: Direction, start: Instant) {
+        if let Some(profiler) = &self.storage.profiler {
+            profiler.transfer(direction, self.len * size_of::<f32>(), start.elapsed());
+        }
+    }
+
+    /// Blocks until the pending copy is done, so the host vector can be touched
+    fn wait_host(&self) {
+        // dropping the transfer waits for it
+        self.storage.pending.borrow_mut().take();
+    }
+
+    /// Makes the kernels enqueued from now on wait for a copy on the transfer queue, without
+    /// blocking the host
+    fn order_device(&self) {
+        let mut pending = self.storage.pending.borrow_mut();
+        if let Some(transfer) = pending.as_mut().filter(|transfer| !transfer.ordered)
+            && let Some(queue) = self.storage.gpu.as_ref().and_then(|buf| buf.borrow().default_queue().cloned()) {
+            queue.enqueue_marker(Some(&transfer.event)).expect("Failed to enqueue marker");
+            transfer.ordered = true;
+        }
+    }
+
+    fn alloc_cpu(&self) -> &RefCell<Vec<f32>> {
+        self.storage.cpu.get_or_init(|| {
+            let mut vec = self.storage.pool.vec(&self.storage.owner, self.capacity);
+            vec.truncate(self.len);
+            RefCell::new(vec)
+        })
     }
 
     pub fn cpu_borrow(&mut self) -> Option<RefMut<'_, Vec<f32>>> {
-        self.cpu();
-        self.cpu.0.as_ref().map(|cell| cell.borrow_mut())
+        self.cpu().map(|cell| cell.borrow_mut())
     }
 
 
     pub fn gpu_borrow(&mut self) -> Option<RefMut<'_, Buffer<f32>>> {
-        self.gpu();
-        self.gpu.0.as_ref().map(|cell| cell.borrow_mut())
+        self.gpu().map(|cell| cell.borrow_mut())
     }
 
     pub fn updated_gpu(&mut self) {
-        if self.gpu.0.is_some() {
-            self.gpu.1 = true;
+        if self.storage.gpu.is_some() {
+            self.storage.gpu_newer.set(true);
         }
     }
 
     pub fn updated_cpu(&mut self) {
-        if self.cpu.0.is_some() {
-            self.cpu.1 = true;
+        if self.storage.cpu.get().is_some() {
+            self.storage.cpu_newer.set(true);
         }
     }
 
@@ -165,9 +356,10 @@ impl DualVec {
     }
 
     pub fn truncate_to(&mut self, size: usize) {
+        self.wait_host();
         // No modifications to the GPU buffer are necessary with OpenCL, since all
         // sizes are passed through the kernel parameters, and so no actual change is needed.
-        if let Some(cpu) = &self.cpu.0 {
+        if let Some(cpu) = self.storage.cpu.get() {
             let mut vec = cpu.borrow_mut();
             if vec.len() > size {
                 vec.truncate(size);
@@ -176,18 +368,28 @@ impl DualVec {
         self.len = self.len.min(size);
     }
 
-    /// Expands the necessary GPU and/or CPU storage to have a capacity of size
+    /// Expands or truncates to have a length of size
+    pub fn resize(&mut self, size: usize) {
+        if self.len < size {
+            self.expand_to(size);
+        }
+        // Expanding restores the full capacity, which can be larger than the requested size
+        if self.len > size {
+            self.truncate_to(size);
+        }
+    }
+
+    /// Expands the necessary GPU and/or CPU storage to have a capacity of size. The storage is
+    /// allocated in size classes, so it is only replaced when size is beyond its class.
     pub fn expand_to(&mut self, size: usize) {
-        if let Some(gpu) = &self.gpu.0 {
+        self.wait_host();
+        let Storage { gpu, cpu, pool, owner, .. } = &*self.storage;
+        if let Some(gpu) = gpu {
             let mut new_buf = None;
             {
                 let buf = gpu.borrow_mut();
                 if buf.len() < size {
-                    let tmp_buffer = Buffer::builder()
-                        .queue(buf.default_queue().unwrap().clone())
-                        .len(size)
-                        .build()
-                        .expect("Failed to create new buffer");
+                    let tmp_buffer = pool.buffer(buf.default_queue().unwrap(), owner, size);
 
                     buf.copy(&tmp_buffer, None, None).enq()
                         .expect("Failed to copy buffer");
@@ -196,16 +398,19 @@ impl DualVec {
                 }
             }
             if let Some(buf) = new_buf {
-                gpu.replace(buf);
+                let old = gpu.replace(buf);
+                pool.recycle_buffer(owner, old);
             }
         }
 
-        if let Some(cpu) = &self.cpu.0 {
+        if let Some(cpu) = cpu.get() {
             let mut vec = cpu.borrow_mut();
 
-            let capacity = vec.capacity();
-            if capacity < size {
-                vec.reserve_exact(size - capacity);
+            if vec.capacity() < size {
+                let mut tmp_vec = pool.vec(owner, size);
+                tmp_vec[..vec.len()].copy_from_slice(&vec);
+                let old = std::mem::replace(&mut *vec, tmp_vec);
+                pool.recycle_vec(owner, old);
             }
             if vec.len() < size {
                 vec.resize(size, 0.);
@@ -216,13 +421,19 @@ impl DualVec {
     }
 }
 
-impl Clone for DualVec {
-    fn clone(&self) -> Self {
-        Self {
-            len: self.len,
-            capacity: self.capacity,
-            cpu: self.cpu.clone(),
-            gpu: self.gpu.clone(),
-        }
+/// The GPU of either executor, where the buffer is kept
+fn gpu_of<'a>(exec: (&'a Executor, &'a Executor)) -> Option<&'a Gpu> {
+    match exec {
+        (GPU(q), _) | (_, GPU(q)) => Some(q),
+        _ => None,
     }
-}
\ No newline at end of file
+}
+
+/// Whether either executor is on the CPU, and so needs a copy on the host
+fn on_cpu(exec: (&Executor, &Executor)) -> bool {
+    matches!(exec, (Executor::CPU | Executor::Threaded(_), _) | (_, Executor::CPU | Executor::Threaded(_)))
+}
+
+fn pool_of(exec: (&Executor, &Executor)) -> Arc<MemoryPool> {
+    gpu_of(exec).map_or(exec.0.pool(), |gpu| gpu.pool()).clone()
+}
@@ -1,3 +1,6 @@
+use crate::format::Kind;
+use crate::tensor::DType;
+
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -9,22 +12,103 @@ pub enum Error {
     Mismatch(MismatchError),
     #[error("{0}")]
     Decode(DecodeError),
+    #[error("{0}")]
+    Tensor(TensorError),
+    #[error("The OpenCL device failed: {0}")]
+    Gpu(ocl::Error),
 }
 
 #[derive(Debug, thiserror::Error)]
 pub enum DecodeError {
     #[error("The encountered layer type ({0}) does not match any known types")]
     InvalidLayerType(usize),
+    #[error("Autograd layers are defined by code, so they can't be read from bytes")]
+    Autograd,
+    #[error("The bytes are neither a model file nor a network written before model files were versioned")]
+    Format,
+    #[error("The format version ({0}) is newer than the latest version this reader supports ({1})")]
+    Version(usize, usize),
+    #[error("The checksum of the file ({0:08x}) does not match its contents ({1:08x})")]
+    Checksum(u32, u32),
+    #[error("The file ends before its last section")]
+    Truncated,
+    #[error("The encountered network kind ({0}) does not match any known kinds")]
+    InvalidKind(usize),
+    #[error("The file holds a {0:?} network rather than a {1:?} one")]
+    Kind(Kind, Kind),
+    #[error("Layer ({0}) read {1} bytes of its section of {2} bytes")]
+    Section(usize, usize, usize),
+    #[error("The graph node ({0}) uses an input or a node which is not defined before it")]
+    InvalidSource(usize),
+    #[error("The graph node ({0}) has {1} inputs, but layer nodes take exactly one and merge nodes at least one")]
+    Sources(usize, usize),
+    #[error("The graph output ({0}) is not a node of the graph")]
+    InvalidOutput(usize),
 }
 
 #[derive(Debug, thiserror::Error)]
 pub enum MismatchError {
     #[error("Input sample count ({0}) does not match output sample count ({1})")]
     Sample(usize, usize),
+    #[error("Step input size ({0}) does not match the expected size ({1})")]
+    Step(usize, usize),
+    #[error("Merged input sizes ({0} and {1}) do not match")]
+    Merge(usize, usize),
+    #[error("Input size ({0}) is not a multiple of the sample size ({1})")]
+    Input(usize, usize),
 }
 
 #[derive(Debug, thiserror::Error)]
 pub enum NetworkError {
     #[error("No layers were provided when creating the network")]
-    ZeroLayers
-}
\ No newline at end of file
+    ZeroLayers,
+    #[error("The shape ({0:?}) has a dimension of 0")]
+    ZeroDimension(Vec<usize>),
+    #[error("The layer type ({0}) can't process a sequence one position at a time")]
+    Incremental(usize),
+    #[error("Position ({0}) is beyond the sequence length of the layer ({1})")]
+    Position(usize, usize),
+    #[error("Samples of {0} inputs can't be split into {1} time steps")]
+    Timesteps(usize, usize),
+    #[error("Attention which isn't causal attends to later positions, so it can't process a sequence one position at a time")]
+    NotCausal,
+    #[error("The graph node ({0}) is not defined")]
+    UnknownNode(String),
+    #[error("The graph node ({0}) is defined more than once")]
+    DuplicateNode(String),
+    #[error("The graph contains a cycle through the node ({0})")]
+    CyclicGraph(String),
+    #[error("The layer node ({0}) has {1} inputs, but layers take exactly one")]
+    LayerInputs(String, usize),
+    #[error("The merge node ({0}) has no inputs, but merges take at least one")]
+    MergeInputs(String),
+    #[error("The layer type ({0}) can't be frozen for shared inference")]
+    Freeze(usize),
+    #[error("The layer type ({0}) can't keep its values in {1:?} on its executor")]
+    Precision(usize, DType),
+    #[error("No executors were provided to replicate the network on")]
+    ZeroReplicas,
+    #[error("The layer type ({0}) can't share its gradients between replicas")]
+    Replicate(usize),
+    #[error("The batch size ({0}) can't be split between {1} replicas")]
+    Replicas(usize, usize),
+    #[error("The token id ({0}) is not a row of the embedding table of {1} rows")]
+    Token(f32, usize),
+}
+#[derive(Debug, thiserror::Error)]
+pub enum TensorError {
+    #[error("The shape ({0:?}) does not fit the {1} values of the tensor")]
+    Shape(Vec<usize>, usize),
+    #[error("Row ({0}) is beyond the rows of the tensor ({1})")]
+    Row(usize, usize),
+    #[error("Sample offset ({0}) is beyond the values of the tensor ({1})")]
+    Offset(usize, usize),
+    #[error("Dimension ({0}) is beyond the dimensions of the tensor ({1})")]
+    Dimension(usize, usize),
+    #[error("The values of the tensor are not contiguous")]
+    NotContiguous,
+    #[error("The shapes ({0:?} and {1:?}) can't be broadcast together")]
+    Broadcast(Vec<usize>, Vec<usize>),
+    #[error("The shapes ({0:?} and {1:?}) can't be multiplied as matrices")]
+    Product(Vec<usize>, Vec<usize>),
+}
@@ -0,0 +1,274 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::{Executor, Execs};
+use crate::error::{DecodeError, Error};
+use crate::Executor::{CPU, GPU, Threaded};
+use crate::layer;
+use crate::layer::Layer;
+use crate::tensor::DType;
+use crate::utils::vec_utils::{CursorReader, VecWriter};
+
+/// The first bytes of every model file
+pub const MAGIC: [u8; 4] = *b"NRXM";
+/// The version model files are written in
+pub const VERSION: usize = 1;
+
+/// The kind of network a model file holds
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Sequential,
+    Graph,
+}
+
+impl TryFrom<usize> for Kind {
+    type Error = Error;
+
+    fn try_from(value: usize) -> Result<Self, Error> {
+        match value {
+            0 => Ok(Kind::Sequential),
+            1 => Ok(Kind::Graph),
+            v => Err(Error::Decode(DecodeError::InvalidKind(v))),
+        }
+    }
+}
+
+impl From<Kind> for usize {
+    fn from(value: Kind) -> Self {
+        match value {
+            Kind::Sequential => 0,
+            Kind::Graph => 1,
+        }
+    }
+}
+
+/// The kind of executor `exec` is, as written with the layers that run on it
+pub(crate) fn exec_kind(exec: &Executor) -> usize {
+    match exec {
+        CPU => 0,
+        GPU(_) => 1,
+        Threaded(_) => 2,
+    }
+}
+
+/// The values of a layer, along with its type and how it was placed
+#[derive(Clone, Debug)]
+pub struct LayerSection {
+    /// The type of the layer, see [Layer::id]
+    pub id: usize,
+    /// The kind of executor the layer ran on: 0 for the CPU, 1 for a GPU and 2 for a threaded executor
+    pub exec: usize,
+    /// The threads of a threaded executor, 1 for the others and 0 when the file doesn't record them
+    pub threads: usize,
+    pub precision: DType,
+    /// The values as written by [Layer::as_bytes]
+    pub bytes: Vec<u8>,
+}
+
+impl LayerSection {
+    pub(crate) fn of(layer: &mut dyn Layer) -> Self {
+        let mut writer = VecWriter::new();
+        layer.as_bytes(&mut writer);
+        LayerSection {
+            id: layer.id(),
+            exec: exec_kind(layer.exec()),
+            threads: layer.exec().threads(),
+            precision: layer.precision(),
+            bytes: writer.vec(),
+        }
+    }
+
+    /// The executor to place the layer on, which is `gpu` for GPU layers and a threaded executor
+    /// of the written thread count for threaded layers, using a thread for every core when the
+    /// count isn't known
+    pub(crate) fn executor(&self, gpu: &Arc<Executor>, cpu: &Arc<Executor>) -> Arc<Executor> {
+        match (self.exec, self.threads) {
+            (1, _) => gpu.clone(),
+            (2, 0) => Arc::new(Executor::threaded()),
+            (2, threads) => Arc::new(Threaded(threads)),
+            _ => cpu.clone(),
+        }
+    }
+
+    /// Reads the layer `index` of a network, which has to read exactly the bytes of its section
+    pub(crate) fn read(&self, index: usize, exec: &Execs) -> Result<Rc<RefCell<dyn Layer>>, Error> {
+        let mut reader = CursorReader::new(&self.bytes);
+        let layer = layer::read_layer(self.id, exec, &mut reader)?;
+        let read = reader.pos() + reader.past_end();
+        if read != self.bytes.len() {
+            return Err(Error::Decode(DecodeError::Section(index, read, self.bytes.len())));
+        }
+
+        if self.precision != DType::F32 {
+            layer.borrow_mut().set_precision(self.precision)?;
+        }
+        Ok(layer)
+    }
+}
+
+/// A network as stored in a file, with the values of every layer in a section of its own.
+///
+/// The file starts with [MAGIC] and the format version, followed by the kind of network, the
+/// metadata, the topology of graph networks and the layer sections, and ends with a CRC32 of all
+/// the bytes before it. The sections are prefixed with their length, so readers can skip the
+/// layers they don't know, and a layer which reads its values differently is caught rather than
+/// misreading the layers after it.
+#[derive(Clone, Debug)]
+pub struct ModelFile {
+    pub kind: Kind,
+    /// The version the file was read from, 0 for bytes written before the format was versioned.
+    /// Files are always written in [VERSION].
+    pub version: usize,
+    /// Free-form values, such as the training configuration, the author or a hash of the dataset
+    pub metadata: BTreeMap<String, String>,
+    /// How the nodes of a graph network are connected, empty for sequential networks
+    pub topology: Vec<u8>,
+    pub layers: Vec<LayerSection>,
+}
+
+impl ModelFile {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut writer = VecWriter::new();
+
+        writer.u32(u32::from_be_bytes(MAGIC));
+        writer.usize(VERSION);
+        writer.index(self.kind);
+
+        writer.usize(self.metadata.len());
+        for (key, value) in &self.metadata {
+            writer.string(key);
+            writer.string(value);
+        }
+        writer.bytes(&self.topology);
+
+        writer.usize(self.layers.len());
+        for section in &self.layers {
+            writer.usize(section.id);
+            writer.usize(section.exec);
+            writer.usize(section.threads);
+            writer.index(section.precision);
+            writer.bytes(&section.bytes);
+        }
+
+        let checksum = crc32(writer.as_slice());
+        writer.u32(checksum);
+        writer.vec()
+    }
+
+    /// Reads a file holding a network of `kind`, checking its checksum. Sequential networks written
+    /// before the format was versioned are migrated, with empty metadata.
+    pub fn from_bytes(bytes: &[u8], kind: Kind) -> Result<ModelFile, Error> {
+        if !bytes.starts_with(&MAGIC) {
+            return Self::migrate(bytes, kind);
+        }
+
+        if bytes.len() < MAGIC.len() + 4 {
+            return Err(Error::Decode(DecodeError::Truncated));
+        }
+        let (contents, checksum) = bytes.split_at(bytes.len() - 4);
+        let (expected, actual) = (CursorReader::new(checksum).u32(), crc32(contents));
+        if expected != actual {
+            return Err(Error::Decode(DecodeError::Checksum(expected, actual)));
+        }
+
+        let mut reader = CursorReader::new(contents);
+        reader.u32(); // the magic
+
+        let version = reader.usize();
+        if version > VERSION {
+            return Err(Error::Decode(DecodeError::Version(version, VERSION)));
+        }
+        let file_kind = Kind::try_from(reader.usize())?;
+        if file_kind != kind {
+            return Err(Error::Decode(DecodeError::Kind(file_kind, kind)));
+        }
+
+        let metadata: BTreeMap<String, String> = reader.counted(|reader| (reader.string(), reader.string())).into_iter().collect();
+        let topology = reader.bytes().ok_or(Error::Decode(DecodeError::Truncated))?.to_vec();
+
+        let mut layers = vec![];
+        for _ in 0..reader.usize() {
+            layers.push(LayerSection {
+                id: reader.usize(),
+                exec: reader.usize(),
+                threads: reader.usize(),
+                precision: reader.indexed(),
+                bytes: reader.bytes().ok_or(Error::Decode(DecodeError::Truncated))?.to_vec(),
+            });
+        }
+        if reader.past_end() > 0 {
+            return Err(Error::Decode(DecodeError::Truncated));
+        }
+
+        Ok(ModelFile {
+            kind,
+            version,
+            metadata,
+            topology,
+            layers,
+        })
+    }
+
+    /// Reads the bytes of sequential networks written before the format was versioned, which start
+    /// with the layer count and the type and executor kind of every layer. The layers follow each
+    /// other without their lengths, so every layer is read to find where the next one starts.
+    fn migrate(bytes: &[u8], kind: Kind) -> Result<ModelFile, Error> {
+        // the counts are far below 2^32, so their first bytes are zero, and graphs were always versioned
+        if kind != Kind::Sequential || bytes.iter().take(4).any(|b| *b != 0) {
+            return Err(Error::Decode(DecodeError::Format));
+        }
+
+        let mut reader = CursorReader::new(bytes);
+        let placements: Vec<(usize, usize)> = reader.counted(|reader| (reader.usize(), reader.usize()));
+
+        let cpu = Arc::new(CPU);
+        let mut layers = vec![];
+        for (id, exec) in placements {
+            let start = reader.pos();
+            layer::read_layer(id, &Execs::all(&cpu), &mut reader)?;
+            layers.push(LayerSection {
+                id,
+                exec,
+                threads: 0,
+                precision: DType::F32,
+                bytes: bytes[start..reader.pos()].to_vec(),
+            });
+        }
+        if reader.past_end() > 0 {
+            return Err(Error::Decode(DecodeError::Truncated));
+        }
+
+        Ok(ModelFile {
+            kind,
+            version: 0,
+            metadata: BTreeMap::new(),
+            topology: vec![],
+            layers,
+        })
+    }
+}
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { 0xedb8_8320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// The CRC32 of `bytes`, with the polynomial used by zip and PNG
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, b| CRC_TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8))
+}
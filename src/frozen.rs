@@ -0,0 +1,105 @@
+use std::cell::RefCell;
+use std::fmt::Debug;
+
+use crate::error::{Error, MismatchError};
+
+/// The inference form of a layer, with its values copied out of the layer so it can be shared
+/// between threads. Frozen layers always compute on the CPU.
+pub trait FrozenLayer: Debug + Send + Sync {
+    fn input_size(&self) -> usize;
+    fn output_size(&self) -> usize;
+
+    /// Computes the outputs of every sample of `inputs` into `outputs`, which has room for all of them.
+    /// Intermediate values are kept in buffers taken from `scratch`.
+    fn forward(&self, inputs: &[f32], outputs: &mut [f32], scratch: &mut Scratch);
+
+    /// Checks the inputs before the forward pass, see [Layer::prepare](crate::layer::Layer::prepare)
+    fn check_inputs(&self, inputs: &[f32]) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Buffers for the activations and intermediate values of a forward pass, which are reused
+/// between passes instead of being allocated every time. Every thread needs its own.
+#[derive(Debug, Default)]
+pub struct Scratch {
+    buffers: Vec<Vec<f32>>,
+}
+
+impl Scratch {
+    pub fn new() -> Self {
+        Scratch::default()
+    }
+
+    /// A zeroed buffer of `len` values, reusing a buffer given back earlier
+    pub(crate) fn take(&mut self, len: usize) -> Vec<f32> {
+        let mut buffer = self.buffers.pop().unwrap_or_default();
+        buffer.clear();
+        buffer.resize(len, 0.);
+        buffer
+    }
+
+    /// Returns a buffer taken with [Scratch::take], so later passes can use it again
+    pub(crate) fn give(&mut self, buffer: Vec<f32>) {
+        self.buffers.push(buffer);
+    }
+}
+
+thread_local! {
+    static SCRATCH: RefCell<Scratch> = RefCell::new(Scratch::new());
+}
+
+/// An immutable copy of a [Network](crate::network::Network) for inference, which can be put in
+/// an `Arc` and used by many threads at once.
+///
+/// Created through [Network::freeze](crate::network::Network::freeze).
+#[derive(Debug)]
+pub struct FrozenNetwork {
+    layers: Vec<Box<dyn FrozenLayer>>,
+}
+
+impl FrozenNetwork {
+    pub(crate) fn new(layers: Vec<Box<dyn FrozenLayer>>) -> Self {
+        FrozenNetwork { layers }
+    }
+
+    pub fn input_size(&self) -> usize {
+        self.layers[0].input_size()
+    }
+
+    pub fn output_size(&self) -> usize {
+        self.layers.last().unwrap().output_size()
+    }
+
+    /// Predicts every sample of `inputs`, using the scratch buffers of the calling thread
+    pub fn predict(&self, inputs: &[f32]) -> Result<Vec<f32>, Error> {
+        SCRATCH.with_borrow_mut(|scratch| self.predict_with(scratch, inputs))
+    }
+
+    /// Predicts every sample of `inputs`, using the buffers of `scratch`
+    pub fn predict_with(&self, scratch: &mut Scratch, inputs: &[f32]) -> Result<Vec<f32>, Error> {
+        if !inputs.len().is_multiple_of(self.input_size()) {
+            return Err(Error::Mismatch(MismatchError::Input(inputs.len(), self.input_size())));
+        }
+        let samples = inputs.len() / self.input_size();
+        self.layers[0].check_inputs(inputs)?;
+
+        let mut outputs = scratch.take(samples * self.layers[0].output_size());
+        self.layers[0].forward(inputs, &mut outputs, scratch);
+        for layer in &self.layers[1..] {
+            if let Err(err) = layer.check_inputs(&outputs) {
+                scratch.give(outputs);
+                return Err(err);
+            }
+            let mut next = scratch.take(samples * layer.output_size());
+            layer.forward(&outputs, &mut next, scratch);
+            scratch.give(outputs);
+            outputs = next;
+        }
+
+        // the buffer is kept for the next prediction, so only the predictions are allocated
+        let predictions = outputs.to_vec();
+        scratch.give(outputs);
+        Ok(predictions)
+    }
+}
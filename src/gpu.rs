@@ -0,0 +1,312 @@
+use std::collections::HashMap;
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use log::warn;
+use ocl::builders::DeviceSpecifier;
+use ocl::enums::{DeviceInfo, ProgramInfo, ProgramInfoResult};
+use ocl::flags::CommandQueueProperties;
+use ocl::{Context, Device, Kernel, Platform, ProQue, Program, Queue};
+
+use crate::pool::MemoryPool;
+use crate::profiler::Profiler;
+use crate::tensor::DType;
+use crate::utils::cl_utils::DeviceLimits;
+
+const SOURCE: &str = include_str!("kernels.c");
+
+/// An OpenCL device with the kernels of the crate built for it.
+///
+/// Dereferences to the [ProQue] of the device, so it can be used wherever one is expected.
+#[derive(Debug)]
+pub struct Gpu {
+    pro_que: ProQue,
+    /// The queue of the asynchronous copies of prefetched data, so they can overlap the kernels
+    transfer: Option<Queue>,
+    limits: DeviceLimits,
+    /// Whether the device has `cl_khr_fp16`
+    fp16: bool,
+    deterministic: bool,
+    cache_dir: Option<PathBuf>,
+    /// The specialized programs built so far, by their compiler options
+    programs: Mutex<HashMap<String, Program>>,
+    /// The kernels of the [ops](crate::ops) built so far, by their names
+    kernels: Mutex<HashMap<&'static str, Kernel>>,
+    pool: Arc<MemoryPool>,
+    profiler: Arc<Profiler>,
+}
+
+impl Gpu {
+    pub fn builder() -> GpuBuilder {
+        GpuBuilder {
+            device: None,
+            deterministic: false,
+            profiling: false,
+            transfer_queue: false,
+            cache_dir: None,
+        }
+    }
+
+    /// Whether sums are computed in a fixed order, so training gives the same results on every run
+    pub fn deterministic(&self) -> bool {
+        self.deterministic
+    }
+
+    pub fn pro_que(&self) -> &ProQue {
+        &self.pro_que
+    }
+
+    /// The queue prefetched data is copied on, which is the queue of the kernels unless the GPU was
+    /// built with [GpuBuilder::transfer_queue]
+    pub fn transfer_queue(&self) -> &Queue {
+        self.transfer.as_ref().unwrap_or(self.pro_que.queue())
+    }
+
+    /// The pool the buffers on the device and their copies on the host are allocated from
+    pub fn pool(&self) -> &Arc<MemoryPool> {
+        &self.pool
+    }
+
+    /// The profiling mode of the GPU, shared with the vectors which have a copy on it
+    pub(crate) fn profiler(&self) -> &Arc<Profiler> {
+        &self.profiler
+    }
+
+    pub fn limits(&self) -> &DeviceLimits {
+        &self.limits
+    }
+
+    /// Whether values can be stored in the half precision `dtype` on the device. IEEE halves need
+    /// `cl_khr_fp16`, while bfloat16 values are converted with integer operations.
+    pub fn supports(&self, dtype: DType) -> bool {
+        match dtype {
+            DType::F32 | DType::BF16 => true,
+            DType::F16 => self.fp16,
+            DType::F64 => false,
+        }
+    }
+
+    /// The width of the square work groups of the matrix product kernels, which their
+    /// `__local` tiles are sized for
+    pub fn tile(&self) -> usize {
+        self.limits.tile()
+    }
+
+    /// The work items of the tree reductions, which their `__local` sums are sized for
+    pub fn reduction(&self) -> usize {
+        self.limits.reduction()
+    }
+
+    /// The kernels compiled with every name of `defines` defined to its value, so the kernels which
+    /// support it can use them as constants instead of their arguments.
+    ///
+    /// Programs are only built once for the same defines, and are loaded from the cache on disk
+    /// when they were built on an earlier run.
+    pub fn program(&self, defines: &[(&str, u64)]) -> Result<Program, ocl::Error> {
+        if defines.is_empty() {
+            return Ok(self.pro_que.program().clone());
+        }
+
+        let options = define_options(defines);
+        let mut programs = self.programs.lock().unwrap();
+        if let Some(program) = programs.get(&options) {
+            return Ok(program.clone());
+        }
+
+        let program = build_program(self.pro_que.context(), self.pro_que.device(), &options, self.cache_dir.as_deref())?;
+        programs.insert(options, program.clone());
+        Ok(program)
+    }
+
+    /// Runs `f` with the kernel called `name`, which is built by `build` the first time and kept for
+    /// the later calls. `f` sets the arguments of the kernel, so it is locked until `f` returns.
+    pub(crate) fn with_kernel<T>(&self, name: &'static str, build: impl FnOnce(&ProQue) -> Kernel, f: impl FnOnce(&Kernel) -> T) -> T {
+        let mut kernels = self.kernels.lock().unwrap();
+        let kernel = kernels.entry(name).or_insert_with(|| build(&self.pro_que));
+        f(kernel)
+    }
+}
+
+impl Deref for Gpu {
+    type Target = ProQue;
+
+    fn deref(&self) -> &ProQue {
+        &self.pro_que
+    }
+}
+
+pub struct GpuBuilder {
+    device: Option<DeviceSpecifier>,
+    deterministic: bool,
+    profiling: bool,
+    transfer_queue: bool,
+    cache_dir: Option<PathBuf>,
+}
+
+impl GpuBuilder {
+    /// The device to build the kernels for, the first device of the first platform is used by default
+    pub fn device<D: Into<DeviceSpecifier>>(mut self, device: D) -> Self {
+        self.device = Some(device.into());
+        self
+    }
+
+    /// Replaces the atomic additions of the kernels with tree reductions in a fixed order.
+    ///
+    /// The order of atomic float additions depends on the scheduling of the device, so the
+    /// results of training differ slightly between runs. Deterministic reductions are a bit
+    /// slower, but make GPU training reproducible run to run.
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    /// Creates the queue with profiling enabled, so [profiling](crate::Executor::enable_profiling)
+    /// records the time every kernel takes on the device
+    pub fn profiling(mut self, profiling: bool) -> Self {
+        self.profiling = profiling;
+        self
+    }
+
+    /// Creates a second queue for the copies started by [DualVec::prefetch_gpu] and
+    /// [DualVec::prefetch_cpu], so devices which can copy and compute at the same time can upload the
+    /// next batch while the current one is computed.
+    ///
+    /// [DualVec::prefetch_gpu]: crate::dual_vec::DualVec::prefetch_gpu
+    /// [DualVec::prefetch_cpu]: crate::dual_vec::DualVec::prefetch_cpu
+    pub fn transfer_queue(mut self, transfer_queue: bool) -> Self {
+        self.transfer_queue = transfer_queue;
+        self
+    }
+
+    /// The directory the compiled programs are cached in, so they aren't compiled again on later
+    /// runs. The cache is disabled by default, and `None` disables it again.
+    ///
+    /// The cached binaries are loaded as they are, so the directory must only be writable by the
+    /// user running the program. When it doesn't exist it is created only accessible to the user.
+    pub fn cache_dir<P: Into<PathBuf>>(mut self, dir: Option<P>) -> Self {
+        self.cache_dir = dir.map(|dir| dir.into());
+        self
+    }
+
+    pub fn build(self) -> Result<Gpu, ocl::Error> {
+        // the default platform panics when there is none, rather than returning an error
+        let platform = Platform::first()?;
+        let device = match self.device {
+            Some(device) => *device.to_device_list(Some(platform))?
+                .first()
+                .ok_or("No device matches the device specifier")?,
+            None => Device::first(platform)?,
+        };
+
+        let context = Context::builder().platform(platform).devices(device).build()?;
+        let properties = self.profiling.then_some(CommandQueueProperties::PROFILING_ENABLE);
+        let queue = Queue::new(&context, device, properties)?;
+        let transfer = match self.transfer_queue {
+            true => Some(Queue::new(&context, device, properties)?),
+            false => None,
+        };
+        let program = build_program(&context, device, "", self.cache_dir.as_deref())?;
+        let limits = DeviceLimits::of(&device)?;
+        let fp16 = device.info(DeviceInfo::Extensions)?.to_string().contains("cl_khr_fp16");
+
+        Ok(Gpu {
+            pro_que: ProQue::new(context, queue, program, None::<usize>),
+            transfer,
+            limits,
+            fp16,
+            deterministic: self.deterministic,
+            cache_dir: self.cache_dir,
+            programs: Mutex::new(HashMap::new()),
+            kernels: Mutex::new(HashMap::new()),
+            pool: Arc::new(MemoryPool::default()),
+            profiler: Arc::new(Profiler::default()),
+        })
+    }
+}
+
+/// The compiler options defining every name of `defines` to its value
+pub fn define_options(defines: &[(&str, u64)]) -> String {
+    defines.iter()
+        .map(|(name, value)| format!("-D {}={}", name, value))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Builds the kernels for `device` with the compiler `options`. The binary is loaded from `cache_dir`
+/// when it was built before for the same device, source and options, otherwise it is compiled and
+/// saved there.
+fn build_program(context: &Context, device: Device, options: &str, cache_dir: Option<&Path>) -> Result<Program, ocl::Error> {
+    let path = cache_dir.map(|dir| cache_path(dir, cache_key(options, &device_info(device))));
+
+    if let Some(binary) = path.as_ref().and_then(|path| fs::read(path).ok()) {
+        let binaries = [&binary[..]];
+        match Program::builder().devices(device).binaries(&binaries).cmplr_opt(options).build(context) {
+            Ok(program) => return Ok(program),
+            // drivers can reject the binaries of their older versions
+            Err(err) => warn!("Failed to load the cached kernels, compiling them again: {}", err),
+        }
+    }
+
+    let program = Program::builder().devices(device).src(SOURCE).cmplr_opt(options).build(context)?;
+    if let Some(path) = path
+        && let Err(err) = save_binary(&program, &path) {
+        warn!("Failed to cache the compiled kernels at {}: {}", path.display(), err);
+    }
+    Ok(program)
+}
+
+fn save_binary(program: &Program, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if let ProgramInfoResult::Binaries(binaries) = program.info(ProgramInfo::Binaries)?
+        && let Some(binary) = binaries.first().filter(|binary| !binary.is_empty()) {
+        write_cached(path, binary)?;
+    }
+    Ok(())
+}
+
+/// The file in `dir` the binary of the cache key `key` is kept in
+pub fn cache_path(dir: &Path, key: u64) -> PathBuf {
+    dir.join(format!("{:016x}.bin", key))
+}
+
+/// Writes `binary` to `path`, creating its missing directories only accessible to the user
+pub fn write_cached(path: &Path, binary: &[u8]) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        create_private_dir(dir)?;
+    }
+    // written to a temporary file first, so other processes never load a partial binary
+    let temp = path.with_extension(format!("{}.tmp", std::process::id()));
+    fs::write(&temp, binary)?;
+    fs::rename(&temp, path)
+}
+
+/// Creates `dir` and its missing parents, with the directory itself only accessible to the user
+fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(dir)
+}
+
+/// The name, vendor, version and driver version of `device`
+fn device_info(device: Device) -> Vec<String> {
+    let info = |info| device.info(info).map(|info| info.to_string()).unwrap_or_default();
+    vec![info(DeviceInfo::Name), info(DeviceInfo::Vendor), info(DeviceInfo::Version), info(DeviceInfo::DriverVersion)]
+}
+
+/// A hash of everything that decides the compiled binary: the source, the compiler `options` and
+/// the `device` info. It uses FNV-1a rather than the hasher of the standard library, whose results
+/// can change between versions of Rust.
+pub fn cache_key(options: &str, device: &[String]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for part in [SOURCE, options].into_iter().chain(device.iter().map(String::as_str)) {
+        // the length separates the parts, so moving bytes between them changes the hash
+        for byte in (part.len() as u64).to_le_bytes().iter().chain(part.as_bytes()) {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
@@ -0,0 +1,872 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::{Executor, Execs, Optimizer};
+use crate::dual_vec::DualVec;
+use crate::error::{DecodeError, Error, MismatchError, NetworkError};
+use crate::format;
+use crate::format::{Kind, LayerSection, ModelFile, VERSION};
+use crate::Executor::{CPU, GPU, Threaded};
+use crate::layer;
+use crate::layer::{Layer, LayerType};
+use crate::loss::Loss;
+use crate::metric::{History, Metric};
+use crate::network::Batches;
+use crate::pool;
+use crate::profiler;
+use crate::tensor::{DType, Tensor};
+use crate::utils::vec_utils::{CursorReader, VecWriter};
+
+/// The name of the input of a network created with [GraphNetwork::new]
+pub const INPUT: &str = "input";
+
+#[derive(Clone, Debug)]
+pub enum Merge {
+    /// Sums the inputs, which all have the same size
+    Add,
+    /// Joins the inputs of every sample one after another
+    Concat,
+    /// Multiplies the inputs element wise, which all have the same size
+    Multiply,
+}
+
+impl From<usize> for Merge {
+    fn from(value: usize) -> Self {
+        match value {
+            1 => Merge::Concat,
+            2 => Merge::Multiply,
+            _ => Merge::Add,
+        }
+    }
+}
+
+impl From<&Merge> for usize {
+    fn from(merge: &Merge) -> usize {
+        match merge {
+            Merge::Add => 0,
+            Merge::Concat => 1,
+            Merge::Multiply => 2,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum NodeType {
+    Layer(LayerType),
+    Merge(Merge),
+}
+
+/// A named node of a [GraphNetwork], computed from the outputs of the nodes named in `inputs`
+#[derive(Clone, Debug)]
+pub struct GraphNode {
+    name: String,
+    exec: Arc<Executor>,
+    node: NodeType,
+    inputs: Vec<String>,
+}
+
+impl GraphNode {
+    pub fn layer(name: &str, exec: &Arc<Executor>, layer_type: LayerType, input: &str) -> Self {
+        GraphNode {
+            name: name.to_string(),
+            exec: exec.clone(),
+            node: NodeType::Layer(layer_type),
+            inputs: vec![input.to_string()],
+        }
+    }
+
+    /// Merge nodes are computed on the host
+    pub fn merge(name: &str, merge: Merge, inputs: &[&str]) -> Self {
+        GraphNode {
+            name: name.to_string(),
+            exec: Arc::new(CPU),
+            node: NodeType::Merge(merge),
+            inputs: inputs.iter().map(|i| i.to_string()).collect(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct MergeNode {
+    merge: Merge,
+    sizes: Vec<usize>,
+    output_size: usize,
+
+    /// The inputs of the last forward pass, which the gradients of a multiplication depend on
+    inputs: Vec<Vec<f32>>,
+
+    outputs: DualVec,
+    sensitivities: Vec<DualVec>,
+}
+
+impl MergeNode {
+    fn new(merge: Merge, sizes: Vec<usize>) -> Result<Self, Error> {
+        let output_size = match merge {
+            Merge::Concat => sizes.iter().sum(),
+            Merge::Add | Merge::Multiply => {
+                for s in &sizes {
+                    if *s != sizes[0] {
+                        return Err(Error::Mismatch(MismatchError::Merge(sizes[0], *s)));
+                    }
+                }
+                sizes[0]
+            }
+        };
+
+        Ok(MergeNode {
+            merge,
+            output_size,
+            inputs: vec![vec![]; sizes.len()],
+            outputs: DualVec::from_exec(&CPU, output_size),
+            sensitivities: sizes.iter().map(|s| DualVec::from_exec(&CPU, *s)).collect(),
+            sizes,
+        })
+    }
+
+    fn forward(&mut self, sources: &mut [Tensor]) {
+        let batch_size = sources[0].batch_size();
+        self.outputs.resize(batch_size * self.output_size);
+
+        for (k, source) in sources.iter_mut().enumerate() {
+            let size = self.sizes[k];
+            let positions = source.row_offsets();
+            let source = source.data_mut().cpu_borrow().unwrap();
+            self.inputs[k].clear();
+            for p in positions.iter() {
+                self.inputs[k].extend_from_slice(&source[*p..*p + size]);
+            }
+        }
+
+        {
+            let mut outputs = self.outputs.cpu_borrow().unwrap();
+            match self.merge {
+                Merge::Add => {
+                    outputs.fill(0.);
+                    for input in &self.inputs {
+                        for i in 0..outputs.len() {
+                            outputs[i] += input[i];
+                        }
+                    }
+                }
+                Merge::Multiply => {
+                    outputs.fill(1.);
+                    for input in &self.inputs {
+                        for i in 0..outputs.len() {
+                            outputs[i] *= input[i];
+                        }
+                    }
+                }
+                Merge::Concat => {
+                    for batch in 0..batch_size {
+                        let mut offset = batch * self.output_size;
+                        for (k, input) in self.inputs.iter().enumerate() {
+                            let size = self.sizes[k];
+                            outputs[offset..offset + size].copy_from_slice(&input[batch * size..(batch + 1) * size]);
+                            offset += size;
+                        }
+                    }
+                }
+            }
+        }
+
+        self.outputs.updated_cpu();
+    }
+
+    fn backward(&mut self, gradients: &mut DualVec) {
+        let batch_size = gradients.len() / self.output_size;
+        let gradients = gradients.cpu_borrow().unwrap();
+
+        for k in 0..self.sizes.len() {
+            let size = self.sizes[k];
+            self.sensitivities[k].resize(batch_size * size);
+            {
+                let mut sensitivities = self.sensitivities[k].cpu_borrow().unwrap();
+                match self.merge {
+                    Merge::Add => sensitivities.copy_from_slice(&gradients),
+                    Merge::Multiply => {
+                        for i in 0..sensitivities.len() {
+                            let mut product = gradients[i];
+                            for (j, input) in self.inputs.iter().enumerate() {
+                                if j != k {
+                                    product *= input[i];
+                                }
+                            }
+                            sensitivities[i] = product;
+                        }
+                    }
+                    Merge::Concat => {
+                        let start: usize = self.sizes[..k].iter().sum();
+                        for batch in 0..batch_size {
+                            let offset = batch * self.output_size + start;
+                            sensitivities[batch * size..(batch + 1) * size].copy_from_slice(&gradients[offset..offset + size]);
+                        }
+                    }
+                }
+            }
+            self.sensitivities[k].updated_cpu();
+        }
+    }
+}
+
+enum Node {
+    Layer(Rc<RefCell<dyn Layer>>),
+    Merge(MergeNode),
+}
+
+impl Node {
+    fn output_size(&self) -> usize {
+        match self {
+            Node::Layer(l) => l.borrow().output_size(),
+            Node::Merge(m) => m.output_size,
+        }
+    }
+
+    /// A handle sharing the storage of the node's outputs
+    fn output(&self) -> DualVec {
+        match self {
+            Node::Layer(l) => l.borrow_mut().activated_output().clone(),
+            Node::Merge(m) => m.outputs.clone(),
+        }
+    }
+
+    /// The outputs of the node as a batch of samples
+    fn output_tensor(&self) -> Tensor {
+        match self {
+            Node::Layer(l) => l.borrow_mut().output(),
+            Node::Merge(m) => Tensor::batch(m.outputs.clone(), m.output_size),
+        }
+    }
+}
+
+/// Where the values of a node's input come from
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Source {
+    /// One of the network's inputs
+    Input(usize),
+    Node(usize),
+}
+
+/// The targets of an output node while training, with the loss and the weight of that loss
+/// within the combined loss of every head
+pub struct Head<'h> {
+    output: String,
+    targets: &'h mut DualVec,
+    loss: Loss,
+    weight: f32,
+    metrics: Vec<Metric>,
+}
+
+impl<'h> Head<'h> {
+    pub fn new(output: &str, targets: &'h mut DualVec, loss: Loss) -> Self {
+        Head {
+            output: output.to_string(),
+            targets,
+            loss,
+            weight: 1.,
+            metrics: vec![],
+        }
+    }
+
+    pub fn weight(mut self, weight: f32) -> Self {
+        self.weight = weight;
+        self
+    }
+
+    /// Adds a metric to report for this head in the [History]
+    pub fn metric(mut self, metric: Metric) -> Self {
+        self.metrics.push(metric);
+        self
+    }
+}
+
+/// A network of layers and merge nodes connected as a directed acyclic graph, allowing
+/// skip connections, branches, several named inputs and several outputs.
+///
+/// The nodes are computed in topological order, and the gradients of nodes used by multiple others are summed.
+pub struct GraphNetwork {
+    /// The name and size of every input of the network
+    inputs: Vec<(String, usize)>,
+    names: Vec<String>,
+    execs: Vec<Arc<Executor>>,
+    nodes: Vec<Node>,
+    sources: Vec<Vec<Source>>,
+    /// The nodes the network outputs
+    outputs: Vec<usize>,
+    metadata: BTreeMap<String, String>,
+}
+
+impl GraphNetwork {
+    /// Creates a graph with a single input called [INPUT], where `output` names the node the network outputs
+    pub fn new(input_size: usize, graph_nodes: &[GraphNode], output: &str) -> Result<Self, Error> {
+        Self::with_inputs(&[(INPUT, input_size)], graph_nodes, &[output])
+    }
+
+    /// Creates the graph from its nodes in any order, where nodes can use the names of `inputs`
+    /// as their inputs, and `outputs` names the nodes the network outputs
+    pub fn with_inputs(inputs: &[(&str, usize)], graph_nodes: &[GraphNode], outputs: &[&str]) -> Result<Self, Error> {
+        if graph_nodes.is_empty() {
+            return Err(Error::Network(NetworkError::ZeroLayers))
+        }
+
+        let mut indices = HashMap::new();
+        for (k, (name, _)) in inputs.iter().enumerate() {
+            if indices.insert(*name, Source::Input(k)).is_some() {
+                return Err(Error::Network(NetworkError::DuplicateNode(name.to_string())));
+            }
+        }
+        for (i, n) in graph_nodes.iter().enumerate() {
+            if indices.insert(n.name.as_str(), Source::Node(i)).is_some() {
+                return Err(Error::Network(NetworkError::DuplicateNode(n.name.clone())));
+            }
+            match n.node {
+                NodeType::Layer(_) if n.inputs.len() != 1 => {
+                    return Err(Error::Network(NetworkError::LayerInputs(n.name.clone(), n.inputs.len())));
+                }
+                NodeType::Merge(_) if n.inputs.is_empty() => {
+                    return Err(Error::Network(NetworkError::MergeInputs(n.name.clone())));
+                }
+                _ => {}
+            }
+        }
+
+        let mut sources = vec![];
+        for n in graph_nodes {
+            let mut node_sources = vec![];
+            for input in &n.inputs {
+                match indices.get(input.as_str()) {
+                    Some(s) => node_sources.push(*s),
+                    None => return Err(Error::Network(NetworkError::UnknownNode(input.clone()))),
+                }
+            }
+            sources.push(node_sources);
+        }
+
+        let order = topological_order(&sources).map_err(|i| Error::Network(NetworkError::CyclicGraph(graph_nodes[i].name.clone())))?;
+        let mut position = vec![0; order.len()];
+        for (p, i) in order.iter().enumerate() {
+            position[*i] = p;
+        }
+
+        let mut output_nodes = vec![];
+        for output in outputs {
+            match indices.get(output) {
+                Some(Source::Node(i)) => output_nodes.push(position[*i]),
+                _ => return Err(Error::Network(NetworkError::UnknownNode(output.to_string()))),
+            }
+        }
+
+        let mut nodes: Vec<Node> = vec![];
+        let mut sorted_sources = vec![];
+        for i in &order {
+            let n = &graph_nodes[*i];
+            let node_sources: Vec<Source> = sources[*i].iter().map(|s| match s {
+                Source::Node(s) => Source::Node(position[*s]),
+                input => *input,
+            }).collect();
+            let sizes: Vec<usize> = node_sources.iter().map(|s| match s {
+                Source::Input(k) => inputs[*k].1,
+                Source::Node(s) => nodes[*s].output_size(),
+            }).collect();
+
+            let node = match &n.node {
+                NodeType::Layer(layer_type) => {
+                    layer_type.check(sizes[0])?;
+                    let cpu = Arc::new(CPU);
+                    let prev_exec = match node_sources[0] {
+                        Source::Node(s) => &graph_nodes[order[s]].exec,
+                        Source::Input(_) => &cpu,
+                    };
+                    let next_exec = first_consumer(&sources, *i).map_or(&cpu, |c| &graph_nodes[c].exec);
+                    Node::Layer(pool::owned_by(n.name.clone(), || layer_type.layer(&Execs::new(prev_exec, &n.exec, next_exec), sizes[0]).0))
+                }
+                NodeType::Merge(merge) => Node::Merge(MergeNode::new(merge.clone(), sizes)?),
+            };
+
+            nodes.push(node);
+            sorted_sources.push(node_sources);
+        }
+
+        Ok(GraphNetwork {
+            inputs: inputs.iter().map(|(name, size)| (name.to_string(), *size)).collect(),
+            names: order.iter().map(|i| graph_nodes[*i].name.clone()).collect(),
+            execs: order.iter().map(|i| graph_nodes[*i].exec.clone()).collect(),
+            nodes,
+            sources: sorted_sources,
+            outputs: output_nodes,
+            metadata: BTreeMap::new(),
+        })
+    }
+
+    /// Forward pass through every node, with a batch of every input of the network
+    fn forward(&mut self, inputs: &[Tensor]) -> Result<(), Error> {
+        for n in 0..self.nodes.len() {
+            let mut sources: Vec<Tensor> = self.sources[n].iter().map(|s| match s {
+                Source::Input(k) => inputs[*k].clone(),
+                Source::Node(s) => self.nodes[*s].output_tensor(),
+            }).collect();
+            if let Node::Layer(l) = &self.nodes[n] {
+                l.borrow_mut().prepare(&mut sources[0])?;
+            }
+
+            let (name, kind) = (&self.names[n], self.kind(n));
+            profiler::time_layer(&self.execs[n], || format!("{} {} forward", name, kind), || match &mut self.nodes[n] {
+                Node::Layer(l) => l.borrow_mut().forward(&mut sources[0]),
+                Node::Merge(m) => m.forward(&mut sources),
+            });
+        }
+        Ok(())
+    }
+
+    /// The type of the node `n` in profiling reports
+    fn kind(&self, n: usize) -> &'static str {
+        match &self.nodes[n] {
+            Node::Layer(l) => layer::layer_name(l.borrow().id()),
+            Node::Merge(_) => "Merge",
+        }
+    }
+
+    /// Predicts the first output of a network with a single input
+    pub fn predict(&mut self, inputs: &mut DualVec) -> Result<DualVec, Error> {
+        self.forward(&[Tensor::batch(inputs.clone(), self.inputs[0].1)])?;
+        Ok(self.nodes[self.outputs[0]].output())
+    }
+
+    /// Predicts every output of the network, from the inputs called by the names of the network's inputs
+    pub fn predict_multi(&mut self, inputs: &mut [(&str, &mut DualVec)]) -> Result<Vec<DualVec>, Error> {
+        let inputs = self.gather_inputs(inputs)?;
+        let batch: Vec<Tensor> = inputs.into_iter().zip(&self.inputs)
+            .map(|(input, (_, size))| Tensor::batch(input, *size))
+            .collect();
+        self.forward(&batch)?;
+
+        Ok(self.outputs.iter().map(|o| self.nodes[*o].output()).collect())
+    }
+
+    /// The outputs of the node called `name` from the last forward pass
+    pub fn node_output(&self, name: &str) -> Option<DualVec> {
+        self.names.iter().position(|n| n == name).map(|i| self.nodes[i].output())
+    }
+
+    /// The layer of the layer node called `name`
+    pub fn layer(&self, name: &str) -> Option<Rc<RefCell<dyn Layer>>> {
+        match self.names.iter().position(|n| n == name).map(|i| &self.nodes[i]) {
+            Some(Node::Layer(l)) => Some(l.clone()),
+            _ => None,
+        }
+    }
+
+    /// Keeps the values of the layer node called `name` in `dtype`, see [Layer::set_precision]
+    pub fn set_precision(&mut self, name: &str, dtype: DType) -> Result<(), Error> {
+        match self.names.iter().position(|n| n == name).map(|i| &self.nodes[i]) {
+            Some(Node::Layer(l)) => l.borrow_mut().set_precision(dtype),
+            _ => Err(Error::Network(NetworkError::UnknownNode(name.to_string()))),
+        }
+    }
+
+    /// Orders the named inputs like the network's inputs, checking that they have the same amount of samples
+    fn gather_inputs(&self, inputs: &mut [(&str, &mut DualVec)]) -> Result<Vec<DualVec>, Error> {
+        let mut gathered = vec![];
+        for (name, size) in &self.inputs {
+            match inputs.iter().find(|(n, _)| n == name) {
+                Some((_, input)) => {
+                    let samples = gathered.first().map_or(input.len() / size, |first: &DualVec| first.len() / self.inputs[0].1);
+                    if input.len() / size != samples {
+                        return Err(Error::Mismatch(MismatchError::Sample(samples, input.len() / size)))
+                    }
+                    gathered.push((*input).clone());
+                }
+                None => return Err(Error::Network(NetworkError::UnknownNode(name.clone()))),
+            }
+        }
+        Ok(gathered)
+    }
+
+    /// Trains the first output of a network with a single input. Returns the loss of the last epoch,
+    /// the mean over its batches of the summed losses of a batch, like [Network::train](crate::network::Network::train).
+    pub fn train(&mut self, inputs: &mut DualVec, targets: &mut DualVec, optimizer: Optimizer, loss: Loss, epochs: u32, batch_size: usize) -> Result<f32, Error> {
+        let input = self.inputs[0].0.clone();
+        let output = self.names[self.outputs[0]].clone();
+
+        let history = self.train_multi(&mut [(&input, inputs)], vec![Head::new(&output, targets, loss)], optimizer, epochs, batch_size)?;
+        Ok(history.loss.last().copied().unwrap_or(f32::INFINITY))
+    }
+
+    /// Trains on the inputs called by the names of the network's inputs, where the gradients of
+    /// every head are weighted by the head's weight and summed
+    pub fn train_multi(&mut self, inputs: &mut [(&str, &mut DualVec)], mut heads: Vec<Head>, optimizer: Optimizer, epochs: u32, batch_size: usize) -> Result<History, Error> {
+        let inputs = self.gather_inputs(inputs)?;
+        let samples = inputs[0].len() / self.inputs[0].1;
+
+        let mut head_nodes = vec![];
+        for head in &heads {
+            match self.names.iter().position(|n| *n == head.output) {
+                Some(n) => {
+                    let output_size = self.nodes[n].output_size();
+                    if head.targets.len() / output_size != samples {
+                        return Err(Error::Mismatch(MismatchError::Sample(samples, head.targets.len() / output_size)))
+                    }
+                    head_nodes.push(n);
+                }
+                None => return Err(Error::Network(NetworkError::UnknownNode(head.output.clone()))),
+            }
+        }
+
+        let mut batches = Batches::new(samples, batch_size);
+        let batch_size = batches.batch_size();
+        let batch_count = batches.count();
+        let input_samples: Vec<Tensor> = inputs.into_iter().zip(&self.inputs)
+            .map(|(input, (_, size))| Tensor::batch(input, *size))
+            .collect();
+        let target_samples: Vec<Tensor> = heads.iter().zip(&head_nodes)
+            .map(|(head, n)| Tensor::batch(head.targets.clone(), self.nodes[*n].output_size()))
+            .collect();
+
+        // The gradients of every node's outputs, summed over the nodes and heads using them
+        let mut gradients: Vec<DualVec> = self.nodes.iter().zip(&self.execs)
+            .map(|(n, exec)| DualVec::from_execs((&CPU, exec), n.output_size() * batch_size))
+            .collect();
+        let mut head_gradients: Vec<DualVec> = head_nodes.iter()
+            .map(|n| DualVec::from_exec(&CPU, self.nodes[*n].output_size() * batch_size))
+            .collect();
+
+        let mut history = History::new(&heads.iter().map(|h| (h.output.clone(), h.metrics.clone())).collect::<Vec<_>>());
+        self.set_training(true);
+        for epoch in 0..epochs {
+            let mut losses = vec![0.; heads.len()];
+            let mut metrics: Vec<Vec<f32>> = heads.iter().map(|h| vec![0.; h.metrics.len()]).collect();
+
+            for batch_samples in batches.epoch() {
+                let batch = input_samples.iter().map(|input| input.gather(batch_samples)).collect::<Result<Vec<_>, _>>()?;
+                self.forward(&batch)?;
+
+                for g in gradients.iter_mut() {
+                    g.cpu_borrow().unwrap().fill(0.);
+                    g.updated_cpu();
+                }
+
+                for (h, head) in heads.iter_mut().enumerate() {
+                    let n = head_nodes[h];
+                    let mut batch_output = self.nodes[n].output_tensor();
+                    let mut batch_targets = target_samples[h].gather(batch_samples)?;
+
+                    let mut res = head.loss.calculate(&CPU, &mut batch_output, &mut batch_targets)?;
+                    losses[h] += res.cpu_borrow().unwrap().iter().sum::<f32>();
+                    for (m, metric) in head.metrics.iter().enumerate() {
+                        metrics[h][m] += metric.calculate(&mut batch_output, &mut batch_targets);
+                    }
+
+                    head.loss.dynamic_derivative(&CPU, &mut batch_output, &mut batch_targets, &mut head_gradients[h])?;
+                    accumulate(&mut head_gradients[h], &mut gradients[n], head.weight);
+                }
+
+                self.backward(&batch, &mut gradients, &optimizer);
+
+                for (n, node) in self.nodes.iter().enumerate() {
+                    if let Node::Layer(l) = node {
+                        let (name, kind) = (&self.names[n], self.kind(n));
+                        profiler::time_layer(&self.execs[n], || format!("{} {} apply", name, kind), || {
+                            l.borrow_mut().apply_gradients(&optimizer, batch_size);
+                        });
+                    }
+                }
+            }
+            profiler::finish_epoch(&self.execs, epoch);
+
+            for h in 0..heads.len() {
+                losses[h] /= batch_count as f32;
+                for m in metrics[h].iter_mut() {
+                    *m /= batch_count as f32;
+                }
+            }
+            let loss = heads.iter().zip(&losses).map(|(head, l)| head.weight * l).sum::<f32>();
+            history.push(loss, &losses, &metrics);
+        }
+        self.set_training(false);
+
+        Ok(history)
+    }
+
+    /// Backward pass through the nodes in reverse topological order, adding the sensitivities
+    /// of every node to the gradients of its inputs
+    fn backward(&mut self, inputs: &[Tensor], gradients: &mut [DualVec], optimizer: &Optimizer) {
+        for n in (0..self.nodes.len()).rev() {
+            let mut node_gradients = gradients[n].clone();
+            let source = self.sources[n][0];
+            let mut source_output = match source {
+                Source::Input(k) => inputs[k].clone(),
+                Source::Node(s) => self.nodes[s].output_tensor(),
+            };
+            let (name, kind) = (&self.names[n], self.kind(n));
+            profiler::time_layer(&self.execs[n], || format!("{} {} backward", name, kind), || match &mut self.nodes[n] {
+                Node::Layer(l) => {
+                    let mut layer = l.borrow_mut();
+                    match source {
+                        Source::Input(_) => layer.backward(&mut source_output, &mut node_gradients, optimizer),
+                        Source::Node(s) => {
+                            layer.backward(&mut source_output, &mut node_gradients, optimizer);
+                            accumulate(layer.sensitivities(), &mut gradients[s], 1.);
+                        }
+                    }
+                }
+                Node::Merge(m) => {
+                    m.backward(&mut node_gradients);
+                    for (k, s) in self.sources[n].iter().enumerate() {
+                        if let Source::Node(s) = s {
+                            accumulate(&mut m.sensitivities[k], &mut gradients[*s], 1.);
+                        }
+                    }
+                }
+            });
+        }
+    }
+
+    fn set_training(&mut self, training: bool) {
+        for n in &self.nodes {
+            if let Node::Layer(l) = n {
+                l.borrow_mut().set_training(training);
+            }
+        }
+    }
+
+    /// Clears the states that stateful layers carry between batches
+    pub fn reset_states(&mut self) {
+        for n in &self.nodes {
+            if let Node::Layer(l) = n {
+                l.borrow_mut().reset_states();
+            }
+        }
+    }
+
+    /// The free-form metadata written with the network, such as the training configuration
+    pub fn metadata(&self) -> &BTreeMap<String, String> {
+        &self.metadata
+    }
+
+    pub fn metadata_mut(&mut self) -> &mut BTreeMap<String, String> {
+        &mut self.metadata
+    }
+
+    /// Writes the network as a [ModelFile], with the layers of the layer nodes in their order
+    pub fn as_bytes(&mut self) -> Vec<u8> {
+        let mut writer = VecWriter::new();
+
+        writer.usize(self.inputs.len());
+        for (name, size) in &self.inputs {
+            writer.string(name);
+            writer.usize(*size);
+        }
+        writer.usize(self.outputs.len());
+        for o in &self.outputs {
+            writer.usize(*o);
+        }
+        writer.usize(self.nodes.len());
+
+        // The topology, with the nodes already in topological order
+        for n in 0..self.nodes.len() {
+            writer.string(&self.names[n]);
+            match &self.nodes[n] {
+                Node::Layer(l) => {
+                    let layer = l.borrow();
+                    writer.usize(0);
+                    writer.usize(layer.id());
+                    writer.usize(format::exec_kind(layer.exec()));
+                }
+                Node::Merge(m) => {
+                    writer.usize(1);
+                    writer.index(&m.merge);
+                }
+            }
+
+            writer.usize(self.sources[n].len());
+            for s in &self.sources[n] {
+                match s {
+                    Source::Input(k) => {
+                        writer.usize(0);
+                        writer.usize(*k);
+                    }
+                    Source::Node(s) => {
+                        writer.usize(1);
+                        writer.usize(*s);
+                    }
+                }
+            }
+        }
+
+        let layers = self.nodes.iter().filter_map(|n| match n {
+            Node::Layer(l) => Some(LayerSection::of(&mut *l.borrow_mut())),
+            Node::Merge(_) => None,
+        }).collect();
+
+        ModelFile {
+            kind: Kind::Graph,
+            version: VERSION,
+            metadata: self.metadata.clone(),
+            topology: writer.vec(),
+            layers,
+        }.as_bytes()
+    }
+
+    pub fn from_bytes(gpu_executor: Option<Arc<Executor>>, bytes: Vec<u8>) -> Result<GraphNetwork, Error> {
+        let file = ModelFile::from_bytes(&bytes, Kind::Graph)?;
+        let Topology { inputs, outputs, names, kinds, sources } = Topology::read(&mut CursorReader::new(&file.topology))?;
+        let node_count = names.len();
+
+        let cpu = Arc::new(CPU);
+        let gpu_exec = gpu_executor.unwrap_or_else(|| cpu.clone()); // If no GPU executor is provided, then default to using the CPU
+
+        // the section of every layer node, with the sections in the order of the layer nodes
+        let mut layer_sections = file.layers.iter().enumerate();
+        let mut sections = vec![];
+        for kind in kinds {
+            sections.push(match kind {
+                Ok(_) => Ok(layer_sections.next().ok_or(Error::Decode(DecodeError::Truncated))?),
+                Err(merge) => Err(merge),
+            });
+        }
+        let exec_of = |i: usize| match &sections[i] {
+            Ok((_, section)) => section.executor(&gpu_exec, &cpu),
+            Err(_) => cpu.clone(),
+        };
+
+        let mut nodes: Vec<Node> = vec![];
+        for n in 0..node_count {
+            let node = match &sections[n] {
+                Ok((index, section)) => {
+                    let prev_exec = match sources[n][0] {
+                        Source::Node(s) => exec_of(s),
+                        Source::Input(_) => cpu.clone(),
+                    };
+                    let next_exec = first_consumer(&sources, n).map_or(cpu.clone(), exec_of);
+                    Node::Layer(pool::owned_by(names[n].clone(), || section.read(*index, &Execs::new(&prev_exec, &exec_of(n), &next_exec)))?)
+                }
+                Err(merge) => {
+                    let sizes = sources[n].iter().map(|s| match s {
+                        Source::Input(k) => inputs[*k].1,
+                        Source::Node(s) => nodes[*s].output_size(),
+                    }).collect();
+                    Node::Merge(MergeNode::new(merge.clone(), sizes)?)
+                }
+            };
+            nodes.push(node);
+        }
+
+        Ok(GraphNetwork {
+            inputs,
+            names,
+            execs: (0..node_count).map(exec_of).collect(),
+            nodes,
+            sources,
+            outputs,
+            metadata: file.metadata,
+        })
+    }
+}
+
+/// How the nodes of a graph are connected, as written before the layers by [GraphNetwork::as_bytes]
+struct Topology {
+    inputs: Vec<(String, usize)>,
+    outputs: Vec<usize>,
+    names: Vec<String>,
+    /// The layer type and executor kind of layer nodes, and the merge of merge nodes
+    kinds: Vec<Result<(usize, usize), Merge>>,
+    sources: Vec<Vec<Source>>,
+}
+
+impl Topology {
+    /// Reads the topology, checking that every node only uses the inputs of the network and the
+    /// nodes before it, that layer nodes have a single input and that the outputs are nodes
+    fn read(reader: &mut CursorReader) -> Result<Self, Error> {
+        let inputs: Vec<(String, usize)> = reader.counted(|reader| (reader.string(), reader.usize()));
+        let outputs: Vec<usize> = reader.counted(|reader| reader.usize());
+
+        let mut names = vec![];
+        let mut kinds = vec![];
+        let mut sources = vec![];
+        for n in 0..reader.usize() {
+            if reader.past_end() > 0 {
+                break;
+            }
+            names.push(reader.string());
+            let kind = match reader.usize() {
+                0 => Ok((reader.usize(), reader.usize())),
+                _ => Err(reader.indexed()),
+            };
+
+            let node_sources: Vec<Source> = reader.counted(|reader| match reader.usize() {
+                0 => Source::Input(reader.usize()),
+                _ => Source::Node(reader.usize()),
+            });
+            for source in &node_sources {
+                let defined = match source {
+                    Source::Input(k) => *k < inputs.len(),
+                    Source::Node(s) => *s < n,
+                };
+                if !defined {
+                    return Err(Error::Decode(DecodeError::InvalidSource(n)));
+                }
+            }
+            match kind {
+                Ok(_) if node_sources.len() != 1 => return Err(Error::Decode(DecodeError::Sources(n, node_sources.len()))),
+                Err(_) if node_sources.is_empty() => return Err(Error::Decode(DecodeError::Sources(n, 0))),
+                _ => {}
+            }
+
+            kinds.push(kind);
+            sources.push(node_sources);
+        }
+        if reader.past_end() > 0 {
+            return Err(Error::Decode(DecodeError::Truncated));
+        }
+        if let Some(output) = outputs.iter().find(|o| **o >= names.len()) {
+            return Err(Error::Decode(DecodeError::InvalidOutput(*output)));
+        }
+
+        Ok(Topology { inputs, outputs, names, kinds, sources })
+    }
+}
+
+/// `into += from * scale`, over the length of `into`
+fn accumulate(from: &mut DualVec, into: &mut DualVec, scale: f32) {
+    {
+        let from = from.cpu_borrow().unwrap();
+        let mut into = into.cpu_borrow().unwrap();
+        for i in 0..into.len() {
+            into[i] += from[i] * scale;
+        }
+    }
+    into.updated_cpu();
+}
+
+/// The first node using the outputs of the node `i`
+fn first_consumer(sources: &[Vec<Source>], i: usize) -> Option<usize> {
+    sources.iter().position(|s| s.contains(&Source::Node(i)))
+}
+
+/// Orders the nodes so that every node comes after its inputs, or returns a node within a cycle
+fn topological_order(sources: &[Vec<Source>]) -> Result<Vec<usize>, usize> {
+    let mut remaining: Vec<usize> = sources.iter().map(|s| s.iter().filter(|s| matches!(s, Source::Node(_))).count()).collect();
+    let mut order = vec![];
+    let mut ready: Vec<usize> = (0..sources.len()).filter(|i| remaining[*i] == 0).collect();
+
+    while !ready.is_empty() {
+        let i = ready.remove(0);
+        order.push(i);
+        for (n, s) in sources.iter().enumerate() {
+            for source in s {
+                if *source == Source::Node(i) {
+                    remaining[n] -= 1;
+                    if remaining[n] == 0 {
+                        ready.push(n);
+                    }
+                }
+            }
+        }
+    }
+
+    match remaining.iter().position(|r| *r > 0) {
+        Some(i) => Err(i),
+        None => Ok(order),
+    }
+}
@@ -35,6 +35,14 @@ float activate(float val, ulong activation) {
         float exp_p = exp(val);
         float exp_n = exp(-val);
         return (exp_p - exp_n) / (exp_p + exp_n);
+    }
+    else if (activation == 3) {
+        // Sigmoid
+        return 1.0 / (1.0 + exp(-val));
+    }
+    else if (activation == 4) {
+        // PNSigmoid
+        return 2.0 / (1.0 + exp(-val)) - 1.0;
     } else {
         // Linear
         return val;
@@ -50,6 +58,16 @@ float activate_derivative(float value, ulong activation) {
         // TanH
         float activated = activate(value, activation);
         return 1.0 - (activated * activated);
+    }
+    else if (activation == 3) {
+        // Sigmoid
+        float activated = activate(value, activation);
+        return activated * (1.0 - activated);
+    }
+    else if (activation == 4) {
+        // PNSigmoid
+        float ex = exp(value);
+        return (2.0 * ex) / ((ex + 1.0) * (ex + 1.0));
     } else {
         // Linear
         return 1.0;
@@ -116,103 +134,532 @@ __kernel void activate_and_error_derivative_calc(__global float* values, __globa
     out[i] = error_derivative(activate(values[i], activation), desired[i]);
 }
 
-__kernel void forward(
+// Layers can build a copy of the kernels with their constants defined, so the compiler removes
+// the branches on them. Without the defines, the values of the arguments are used instead.
+#ifdef ACTIVATION
+#define SPECIALIZED_ACTIVATION(arg) ACTIVATION
+#else
+#define SPECIALIZED_ACTIVATION(arg) (arg)
+#endif
+#ifdef INPUT_LENGTH
+#define SPECIALIZED_INPUT_LENGTH(arg) INPUT_LENGTH
+#else
+#define SPECIALIZED_INPUT_LENGTH(arg) (arg)
+#endif
+#ifdef LAYER_LEN
+#define SPECIALIZED_LAYER_LEN(arg) LAYER_LEN
+#else
+#define SPECIALIZED_LAYER_LEN(arg) (arg)
+#endif
+
+// Layers keeping their values in half precision build the kernels with STORAGE defined to the id
+// of the type (2 for f16, 3 for bf16). The values are converted when loaded and stored, and are
+// computed and summed as floats.
+ushort bf16_bits(float value) {
+    uint bits = as_uint(value);
+    if (isnan(value)) { return (ushort) ((bits >> 16) | 0x40); }
+    // rounds to the nearest even value, where rounding up the largest values gives infinity
+    return (ushort) ((bits + 0x7fff + ((bits >> 16) & 1)) >> 16);
+}
+
+#if STORAGE == 2
+typedef ushort storage_t;
+#define LOAD(values, i) vload_half((i), (__global const half*) (values))
+#define STORE(value, values, i) vstore_half_rte((value), (i), (__global half*) (values))
+#elif STORAGE == 3
+typedef ushort storage_t;
+#define LOAD(values, i) as_float(((uint) (values)[i]) << 16)
+#define STORE(value, values, i) ((values)[i] = bf16_bits(value))
+#else
+typedef float storage_t;
+#define LOAD(values, i) ((values)[i])
+#define STORE(value, values, i) ((values)[i] = (value))
+#endif
+
+// The matrix products are run in square work groups of tile x tile work items, with two local
+// buffers of tile * (tile + 1) values sized for the device. The extra column avoids bank conflicts.
+
+// outputs[b][x] = biases[x] + sum(inputs[positions[b] + y] * weights[x][y]), run over (batch, layer_len)
+__kernel void dense_forward(
     ulong activation,
     ulong input_length,
     ulong layer_len,
-    __constant float* weights,
-    __constant float* biases,
-    __constant float* input,
-    __global float* output,
+    ulong batch,
+    __global const storage_t* weights,
+    __global const float* biases,
+    __global const float* inputs,
+    __global const ulong* positions,
+    __global storage_t* output,
     __global float* activated_output,
-    ulong offset_in,
-    ulong offset_out
+    __local float* input_tile,
+    __local float* weight_tile
 ) {
+    activation = SPECIALIZED_ACTIVATION(activation);
+    input_length = SPECIALIZED_INPUT_LENGTH(input_length);
+    layer_len = SPECIALIZED_LAYER_LEN(layer_len);
+
+    int b = get_global_id(0); // batch
+    int x = get_global_id(1); // out dims
+    int lb = get_local_id(0);
+    int lx = get_local_id(1);
+    int tile = get_local_size(0);
+    int stride = tile + 1;
+    int group_x = get_group_id(1) * tile;
+
+    ulong position = b < batch ? positions[b] : 0;
+    float sum = 0;
+    for (ulong t = 0; t < input_length; t += tile) {
+        // every work item loads a single input of its sample and a single weight of the tile
+        input_tile[lb * stride + lx] = (b < batch && t + lx < input_length) ? inputs[position + t + lx] : 0;
+        weight_tile[lx * stride + lb] = (group_x + lx < layer_len && t + lb < input_length) ? LOAD(weights, (group_x + lx) * input_length + t + lb) : 0;
+        barrier(CLK_LOCAL_MEM_FENCE);
+
+        for (int k = 0; k < tile; k++) {
+            sum += input_tile[lb * stride + k] * weight_tile[lx * stride + k];
+        }
+        barrier(CLK_LOCAL_MEM_FENCE);
+    }
+
+    if (b < batch && x < layer_len) {
+        float out = sum + biases[x];
+        STORE(out, output, b * layer_len + x);
+        activated_output[b * layer_len + x] = activate(out, activation);
+    }
+}
+
+// gradients[i] = activate_derivative(outputs[i]) * sensitivities[i] * scale, run over every output of
+// the batch. The gradients are scaled so the small ones aren't flushed to zero in half precision, and
+// overflow is set when a scaled gradient isn't finite.
+__kernel void dense_gradients(
+    ulong activation,
+    float scale,
+    __global const storage_t* layer_output,
+    __global const float* sensitivities,
+    __global storage_t* gradients,
+    __global int* overflow
+) {
+    activation = SPECIALIZED_ACTIVATION(activation);
+
+    int i = get_global_id(0);
+    STORE(activate_derivative(LOAD(layer_output, i), activation) * sensitivities[i] * scale, gradients, i);
+    if (!isfinite(LOAD(gradients, i))) {
+        overflow[0] = 1;
+    }
+}
+
+// weight_mods[x][y] -= learn_rate * sum(gradients[b][x] * inputs[positions[b] + y]), run over (layer_len, input_length)
+__kernel void dense_weight_mods(
+    ulong input_length,
+    ulong layer_len,
+    ulong batch,
+    float learn_rate,
+    __global const float* inputs,
+    __global const ulong* positions,
+    __global const storage_t* gradients,
+    __global float* weight_mods,
+    __global float* bias_mods,
+    __local float* gradient_tile,
+    __local float* input_tile
+) {
+    input_length = SPECIALIZED_INPUT_LENGTH(input_length);
+    layer_len = SPECIALIZED_LAYER_LEN(layer_len);
+
     int x = get_global_id(0); // out dims
     int y = get_global_id(1); // in dims
-    int w_ind = (input_length * x) + y;
-    int wg_size_x = get_local_size(0);
-    int wg_size_y = get_local_size(1);
-    int wg_x = get_local_id(0);
-    int wg_y = get_local_id(1);
+    int lx = get_local_id(0);
+    int ly = get_local_id(1);
+    int tile = get_local_size(0);
+    int stride = tile + 1;
+
+    float sum = 0;
+    for (ulong t = 0; t < batch; t += tile) {
+        // gradient_tile[sample][x] and input_tile[sample][y]
+        gradient_tile[ly * stride + lx] = (t + ly < batch && x < layer_len) ? LOAD(gradients, (t + ly) * layer_len + x) : 0;
+        input_tile[lx * stride + ly] = (t + lx < batch && y < input_length) ? inputs[positions[t + lx] + y] : 0;
+        barrier(CLK_LOCAL_MEM_FENCE);
+
+        for (int k = 0; k < tile; k++) {
+            sum += gradient_tile[k * stride + lx] * input_tile[k * stride + ly];
+        }
+        barrier(CLK_LOCAL_MEM_FENCE);
+    }
+
+    if (x < layer_len && y < input_length) {
+        weight_mods[x * input_length + y] -= learn_rate * sum;
+
+        if (y == 0) {
+            float bias_sum = 0;
+            for (ulong b = 0; b < batch; b++) {
+                bias_sum += LOAD(gradients, b * layer_len + x);
+            }
+            bias_mods[x] -= learn_rate * bias_sum;
+        }
+    }
+}
+
+// sensitivities[b][y] = sum(gradients[b][x] * weights[x][y]) / scale, run over (batch, input_length)
+__kernel void dense_sensitivities(
+    ulong input_length,
+    ulong layer_len,
+    ulong batch,
+    float scale,
+    __global const storage_t* weights,
+    __global const storage_t* gradients,
+    __global float* sensitivities,
+    __local float* gradient_tile,
+    __local float* weight_tile
+) {
+    input_length = SPECIALIZED_INPUT_LENGTH(input_length);
+    layer_len = SPECIALIZED_LAYER_LEN(layer_len);
+
+    int b = get_global_id(0); // batch
+    int y = get_global_id(1); // in dims
+    int lb = get_local_id(0);
+    int ly = get_local_id(1);
+    int tile = get_local_size(0);
+    int stride = tile + 1;
+
+    float sum = 0;
+    for (ulong t = 0; t < layer_len; t += tile) {
+        // gradient_tile[b][x] and weight_tile[x][y]
+        gradient_tile[lb * stride + ly] = (b < batch && t + ly < layer_len) ? LOAD(gradients, b * layer_len + t + ly) : 0;
+        weight_tile[lb * stride + ly] = (t + lb < layer_len && y < input_length) ? LOAD(weights, (t + lb) * input_length + y) : 0;
+        barrier(CLK_LOCAL_MEM_FENCE);
+
+        for (int k = 0; k < tile; k++) {
+            sum += gradient_tile[lb * stride + k] * weight_tile[k * stride + ly];
+        }
+        barrier(CLK_LOCAL_MEM_FENCE);
+    }
 
-    __local float local_outputs[64][64];
+    if (b < batch && y < input_length) {
+        sensitivities[b * input_length + y] = sum / scale;
+    }
+}
 
-    int offset_x = x + offset_out;
-    int offset_y = y + offset_in;
+// run over (batch * input_len, dim)
+__kernel void embedding_forward(
+    ulong dim,
+    ulong input_len,
+    __constant float* table,
+    __global const float* inputs,
+    __global const ulong* positions,
+    __global float* outputs
+) {
+    int i = get_global_id(0); // token of the batch
+    int d = get_global_id(1); // embedding dims
+    ulong b = i / input_len;
+    ulong t = i % input_len;
 
-    float in = input[offset_y];
+    ulong row = (ulong) inputs[positions[b] + t];
+    outputs[(i * dim) + d] = table[(row * dim) + d];
+}
 
-    // calculate each local multiplication in a local array
-    local_outputs[wg_y][wg_x] = in*weights[w_ind];
+// run over (batch * input_len, dim)
+__kernel void embedding_backward(
+    ulong dim,
+    ulong input_len,
+    float learn_rate,
+    __global const float* inputs,
+    __global const ulong* positions,
+    __global const float* sensitivities,
+    __global float* table_mods
+) {
+    int i = get_global_id(0); // token of the batch
+    int d = get_global_id(1); // embedding dims
+    ulong b = i / input_len;
+    ulong t = i % input_len;
+
+    ulong row = (ulong) inputs[positions[b] + t];
+    // the same token can appear more than once in a batch
+    atomicAdd_g_f(&table_mods[(row * dim) + d], -(learn_rate * sensitivities[(i * dim) + d]));
+}
 
+// run over (groups * width, dim) with work groups of (width, 1), where the width is a power of two
+// the deterministic form of embedding_backward, where the tokens of the batch are sorted by
+// their row and every row is summed by a work group as a tree in a fixed order
+__kernel void embedding_backward_sorted(
+    ulong dim,
+    float learn_rate,
+    __global const ulong* rows,
+    __global const ulong* starts,
+    __global const ulong* tokens,
+    __global const float* sensitivities,
+    __global float* table_mods,
+    __local float* sums
+) {
+    int g = get_group_id(0); // row of the table used by the batch
+    int l = get_local_id(0);
+    int d = get_global_id(1); // embedding dims
+    int width = get_local_size(0);
+
+    float sum = 0;
+    for (ulong i = starts[g] + l; i < starts[g + 1]; i += width) {
+        sum += sensitivities[(tokens[i] * dim) + d];
+    }
+    sums[l] = sum;
     barrier(CLK_LOCAL_MEM_FENCE);
 
-    if (wg_y == 0) {
-        // sum each local array together into the global array to spend less time waiting for the atomic to unlock
-        float sum = 0;
-        for (int i = 0; i < wg_size_y; i++) {
-            sum += local_outputs[i][wg_x];
+    for (int s = width / 2; s > 0; s >>= 1) {
+        if (l < s) {
+            sums[l] += sums[l + s];
         }
-        atomicAdd_g_f(&output[offset_x], sum);
+        barrier(CLK_LOCAL_MEM_FENCE);
     }
 
-    if (y == 0) {
-        atomicAdd_g_f(&output[offset_x], biases[x]);
+    if (l == 0) {
+        ulong index = (rows[g] * dim) + d;
+        table_mods[index] = table_mods[index] - (learn_rate * sums[0]);
     }
-    barrier(CLK_GLOBAL_MEM_FENCE);
-
-    activated_output[offset_x] = activate(output[offset_x], activation);
 }
 
-__kernel void backward(
-    ulong activation,
+__kernel void embedding_apply(
+    ulong dim,
+    __global float* table,
+    __global float* table_mods,
+    __constant ulong* rows,
+    float mult
+) {
+    int r = get_global_id(0); // used row
+    int d = get_global_id(1); // embedding dims
+
+    ulong index = (rows[r] * dim) + d;
+    table[index] = table[index] + (table_mods[index] * mult);
+    table_mods[index] = 0;
+}
+__kernel void attention_project(
     ulong input_length,
-    float learn_rate,
-    __constant float* inputs,
-    __constant float* layer_output,
-    __constant float* sensitivities,
+    ulong sequence,
     __constant float* weights,
+    ulong offset_w,
     __constant float* biases,
-    __global float* weight_mods,
-    __global float* bias_mods,
-    __global float* gradients_out,
-    ulong offset_in,
-    ulong offset_sens,
-    ulong offset_out
+    ulong offset_b,
+    __constant float* inputs,
+    __constant ulong* positions,
+    __global float* outputs
 ) {
-    int x = get_global_id(0); // out dims
-    int y = get_global_id(1); // in dims
-    int wg_size_x = get_local_size(0);
-    int wg_size_y = get_local_size(1);
-    int wg_x = get_local_id(0);
-    int wg_y = get_local_id(1);
-    ulong weight_index = (input_length * x) + y;
-    ulong bias_index = x;
+    int r = get_global_id(0); // rows of every sample
+    int o = get_global_id(1); // out dims
+    int out_length = get_global_size(1);
 
-    __local float local_gradients[128][128];
+    ulong offset_in = positions[r / sequence] + (r % sequence) * input_length;
+    ulong offset_weights = offset_w + o * input_length;
 
-    int offset_x = x + offset_out;
-    int offset_y = y + offset_in;
+    float sum = biases[offset_b + o];
+    for (int i = 0; i < input_length; i++) {
+        sum += inputs[offset_in + i] * weights[offset_weights + i];
+    }
+    outputs[r * out_length + o] = sum;
+}
 
-    float gradient = activate_derivative(layer_output[offset_x], activation) * sensitivities[offset_x];
+__kernel void attention_weights(
+    ulong heads,
+    ulong internal,
+    ulong q_length,
+    ulong kv_length,
+    ulong causal,
+    ulong offset,
+    __constant float* queries,
+    __constant float* keys,
+    __constant ulong* lengths,
+    __global float* weights
+) {
+    int bh = get_global_id(0); // batch and head
+    int q = get_global_id(1); // queries
+
+    ulong b = bh / heads;
+    ulong h = bh % heads;
+    ulong row = heads * internal;
+    float scale = 1.0 / sqrt((float) internal);
+
+    // only a prefix of the keys is visible to a query
+    ulong visible = lengths[b];
+    if (causal != 0 && offset + q + 1 < visible) {
+        visible = offset + q + 1;
+    }
+    if (kv_length < visible) {
+        visible = kv_length;
+    }
 
-    weight_mods[weight_index] -= (learn_rate * inputs[offset_y] * gradient);
+    ulong offset_q = (b * q_length + q) * row + h * internal;
+    ulong offset_w = (bh * q_length + q) * kv_length;
 
-    if (y == 0) {
-        bias_mods[bias_index] -= (learn_rate * gradient);
+    float max = -INFINITY;
+    for (int k = 0; k < visible; k++) {
+        ulong offset_k = (b * kv_length + k) * row + h * internal;
+        float score = 0;
+        for (int e = 0; e < internal; e++) {
+            score += queries[offset_q + e] * keys[offset_k + e];
+        }
+        weights[offset_w + k] = score * scale;
+        max = fmax(max, score * scale);
     }
 
-    local_gradients[wg_y][wg_x] = weights[weight_index] * gradient;
+    float sum = 0;
+    for (int k = 0; k < visible; k++) {
+        float w = exp(weights[offset_w + k] - max);
+        weights[offset_w + k] = w;
+        sum += w;
+    }
+    for (int k = 0; k < kv_length; k++) {
+        if (k < visible) {
+            weights[offset_w + k] /= sum;
+        } else {
+            weights[offset_w + k] = 0;
+        }
+    }
+}
 
-    barrier(CLK_LOCAL_MEM_FENCE);
+__kernel void attention_context(
+    ulong heads,
+    ulong internal,
+    ulong q_length,
+    ulong kv_length,
+    __constant float* weights,
+    __constant float* values,
+    __global float* context
+) {
+    int r = get_global_id(0); // rows of every sample
+    int c = get_global_id(1); // head values
+
+    ulong b = r / q_length;
+    ulong q = r % q_length;
+    ulong h = c / internal;
+    ulong row = heads * internal;
+    ulong offset_w = ((b * heads + h) * q_length + q) * kv_length;
+
+    // masked keys have a weight of zero
+    float sum = 0;
+    for (int k = 0; k < kv_length; k++) {
+        sum += weights[offset_w + k] * values[(b * kv_length + k) * row + c];
+    }
+    context[r * row + c] = sum;
+}
+
+// The kernels of the ops module. Operands are strided views, described by a layout of the shape
+// of the output followed by the strides of every operand over it, where broadcast dimensions
+// have a stride of 0.
+
+ulong op_index(ulong i, __global const ulong* layout, int rank, int operand, ulong offset) {
+    ulong index = offset;
+    for (int d = rank - 1; d >= 0; d--) {
+        index += (i % layout[d]) * layout[(operand + 1) * rank + d];
+        i /= layout[d];
+    }
+    return index;
+}
+
+__kernel void op_unary(
+    __global const float* a,
+    ulong a_offset,
+    __global float* out,
+    __global const ulong* layout,
+    int rank,
+    ulong op,
+    float factor
+) {
+    ulong i = get_global_id(0);
+    float value = a[op_index(i, layout, rank, 0, a_offset)];
+    if (op == 1) {
+        value *= factor;
+    } else if (op == 2) {
+        value = exp(value);
+    } else if (op == 3) {
+        value = log(value);
+    }
+    out[i] = value;
+}
+
+__kernel void op_binary(
+    __global const float* a,
+    ulong a_offset,
+    __global const float* b,
+    ulong b_offset,
+    __global float* out,
+    __global const ulong* layout,
+    int rank,
+    ulong op
+) {
+    ulong i = get_global_id(0);
+    float x = a[op_index(i, layout, rank, 0, a_offset)];
+    float y = b[op_index(i, layout, rank, 1, b_offset)];
+    if (op == 1) {
+        out[i] = x - y;
+    } else if (op == 2) {
+        out[i] = x * y;
+    } else if (op == 3) {
+        out[i] = x / y;
+    } else {
+        out[i] = x + y;
+    }
+}
 
-    if (wg_x == 0) {
-        float sum = 0;
-        for (int i = 0; i < wg_size_x; i++) {
-            sum += local_gradients[wg_y][i];
+// reduces the len values `stride` apart starting at every value of the output, summed in order
+__kernel void op_reduce(
+    __global const float* a,
+    ulong a_offset,
+    __global float* out,
+    __global const ulong* layout,
+    int rank,
+    ulong len,
+    ulong stride,
+    ulong op
+) {
+    ulong i = get_global_id(0);
+    ulong start = op_index(i, layout, rank, 0, a_offset);
+
+    float result = op >= 2 ? -INFINITY : 0;
+    ulong best = 0;
+    for (ulong j = 0; j < len; j++) {
+        float value = a[start + j * stride];
+        if (op >= 2) {
+            if (value > result) {
+                result = value;
+                best = j;
+            }
+        } else {
+            result += value;
         }
-        atomicAdd_g_f(&gradients_out[y + offset_sens], sum);
     }
 
-}
\ No newline at end of file
+    if (op == 1) {
+        result /= len;
+    } else if (op == 3) {
+        result = best;
+    }
+    out[i] = result;
+}
+
+// copies the samples starting at `rows`, where the layout is the shape of a sample and its strides
+__kernel void op_gather(
+    __global const float* a,
+    __global const ulong* rows,
+    __global float* out,
+    __global const ulong* layout,
+    int rank,
+    ulong sample
+) {
+    ulong i = get_global_id(0);
+    out[i] = a[op_index(i % sample, layout, rank, 0, rows[i / sample])];
+}
+
+// out[r][c] = sum(a[r][i] * b[i][c]), where the layout is the row and column strides of a and b
+__kernel void op_matmul(
+    __global const float* a,
+    ulong a_offset,
+    __global const float* b,
+    ulong b_offset,
+    __global float* out,
+    __global const ulong* layout,
+    ulong k,
+    ulong n
+) {
+    ulong r = get_global_id(0);
+    ulong c = get_global_id(1);
+
+    float sum = 0;
+    for (ulong i = 0; i < k; i++) {
+        sum += a[a_offset + r * layout[0] + i * layout[1]] * b[b_offset + i * layout[2] + c * layout[3]];
+    }
+    out[r * n + c] = sum;
+}
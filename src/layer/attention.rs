@@ -1,50 +1,652 @@
 use std::cell::RefCell;
 use std::rc::Rc;
 
-use crate::{Executor, Optimizer};
+use ocl::{Buffer, Kernel, ProQue};
+
+use crate::{Executor, Execs, Optimizer};
+use std::sync::Arc;
 use crate::dual_vec::DualVec;
+use crate::tensor::Tensor;
+use crate::error::{Error, MismatchError, NetworkError};
+use crate::frozen::{FrozenLayer, Scratch};
+use crate::gpu::Gpu;
 use crate::layer::Layer;
+use crate::utils::cl_utils;
+use crate::utils::gemm::{gemm, Matrix};
+use crate::utils::cl_utils::execute_kernel;
 use crate::utils::vec_utils::{CursorReader, VecWriter};
 
+/// `out[r][o] = b[o] + sum(x[r][i] * w[o][i])` for every row of `x`
+pub(crate) fn linear(x: &[f32], w: &[f32], b: &[f32], in_len: usize, out: &mut [f32]) {
+    let out_len = b.len();
+    let rows = x.len() / in_len;
+    for r in 0..rows {
+        out[r * out_len..(r + 1) * out_len].copy_from_slice(b);
+    }
+    gemm(1, 1., Matrix::new(x, rows, in_len), Matrix::new(w, out_len, in_len).t(), 1., out);
+}
+
+/// Accumulates the modifications of a [linear] map, and adds the gradients of its inputs to `x_gradients`
+pub(crate) fn linear_backward(x: &[f32], w: &[f32], gradients: &[f32], lr: f32, w_mods: &mut [f32], b_mods: &mut [f32], x_gradients: &mut [f32]) {
+    let out_len = b_mods.len();
+    let in_len = w.len() / out_len;
+    let rows = x.len() / in_len;
+    for r in 0..rows {
+        for o in 0..out_len {
+            b_mods[o] -= lr * gradients[r * out_len + o];
+        }
+    }
+
+    let gradients = Matrix::new(gradients, rows, out_len);
+    gemm(1, -lr, gradients.t(), Matrix::new(x, rows, in_len), 1., w_mods);
+    gemm(1, 1., gradients, Matrix::new(w, out_len, in_len), 1., x_gradients);
+}
+
+/// The heads of an [attend] call, which split every row of the queries, keys and values
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Heads {
+    pub count: usize,
+    /// The values of a row in a single head
+    pub internal: usize,
+}
+
+impl Heads {
+    fn row(&self) -> usize {
+        self.count * self.internal
+    }
+}
+
+/// The keys a query is allowed to attend to, which is always a prefix of the keys
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Mask {
+    /// Hides the keys after the position of the query
+    pub causal: bool,
+    /// The position of the first query within the keys
+    pub offset: usize,
+    /// The amount of keys which aren't padding
+    pub length: usize,
+}
+
+impl Mask {
+    /// The amount of keys visible to a query
+    pub fn visible(&self, query: usize) -> usize {
+        if self.causal {
+            self.length.min(self.offset + query + 1)
+        } else {
+            self.length
+        }
+    }
+}
+
+/// The keys and values of the positions an attention layer has processed through [Layer::step].
+///
+/// Cloning the cache forks the sequence, such as for every beam of a beam search.
+#[derive(Clone, Debug, Default)]
+pub struct KeyValueCache {
+    keys: Vec<f32>,
+    values: Vec<f32>,
+    length: usize,
+}
+
+impl KeyValueCache {
+    /// The amount of cached positions
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Removes every cached position, starting a new sequence
+    pub fn clear(&mut self) {
+        self.keys.clear();
+        self.values.clear();
+        self.length = 0;
+    }
+}
+
+/// Scaled dot product attention of every head, keeping the softmax weights for the backward pass.
+///
+/// `queries`, `keys` and `values` have a row for every query or key, each of `heads.count * heads.internal` values.
+/// Masked keys get a weight of zero, and a query without any visible keys gets a context of zero.
+pub(crate) fn attend(queries: &[f32], keys: &[f32], values: &[f32], heads: Heads, mask: Mask, weights: &mut [f32], context: &mut [f32]) {
+    let Heads { count: heads, internal } = heads;
+    let row = heads * internal;
+    let (q_len, kv_len) = (queries.len() / row, keys.len() / row);
+    let scale = 1. / (internal as f32).sqrt();
+    for h in 0..heads {
+        for i in 0..q_len {
+            let q = &queries[i * row + h * internal..i * row + (h + 1) * internal];
+            let w = &mut weights[(h * q_len + i) * kv_len..(h * q_len + i + 1) * kv_len];
+            let visible = mask.visible(i).min(kv_len);
+            w[visible..].fill(0.);
+
+            let mut max = f32::NEG_INFINITY;
+            for j in 0..visible {
+                let k = &keys[j * row + h * internal..j * row + (h + 1) * internal];
+                let mut score = 0.;
+                for e in 0..internal {
+                    score += q[e] * k[e];
+                }
+                w[j] = score * scale;
+                max = max.max(w[j]);
+            }
+
+            let mut sum = 0.;
+            for weight in &mut w[..visible] {
+                *weight = (*weight - max).exp();
+                sum += *weight;
+            }
+
+            let c = &mut context[i * row + h * internal..i * row + (h + 1) * internal];
+            c.fill(0.);
+            for j in 0..visible {
+                w[j] /= sum;
+                let v = &values[j * row + h * internal..j * row + (h + 1) * internal];
+                for e in 0..internal {
+                    c[e] += w[j] * v[e];
+                }
+            }
+        }
+    }
+}
+
+/// Adds the gradients of the queries, keys and values of an [attend] call to `gradients`, in that
+/// order, given the gradients of its context
+pub(crate) fn attend_backward(queries: &[f32], keys: &[f32], values: &[f32], weights: &[f32], context_gradients: &[f32], heads: Heads, gradients: [&mut [f32]; 3]) {
+    let [query_gradients, key_gradients, value_gradients] = gradients;
+    let Heads { count: heads, internal } = heads;
+    let row = heads * internal;
+    let (q_len, kv_len) = (queries.len() / row, keys.len() / row);
+    let scale = 1. / (internal as f32).sqrt();
+    let mut weight_gradients = vec![0.; kv_len];
+    for h in 0..heads {
+        for i in 0..q_len {
+            let w = &weights[(h * q_len + i) * kv_len..(h * q_len + i + 1) * kv_len];
+            let dc = &context_gradients[i * row + h * internal..i * row + (h + 1) * internal];
+
+            // gradients of the softmax weights, and their weighted sum for the softmax derivative
+            let mut weighted = 0.;
+            for j in 0..kv_len {
+                let v = j * row + h * internal;
+                let mut gradient = 0.;
+                for e in 0..internal {
+                    gradient += dc[e] * values[v + e];
+                    value_gradients[v + e] += w[j] * dc[e];
+                }
+                weight_gradients[j] = gradient;
+                weighted += w[j] * gradient;
+            }
+
+            let q = i * row + h * internal;
+            for j in 0..kv_len {
+                let score_gradient = w[j] * (weight_gradients[j] - weighted) * scale;
+                if score_gradient == 0. {
+                    continue;
+                }
+                let k = j * row + h * internal;
+                for e in 0..internal {
+                    query_gradients[q + e] += score_gradient * keys[k + e];
+                    key_gradients[k + e] += score_gradient * queries[q + e];
+                }
+            }
+        }
+    }
+}
+
+/// The kernels of the forward pass on a GPU along with the offsets and lengths of the samples of
+/// the last batch, which are kept between batches. The buffer arguments are set before every launch,
+/// since the buffers of the layer are replaced when a larger batch doesn't fit them.
 #[derive(Debug)]
-pub struct Attention<'a> {
-    exec: &'a Executor,
+struct GpuForward {
+    /// The query, key and value projections
+    projections: [Kernel; 3],
+    weights: Kernel,
+    context: Kernel,
+    output: Kernel,
+
+    batch_size: usize,
+    positions: Buffer<u64>,
+    lengths: Buffer<u64>,
+    /// The offsets of the contexts of the samples, which only depend on the batch size
+    context_positions: Buffer<u64>,
+}
+
+impl GpuForward {
+    fn new(pq: &ProQue, attention: &Attention) -> Self {
+        let (heads, internal, seq) = (attention.heads, attention.internal, attention.sequence);
+        let projected = heads * internal;
+        let project = |input_len: usize, weight_offset: usize, bias_offset: usize| pq.kernel_builder("attention_project")
+            .arg(input_len as u64)
+            .arg(seq as u64)
+            .arg_named("weights", None::<&Buffer<f32>>)
+            .arg(weight_offset as u64)
+            .arg_named("biases", None::<&Buffer<f32>>)
+            .arg(bias_offset as u64)
+            .arg_named("inputs", None::<&Buffer<f32>>)
+            .arg_named("positions", None::<&Buffer<u64>>)
+            .arg_named("outputs", None::<&Buffer<f32>>)
+            .build().unwrap();
+
+        GpuForward {
+            projections: [0, 1, 2].map(|p| project(attention.characteristics, p * projected * attention.characteristics, p * projected)),
+            weights: pq.kernel_builder("attention_weights")
+                .arg(heads as u64)
+                .arg(internal as u64)
+                .arg(seq as u64)
+                .arg(seq as u64)
+                .arg(attention.causal as u64)
+                .arg(0u64)
+                .arg_named("queries", None::<&Buffer<f32>>)
+                .arg_named("keys", None::<&Buffer<f32>>)
+                .arg_named("lengths", None::<&Buffer<u64>>)
+                .arg_named("attention_weights", None::<&Buffer<f32>>)
+                .build().unwrap(),
+            context: pq.kernel_builder("attention_context")
+                .arg(heads as u64)
+                .arg(internal as u64)
+                .arg(seq as u64)
+                .arg(seq as u64)
+                .arg_named("attention_weights", None::<&Buffer<f32>>)
+                .arg_named("values", None::<&Buffer<f32>>)
+                .arg_named("context", None::<&Buffer<f32>>)
+                .build().unwrap(),
+            output: project(projected, 0, 0),
+
+            batch_size: 0,
+            positions: cl_utils::new_buffer(pq, 1),
+            lengths: cl_utils::new_buffer(pq, 1),
+            context_positions: cl_utils::new_buffer(pq, 1),
+        }
+    }
+
+    /// Writes the offsets and lengths of the samples of the batch, allocating the buffers again
+    /// when the batch size changed
+    fn write_batch(&mut self, pq: &ProQue, positions: &[usize], lengths: &[usize], context_len: usize) {
+        if self.batch_size != positions.len() {
+            self.batch_size = positions.len();
+            let context_positions: Vec<usize> = (0..self.batch_size).map(|b| b * context_len).collect();
+            self.positions = cl_utils::new_buffer(pq, self.batch_size);
+            self.lengths = cl_utils::new_buffer(pq, self.batch_size);
+            self.context_positions = cl_utils::indices_buffer(pq, &context_positions);
+        }
+        let positions: Vec<u64> = positions.iter().map(|p| *p as u64).collect();
+        cl_utils::buf_write(&self.positions, &positions);
+        let lengths: Vec<u64> = lengths.iter().map(|l| *l as u64).collect();
+        cl_utils::buf_write(&self.lengths, &lengths);
+    }
+}
+
+/// Multi-head self attention over samples of `[sequence, characteristics]`.
+///
+/// Every head projects the inputs to queries, keys and values of the internal size,
+/// and the concatenated heads are projected to the output size for every position.
+/// A causal layer only lets every position attend to itself and earlier positions, and
+/// [Layer::padded_forward] hides the padding at the end of every sample.
+///
+/// The backward pass is computed on the host for every executor.
+#[derive(Debug)]
+pub struct Attention {
+    exec: Arc<Executor>,
+    heads: usize,
+    internal: usize,
+    characteristics: usize,
+    output: usize,
+    sequence: usize,
+    causal: bool,
+
+    /// query, key and value weights, `[3 * heads * internal, characteristics]`
+    weights: DualVec,
+    biases: DualVec,
+    /// `[output, heads * internal]`
+    output_weights: DualVec,
+    output_biases: DualVec,
+
+    weight_mods: DualVec,
+    bias_mods: DualVec,
+    output_weight_mods: DualVec,
+    output_bias_mods: DualVec,
+
+    outputs: DualVec,
+    sensitivities: DualVec,
+
+    queries: DualVec,
+    keys: DualVec,
+    values: DualVec,
+    attention_weights: DualVec,
+    context: DualVec,
+
+    gpu_forward: Option<GpuForward>,
 }
 
-impl<'a> Attention<'a> {
-    pub fn new(exec: (&'a Executor, &'a Executor, &'a Executor), inputs: usize, head_count: usize, internal: usize, characteristics: usize, output: usize) -> Self {
-        let p_to_c = (exec.0, exec.1); // previous to current
-        let c_to_n = (exec.1, exec.2); // current to next
-        let c = exec.1; // current
-        Attention {
-            exec: c,
+impl Attention {
+    pub(crate) fn heads(&self) -> usize {
+        self.heads
+    }
+
+    pub fn new(exec: &Execs, inputs: usize, head_count: usize, internal: usize, characteristics: usize, output: usize, causal: bool) -> Self {
+        let p_to_c = exec.p_to_c(); // previous to current
+        let c_to_n = exec.c_to_n(); // current to next
+        let c = &*exec.current; // current
+
+        let sequence = inputs / characteristics;
+        let projected = head_count * internal;
+
+        let mut a = Attention {
+            exec: exec.current.clone(),
+            heads: head_count,
+            internal,
+            characteristics,
+            output,
+            sequence,
+            causal,
+
+            weights: DualVec::from_exec(c, 3 * projected * characteristics),
+            biases: DualVec::from_exec(c, 3 * projected),
+            output_weights: DualVec::from_exec(c, output * projected),
+            output_biases: DualVec::from_exec(c, output),
+
+            weight_mods: DualVec::from_exec(c, 3 * projected * characteristics),
+            bias_mods: DualVec::from_exec(c, 3 * projected),
+            output_weight_mods: DualVec::from_exec(c, output * projected),
+            output_bias_mods: DualVec::from_exec(c, output),
+
+            outputs: DualVec::from_execs(c_to_n, sequence * output),
+            sensitivities: DualVec::from_execs(p_to_c, inputs),
+
+            queries: DualVec::from_exec(c, sequence * projected),
+            keys: DualVec::from_exec(c, sequence * projected),
+            values: DualVec::from_exec(c, sequence * projected),
+            attention_weights: DualVec::from_exec(c, head_count * sequence * sequence),
+            context: DualVec::from_exec(c, sequence * projected),
+
+            gpu_forward: None,
+        };
+
+        a.weights.randomize(c, (characteristics as f32).sqrt());
+        a.biases.randomize(c, ((3 * projected) as f32).sqrt());
+        a.output_weights.randomize(c, (projected as f32).sqrt());
+        a.output_biases.randomize(c, (output as f32).sqrt());
+
+        a
+    }
+
+    fn ensure_batch_size(&mut self, batch_size: usize) {
+        let projected = self.heads * self.internal;
+        self.outputs.resize(batch_size * self.sequence * self.output);
+        self.sensitivities.resize(batch_size * self.sequence * self.characteristics);
+
+        self.queries.resize(batch_size * self.sequence * projected);
+        self.keys.resize(batch_size * self.sequence * projected);
+        self.values.resize(batch_size * self.sequence * projected);
+        self.context.resize(batch_size * self.sequence * projected);
+        self.attention_weights.resize(batch_size * self.heads * self.sequence * self.sequence);
+    }
+
+    /// The forward pass, where `lengths` is the amount of positions of each sample which aren't padding
+    fn masked_forward(&mut self, positions: &[usize], lengths: Option<&[usize]>, inputs: &mut DualVec) {
+        self.ensure_batch_size(positions.len());
+
+        let lengths = match lengths {
+            Some(lengths) => lengths.to_vec(),
+            None => vec![self.sequence; positions.len()],
+        };
+
+        match &*self.exec.clone() {
+            Executor::GPU(pq) => self.gpu_forward(positions, &lengths, inputs, pq),
+            Executor::CPU | Executor::Threaded(_) => self.cpu_forward(positions, &lengths, inputs),
+        }
+    }
+
+    fn gpu_forward(&mut self, positions: &[usize], lengths: &[usize], inputs: &mut DualVec, pq: &Gpu) {
+        let projected = self.heads * self.internal;
+        let batch_size = positions.len();
+        let rows = batch_size * self.sequence;
+
+        if self.gpu_forward.is_none() {
+            self.gpu_forward = Some(GpuForward::new(pq, self));
+        }
+        let gpu = self.gpu_forward.as_mut().unwrap();
+        gpu.write_batch(pq, positions, lengths, self.sequence * projected);
+
+        {
+            let inputs = inputs.gpu_borrow().unwrap();
+            let weights = self.weights.gpu_borrow().unwrap();
+            let biases = self.biases.gpu_borrow().unwrap();
+
+            for (kernel, target) in gpu.projections.iter().zip([&mut self.queries, &mut self.keys, &mut self.values]) {
+                kernel.set_arg("weights", &*weights).unwrap();
+                kernel.set_arg("biases", &*biases).unwrap();
+                kernel.set_arg("inputs", &*inputs).unwrap();
+                kernel.set_arg("positions", &gpu.positions).unwrap();
+                kernel.set_arg("outputs", &*target.gpu_borrow().unwrap()).unwrap();
+
+                unsafe {
+                    execute_kernel(pq, kernel, (rows, projected));
+                }
+            }
+        }
+
+        gpu.weights.set_arg("queries", &*self.queries.gpu_borrow().unwrap()).unwrap();
+        gpu.weights.set_arg("keys", &*self.keys.gpu_borrow().unwrap()).unwrap();
+        gpu.weights.set_arg("lengths", &gpu.lengths).unwrap();
+        gpu.weights.set_arg("attention_weights", &*self.attention_weights.gpu_borrow().unwrap()).unwrap();
+
+        gpu.context.set_arg("attention_weights", &*self.attention_weights.gpu_borrow().unwrap()).unwrap();
+        gpu.context.set_arg("values", &*self.values.gpu_borrow().unwrap()).unwrap();
+        gpu.context.set_arg("context", &*self.context.gpu_borrow().unwrap()).unwrap();
+
+        gpu.output.set_arg("weights", &*self.output_weights.gpu_borrow().unwrap()).unwrap();
+        gpu.output.set_arg("biases", &*self.output_biases.gpu_borrow().unwrap()).unwrap();
+        gpu.output.set_arg("inputs", &*self.context.gpu_borrow().unwrap()).unwrap();
+        gpu.output.set_arg("positions", &gpu.context_positions).unwrap();
+        gpu.output.set_arg("outputs", &*self.outputs.gpu_borrow().unwrap()).unwrap();
+
+        unsafe {
+            execute_kernel(pq, &gpu.weights, (batch_size * self.heads, self.sequence));
+            execute_kernel(pq, &gpu.context, (rows, projected));
+            execute_kernel(pq, &gpu.output, (rows, self.output));
+        }
+
+        self.queries.updated_gpu();
+        self.keys.updated_gpu();
+        self.values.updated_gpu();
+        self.attention_weights.updated_gpu();
+        self.context.updated_gpu();
+        self.outputs.updated_gpu();
+    }
+
+    fn cpu_forward(&mut self, positions: &[usize], lengths: &[usize], inputs: &mut DualVec) {
+        let (heads, internal, seq) = (self.heads, self.internal, self.sequence);
+        let projected = heads * internal;
+        let input_len = seq * self.characteristics;
+
+        {
+            let inputs = inputs.cpu_borrow().unwrap();
+            let weights = self.weights.cpu_borrow().unwrap();
+            let biases = self.biases.cpu_borrow().unwrap();
+            let output_weights = self.output_weights.cpu_borrow().unwrap();
+            let output_biases = self.output_biases.cpu_borrow().unwrap();
+            let mut queries = self.queries.cpu_borrow().unwrap();
+            let mut keys = self.keys.cpu_borrow().unwrap();
+            let mut values = self.values.cpu_borrow().unwrap();
+            let mut attention_weights = self.attention_weights.cpu_borrow().unwrap();
+            let mut context = self.context.cpu_borrow().unwrap();
+            let mut outputs = self.outputs.cpu_borrow().unwrap();
+
+            let w_len = projected * self.characteristics;
+            for batch in 0..positions.len() {
+                let x = &inputs[positions[batch]..positions[batch] + input_len];
+                let rows = batch * seq * projected..(batch + 1) * seq * projected;
+                let mask = Mask { causal: self.causal, offset: 0, length: lengths[batch] };
+
+                linear(x, &weights[..w_len], &biases[..projected], self.characteristics, &mut queries[rows.clone()]);
+                linear(x, &weights[w_len..2 * w_len], &biases[projected..2 * projected], self.characteristics, &mut keys[rows.clone()]);
+                linear(x, &weights[2 * w_len..], &biases[2 * projected..], self.characteristics, &mut values[rows.clone()]);
+
+                attend(
+                    &queries[rows.clone()], &keys[rows.clone()], &values[rows.clone()],
+                    Heads { count: heads, internal }, mask,
+                    &mut attention_weights[batch * heads * seq * seq..(batch + 1) * heads * seq * seq],
+                    &mut context[rows.clone()],
+                );
+
+                linear(&context[rows], &output_weights, &output_biases, projected, &mut outputs[batch * seq * self.output..(batch + 1) * seq * self.output]);
+            }
+        }
+
+        self.queries.updated_cpu();
+        self.keys.updated_cpu();
+        self.values.updated_cpu();
+        self.attention_weights.updated_cpu();
+        self.context.updated_cpu();
+        self.outputs.updated_cpu();
+    }
+
+    pub(crate) fn read(exec: &Execs, bytes: &mut CursorReader) -> Self {
+        let mut l = Attention::new(exec, bytes.usize(), bytes.usize(), bytes.usize(), bytes.usize(), bytes.usize(), bytes.bool());
+
+        for values in [&mut l.weights, &mut l.biases, &mut l.output_weights, &mut l.output_biases] {
+            if let Some(mut values) = values.cpu_borrow() {
+                for i in 0..values.len() {
+                    values[i] = bytes.f32();
+                }
+            }
+            values.updated_cpu();
+        }
+
+        l
+    }
+
+    /// A copy of the current values in the inference form
+    pub(crate) fn frozen(&mut self) -> FrozenAttention {
+        FrozenAttention {
+            heads: self.heads,
+            internal: self.internal,
+            characteristics: self.characteristics,
+            output: self.output,
+            sequence: self.sequence,
+            causal: self.causal,
+            weights: self.weights.cpu_borrow().unwrap().clone(),
+            biases: self.biases.cpu_borrow().unwrap().clone(),
+            output_weights: self.output_weights.cpu_borrow().unwrap().clone(),
+            output_biases: self.output_biases.cpu_borrow().unwrap().clone(),
         }
     }
 }
 
-impl<'a> Layer<'a> for Attention<'_> {
-    fn dynamic_forward(&mut self, positions: &Vec<usize>, inputs: &mut DualVec) {
-        todo!()
+impl Layer for Attention {
+    fn forward(&mut self, inputs: &mut Tensor) {
+        let positions = &inputs.row_offsets();
+        let inputs = inputs.data_mut();
+        self.masked_forward(positions, None, inputs);
     }
 
-    fn forward(&mut self, inputs: &mut DualVec) -> usize {
-        todo!()
+    fn padded_forward(&mut self, inputs: &mut Tensor, lengths: &[usize]) {
+        let positions = &inputs.row_offsets();
+        let inputs = inputs.data_mut();
+        self.masked_forward(positions, Some(lengths), inputs);
     }
 
-    fn backward(&mut self, inputs: &mut DualVec, input_indices: Option<&Vec<usize>>, next_gradients: &mut DualVec, optimizer: &Optimizer) {
-        todo!()
+    fn backward(&mut self, inputs: &mut Tensor, next_gradients: &mut DualVec, optimizer: &Optimizer) {
+        let input_indices = inputs.gather_offsets();
+        let (inputs, input_indices) = (inputs.data_mut(), input_indices.as_ref());
+        let lr = optimizer.learn_rate();
+        let (heads, internal, seq) = (self.heads, self.internal, self.sequence);
+        let projected = heads * internal;
+        let input_len = seq * self.characteristics;
+        let batch_size = next_gradients.len() / (seq * self.output);
+
+        {
+            let next_gradients = next_gradients.cpu_borrow().unwrap();
+            let inputs = inputs.cpu_borrow().unwrap();
+            let weights = self.weights.cpu_borrow().unwrap();
+            let output_weights = self.output_weights.cpu_borrow().unwrap();
+            let queries = self.queries.cpu_borrow().unwrap();
+            let keys = self.keys.cpu_borrow().unwrap();
+            let values = self.values.cpu_borrow().unwrap();
+            let attention_weights = self.attention_weights.cpu_borrow().unwrap();
+            let context = self.context.cpu_borrow().unwrap();
+            let mut weight_mods = self.weight_mods.cpu_borrow().unwrap();
+            let mut bias_mods = self.bias_mods.cpu_borrow().unwrap();
+            let mut output_weight_mods = self.output_weight_mods.cpu_borrow().unwrap();
+            let mut output_bias_mods = self.output_bias_mods.cpu_borrow().unwrap();
+            let mut sensitivities = self.sensitivities.cpu_borrow().unwrap();
+            sensitivities.fill(0.);
+
+            let mut context_gradients = vec![0.; seq * projected];
+            let mut query_gradients = vec![0.; seq * projected];
+            let mut key_gradients = vec![0.; seq * projected];
+            let mut value_gradients = vec![0.; seq * projected];
+
+            let w_len = projected * self.characteristics;
+            for batch in 0..batch_size {
+                let in_offset = match input_indices {
+                    None => batch * input_len,
+                    Some(indices) => indices[batch],
+                };
+                let x = &inputs[in_offset..in_offset + input_len];
+                let rows = batch * seq * projected..(batch + 1) * seq * projected;
+                let sensitivities = &mut sensitivities[batch * input_len..(batch + 1) * input_len];
+
+                context_gradients.fill(0.);
+                linear_backward(
+                    &context[rows.clone()], &output_weights,
+                    &next_gradients[batch * seq * self.output..(batch + 1) * seq * self.output],
+                    lr, &mut output_weight_mods, &mut output_bias_mods, &mut context_gradients,
+                );
+
+                query_gradients.fill(0.);
+                key_gradients.fill(0.);
+                value_gradients.fill(0.);
+                attend_backward(
+                    &queries[rows.clone()], &keys[rows.clone()], &values[rows],
+                    &attention_weights[batch * heads * seq * seq..(batch + 1) * heads * seq * seq],
+                    &context_gradients, Heads { count: heads, internal },
+                    [&mut query_gradients, &mut key_gradients, &mut value_gradients],
+                );
+
+                for (p, gradients) in [&query_gradients, &key_gradients, &value_gradients].into_iter().enumerate() {
+                    linear_backward(
+                        x, &weights[p * w_len..(p + 1) * w_len], gradients, lr,
+                        &mut weight_mods[p * w_len..(p + 1) * w_len], &mut bias_mods[p * projected..(p + 1) * projected],
+                        sensitivities,
+                    );
+                }
+            }
+        }
+
+        self.sensitivities.updated_cpu();
+        self.weight_mods.updated_cpu();
+        self.bias_mods.updated_cpu();
+        self.output_weight_mods.updated_cpu();
+        self.output_bias_mods.updated_cpu();
     }
 
     fn activated_output(&mut self) -> &mut DualVec {
-        todo!()
+        &mut self.outputs
     }
 
-    fn as_bytes(&mut self, writer: &mut VecWriter) {
-        todo!()
+    fn as_bytes(&mut self, bytes: &mut VecWriter) {
+        bytes.usize(self.sequence * self.characteristics);
+        bytes.usize(self.heads);
+        bytes.usize(self.internal);
+        bytes.usize(self.characteristics);
+        bytes.usize(self.output);
+        bytes.bool(self.causal);
+
+        for values in [&mut self.weights, &mut self.biases, &mut self.output_weights, &mut self.output_biases] {
+            let values = values.cpu_borrow().unwrap();
+            bytes.reserve(values.len() * 4);
+            for v in values.iter() {
+                bytes.f32(*v);
+            }
+        }
     }
 
-    fn from_bytes(exec: (&'a Executor, &'a Executor, &'a Executor), bytes: &mut CursorReader) -> Rc<RefCell<dyn Layer<'a> + 'a>> {
-        todo!()
+    fn from_bytes(exec: &Execs, bytes: &mut CursorReader) -> Rc<RefCell<dyn Layer>> {
+        Rc::new(RefCell::new(Attention::read(exec, bytes)))
     }
 
     fn id(&self) -> usize {
@@ -52,26 +654,146 @@ impl<'a> Layer<'a> for Attention<'_> {
     }
 
     fn exec(&self) -> &Executor {
-        self.exec
+        &self.exec
     }
 
     fn values(&self) -> Vec<&DualVec> {
-        todo!()
+        vec![&self.weights, &self.biases, &self.output_weights, &self.output_biases]
+    }
+
+    fn mods(&mut self) -> Result<Vec<&mut DualVec>, Error> {
+        Ok(vec![&mut self.weight_mods, &mut self.bias_mods, &mut self.output_weight_mods, &mut self.output_bias_mods])
     }
 
     fn input_size(&self) -> usize {
-        todo!()
+        self.sequence * self.characteristics
     }
 
     fn output_size(&self) -> usize {
-        todo!()
+        self.sequence * self.output
     }
 
     fn sensitivities(&mut self) -> &mut DualVec {
-        todo!()
+        &mut self.sensitivities
     }
 
     fn apply_gradients(&mut self, optimizer: &Optimizer, batch_size: usize) {
-        todo!()
+        match optimizer {
+            Optimizer::GradientDecent(_) => {
+                let i_batch_size = 1. / batch_size as f32;
+
+                for (values, mods) in [
+                    (&mut self.weights, &mut self.weight_mods),
+                    (&mut self.biases, &mut self.bias_mods),
+                    (&mut self.output_weights, &mut self.output_weight_mods),
+                    (&mut self.output_biases, &mut self.output_bias_mods),
+                ] {
+                    if let (Some(mut values), Some(mut mods)) = (values.cpu_borrow(), mods.cpu_borrow()) {
+                        for i in 0..values.len() {
+                            values[i] += mods[i] * i_batch_size;
+                        }
+                        mods.fill(0.);
+                    }
+                    values.updated_cpu();
+                    mods.updated_cpu();
+                }
+            }
+        }
+    }
+
+    fn step(&mut self, _position: usize, cache: &mut KeyValueCache, inputs: &[f32]) -> Result<Vec<f32>, Error> {
+        if inputs.len() != self.characteristics {
+            return Err(Error::Mismatch(MismatchError::Step(inputs.len(), self.characteristics)));
+        }
+        if !self.causal {
+            return Err(Error::Network(NetworkError::NotCausal));
+        }
+        if cache.length >= self.sequence {
+            return Err(Error::Network(NetworkError::Position(cache.length, self.sequence)));
+        }
+
+        let projected = self.heads * self.internal;
+        let w_len = projected * self.characteristics;
+        let weights = self.weights.cpu_borrow().unwrap();
+        let biases = self.biases.cpu_borrow().unwrap();
+
+        let mut query = vec![0.; projected];
+        let mut key = vec![0.; projected];
+        let mut value = vec![0.; projected];
+        linear(inputs, &weights[..w_len], &biases[..projected], self.characteristics, &mut query);
+        linear(inputs, &weights[w_len..2 * w_len], &biases[projected..2 * projected], self.characteristics, &mut key);
+        linear(inputs, &weights[2 * w_len..], &biases[2 * projected..], self.characteristics, &mut value);
+
+        cache.keys.extend(key);
+        cache.values.extend(value);
+        cache.length += 1;
+
+        // Every cached position precedes the new one, so they are all visible even to a causal layer
+        let mask = Mask { causal: false, offset: 0, length: cache.length };
+        let mut attention_weights = vec![0.; self.heads * cache.length];
+        let mut context = vec![0.; projected];
+        attend(&query, &cache.keys, &cache.values, Heads { count: self.heads, internal: self.internal }, mask, &mut attention_weights, &mut context);
+
+        let mut outputs = vec![0.; self.output];
+        linear(&context, &self.output_weights.cpu_borrow().unwrap(), &self.output_biases.cpu_borrow().unwrap(), projected, &mut outputs);
+        Ok(outputs)
+    }
+    fn freeze(&mut self) -> Result<Box<dyn FrozenLayer>, Error> {
+        Ok(Box::new(self.frozen()))
+    }
+}
+
+/// The inference form of [Attention]
+#[derive(Debug)]
+pub struct FrozenAttention {
+    heads: usize,
+    internal: usize,
+    characteristics: usize,
+    output: usize,
+    sequence: usize,
+    causal: bool,
+    weights: Vec<f32>,
+    biases: Vec<f32>,
+    output_weights: Vec<f32>,
+    output_biases: Vec<f32>,
+}
+
+impl FrozenLayer for FrozenAttention {
+    fn input_size(&self) -> usize {
+        self.sequence * self.characteristics
     }
-}
\ No newline at end of file
+
+    fn output_size(&self) -> usize {
+        self.sequence * self.output
+    }
+
+    fn forward(&self, inputs: &[f32], outputs: &mut [f32], scratch: &mut Scratch) {
+        let (heads, internal, seq) = (self.heads, self.internal, self.sequence);
+        let projected = heads * internal;
+        let input_len = self.input_size();
+        let output_len = self.output_size();
+        let w_len = projected * self.characteristics;
+        let mask = Mask { causal: self.causal, offset: 0, length: seq };
+
+        let mut queries = scratch.take(seq * projected);
+        let mut keys = scratch.take(seq * projected);
+        let mut values = scratch.take(seq * projected);
+        let mut context = scratch.take(seq * projected);
+        let mut attention_weights = scratch.take(heads * seq * seq);
+
+        for batch in 0..inputs.len() / input_len {
+            let x = &inputs[batch * input_len..(batch + 1) * input_len];
+            linear(x, &self.weights[..w_len], &self.biases[..projected], self.characteristics, &mut queries);
+            linear(x, &self.weights[w_len..2 * w_len], &self.biases[projected..2 * projected], self.characteristics, &mut keys);
+            linear(x, &self.weights[2 * w_len..], &self.biases[2 * projected..], self.characteristics, &mut values);
+
+            attend(&queries, &keys, &values, Heads { count: heads, internal }, mask, &mut attention_weights, &mut context);
+
+            linear(&context, &self.output_weights, &self.output_biases, projected, &mut outputs[batch * output_len..(batch + 1) * output_len]);
+        }
+
+        for buffer in [queries, keys, values, context, attention_weights] {
+            scratch.give(buffer);
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use std::cell::RefCell;
+use std::fmt::Debug;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::{Executor, Execs, Optimizer};
+use crate::autograd::{Tape, Var};
+use crate::dual_vec::DualVec;
+use crate::error::Error;
+use crate::layer::Layer;
+use crate::ops;
+use crate::tensor::Tensor;
+use crate::utils::vec_utils::{CursorReader, VecWriter};
+
+/// A layer written as its forward pass alone, whose gradients are found by recording the pass on a
+/// [Tape], see [LayerType::Autograd](crate::layer::LayerType::Autograd)
+pub trait Definition: Debug {
+    /// The shapes of the parameters of the layer for input samples of size `inputs`
+    fn parameters(&self, inputs: usize) -> Vec<Vec<usize>>;
+
+    fn output_size(&self, inputs: usize) -> usize;
+
+    /// Records the outputs of `inputs`, a `[batch, inputs]` tensor, computed with the `parameters`
+    /// in the order of [Definition::parameters]. The outputs are `[batch, output_size]`.
+    fn forward(&self, tape: &mut Tape, inputs: Var, parameters: &[Var]) -> Result<Var, Error>;
+}
+
+/// Runs a [Definition] as a layer. The forward pass is recorded again when going backward, so no
+/// tape is kept between the passes.
+///
+/// The values are written by [Layer::as_bytes] like those of other layers, but as the definition
+/// is code, networks with these layers can't be read back with [Network::from_bytes].
+///
+/// [Network::from_bytes]: crate::network::Network::from_bytes
+#[derive(Debug)]
+pub struct AutogradLayer {
+    exec: Arc<Executor>,
+    definition: Rc<dyn Definition>,
+    input_len: usize,
+    output_len: usize,
+
+    parameters: Vec<Tensor>,
+    /// The changes of every parameter summed over the batch, applied by [Layer::apply_gradients]
+    mods: Vec<DualVec>,
+
+    outputs: DualVec,
+    sensitivities: DualVec,
+}
+
+impl AutogradLayer {
+    pub fn new(exec: &Execs, inputs: usize, definition: Rc<dyn Definition>) -> Self {
+        let c = &*exec.current;
+        let parameters = definition.parameters(inputs).iter()
+            .map(|shape| {
+                let mut values = DualVec::from_exec(c, shape.iter().product());
+                // scaled by the inputs like the weights of dense layers
+                values.randomize(c, (inputs as f32).sqrt());
+                Tensor::new(values, shape).expect("Parameters must have at least one dimension")
+            })
+            .collect::<Vec<_>>();
+
+        AutogradLayer {
+            exec: exec.current.clone(),
+            input_len: inputs,
+            output_len: definition.output_size(inputs),
+            mods: parameters.iter().map(|p| DualVec::from_exec(c, p.len())).collect(),
+            parameters,
+            definition,
+
+            outputs: DualVec::from_execs(exec.c_to_n(), 0),
+            sensitivities: DualVec::from_execs(exec.p_to_c(), 0),
+        }
+    }
+
+    pub fn parameters(&self) -> &[Tensor] {
+        &self.parameters
+    }
+
+    /// Records the forward pass of `inputs` on `tape`, returning the variables of the inputs and
+    /// parameters along with the outputs
+    fn record(&self, tape: &mut Tape, inputs: &Tensor) -> Result<(Var, Vec<Var>, Var), Error> {
+        let batch_size = inputs.batch_size();
+        let inputs = tape.var(inputs.clone());
+        let inputs = tape.reshape(inputs, &[batch_size, self.input_len])?;
+        let parameters: Vec<Var> = self.parameters.iter().map(|p| tape.var(p.clone())).collect();
+
+        let outputs = self.definition.forward(tape, inputs, &parameters)?;
+        Ok((inputs, parameters, outputs))
+    }
+}
+
+impl Layer for AutogradLayer {
+    fn forward(&mut self, inputs: &mut Tensor) {
+        let mut tape = Tape::new(&self.exec);
+        let (_, _, outputs) = self.record(&mut tape, inputs)
+            .expect("Failed to record the forward pass of the autograd layer");
+
+        let outputs = ops::contiguous(&self.exec, &mut tape.value(outputs).clone())
+            .expect("Failed to copy the outputs of the autograd layer");
+        self.outputs = outputs.into_data();
+    }
+
+    fn backward(&mut self, inputs: &mut Tensor, gradients: &mut DualVec, optimizer: &Optimizer) {
+        let lr = optimizer.learn_rate();
+        let batch_size = inputs.batch_size();
+
+        let mut tape = Tape::new(&self.exec);
+        let (input_var, parameter_vars, outputs) = self.record(&mut tape, inputs)
+            .expect("Failed to record the forward pass of the autograd layer");
+        let seed = Tensor::new(gradients.clone(), &[batch_size, self.output_len])
+            .expect("The gradients don't fit the outputs of the autograd layer");
+        let mut found = tape.backward(outputs, Some(seed))
+            .expect("Failed to find the gradients of the autograd layer");
+
+        for (mods, var) in self.mods.iter_mut().zip(&parameter_vars) {
+            if let Some(mut gradient) = found.take(*var) {
+                ops::add_scaled(&self.exec, mods, gradient.data_mut(), -lr)
+                    .expect("Failed to add the parameter gradients");
+            }
+        }
+
+        self.sensitivities = match found.take(input_var) {
+            Some(gradient) => gradient.into_data(),
+            None => DualVec::from_exec(&self.exec, batch_size * self.input_len),
+        };
+    }
+
+    fn apply_gradients(&mut self, optimizer: &Optimizer, batch_size: usize) {
+        match optimizer {
+            Optimizer::GradientDecent(_) => {
+                for (parameter, mods) in self.parameters.iter_mut().zip(self.mods.iter_mut()) {
+                    ops::add_scaled(&self.exec, parameter.data_mut(), mods, 1. / batch_size as f32)
+                        .expect("Failed to apply the parameter gradients");
+                    *mods = DualVec::from_exec(&self.exec, mods.len());
+                }
+            }
+        }
+    }
+
+    fn as_bytes(&mut self, bytes: &mut VecWriter) {
+        bytes.usize(self.input_len);
+        bytes.usize(self.parameters.len());
+        for parameter in self.parameters.iter_mut() {
+            let values = parameter.data_mut().cpu_borrow().unwrap();
+            bytes.reserve(8 + values.len() * 4);
+            bytes.usize(values.len());
+            for v in values.iter() {
+                bytes.f32(*v);
+            }
+        }
+    }
+
+    fn from_bytes(exec: &Execs, bytes: &mut CursorReader) -> Rc<RefCell<dyn Layer>> {
+        // layers are only read through `layer::read_layer`, which returns `DecodeError::Autograd`
+        // for the id of autograd layers instead of calling this
+        unreachable!("Autograd layers are defined by code, so they can't be read from bytes")
+    }
+
+    fn id(&self) -> usize {
+        9
+    }
+
+    fn exec(&self) -> &Executor {
+        &self.exec
+    }
+
+    fn values(&self) -> Vec<&DualVec> {
+        self.parameters.iter().map(|p| p.data()).collect()
+    }
+
+    fn mods(&mut self) -> Result<Vec<&mut DualVec>, Error> {
+        Ok(self.mods.iter_mut().collect())
+    }
+
+    fn input_size(&self) -> usize {
+        self.input_len
+    }
+
+    fn output_size(&self) -> usize {
+        self.output_len
+    }
+
+    fn activated_output(&mut self) -> &mut DualVec {
+        &mut self.outputs
+    }
+
+    fn sensitivities(&mut self) -> &mut DualVec {
+        &mut self.sensitivities
+    }
+}
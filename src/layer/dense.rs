@@ -1,22 +1,126 @@
+use std::borrow::Cow;
 use std::cell::RefCell;
 use std::rc::Rc;
 use std::time::Instant;
 
-use ocl::{Kernel, ProQue};
+use ocl::{Buffer, Kernel, ProQue, Program};
 use ocl::builders::KernelBuilder;
 
-use crate::{Executor, Optimizer};
+use crate::{Executor, Execs, Optimizer};
+use std::sync::Arc;
 use crate::activation::Activation;
 use crate::dual_vec::DualVec;
+use crate::tensor::Tensor;
+use crate::error::{Error, MismatchError, NetworkError};
+use crate::frozen::{FrozenLayer, Scratch};
+use crate::gpu::Gpu;
+use crate::layer::attention::{KeyValueCache, linear};
 use crate::layer::Layer;
-use crate::utils::cl_utils::execute_kernel;
-use crate::utils::{cl_utils, gpu_math};
+use crate::ops;
+use crate::precision;
+use crate::precision::LossScale;
+use crate::tensor::DType;
+use crate::utils::cl_utils::{execute_kernel, execute_tiled};
+use crate::utils::{cl_utils, parallel};
+use crate::utils::gemm::{gemm, Matrix};
 use crate::utils::vec_utils::{CursorReader, VecWriter};
 
+/// The rows of `row_len` values starting at `offsets`, which are only copied when they aren't
+/// already next to each other
+fn gather_rows<'v>(values: &'v [f32], offsets: &[usize], row_len: usize) -> Cow<'v, [f32]> {
+    let first = offsets.first().copied().unwrap_or(0);
+    if offsets.iter().enumerate().all(|(i, offset)| *offset == first + i * row_len) {
+        return Cow::Borrowed(&values[first..first + offsets.len() * row_len]);
+    }
+
+    let mut rows = Vec::with_capacity(offsets.len() * row_len);
+    for offset in offsets {
+        rows.extend_from_slice(&values[*offset..*offset + row_len]);
+    }
+    Cow::Owned(rows)
+}
+
+/// The sum of the products of `inputs` and `weights` in double precision
+fn dot(inputs: &[f32], weights: &[f64]) -> f64 {
+    inputs.iter().zip(weights).map(|(i, w)| *i as f64 * w).sum()
+}
+
+/// The values of a layer kept in double precision, which are computed on the host
+#[derive(Debug)]
+struct DoubleValues {
+    weights: Vec<f64>,
+    biases: Vec<f64>,
+    weight_mods: Vec<f64>,
+    bias_mods: Vec<f64>,
+}
+
+/// The values of a layer stored in half precision on the device. The weights are copied from the
+/// f32 master weights on the host after every update, and the gradients are scaled by `scale`.
+#[derive(Debug)]
+struct HalfValues {
+    dtype: DType,
+    weights: Buffer<u16>,
+    /// The outputs of the batch before the activation, kept for the backward pass
+    outputs: Buffer<u16>,
+    gradients: Buffer<u16>,
+    scale: LossScale,
+}
+
+impl HalfValues {
+    fn new(gpu: &Gpu, dtype: DType, weights: &[f32], len: usize) -> Self {
+        let half = HalfValues {
+            dtype,
+            weights: cl_utils::new_buffer(gpu, weights.len()),
+            outputs: cl_utils::new_buffer(gpu, len.max(1)),
+            gradients: cl_utils::new_buffer(gpu, len.max(1)),
+            scale: LossScale::default(),
+        };
+        half.write_weights(weights);
+        half
+    }
+
+    fn write_weights(&self, weights: &[f32]) {
+        cl_utils::buf_write(&self.weights, &precision::to_half(self.dtype, weights));
+    }
+
+    /// Grows the buffers of the batch to `len` values. Every pass writes them before they are read,
+    /// so their values aren't kept.
+    fn reserve(&mut self, gpu: &Gpu, len: usize) {
+        if self.outputs.len() < len {
+            self.outputs = cl_utils::new_buffer(gpu, len);
+            self.gradients = cl_utils::new_buffer(gpu, len);
+        }
+    }
+}
+
+/// Kernel arguments of the buffers of values kept in the precision of the layer, which are set
+/// with [set_storage] before every launch
+trait StorageArg {
+    fn storage_arg(&mut self, name: &'static str, dtype: DType) -> &mut Self;
+}
+
+impl StorageArg for KernelBuilder<'_> {
+    fn storage_arg(&mut self, name: &'static str, dtype: DType) -> &mut Self {
+        match dtype.is_half() {
+            true => self.arg_named(name, None::<&Buffer<u16>>),
+            false => self.arg_named(name, None::<&Buffer<f32>>),
+        }
+    }
+}
+
+/// Sets the argument `name` to the half precision buffer when the values are stored in one, and
+/// to the buffer of `values` otherwise
+fn set_storage(kernel: &Kernel, name: &'static str, values: &mut DualVec, half: Option<&Buffer<u16>>) {
+    match half {
+        Some(buffer) => kernel.set_arg(name, buffer),
+        None => kernel.set_arg(name, &*values.gpu_borrow().unwrap()),
+    }.unwrap();
+}
+
 #[derive(Debug)]
-pub struct Dense<'a> {
-    exec: &'a Executor,
-    execs:  (&'a Executor, &'a Executor, &'a Executor),
+pub struct Dense {
+    exec: Arc<Executor>,
+    execs: Execs,
     size: usize,
     input_len: usize,
 
@@ -32,20 +136,33 @@ pub struct Dense<'a> {
     activated_outputs: DualVec,
     sensitivities: DualVec,
 
+    /// The gradients of the outputs of the last backward pass on the GPU
+    gradients: DualVec,
+
+    /// The kernels compiled for the constants of the layer, which are built by [Layer::prepare]
+    program: Option<Program>,
     forward_kernel: Option<Kernel>,
-    backward_kernel: Option<Kernel>,
+    /// The gradient, modification and sensitivity kernels
+    backward_kernels: Option<[Kernel; 3]>,
+    /// Set by the gradient kernel when a scaled gradient overflowed
+    overflow: Option<Buffer<i32>>,
+
+    /// The precision the values are kept in, see [Layer::set_precision]
+    precision: DType,
+    double: Option<DoubleValues>,
+    half: Option<HalfValues>,
 }
 
-impl<'a> Dense<'a> {
+impl Dense {
 
-    pub fn new(exec: (&'a Executor, &'a Executor, &'a Executor), inputs: usize, size: usize, activation: Activation) -> Self {
-        let c = exec.1; // current
-        let p_to_c = (exec.0, exec.1); // previous to current
-        let c_to_n = (exec.1, exec.2); // current to next
+    pub fn new(exec: &Execs, inputs: usize, size: usize, activation: Activation) -> Self {
+        let c = &*exec.current; // current
+        let p_to_c = exec.p_to_c(); // previous to current
+        let c_to_n = exec.c_to_n(); // current to next
 
         let mut a = Dense {
-            exec: exec.1,
-            execs: exec,
+            exec: exec.current.clone(),
+            execs: exec.clone(),
             size,
             input_len: inputs,
 
@@ -60,8 +177,16 @@ impl<'a> Dense<'a> {
 
             weight_mods: DualVec::from_exec(c, inputs * size),
             bias_mods: DualVec::from_exec(c, size),
+            gradients: DualVec::from_exec(c, size),
+
+            program: None,
             forward_kernel: None,
-            backward_kernel: None,
+            backward_kernels: None,
+            overflow: None,
+
+            precision: DType::F32,
+            double: None,
+            half: None,
         };
 
         a.weights.randomize(c, (a.weights.len() as f32).cbrt());
@@ -73,188 +198,283 @@ impl<'a> Dense<'a> {
         a
     }
 
-    fn ensure_batch_size(&mut self, batch_size: usize) -> bool {
+    fn ensure_batch_size(&mut self, batch_size: usize) {
         let target = batch_size * self.size;
-        if self.outputs.len() < target {
-            self.outputs.expand_to(self.size * batch_size);
+        // the values stored in half precision replace the outputs and gradients
+        let full = self.half.is_none();
+        if self.activated_outputs.len() < target {
             self.activated_outputs.expand_to(self.size * batch_size);
             self.sensitivities.expand_to(self.input_len * batch_size);
-            true
-        } else if self.outputs.len() > target {
-            self.outputs.truncate_to(self.size * batch_size);
+            if full {
+                self.outputs.expand_to(self.size * batch_size);
+                self.gradients.expand_to(self.size * batch_size);
+            }
+        } else if self.activated_outputs.len() > target {
             self.activated_outputs.truncate_to(self.size * batch_size);
             self.sensitivities.truncate_to(self.input_len * batch_size);
-            true
-        } else {
-            false
+            if full {
+                self.outputs.truncate_to(self.size * batch_size);
+                self.gradients.truncate_to(self.size * batch_size);
+            }
+        }
+
+        if let (Some(half), Executor::GPU(gpu)) = (&mut self.half, &*self.exec) {
+            half.reserve(gpu, target);
         }
     }
 
-    fn gpu_forward(&mut self, positions: &Vec<usize>, activated_inputs: &mut DualVec, pq: &ProQue) {
-        {
-            let outputs = self.outputs.gpu().unwrap();
-            let mut outputs = outputs.borrow_mut();
-            outputs.cmd().fill(0., None).enq();
-
-            if self.forward_kernel.is_none() {
-                let activation_id: usize = (&self.activation).into();
-                self.forward_kernel = Some(
-                    pq.kernel_builder("forward")
-                        .arg(activation_id as u64)
-                        .arg(self.input_len as u64)
-                        .arg(self.size as u64)
-                        .arg(&*self.weights.gpu_borrow().unwrap())
-                        .arg(&*self.biases.gpu_borrow().unwrap())
-                        .arg_named("inputs", &*activated_inputs.gpu_borrow().unwrap())
-                        .arg(&*outputs)
-                        .arg(&*self.activated_outputs.gpu().unwrap().borrow())
-                        .arg_named("bo_i", 0u64)
-                        .arg_named("bo_o", 0u64)
-                        .build().unwrap()
-                );
-            }
+    /// The constants the GPU kernels of the layer are compiled with
+    fn defines(&self) -> [(&'static str, u64); 4] {
+        let activation_id: usize = (&self.activation).into();
+        [
+            ("ACTIVATION", activation_id as u64),
+            ("INPUT_LENGTH", self.input_len as u64),
+            ("LAYER_LEN", self.size as u64),
+            ("STORAGE", usize::from(self.precision) as u64),
+        ]
+    }
 
-            if let Some(kernel) = &self.forward_kernel {
-                for batch in 0..positions.len() {
-                    kernel.set_arg("bo_i", positions[batch] as u64);
-                    kernel.set_arg("bo_o", (batch * self.size) as u64);
-                    kernel.set_arg("inputs", &*activated_inputs.gpu_borrow().unwrap());
+    /// Builds the kernels of the layer on a GPU, unless they were built already
+    pub(crate) fn build_program(&mut self) -> Result<(), Error> {
+        if let (None, Executor::GPU(gpu)) = (&self.program, &*self.exec) {
+            self.program = Some(gpu.program(&self.defines()).map_err(Error::Gpu)?);
+        }
+        Ok(())
+    }
 
-                    unsafe {
-                        execute_kernel(pq, &kernel, (self.size, self.input_len));
-                    }
-                }
+    /// Computes the whole batch in a single launch, gathering the samples at `positions` on the device
+    fn gpu_forward(&mut self, positions: &[usize], activated_inputs: &mut DualVec, pq: &Gpu) {
+        let positions_buf = cl_utils::positions_buffer(pq, positions);
+
+        if self.forward_kernel.is_none() {
+            let activation_id: usize = (&self.activation).into();
+            let program = self.program.as_ref().expect("The layer is prepared before its forward pass");
+            self.forward_kernel = Some(
+                Kernel::builder()
+                    .program(program)
+                    .name("dense_forward")
+                    .queue(pq.queue().clone())
+                    .arg(activation_id as u64)
+                    .arg(self.input_len as u64)
+                    .arg(self.size as u64)
+                    .arg_named("batch", positions.len() as u64)
+                    .storage_arg("weights", self.precision)
+                    .arg(&*self.biases.gpu_borrow().unwrap())
+                    .arg_named("inputs", &*activated_inputs.gpu_borrow().unwrap())
+                    .arg_named("positions", &positions_buf)
+                    .storage_arg("outputs", self.precision)
+                    .arg_named("activated_outputs", &*self.activated_outputs.gpu_borrow().unwrap())
+                    .arg_local::<f32>(pq.tile() * (pq.tile() + 1))
+                    .arg_local::<f32>(pq.tile() * (pq.tile() + 1))
+                    .build().unwrap()
+            );
+        }
 
-                self.activated_outputs.updated_gpu();
-                self.outputs.updated_gpu();
+        if let Some(kernel) = &self.forward_kernel {
+            // The outputs are reallocated when the batch size changes, so they are set on every launch
+            kernel.set_arg("batch", positions.len() as u64).unwrap();
+            kernel.set_arg("inputs", &*activated_inputs.gpu_borrow().unwrap()).unwrap();
+            kernel.set_arg("positions", &positions_buf).unwrap();
+            set_storage(kernel, "weights", &mut self.weights, self.half.as_ref().map(|h| &h.weights));
+            set_storage(kernel, "outputs", &mut self.outputs, self.half.as_ref().map(|h| &h.outputs));
+            kernel.set_arg("activated_outputs", &*self.activated_outputs.gpu_borrow().unwrap()).unwrap();
+
+            unsafe {
+                execute_tiled(pq, kernel, (positions.len(), self.size));
             }
+
+            self.activated_outputs.updated_gpu();
+            self.outputs.updated_gpu();
         }
     }
 
-    fn cpu_forward(&mut self, positions: &Vec<usize>, activated_inputs: &mut DualVec) {
+    /// Computes the outputs as a single matrix product, splitting the batch rows between the threads of the executor
+    fn cpu_forward(&mut self, positions: &[usize], activated_inputs: &mut DualVec) {
+        let threads = self.exec.threads();
+        let (size, input_len) = (self.size, self.input_len);
+        let activation = &self.activation;
+
         {
             let activated_inputs = activated_inputs.cpu_borrow().unwrap();
-            let biases = self.biases.cpu_borrow().unwrap();
-            let weights = self.weights.cpu_borrow().unwrap();
+            let inputs = gather_rows(&activated_inputs, positions, input_len);
+            let biases: &[f32] = &self.biases.cpu_borrow().unwrap();
+            let weights: &[f32] = &self.weights.cpu_borrow().unwrap();
             let mut outputs = self.outputs.cpu_borrow().unwrap();
             let mut activated_outputs = self.activated_outputs.cpu_borrow().unwrap();
+            let len = positions.len() * size;
 
             for batch in 0..positions.len() {
-                let output_offset = batch * self.size;
-                let input_offset = positions[batch];
-
-                let mut x = 0;
-                while x < self.size {
-                    let output_index = x + output_offset;
-
-                    outputs[output_index] = biases[x];
-
-                    let mut y = 0;
-                    while y < self.input_len {
-                        let weight_index = (self.input_len * x) + y;
-                        let in_value = activated_inputs[y + input_offset];
-
-                        outputs[output_index] += weights[weight_index] * in_value;
-                        y += 1
-                    }
-                    activated_outputs[output_index] = self.activation.activate(outputs[output_index]);
-
-                    x += 1;
-                }
+                outputs[batch * size..(batch + 1) * size].copy_from_slice(biases);
             }
+            gemm(
+                threads, 1.,
+                Matrix::new(&inputs, positions.len(), input_len),
+                Matrix::new(weights, size, input_len).t(),
+                1., &mut outputs[..len],
+            );
+
+            let outputs: &[f32] = &outputs;
+            parallel::for_chunks(threads, &mut activated_outputs[..len], 1, |start, activated_outputs| {
+                for i in 0..activated_outputs.len() {
+                    activated_outputs[i] = activation.activate(outputs[start + i]);
+                }
+            });
         }
 
         self.activated_outputs.updated_cpu();
         self.outputs.updated_cpu();
     }
 
-    fn gpu_backward(&mut self, inputs: &mut DualVec, input_indices: Option<&Vec<usize>>, in_sensitivities: &mut DualVec, optimizer: &Optimizer, batch_size: usize, pq: &ProQue) {
-        let lr = optimizer.learn_rate();
-
-        self.sensitivities.gpu_borrow().unwrap().cmd().fill(0., None).enq();
-
-        if self.backward_kernel.is_none() {
-            self.backward_kernel = Some(pq.kernel_builder("backward")
-                .arg(<&Activation as Into<usize>>::into((&self.activation)) as u64)
+    /// Computes the whole batch in three launches: the gradients of the outputs, then the modifications
+    /// and the sensitivities as tiled matrix products, which don't need any atomics.
+    ///
+    /// Gradients stored in half precision are scaled, and when one of them overflows the batch is
+    /// skipped, leaving the modifications as they are and passing back no sensitivities.
+    fn gpu_backward(&mut self, inputs: &mut DualVec, input_indices: Option<&Vec<usize>>, in_sensitivities: &mut DualVec, optimizer: &Optimizer, batch_size: usize, pq: &Gpu) {
+        let in_offsets: Vec<usize> = match input_indices {
+            None => (0..batch_size).map(|batch| batch * self.input_len).collect(),
+            Some(indices) => indices[..batch_size].to_vec(),
+        };
+        let positions_buf = cl_utils::positions_buffer(pq, &in_offsets);
+        let scale = self.half.as_ref().map_or(1., |half| half.scale.scale());
+
+        if self.backward_kernels.is_none() {
+            let activation_id: usize = (&self.activation).into();
+            let program = self.program.as_ref().expect("The layer is prepared before its forward pass");
+            let overflow = cl_utils::new_buffer::<i32>(pq, 1);
+            let gradients = Kernel::builder()
+                .program(program)
+                .name("dense_gradients")
+                .queue(pq.queue().clone())
+                .arg(activation_id as u64)
+                .arg_named("scale", scale)
+                .storage_arg("outputs", self.precision)
+                .arg_named("in_sensitivities", &*in_sensitivities.gpu_borrow().unwrap())
+                .storage_arg("gradients", self.precision)
+                .arg(&overflow)
+                .build().unwrap();
+            let weight_mods = Kernel::builder()
+                .program(program)
+                .name("dense_weight_mods")
+                .queue(pq.queue().clone())
                 .arg(self.input_len as u64)
-                .arg(lr)
+                .arg(self.size as u64)
+                .arg_named("batch", batch_size as u64)
+                .arg_named("learn_rate", optimizer.learn_rate())
                 .arg_named("inputs", &*inputs.gpu_borrow().unwrap())
-                .arg(&*self.outputs.gpu_borrow().unwrap())
-                .arg_named("in_sensitivities", &*in_sensitivities.gpu_borrow().unwrap())
-                .arg(&*self.weights.gpu_borrow().unwrap())
-                .arg(&*self.biases.gpu_borrow().unwrap())
+                .arg_named("positions", &positions_buf)
+                .storage_arg("gradients", self.precision)
                 .arg(&*self.weight_mods.gpu_borrow().unwrap())
                 .arg(&*self.bias_mods.gpu_borrow().unwrap())
-                .arg(&*self.sensitivities.gpu_borrow().unwrap())
-                .arg_named("bo_i", 0u64)
-                .arg_named("bo_s", 0u64)
-                .arg_named("bo_o", 0u64)
-                .build().unwrap());
+                .arg_local::<f32>(pq.tile() * (pq.tile() + 1))
+                .arg_local::<f32>(pq.tile() * (pq.tile() + 1))
+                .build().unwrap();
+            let sensitivities = Kernel::builder()
+                .program(program)
+                .name("dense_sensitivities")
+                .queue(pq.queue().clone())
+                .arg(self.input_len as u64)
+                .arg(self.size as u64)
+                .arg_named("batch", batch_size as u64)
+                .arg_named("scale", scale)
+                .storage_arg("weights", self.precision)
+                .storage_arg("gradients", self.precision)
+                .arg_named("sensitivities", &*self.sensitivities.gpu_borrow().unwrap())
+                .arg_local::<f32>(pq.tile() * (pq.tile() + 1))
+                .arg_local::<f32>(pq.tile() * (pq.tile() + 1))
+                .build().unwrap();
+            self.backward_kernels = Some([gradients, weight_mods, sensitivities]);
+            self.overflow = Some(overflow);
         }
 
-        if let Some(k) = &self.backward_kernel {
-            for batch in 0..batch_size {
-                let out_offset = batch * self.size;
-                let in_offset = match input_indices {
-                    None => batch * self.input_len,
-                    Some(indices) => {
-                        indices[batch]
-                    },
-                };
-                k.set_arg("bo_i", in_offset as u64);
-                k.set_arg("bo_s", (batch * self.input_len) as u64);
-                k.set_arg("bo_o", out_offset as u64);
-                k.set_arg("inputs", &*inputs.gpu_borrow().unwrap());
-                k.set_arg("in_sensitivities", &*in_sensitivities.gpu_borrow().unwrap());
+        if let (Some([gradients, weight_mods, sensitivities]), Some(overflow)) = (&self.backward_kernels, &self.overflow) {
+            let half = self.half.as_ref();
+            gradients.set_arg("scale", scale).unwrap();
+            set_storage(gradients, "outputs", &mut self.outputs, half.map(|h| &h.outputs));
+            gradients.set_arg("in_sensitivities", &*in_sensitivities.gpu_borrow().unwrap()).unwrap();
+            set_storage(gradients, "gradients", &mut self.gradients, half.map(|h| &h.gradients));
+            weight_mods.set_arg("batch", batch_size as u64).unwrap();
+            // the gradients are scaled, so the learn rate is scaled down by as much
+            weight_mods.set_arg("learn_rate", optimizer.learn_rate() / scale).unwrap();
+            weight_mods.set_arg("inputs", &*inputs.gpu_borrow().unwrap()).unwrap();
+            weight_mods.set_arg("positions", &positions_buf).unwrap();
+            set_storage(weight_mods, "gradients", &mut self.gradients, half.map(|h| &h.gradients));
+            sensitivities.set_arg("batch", batch_size as u64).unwrap();
+            sensitivities.set_arg("scale", scale).unwrap();
+            set_storage(sensitivities, "weights", &mut self.weights, half.map(|h| &h.weights));
+            set_storage(sensitivities, "gradients", &mut self.gradients, half.map(|h| &h.gradients));
+            sensitivities.set_arg("sensitivities", &*self.sensitivities.gpu_borrow().unwrap()).unwrap();
+
+            unsafe {
+                if half.is_some() {
+                    overflow.cmd().fill(0, None).enq().expect("Failed to clear the overflow flag");
+                }
+                execute_kernel(pq, gradients, batch_size * self.size);
+            }
 
+            // only gradients in half precision are checked, as reading the flag waits for the kernel
+            let overflowed = half.is_some() && cl_utils::buf_read(overflow)[0] != 0;
+            if overflowed {
+                self.sensitivities.gpu_borrow().unwrap().cmd().fill(0., None).enq()
+                    .expect("Failed to clear the sensitivities");
+            } else {
                 unsafe {
-                    execute_kernel(pq, k, (self.size, self.input_len));
+                    execute_tiled(pq, weight_mods, (self.size, self.input_len));
+                    execute_tiled(pq, sensitivities, (batch_size, self.input_len));
                 }
             }
+            if let Some(half) = &mut self.half {
+                half.scale.update(overflowed);
+            }
 
+            self.gradients.updated_gpu();
             self.sensitivities.updated_gpu();
             self.bias_mods.updated_gpu();
             self.weight_mods.updated_gpu();
         }
     }
 
+    /// Computes the modifications and sensitivities as matrix products, splitting their rows between the
+    /// threads of the executor. Every value is summed in the same order for any amount of threads.
     fn cpu_backward(&mut self, inputs: &mut DualVec, input_indices: Option<&Vec<usize>>, in_sensitivities: &mut DualVec, optimizer: &Optimizer, batch_size: usize) {
         let lr = optimizer.learn_rate();
+        let threads = self.exec.threads();
+        let (size, input_len) = (self.size, self.input_len);
+        let activation = &self.activation;
 
-        {
-            let in_sensitivities = in_sensitivities.cpu_borrow().unwrap(); // 0
-
-            let mut outputs = self.outputs.cpu_borrow().unwrap();
-
-            let mut sensitivities = self.sensitivities.cpu_borrow().unwrap(); // -1
-            sensitivities.fill(0.);
+        let in_offsets: Vec<usize> = match input_indices {
+            None => (0..batch_size).map(|batch| batch * input_len).collect(),
+            Some(indices) => indices[..batch_size].to_vec(),
+        };
 
-            let biases = self.biases.cpu_borrow().unwrap();
-            let weights = self.weights.cpu_borrow().unwrap();
+        {
+            let in_sensitivities: &[f32] = &in_sensitivities.cpu_borrow().unwrap();
+            let outputs: &[f32] = &self.outputs.cpu_borrow().unwrap();
+            let weights: &[f32] = &self.weights.cpu_borrow().unwrap();
+            let inputs = inputs.cpu_borrow().unwrap();
+            let inputs = gather_rows(&inputs, &in_offsets, input_len);
 
+            let mut sensitivities = self.sensitivities.cpu_borrow().unwrap();
             let mut weight_mods = self.weight_mods.cpu_borrow().unwrap();
             let mut bias_mods = self.bias_mods.cpu_borrow().unwrap();
-            let inputs = inputs.cpu_borrow().unwrap();
 
-            for batch in 0..batch_size {
-                let out_offset = batch * self.size;
-                let in_offset = match &input_indices {
-                    None => batch * self.input_len,
-                    Some(indices) => indices[batch],
-                };
-                for x in 0..self.size {
-                    let gradient = self.activation.derivative(outputs[x + out_offset]) * in_sensitivities[x + out_offset];
-
-                    bias_mods[x] -= (lr * gradient);
-                    for y in 0..self.input_len {
-                        let weight_index = (self.input_len * x) + y;
-
-                        weight_mods[weight_index] -= (lr * inputs[y + in_offset] * gradient);
+            let mut gradients = vec![0.; batch_size * size];
+            parallel::for_chunks(threads, &mut gradients, 1, |start, gradients| {
+                for i in 0..gradients.len() {
+                    gradients[i] = activation.derivative(outputs[start + i]) * in_sensitivities[start + i];
+                }
+            });
 
-                        sensitivities[y + (batch * self.input_len)] += gradient * weights[weight_index];
-                    }
+            for batch in 0..batch_size {
+                for x in 0..size {
+                    bias_mods[x] -= lr * gradients[batch * size + x];
                 }
             }
+
+            let gradients = Matrix::new(&gradients, batch_size, size);
+            gemm(threads, -lr, gradients.t(), Matrix::new(&inputs, batch_size, input_len), 1., &mut weight_mods);
+            gemm(threads, 1., gradients, Matrix::new(weights, size, input_len), 0., &mut sensitivities[..batch_size * input_len]);
+            sensitivities[batch_size * input_len..].fill(0.);
         }
 
         self.sensitivities.updated_cpu();
@@ -262,30 +482,38 @@ impl<'a> Dense<'a> {
         self.bias_mods.updated_cpu();
     }
 
-    fn gpu_apply(&mut self, optimizer: &Optimizer, batch_size: usize, pq: &ProQue) {
+    fn gpu_apply(&mut self, optimizer: &Optimizer, batch_size: usize, exec: &Executor) {
         match optimizer {
             Optimizer::GradientDecent(lr) => {
                 let lr = *lr;
 
-                gpu_math::mult_second_and_add(
-                    pq,
-                    &*self.weights.gpu_borrow().unwrap(),
-                    &*self.weight_mods.gpu_borrow().unwrap(),
-                    1. / batch_size as f32
-                );
-                gpu_math::mult_second_and_add(
-                    pq,
-                    &*self.biases.gpu_borrow().unwrap(),
-                    &*self.bias_mods.gpu_borrow().unwrap(),
-                    1. / batch_size as f32
-                );
+                match &self.half {
+                    // the master weights are updated on the host and stored on the device again
+                    Some(half) => {
+                        let mut weights = self.weights.cpu_borrow().unwrap();
+                        let weight_mods = self.weight_mods.cpu_borrow().unwrap();
+                        for i in 0..weights.len() {
+                            weights[i] += weight_mods[i] / batch_size as f32;
+                        }
+                        half.write_weights(&weights);
+                    }
+                    None => {
+                        ops::add_scaled(exec, &mut self.weights, &mut self.weight_mods, 1. / batch_size as f32)
+                            .expect("Failed to apply the weight gradients");
+                    }
+                }
+                ops::add_scaled(exec, &mut self.biases, &mut self.bias_mods, 1. / batch_size as f32)
+                    .expect("Failed to apply the bias gradients");
 
                 self.weight_mods.gpu_borrow().unwrap().cmd().fill(0., None).enq();
                 self.bias_mods.gpu_borrow().unwrap().cmd().fill(0., None).enq();
 
                 self.weight_mods.updated_gpu();
                 self.bias_mods.updated_gpu();
-                self.weights.updated_gpu();
+                match self.half {
+                    Some(_) => self.weights.updated_cpu(),
+                    None => self.weights.updated_gpu(),
+                }
                 self.biases.updated_gpu();
             }
         }
@@ -322,49 +550,200 @@ impl<'a> Dense<'a> {
             }
         }
     }
-}
 
-impl<'a> Layer<'a> for Dense<'a> {
-    fn dynamic_forward(&mut self, positions: &Vec<usize>, inputs: &mut DualVec) {
-        if self.ensure_batch_size(positions.len()) {
-            // If the batch size changes, the GPU buffers will change, and so the kernels must be
-            // re-created in order to not maintain old buffer references
-            self.forward_kernel = None;
-            self.backward_kernel = None;
+    /// Computes the outputs in double precision, splitting the batch rows between the threads of the executor
+    fn double_forward(&mut self, positions: &[usize], activated_inputs: &mut DualVec) {
+        let threads = self.exec.threads();
+        let (size, input_len) = (self.size, self.input_len);
+        let activation = &self.activation;
+        let double = self.double.as_ref().unwrap();
+
+        {
+            let activated_inputs = activated_inputs.cpu_borrow().unwrap();
+            let inputs = gather_rows(&activated_inputs, positions, input_len);
+            let mut outputs = self.outputs.cpu_borrow().unwrap();
+            let mut activated_outputs = self.activated_outputs.cpu_borrow().unwrap();
+            let len = positions.len() * size;
+
+            parallel::for_rows(threads, &mut outputs[..len], size, 1, input_len, |start, outputs| {
+                for (i, outputs) in outputs.chunks_mut(size).enumerate() {
+                    let inputs = &inputs[(start + i) * input_len..(start + i + 1) * input_len];
+                    for (x, output) in outputs.iter_mut().enumerate() {
+                        *output = (double.biases[x] + dot(inputs, &double.weights[x * input_len..(x + 1) * input_len])) as f32;
+                    }
+                }
+            });
+
+            let outputs: &[f32] = &outputs;
+            parallel::for_chunks(threads, &mut activated_outputs[..len], 1, |start, activated_outputs| {
+                for i in 0..activated_outputs.len() {
+                    activated_outputs[i] = activation.activate(outputs[start + i]);
+                }
+            });
         }
 
-        match self.exec {
-            Executor::GPU(pq) => self.gpu_forward(positions, inputs, pq),
-            Executor::CPU => self.cpu_forward(positions, inputs),
+        self.activated_outputs.updated_cpu();
+        self.outputs.updated_cpu();
+    }
+
+    /// Accumulates the modifications in double precision, splitting the weight and sensitivity rows
+    /// between the threads of the executor
+    fn double_backward(&mut self, inputs: &mut DualVec, input_indices: Option<&Vec<usize>>, in_sensitivities: &mut DualVec, optimizer: &Optimizer, batch_size: usize) {
+        let lr = optimizer.learn_rate() as f64;
+        let threads = self.exec.threads();
+        let (size, input_len) = (self.size, self.input_len);
+        let activation = &self.activation;
+        let double = self.double.as_mut().unwrap();
+
+        let in_offsets: Vec<usize> = match input_indices {
+            None => (0..batch_size).map(|batch| batch * input_len).collect(),
+            Some(indices) => indices[..batch_size].to_vec(),
+        };
+
+        {
+            let in_sensitivities: &[f32] = &in_sensitivities.cpu_borrow().unwrap();
+            let outputs: &[f32] = &self.outputs.cpu_borrow().unwrap();
+            let inputs = inputs.cpu_borrow().unwrap();
+            let inputs = gather_rows(&inputs, &in_offsets, input_len);
+            let mut sensitivities = self.sensitivities.cpu_borrow().unwrap();
+
+            let gradients: Vec<f64> = (0..batch_size * size)
+                .map(|i| activation.derivative(outputs[i]) as f64 * in_sensitivities[i] as f64)
+                .collect();
+
+            for batch in 0..batch_size {
+                for x in 0..size {
+                    double.bias_mods[x] -= lr * gradients[batch * size + x];
+                }
+            }
+
+            parallel::for_rows(threads, &mut double.weight_mods, input_len, 1, batch_size, |start, weight_mods| {
+                for (i, weight_mods) in weight_mods.chunks_mut(input_len).enumerate() {
+                    for batch in 0..batch_size {
+                        let gradient = lr * gradients[batch * size + start + i];
+                        let inputs = &inputs[batch * input_len..(batch + 1) * input_len];
+                        for y in 0..input_len {
+                            weight_mods[y] -= gradient * inputs[y] as f64;
+                        }
+                    }
+                }
+            });
+
+            let weights: &[f64] = &double.weights;
+            parallel::for_rows(threads, &mut sensitivities[..batch_size * input_len], input_len, 1, size, |start, sensitivities| {
+                for (i, sensitivities) in sensitivities.chunks_mut(input_len).enumerate() {
+                    let gradients = &gradients[(start + i) * size..(start + i + 1) * size];
+                    for y in 0..input_len {
+                        sensitivities[y] = (0..size).map(|x| gradients[x] * weights[x * input_len + y]).sum::<f64>() as f32;
+                    }
+                }
+            });
+            sensitivities[batch_size * input_len..].fill(0.);
         }
+
+        self.sensitivities.updated_cpu();
     }
 
-    fn forward(&mut self, activated_inputs: &mut DualVec) -> usize {
-        let batch_size = activated_inputs.len() / self.input_len;
+    fn double_apply(&mut self, optimizer: &Optimizer, batch_size: usize) {
+        match optimizer {
+            Optimizer::GradientDecent(_) => {
+                let double = self.double.as_mut().unwrap();
+                let i_batch_size = 1. / batch_size as f64;
+
+                for i in 0..double.weights.len() {
+                    double.weights[i] += double.weight_mods[i] * i_batch_size;
+                }
+                for i in 0..double.biases.len() {
+                    double.biases[i] += double.bias_mods[i] * i_batch_size;
+                }
+                double.weight_mods.fill(0.);
+                double.bias_mods.fill(0.);
 
-        let mut positions = vec![];
-        for i in 0..batch_size {
-            positions.push(i * self.input_len);
+                // the f32 values are used for inference and are the ones saved
+                if let (Some(mut weights), Some(mut biases)) = (self.weights.cpu_borrow(), self.biases.cpu_borrow()) {
+                    for i in 0..weights.len() {
+                        weights[i] = double.weights[i] as f32;
+                    }
+                    for i in 0..biases.len() {
+                        biases[i] = double.biases[i] as f32;
+                    }
+                }
+                self.weights.updated_cpu();
+                self.biases.updated_cpu();
+            }
         }
+    }
 
-        self.dynamic_forward(&positions, activated_inputs);
+    pub(crate) fn read(exec: &Execs, bytes: &mut CursorReader) -> Self {
+        let mut l = Dense::new(exec, bytes.usize(), bytes.usize(), bytes.indexed());
 
-        batch_size
+        if let Some(mut weights) = l.weights.cpu_borrow() {
+            for i in 0..weights.len() {
+                weights[i] = bytes.f32();
+            }
+        }
+
+        if let Some(mut biases) = l.biases.cpu_borrow() {
+            for i in 0..biases.len() {
+                biases[i] = bytes.f32();
+            }
+        }
+
+        l.weights.updated_cpu();
+        l.biases.updated_cpu();
+
+        l
+    }
+
+    /// A copy of the current values in the inference form
+    pub(crate) fn frozen(&mut self) -> FrozenDense {
+        FrozenDense {
+            input_len: self.input_len,
+            size: self.size,
+            activation: self.activation.clone(),
+            weights: self.weights.cpu_borrow().unwrap().clone(),
+            biases: self.biases.cpu_borrow().unwrap().clone(),
+        }
     }
+}
 
-    fn backward(&mut self, inputs: &mut DualVec, input_indices: Option<&Vec<usize>>, in_sensitivities: &mut DualVec, optimizer: &Optimizer)  {
+impl Layer for Dense {
+    fn prepare(&mut self, inputs: &mut Tensor) -> Result<(), Error> {
+        self.build_program()
+    }
+
+    fn forward(&mut self, inputs: &mut Tensor) {
+        let positions = &inputs.row_offsets();
+        let inputs = inputs.data_mut();
+        // The kernels are kept when the batch size changes, as they set the reallocated buffers on every launch
+        self.ensure_batch_size(positions.len());
+
+        match &*self.exec.clone() {
+            Executor::GPU(pq) => self.gpu_forward(positions, inputs, pq),
+            Executor::CPU | Executor::Threaded(_) if self.double.is_some() => self.double_forward(positions, inputs),
+            Executor::CPU | Executor::Threaded(_) => self.cpu_forward(positions, inputs),
+        }
+    }
+
+    fn backward(&mut self, inputs: &mut Tensor, in_sensitivities: &mut DualVec, optimizer: &Optimizer) {
+        let input_indices = inputs.gather_offsets();
+        let (inputs, input_indices) = (inputs.data_mut(), input_indices.as_ref());
         let batch_size = in_sensitivities.len() / self.size;
 
-        match &self.exec {
+        match &*self.exec.clone() {
             Executor::GPU(pq) => self.gpu_backward(inputs, input_indices, in_sensitivities, optimizer, batch_size, pq),
-            Executor::CPU => self.cpu_backward(inputs, input_indices, in_sensitivities, optimizer, batch_size),
+            Executor::CPU | Executor::Threaded(_) if self.double.is_some() => {
+                self.double_backward(inputs, input_indices, in_sensitivities, optimizer, batch_size)
+            }
+            Executor::CPU | Executor::Threaded(_) => self.cpu_backward(inputs, input_indices, in_sensitivities, optimizer, batch_size),
         }
     }
 
     fn apply_gradients(&mut self, optimizer: &Optimizer, batch_size: usize) {
-        match self.exec {
-            Executor::GPU(pq) => self.gpu_apply(optimizer, batch_size, pq),
-            Executor::CPU => self.cpu_apply(optimizer, batch_size),
+        match &*self.exec.clone() {
+            exec @ Executor::GPU(_) => self.gpu_apply(optimizer, batch_size, exec),
+            Executor::CPU | Executor::Threaded(_) if self.double.is_some() => self.double_apply(optimizer, batch_size),
+            Executor::CPU | Executor::Threaded(_) => self.cpu_apply(optimizer, batch_size),
         }
     }
 
@@ -386,25 +765,8 @@ impl<'a> Layer<'a> for Dense<'a> {
         }
     }
 
-    fn from_bytes(exec: (&'a Executor, &'a Executor, &'a Executor), bytes: &mut CursorReader) -> Rc<RefCell<dyn Layer<'a> + 'a>> {
-        let mut l = Dense::new(exec, bytes.usize(), bytes.usize(), bytes.indexed());
-
-        if let Some(mut weights) = l.weights.cpu_borrow() {
-            for i in 0..weights.len() {
-                weights[i] = bytes.f32();
-            }
-        }
-
-        if let Some(mut biases) = l.biases.cpu_borrow() {
-            for i in 0..biases.len() {
-                biases[i] = bytes.f32();
-            }
-        }
-
-        l.weights.updated_cpu();
-        l.biases.updated_cpu();
-
-        Rc::new(RefCell::new(l))
+    fn from_bytes(exec: &Execs, bytes: &mut CursorReader) -> Rc<RefCell<dyn Layer>> {
+        Rc::new(RefCell::new(Dense::read(exec, bytes)))
     }
 
     fn id(&self) -> usize {
@@ -412,13 +774,21 @@ impl<'a> Layer<'a> for Dense<'a> {
     }
 
     fn exec(&self) -> &Executor {
-        self.exec
+        &self.exec
     }
 
     fn values(&self) -> Vec<&DualVec> {
         vec![&self.weights, &self.biases]
     }
 
+    fn mods(&mut self) -> Result<Vec<&mut DualVec>, Error> {
+        // f64 modifications are kept apart from the f32 buffers
+        if self.double.is_some() {
+            return Err(Error::Network(NetworkError::Precision(self.id(), self.precision)));
+        }
+        Ok(vec![&mut self.weight_mods, &mut self.bias_mods])
+    }
+
     fn input_size(&self) -> usize {
         self.input_len
     }
@@ -434,4 +804,94 @@ impl<'a> Layer<'a> for Dense<'a> {
     fn sensitivities(&mut self) -> &mut DualVec {
         &mut self.sensitivities
     }
-}
\ No newline at end of file
+
+    fn step(&mut self, _position: usize, _cache: &mut KeyValueCache, inputs: &[f32]) -> Result<Vec<f32>, Error> {
+        // Only a dense layer applied to every position separately can process a single one
+        if inputs.len() != self.input_len {
+            return Err(Error::Mismatch(MismatchError::Step(inputs.len(), self.input_len)));
+        }
+
+        let mut outputs = vec![0.; self.size];
+        linear(inputs, &self.weights.cpu_borrow().unwrap(), &self.biases.cpu_borrow().unwrap(), self.input_len, &mut outputs);
+        for v in outputs.iter_mut() {
+            *v = self.activation.activate(*v);
+        }
+        Ok(outputs)
+    }
+    fn freeze(&mut self) -> Result<Box<dyn FrozenLayer>, Error> {
+        Ok(Box::new(self.frozen()))
+    }
+
+    fn precision(&self) -> DType {
+        self.precision
+    }
+
+    /// Dense layers compute in double precision on the CPU executors, and store their weights, outputs
+    /// and gradients in half precision on GPUs supporting it. Half precision layers keep their f32
+    /// master weights on the host and scale their gradients, see [LossScale].
+    fn set_precision(&mut self, dtype: DType) -> Result<(), Error> {
+        let supported = match (&*self.exec, dtype) {
+            (_, DType::F32) => true,
+            (Executor::CPU | Executor::Threaded(_), DType::F64) => true,
+            (Executor::GPU(gpu), DType::F16 | DType::BF16) => gpu.supports(dtype),
+            _ => false,
+        };
+        if !supported {
+            return Err(Error::Network(NetworkError::Precision(self.id(), dtype)));
+        }
+
+        let weights = self.weights.cpu_borrow().unwrap().clone();
+        let biases = self.biases.cpu_borrow().unwrap().clone();
+        self.double = None;
+        self.half = None;
+        // the kernels are built for the storage of the precision
+        self.program = None;
+        self.forward_kernel = None;
+        self.backward_kernels = None;
+        self.precision = dtype;
+
+        match (&*self.exec.clone(), dtype) {
+            (_, DType::F64) => {
+                self.double = Some(DoubleValues {
+                    weights: weights.iter().map(|v| *v as f64).collect(),
+                    biases: biases.iter().map(|v| *v as f64).collect(),
+                    weight_mods: vec![0.; weights.len()],
+                    bias_mods: vec![0.; biases.len()],
+                });
+            }
+            (Executor::GPU(gpu), DType::F16 | DType::BF16) => {
+                self.half = Some(HalfValues::new(gpu, dtype, &weights, self.activated_outputs.len()));
+                self.weights = DualVec::from_vec((&Executor::CPU, &Executor::CPU), weights);
+            }
+            (exec, _) => self.weights = DualVec::from_vec((exec, exec), weights),
+        }
+        Ok(())
+    }
+}
+
+/// The inference form of [Dense]
+#[derive(Debug)]
+pub struct FrozenDense {
+    input_len: usize,
+    size: usize,
+    activation: Activation,
+    weights: Vec<f32>,
+    biases: Vec<f32>,
+}
+
+impl FrozenLayer for FrozenDense {
+    fn input_size(&self) -> usize {
+        self.input_len
+    }
+
+    fn output_size(&self) -> usize {
+        self.size
+    }
+
+    fn forward(&self, inputs: &[f32], outputs: &mut [f32], _scratch: &mut Scratch) {
+        linear(inputs, &self.weights, &self.biases, self.input_len, outputs);
+        for v in outputs.iter_mut() {
+            *v = self.activation.activate(*v);
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use rand::random;
+
+use crate::{Executor, Execs, Optimizer};
+use std::sync::Arc;
+use crate::dual_vec::DualVec;
+use crate::tensor::Tensor;
+use crate::error::Error;
+use crate::frozen::{FrozenLayer, Scratch};
+use crate::layer::attention::KeyValueCache;
+use crate::layer::Layer;
+use crate::utils::vec_utils::{CursorReader, VecWriter};
+
+/// Zeroes a random fraction (`rate`) of the inputs while training, and scales the
+/// remaining ones so the expected sum is unchanged. Outside of training the inputs pass through.
+#[derive(Debug)]
+pub struct Dropout {
+    exec: Arc<Executor>,
+    rate: f32,
+    input_len: usize,
+    training: bool,
+
+    /// The scale applied to every input of the last forward pass, zero if it was dropped
+    mask: Vec<f32>,
+
+    outputs: DualVec,
+    sensitivities: DualVec,
+}
+
+impl Dropout {
+    pub fn new(exec: &Execs, inputs: usize, rate: f32) -> Self {
+        let c = &*exec.current; // current
+        let p_to_c = exec.p_to_c(); // previous to current
+        let c_to_n = exec.c_to_n(); // current to next
+
+        Dropout {
+            exec: exec.current.clone(),
+            rate,
+            input_len: inputs,
+            training: false,
+
+            mask: vec![],
+
+            outputs: DualVec::from_execs(c_to_n, inputs),
+            sensitivities: DualVec::from_execs(p_to_c, inputs),
+        }
+    }
+
+    /// The inference form, which passes the inputs through unchanged
+    pub(crate) fn frozen(&mut self) -> FrozenDropout {
+        FrozenDropout { input_len: self.input_len }
+    }
+}
+
+impl Layer for Dropout {
+    fn forward(&mut self, inputs: &mut Tensor) {
+        let positions = &inputs.row_offsets();
+        let inputs = inputs.data_mut();
+        let batch_size = positions.len();
+        self.outputs.resize(batch_size * self.input_len);
+        self.sensitivities.resize(batch_size * self.input_len);
+        self.mask.resize(batch_size * self.input_len, 1.);
+
+        let active = self.training && self.rate > 0.;
+        let keep = 1. / (1. - self.rate);
+        for m in self.mask.iter_mut() {
+            *m = if !active {
+                1.
+            } else if random::<f32>() < self.rate {
+                0.
+            } else {
+                keep
+            };
+        }
+
+        {
+            let inputs = inputs.cpu_borrow().unwrap();
+            let mut outputs = self.outputs.cpu_borrow().unwrap();
+
+            for batch in 0..batch_size {
+                let offset = batch * self.input_len;
+                for i in 0..self.input_len {
+                    outputs[offset + i] = inputs[positions[batch] + i] * self.mask[offset + i];
+                }
+            }
+        }
+
+        self.outputs.updated_cpu();
+    }
+
+    fn backward(&mut self, _inputs: &mut Tensor, in_sensitivities: &mut DualVec, _optimizer: &Optimizer) {
+        {
+            let in_sensitivities = in_sensitivities.cpu_borrow().unwrap();
+            let mut sensitivities = self.sensitivities.cpu_borrow().unwrap();
+
+            for i in 0..in_sensitivities.len() {
+                sensitivities[i] = in_sensitivities[i] * self.mask[i];
+            }
+        }
+
+        self.sensitivities.updated_cpu();
+    }
+
+    fn apply_gradients(&mut self, _optimizer: &Optimizer, _batch_size: usize) {}
+
+    fn as_bytes(&mut self, bytes: &mut VecWriter) {
+        bytes.usize(self.input_len);
+        bytes.f32(self.rate);
+    }
+
+    fn from_bytes(exec: &Execs, bytes: &mut CursorReader) -> Rc<RefCell<dyn Layer>> {
+        Rc::new(RefCell::new(Dropout::new(exec, bytes.usize(), bytes.f32())))
+    }
+
+    fn id(&self) -> usize {
+        6
+    }
+
+    fn exec(&self) -> &Executor {
+        &self.exec
+    }
+
+    fn values(&self) -> Vec<&DualVec> {
+        vec![]
+    }
+
+    fn mods(&mut self) -> Result<Vec<&mut DualVec>, Error> {
+        Ok(vec![])
+    }
+
+    fn input_size(&self) -> usize {
+        self.input_len
+    }
+
+    fn output_size(&self) -> usize {
+        self.input_len
+    }
+
+    fn activated_output(&mut self) -> &mut DualVec {
+        &mut self.outputs
+    }
+
+    fn sensitivities(&mut self) -> &mut DualVec {
+        &mut self.sensitivities
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+
+    fn step(&mut self, _position: usize, _cache: &mut KeyValueCache, inputs: &[f32]) -> Result<Vec<f32>, Error> {
+        Ok(inputs.to_vec())
+    }
+    fn freeze(&mut self) -> Result<Box<dyn FrozenLayer>, Error> {
+        Ok(Box::new(self.frozen()))
+    }
+}
+
+/// The inference form of [Dropout], which never drops any inputs
+#[derive(Debug)]
+pub struct FrozenDropout {
+    input_len: usize,
+}
+
+impl FrozenLayer for FrozenDropout {
+    fn input_size(&self) -> usize {
+        self.input_len
+    }
+
+    fn output_size(&self) -> usize {
+        self.input_len
+    }
+
+    fn forward(&self, inputs: &[f32], outputs: &mut [f32], _scratch: &mut Scratch) {
+        outputs.copy_from_slice(inputs);
+    }
+}
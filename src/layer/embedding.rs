@@ -0,0 +1,494 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use ocl::Kernel;
+
+use crate::{Executor, Execs, Optimizer};
+use std::sync::Arc;
+use crate::dual_vec::DualVec;
+use crate::tensor::Tensor;
+use crate::error::{Error, NetworkError};
+use crate::frozen::{FrozenLayer, Scratch};
+use crate::gpu::Gpu;
+use crate::layer::attention::KeyValueCache;
+use crate::layer::Layer;
+use crate::utils::cl_utils;
+use crate::utils::cl_utils::{execute_kernel, execute_reduction};
+use crate::utils::vec_utils::{CursorReader, VecWriter};
+
+/// Looks up a learnable row of `dim` values for every token id of the input.
+///
+/// Token ids are read from the regular `f32` inputs, so ids are exact up to 2^24.
+/// Only the rows that were used since the last `apply_gradients` are updated.
+#[derive(Debug)]
+pub struct Embedding {
+    exec: Arc<Executor>,
+    vocab: usize,
+    dim: usize,
+    input_len: usize,
+
+    table: DualVec,
+    table_mods: DualVec,
+
+    outputs: DualVec,
+    sensitivities: DualVec,
+
+    /// Rows of the table which have pending modifications
+    used_rows: Vec<usize>,
+    row_used: Vec<bool>,
+
+    forward_kernel: Option<Kernel>,
+    backward_kernel: Option<Kernel>,
+}
+
+impl Embedding {
+    pub fn new(exec: &Execs, inputs: usize, vocab: usize, dim: usize) -> Self {
+        let c = &*exec.current; // current
+        let p_to_c = exec.p_to_c(); // previous to current
+        let c_to_n = exec.c_to_n(); // current to next
+
+        let mut e = Embedding {
+            exec: exec.current.clone(),
+            vocab,
+            dim,
+            input_len: inputs,
+
+            table: DualVec::from_exec(c, vocab * dim),
+            table_mods: DualVec::from_exec(c, vocab * dim),
+
+            outputs: DualVec::from_execs(c_to_n, inputs * dim),
+            sensitivities: DualVec::from_execs(p_to_c, inputs),
+
+            used_rows: Vec::new(),
+            row_used: vec![false; vocab],
+
+            forward_kernel: None,
+            backward_kernel: None,
+        };
+
+        e.table.randomize(c, (dim as f32).sqrt());
+
+        e
+    }
+
+    fn ensure_batch_size(&mut self, batch_size: usize) {
+        let target = batch_size * self.input_len * self.dim;
+        if self.outputs.len() < target {
+            self.outputs.expand_to(target);
+            self.sensitivities.expand_to(self.input_len * batch_size);
+        } else if self.outputs.len() > target {
+            self.outputs.truncate_to(target);
+            self.sensitivities.truncate_to(self.input_len * batch_size);
+        }
+    }
+
+    /// The row of the table of the token id `token`, which has to be a whole number below the vocabulary size
+    fn row_of(token: f32, vocab: usize) -> Result<usize, Error> {
+        if token >= 0. && token.fract() == 0. && (token as usize) < vocab {
+            Ok(token as usize)
+        } else {
+            Err(Error::Network(NetworkError::Token(token, vocab)))
+        }
+    }
+
+    /// Records every token id of the batch as a row which must be updated
+    fn mark_rows(&mut self, inputs: &mut DualVec, input_indices: Option<&Vec<usize>>, batch_size: usize) {
+        let inputs = inputs.cpu_borrow().unwrap();
+        for batch in 0..batch_size {
+            let in_offset = match input_indices {
+                None => batch * self.input_len,
+                Some(indices) => indices[batch],
+            };
+            for t in 0..self.input_len {
+                let row = inputs[t + in_offset] as usize;
+                if !self.row_used[row] {
+                    self.row_used[row] = true;
+                    self.used_rows.push(row);
+                }
+            }
+        }
+    }
+
+    fn gpu_forward(&mut self, positions: &[usize], inputs: &mut DualVec, pq: &Gpu) {
+        let positions_buf = cl_utils::positions_buffer(pq, positions);
+
+        if self.forward_kernel.is_none() {
+            self.forward_kernel = Some(
+                pq.kernel_builder("embedding_forward")
+                    .arg(self.dim as u64)
+                    .arg(self.input_len as u64)
+                    .arg(&*self.table.gpu_borrow().unwrap())
+                    .arg_named("inputs", &*inputs.gpu_borrow().unwrap())
+                    .arg_named("positions", &positions_buf)
+                    .arg_named("outputs", &*self.outputs.gpu_borrow().unwrap())
+                    .build().unwrap()
+            );
+        }
+
+        if let Some(kernel) = &self.forward_kernel {
+            kernel.set_arg("inputs", &*inputs.gpu_borrow().unwrap()).unwrap();
+            kernel.set_arg("positions", &positions_buf).unwrap();
+            // the outputs are replaced when a larger batch doesn't fit them
+            kernel.set_arg("outputs", &*self.outputs.gpu_borrow().unwrap()).unwrap();
+
+            unsafe {
+                execute_kernel(pq, kernel, (positions.len() * self.input_len, self.dim));
+            }
+
+            self.outputs.updated_gpu();
+        }
+    }
+
+    fn cpu_forward(&mut self, positions: &[usize], inputs: &mut DualVec) {
+        {
+            let inputs = inputs.cpu_borrow().unwrap();
+            let table = self.table.cpu_borrow().unwrap();
+            let mut outputs = self.outputs.cpu_borrow().unwrap();
+
+            for (batch, input_offset) in positions.iter().enumerate() {
+                let output_offset = batch * self.input_len * self.dim;
+
+                for t in 0..self.input_len {
+                    let row = inputs[t + input_offset] as usize * self.dim;
+                    let out = output_offset + t * self.dim;
+                    outputs[out..out + self.dim].copy_from_slice(&table[row..row + self.dim]);
+                }
+            }
+        }
+
+        self.outputs.updated_cpu();
+    }
+
+    fn gpu_backward(&mut self, inputs: &mut DualVec, input_indices: Option<&Vec<usize>>, in_sensitivities: &mut DualVec, optimizer: &Optimizer, batch_size: usize, pq: &Gpu) {
+        let in_offsets: Vec<usize> = match input_indices {
+            None => (0..batch_size).map(|batch| batch * self.input_len).collect(),
+            Some(indices) => indices[..batch_size].to_vec(),
+        };
+        let positions_buf = cl_utils::positions_buffer(pq, &in_offsets);
+
+        if self.backward_kernel.is_none() {
+            self.backward_kernel = Some(pq.kernel_builder("embedding_backward")
+                .arg(self.dim as u64)
+                .arg(self.input_len as u64)
+                .arg_named("learn_rate", optimizer.learn_rate())
+                .arg_named("inputs", &*inputs.gpu_borrow().unwrap())
+                .arg_named("positions", &positions_buf)
+                .arg_named("in_sensitivities", &*in_sensitivities.gpu_borrow().unwrap())
+                .arg(&*self.table_mods.gpu_borrow().unwrap())
+                .build().unwrap());
+        }
+
+        if let Some(k) = &self.backward_kernel {
+            k.set_arg("learn_rate", optimizer.learn_rate()).unwrap();
+            k.set_arg("inputs", &*inputs.gpu_borrow().unwrap()).unwrap();
+            k.set_arg("positions", &positions_buf).unwrap();
+            k.set_arg("in_sensitivities", &*in_sensitivities.gpu_borrow().unwrap()).unwrap();
+
+            unsafe {
+                execute_kernel(pq, k, (batch_size * self.input_len, self.dim));
+            }
+
+            self.table_mods.updated_gpu();
+        }
+    }
+
+    /// The deterministic form of [Self::gpu_backward], which groups the tokens of the batch by their
+    /// row so the sensitivities of every row are summed in a fixed order instead of atomically
+    fn gpu_backward_sorted(&mut self, inputs: &mut DualVec, input_indices: Option<&Vec<usize>>, in_sensitivities: &mut DualVec, optimizer: &Optimizer, batch_size: usize, gpu: &Gpu) {
+        let mut tokens: Vec<(usize, usize)> = Vec::with_capacity(batch_size * self.input_len);
+        {
+            let inputs = inputs.cpu_borrow().unwrap();
+            for batch in 0..batch_size {
+                let in_offset = match input_indices {
+                    None => batch * self.input_len,
+                    Some(indices) => indices[batch],
+                };
+                for t in 0..self.input_len {
+                    tokens.push((inputs[t + in_offset] as usize, batch * self.input_len + t));
+                }
+            }
+        }
+        // the sort is stable, so the tokens of a row stay in the order of the batch
+        tokens.sort_by_key(|(row, _)| *row);
+
+        let mut rows = Vec::new();
+        let mut starts = Vec::new();
+        for (i, (row, _)) in tokens.iter().enumerate() {
+            if rows.last() != Some(row) {
+                rows.push(*row);
+                starts.push(i);
+            }
+        }
+        if rows.is_empty() {
+            return;
+        }
+        starts.push(tokens.len());
+        let tokens: Vec<usize> = tokens.into_iter().map(|(_, token)| token).collect();
+
+        let kernel = gpu.kernel_builder("embedding_backward_sorted")
+            .arg(self.dim as u64)
+            .arg(optimizer.learn_rate())
+            .arg(cl_utils::indices_buffer(gpu, &rows))
+            .arg(cl_utils::indices_buffer(gpu, &starts))
+            .arg(cl_utils::indices_buffer(gpu, &tokens))
+            .arg(&*in_sensitivities.gpu_borrow().unwrap())
+            .arg(&*self.table_mods.gpu_borrow().unwrap())
+            .arg_local::<f32>(gpu.reduction())
+            .build().unwrap();
+
+        unsafe {
+            execute_reduction(gpu, &kernel, rows.len(), self.dim);
+        }
+
+        self.table_mods.updated_gpu();
+    }
+
+    fn cpu_backward(&mut self, inputs: &mut DualVec, input_indices: Option<&Vec<usize>>, in_sensitivities: &mut DualVec, optimizer: &Optimizer, batch_size: usize) {
+        let lr = optimizer.learn_rate();
+
+        {
+            let in_sensitivities = in_sensitivities.cpu_borrow().unwrap();
+            let mut table_mods = self.table_mods.cpu_borrow().unwrap();
+            let inputs = inputs.cpu_borrow().unwrap();
+
+            for batch in 0..batch_size {
+                let sens_offset = batch * self.input_len * self.dim;
+                let in_offset = match input_indices {
+                    None => batch * self.input_len,
+                    Some(indices) => indices[batch],
+                };
+                for t in 0..self.input_len {
+                    let row = inputs[t + in_offset] as usize * self.dim;
+                    for d in 0..self.dim {
+                        table_mods[row + d] -= lr * in_sensitivities[sens_offset + t * self.dim + d];
+                    }
+                }
+            }
+        }
+
+        self.table_mods.updated_cpu();
+    }
+
+    fn gpu_apply(&mut self, batch_size: usize, pq: &Gpu) {
+        let rows: Vec<u64> = self.used_rows.iter().map(|r| *r as u64).collect();
+        let rows_buf = cl_utils::new_buffer(pq, rows.len());
+        cl_utils::buf_write(&rows_buf, &rows);
+
+        let kernel = pq.kernel_builder("embedding_apply")
+            .arg(self.dim as u64)
+            .arg(&*self.table.gpu_borrow().unwrap())
+            .arg(&*self.table_mods.gpu_borrow().unwrap())
+            .arg(&rows_buf)
+            .arg(1. / batch_size as f32)
+            .build().unwrap();
+
+        unsafe {
+            execute_kernel(pq, &kernel, (rows.len(), self.dim));
+        }
+
+        self.table.updated_gpu();
+        self.table_mods.updated_gpu();
+    }
+
+    fn cpu_apply(&mut self, batch_size: usize) {
+        let i_batch_size = 1. / batch_size as f32;
+
+        if let (Some(mut table), Some(mut mods)) = (self.table.cpu_borrow(), self.table_mods.cpu_borrow()) {
+            for row in &self.used_rows {
+                let start = row * self.dim;
+                for i in start..start + self.dim {
+                    table[i] += mods[i] * i_batch_size;
+                    mods[i] = 0.;
+                }
+            }
+        }
+
+        self.table.updated_cpu();
+        self.table_mods.updated_cpu();
+    }
+
+    /// A copy of the current values in the inference form
+    pub(crate) fn frozen(&mut self) -> FrozenEmbedding {
+        FrozenEmbedding {
+            dim: self.dim,
+            vocab: self.vocab,
+            input_len: self.input_len,
+            table: self.table.cpu_borrow().unwrap().clone(),
+        }
+    }
+}
+
+impl Layer for Embedding {
+    fn prepare(&mut self, inputs: &mut Tensor) -> Result<(), Error> {
+        let positions = inputs.row_offsets();
+        let inputs = inputs.data_mut().cpu_borrow().unwrap();
+        for position in positions {
+            for token in &inputs[position..position + self.input_len] {
+                Self::row_of(*token, self.vocab)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn forward(&mut self, inputs: &mut Tensor) {
+        let positions = &inputs.row_offsets();
+        let inputs = inputs.data_mut();
+        self.ensure_batch_size(positions.len());
+
+        match &*self.exec.clone() {
+            Executor::GPU(pq) => self.gpu_forward(positions, inputs, pq),
+            Executor::CPU | Executor::Threaded(_) => self.cpu_forward(positions, inputs),
+        }
+    }
+
+    fn backward(&mut self, inputs: &mut Tensor, in_sensitivities: &mut DualVec, optimizer: &Optimizer) {
+        let input_indices = inputs.gather_offsets();
+        let (inputs, input_indices) = (inputs.data_mut(), input_indices.as_ref());
+        let batch_size = in_sensitivities.len() / (self.input_len * self.dim);
+
+        self.mark_rows(inputs, input_indices, batch_size);
+
+        // Token ids are not differentiable, so the sensitivities passed back are always zero
+        match &*self.exec.clone() {
+            Executor::GPU(gpu) if gpu.deterministic() => self.gpu_backward_sorted(inputs, input_indices, in_sensitivities, optimizer, batch_size, gpu),
+            Executor::GPU(pq) => self.gpu_backward(inputs, input_indices, in_sensitivities, optimizer, batch_size, pq),
+            Executor::CPU | Executor::Threaded(_) => self.cpu_backward(inputs, input_indices, in_sensitivities, optimizer, batch_size),
+        }
+    }
+
+    fn apply_gradients(&mut self, optimizer: &Optimizer, batch_size: usize) {
+        if self.used_rows.is_empty() {
+            return;
+        }
+
+        match optimizer {
+            Optimizer::GradientDecent(_) => match &*self.exec.clone() {
+                Executor::GPU(pq) => self.gpu_apply(batch_size, pq),
+                Executor::CPU | Executor::Threaded(_) => self.cpu_apply(batch_size),
+            }
+        }
+
+        for row in self.used_rows.drain(..) {
+            self.row_used[row] = false;
+        }
+    }
+
+    fn as_bytes(&mut self, bytes: &mut VecWriter) {
+        let table = self.table.cpu_borrow().unwrap();
+        bytes.reserve((3 * 8) + table.len() * 4);
+
+        bytes.usize(self.input_len);
+        bytes.usize(self.vocab);
+        bytes.usize(self.dim);
+
+        for v in table.iter() {
+            bytes.f32(*v);
+        }
+    }
+
+    fn from_bytes(exec: &Execs, bytes: &mut CursorReader) -> Rc<RefCell<dyn Layer>> {
+        let mut l = Embedding::new(exec, bytes.usize(), bytes.usize(), bytes.usize());
+
+        if let Some(mut table) = l.table.cpu_borrow() {
+            for i in 0..table.len() {
+                table[i] = bytes.f32();
+            }
+        }
+
+        l.table.updated_cpu();
+
+        Rc::new(RefCell::new(l))
+    }
+
+    fn id(&self) -> usize {
+        2
+    }
+
+    fn exec(&self) -> &Executor {
+        &self.exec
+    }
+
+    fn values(&self) -> Vec<&DualVec> {
+        vec![&self.table]
+    }
+
+    fn mods(&mut self) -> Result<Vec<&mut DualVec>, Error> {
+        Ok(vec![&mut self.table_mods])
+    }
+
+    fn modified_rows(&self) -> Option<(usize, &[usize])> {
+        Some((self.dim, &self.used_rows))
+    }
+
+    fn mark_modified(&mut self, rows: &[usize]) {
+        for row in rows {
+            if !self.row_used[*row] {
+                self.row_used[*row] = true;
+                self.used_rows.push(*row);
+            }
+        }
+    }
+
+    fn input_size(&self) -> usize {
+        self.input_len
+    }
+
+    fn output_size(&self) -> usize {
+        self.input_len * self.dim
+    }
+
+    fn activated_output(&mut self) -> &mut DualVec {
+        &mut self.outputs
+    }
+
+    fn sensitivities(&mut self) -> &mut DualVec {
+        &mut self.sensitivities
+    }
+
+    fn step(&mut self, _position: usize, _cache: &mut KeyValueCache, inputs: &[f32]) -> Result<Vec<f32>, Error> {
+        let table = self.table.cpu_borrow().unwrap();
+
+        let mut outputs = Vec::with_capacity(inputs.len() * self.dim);
+        for token in inputs {
+            let row = Self::row_of(*token, self.vocab)? * self.dim;
+            outputs.extend_from_slice(&table[row..row + self.dim]);
+        }
+        Ok(outputs)
+    }
+    fn freeze(&mut self) -> Result<Box<dyn FrozenLayer>, Error> {
+        Ok(Box::new(self.frozen()))
+    }
+}
+
+/// The inference form of [Embedding]
+#[derive(Debug)]
+pub struct FrozenEmbedding {
+    dim: usize,
+    vocab: usize,
+    input_len: usize,
+    table: Vec<f32>,
+}
+
+impl FrozenLayer for FrozenEmbedding {
+    fn input_size(&self) -> usize {
+        self.input_len
+    }
+
+    fn output_size(&self) -> usize {
+        self.input_len * self.dim
+    }
+
+    fn check_inputs(&self, inputs: &[f32]) -> Result<(), Error> {
+        for token in inputs {
+            Embedding::row_of(*token, self.vocab)?;
+        }
+        Ok(())
+    }
+
+    fn forward(&self, inputs: &[f32], outputs: &mut [f32], _scratch: &mut Scratch) {
+        for (token, out) in inputs.iter().zip(outputs.chunks_mut(self.dim)) {
+            let row = *token as usize * self.dim;
+            out.copy_from_slice(&self.table[row..row + self.dim]);
+        }
+    }
+}
@@ -2,26 +2,55 @@ use std::cell::RefCell;
 use std::fmt::Debug;
 use std::rc::Rc;
 
-use crate::{Executor, Optimizer};
+use crate::{Executor, Execs, Optimizer};
+use std::sync::Arc;
 use crate::activation::Activation;
 use crate::dual_vec::DualVec;
-use crate::layer::attention::Attention;
+use crate::error::{DecodeError, Error, NetworkError};
+use crate::frozen::FrozenLayer;
+use crate::layer::attention::{Attention, KeyValueCache};
+use crate::layer::autograd::{AutogradLayer, Definition};
 use crate::layer::dense::Dense;
+use crate::layer::embedding::Embedding;
+use crate::layer::dropout::Dropout;
+use crate::layer::norm::LayerNorm;
+use crate::layer::positional::{Encoding, PositionalEncoding};
+use crate::layer::recurrent::{Bidirectional, Cell, Recurrent};
+use crate::layer::transformer::TransformerEncoder;
+use crate::tensor::{DType, Tensor};
 use crate::utils::vec_utils::{CursorReader, VecWriter};
 
 pub mod dense;
 pub mod attention;
+pub mod embedding;
+pub mod recurrent;
+pub mod norm;
+pub mod dropout;
+pub mod positional;
+pub mod transformer;
+pub mod autograd;
 
-pub trait Layer<'a> {
-    fn dynamic_forward(&mut self, positions: &Vec<usize>, inputs: &mut DualVec);
-    fn forward(&mut self, activated_inputs: &mut DualVec) -> usize;
+pub trait Layer {
+    /// The forward pass of every sample of `inputs`, whose samples can be gathered from anywhere in its values
+    fn forward(&mut self, inputs: &mut Tensor);
+    /// The forward pass of samples where only the first `lengths[batch]` positions aren't padding.
+    /// Layers that don't mix positions ignore the padding.
+    fn padded_forward(&mut self, inputs: &mut Tensor, lengths: &[usize]) {
+        self.forward(inputs)
+    }
+
+    /// Prepares the layer for the forward pass of a batch, returning the errors the passes can't,
+    /// such as token ids which aren't rows of an embedding table or kernels which fail to build
+    fn prepare(&mut self, inputs: &mut Tensor) -> Result<(), Error> {
+        Ok(())
+    }
 
-    fn backward(&mut self, inputs: &mut DualVec, input_indices: Option<&Vec<usize>>, gradients: &mut DualVec, optimizer: &Optimizer);
+    fn backward(&mut self, inputs: &mut Tensor, gradients: &mut DualVec, optimizer: &Optimizer);
     fn apply_gradients(&mut self, optimizer: &Optimizer, batch_size: usize);
 
     fn as_bytes(&mut self, writer: &mut VecWriter);
-    fn from_bytes(exec: (&'a Executor, &'a Executor, &'a Executor), bytes: &mut CursorReader)
-        -> Rc<RefCell<dyn Layer<'a> + 'a>> where Self: Sized;
+    fn from_bytes(exec: &Execs, bytes: &mut CursorReader)
+        -> Rc<RefCell<dyn Layer>> where Self: Sized;
 
     fn id(&self) -> usize;
     fn exec(&self) -> &Executor;
@@ -31,22 +60,196 @@ pub trait Layer<'a> {
     fn output_size(&self) -> usize;
 
     fn activated_output(&mut self) -> &mut DualVec;
+    /// The activated outputs of the last batch, as the inputs of the next layer
+    fn output(&mut self) -> Tensor {
+        let size = self.output_size();
+        Tensor::batch(self.activated_output().clone(), size)
+    }
     fn sensitivities(&mut self) -> &mut DualVec;
+
+    /// Clears any state carried between batches, such as the states of stateful recurrent layers
+    fn reset_states(&mut self) {}
+
+    /// Switches between training and inference behaviour, such as whether dropout is applied
+    fn set_training(&mut self, training: bool) {}
+
+    /// Processes the values of a single position of a sequence at inference, where `position` is the
+    /// amount of positions processed before it. Attention layers keep the keys and values of the
+    /// previous positions in `cache`, so the earlier positions don't have to be computed again.
+    fn step(&mut self, position: usize, cache: &mut KeyValueCache, inputs: &[f32]) -> Result<Vec<f32>, Error> {
+        Err(Error::Network(NetworkError::Incremental(self.id())))
+    }
+
+    /// Copies the values of the layer into its inference form, which can be shared between threads
+    fn freeze(&mut self) -> Result<Box<dyn FrozenLayer>, Error> {
+        Err(Error::Network(NetworkError::Freeze(self.id())))
+    }
+
+    /// The precision the values of the layer are kept in
+    fn precision(&self) -> DType {
+        DType::F32
+    }
+
+    /// Keeps the values of the layer in `dtype`. The layers pass f32 values between each other
+    /// whatever their precision, and layers only support the precisions their executor can compute.
+    fn set_precision(&mut self, dtype: DType) -> Result<(), Error> {
+        match dtype {
+            DType::F32 => Ok(()),
+            _ => Err(Error::Network(NetworkError::Precision(self.id(), dtype))),
+        }
+    }
+
+    /// The modifications of the values accumulated by the backward passes since the gradients were
+    /// last applied, in the same order as [Layer::values]. Data parallel training sums them
+    /// between the replicas of a network, so the replicas apply the same modifications.
+    fn mods(&mut self) -> Result<Vec<&mut DualVec>, Error> {
+        Err(Error::Network(NetworkError::Replicate(self.id())))
+    }
+
+    /// For layers which only modify some rows of their values, the size of a row and the rows of
+    /// [Layer::mods] modified since the gradients were last applied. All of the modifications are
+    /// taken as modified when this is `None`.
+    fn modified_rows(&self) -> Option<(usize, &[usize])> {
+        None
+    }
+
+    /// Marks `rows` of [Layer::mods] as modified, such as the rows another replica modified, so
+    /// that they are applied with the rows this layer modified
+    fn mark_modified(&mut self, rows: &[usize]) {}
+}
+
+/// All of `data` as a batch of the inputs of `layer`
+pub(crate) fn inputs_of(layer: &dyn Layer, data: &DualVec) -> Tensor {
+    Tensor::batch(data.clone(), layer.input_size())
+}
+
+/// Reads a layer of the type `id`, as written by [Layer::as_bytes]
+pub(crate) fn read_layer(id: usize, exec: &Execs, bytes: &mut CursorReader) -> Result<Rc<RefCell<dyn Layer>>, Error> {
+    Ok(match id {
+        0 => Dense::from_bytes(exec, bytes),
+        1 => Attention::from_bytes(exec, bytes),
+        2 => Embedding::from_bytes(exec, bytes),
+        3 => Recurrent::from_bytes(exec, bytes),
+        4 => Bidirectional::from_bytes(exec, bytes),
+        5 => LayerNorm::from_bytes(exec, bytes),
+        6 => Dropout::from_bytes(exec, bytes),
+        7 => PositionalEncoding::from_bytes(exec, bytes),
+        8 => TransformerEncoder::from_bytes(exec, bytes),
+        9 => return Err(Error::Decode(DecodeError::Autograd)),
+        v => return Err(Error::Decode(DecodeError::InvalidLayerType(v))),
+    })
+}
+
+/// The name of the layer type with the `id`, used in reports
+pub(crate) fn layer_name(id: usize) -> &'static str {
+    match id {
+        0 => "Dense",
+        1 => "Attention",
+        2 => "Embedding",
+        3 => "Recurrent",
+        4 => "Bidirectional",
+        5 => "LayerNorm",
+        6 => "Dropout",
+        7 => "PositionalEncoding",
+        8 => "TransformerEncoder",
+        9 => "Autograd",
+        _ => "Unknown",
+    }
 }
 
 #[derive(Clone, Debug)]
 pub enum LayerType {
     /// size, activation
     Dense(usize, Activation),
-    /// head count, internal size (d_k), characteristics (d_model), output size (d_v)
-    Attention(usize, usize, usize, usize),
+    /// head count, internal size (d_k), characteristics (d_model), output size (d_v), causal
+    Attention(usize, usize, usize, usize, bool),
+    /// vocabulary size, embedding size
+    Embedding(usize, usize),
+    /// Processes samples of `[timesteps, features]`, outputting every hidden state when
+    /// `return_sequences` is set, and only the last one otherwise. Stateful layers carry
+    /// their last states over to the next batch.
+    Recurrent { cell: Cell, units: usize, timesteps: usize, return_sequences: bool, stateful: bool },
+    /// A recurrent layer in each direction with their outputs concatenated
+    Bidirectional { cell: Cell, units: usize, timesteps: usize, return_sequences: bool },
+    /// normalized size
+    LayerNorm(usize),
+    /// rate
+    Dropout(f32),
+    /// encoding, characteristics (d_model)
+    PositionalEncoding(Encoding, usize),
+    /// Self attention and a feed forward network of `d_ff` hidden values, each followed by
+    /// dropout, a residual connection and layer normalization
+    TransformerEncoder { heads: usize, d_model: usize, d_ff: usize, dropout: f32, causal: bool },
+    /// A layer defined by its forward pass, whose gradients are found automatically
+    Autograd(Rc<dyn Definition>),
 }
 
 impl LayerType {
-    pub fn layer<'a>(&'a self, exec: (&'a Executor, &'a Executor, &'a Executor), inputs: usize) -> (Rc<RefCell<dyn Layer<'a> + 'a>>, usize) {
+    pub fn layer(&self, exec: &Execs, inputs: usize) -> (Rc<RefCell<dyn Layer>>, usize) {
+        let layer: Rc<RefCell<dyn Layer>> = match self {
+            LayerType::Dense(s, a) => Rc::new(RefCell::new(Dense::new(exec, inputs, *s, a.clone()))),
+            LayerType::Attention(h, i, m, o, c) => Rc::new(RefCell::new(Attention::new(exec, inputs, *h, *i, *m, *o, *c))),
+            LayerType::Embedding(v, d) => Rc::new(RefCell::new(Embedding::new(exec, inputs, *v, *d))),
+            LayerType::Recurrent { cell, units, timesteps, return_sequences, stateful } => {
+                Rc::new(RefCell::new(Recurrent::new(exec, inputs, cell.clone(), *units, *timesteps, *return_sequences, *stateful)))
+            }
+            LayerType::Bidirectional { cell, units, timesteps, return_sequences } => {
+                Rc::new(RefCell::new(Bidirectional::new(exec, inputs, cell.clone(), *units, *timesteps, *return_sequences)))
+            }
+            LayerType::LayerNorm(s) => Rc::new(RefCell::new(LayerNorm::new(exec, inputs, *s))),
+            LayerType::Dropout(r) => Rc::new(RefCell::new(Dropout::new(exec, inputs, *r))),
+            LayerType::PositionalEncoding(e, m) => Rc::new(RefCell::new(PositionalEncoding::new(exec, inputs, e.clone(), *m))),
+            LayerType::TransformerEncoder { heads, d_model, d_ff, dropout, causal } => {
+                Rc::new(RefCell::new(TransformerEncoder::new(exec, inputs, *heads, *d_model, *d_ff, *dropout, *causal)))
+            }
+            LayerType::Autograd(d) => Rc::new(RefCell::new(AutogradLayer::new(exec, inputs, d.clone()))),
+        };
+        (layer, self.output_size(inputs))
+    }
+
+    /// Checks that samples of `inputs` values can be processed by a layer of this type
+    pub fn check(&self, inputs: usize) -> Result<(), Error> {
         match self {
-            LayerType::Dense(s, a) => (Rc::new(RefCell::new(Dense::new(exec, inputs, *s, a.clone()))), *s),
-            LayerType::Attention(h, i, m, o) => (Rc::new(RefCell::new(Attention::new(exec, inputs, *h, *i, *m, *o))), *o),
+            LayerType::Recurrent { timesteps, .. } | LayerType::Bidirectional { timesteps, .. } => {
+                if *timesteps == 0 || !inputs.is_multiple_of(*timesteps) {
+                    return Err(Error::Network(NetworkError::Timesteps(inputs, *timesteps)));
+                }
+                Ok(())
+            }
+            _ => Ok(()),
         }
     }
-}
\ No newline at end of file
+
+    /// The id of the layers of this type, see [Layer::id]
+    pub fn id(&self) -> usize {
+        match self {
+            LayerType::Dense(..) => 0,
+            LayerType::Attention(..) => 1,
+            LayerType::Embedding(..) => 2,
+            LayerType::Recurrent { .. } => 3,
+            LayerType::Bidirectional { .. } => 4,
+            LayerType::LayerNorm(_) => 5,
+            LayerType::Dropout(_) => 6,
+            LayerType::PositionalEncoding(..) => 7,
+            LayerType::TransformerEncoder { .. } => 8,
+            LayerType::Autograd(_) => 9,
+        }
+    }
+
+    /// The size of every output sample of the layer for input samples of size `inputs`
+    pub fn output_size(&self, inputs: usize) -> usize {
+        match self {
+            LayerType::Dense(s, _) => *s,
+            LayerType::Attention(_, _, m, o, _) => (inputs / m) * o,
+            LayerType::Embedding(_, d) => inputs * d,
+            LayerType::Recurrent { units, timesteps, return_sequences, .. } => {
+                if *return_sequences { timesteps * units } else { *units }
+            }
+            LayerType::Bidirectional { units, timesteps, return_sequences, .. } => {
+                if *return_sequences { timesteps * units * 2 } else { units * 2 }
+            }
+            LayerType::Autograd(d) => d.output_size(inputs),
+            LayerType::LayerNorm(_) | LayerType::Dropout(_) | LayerType::PositionalEncoding(_, _) | LayerType::TransformerEncoder { .. } => inputs,
+        }
+    }
+}
@@ -0,0 +1,292 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::{Executor, Execs, Optimizer};
+use std::sync::Arc;
+use crate::dual_vec::DualVec;
+use crate::tensor::Tensor;
+use crate::error::{Error, MismatchError};
+use crate::frozen::{FrozenLayer, Scratch};
+use crate::layer::attention::KeyValueCache;
+use crate::layer::Layer;
+use crate::utils::vec_utils::{CursorReader, VecWriter};
+
+const EPSILON: f32 = 1e-5;
+
+/// Normalizes every group of `size` inputs to a mean of zero and a variance of one,
+/// followed by a learnable scale and shift. The layer is computed on the host for every executor.
+#[derive(Debug)]
+pub struct LayerNorm {
+    exec: Arc<Executor>,
+    size: usize,
+    input_len: usize,
+
+    gains: DualVec,
+    biases: DualVec,
+
+    gain_mods: DualVec,
+    bias_mods: DualVec,
+
+    outputs: DualVec,
+    sensitivities: DualVec,
+
+    normalized: Vec<f32>,
+    inv_deviations: Vec<f32>,
+}
+
+impl LayerNorm {
+    pub fn new(exec: &Execs, inputs: usize, size: usize) -> Self {
+        let c = &*exec.current; // current
+        let p_to_c = exec.p_to_c(); // previous to current
+        let c_to_n = exec.c_to_n(); // current to next
+
+        LayerNorm {
+            exec: exec.current.clone(),
+            size,
+            input_len: inputs,
+
+            gains: DualVec::from_vec((c, c), vec![1.; size]),
+            biases: DualVec::from_exec(c, size),
+
+            gain_mods: DualVec::from_exec(c, size),
+            bias_mods: DualVec::from_exec(c, size),
+
+            outputs: DualVec::from_execs(c_to_n, inputs),
+            sensitivities: DualVec::from_execs(p_to_c, inputs),
+
+            normalized: vec![],
+            inv_deviations: vec![],
+        }
+    }
+
+    pub(crate) fn read(exec: &Execs, bytes: &mut CursorReader) -> Self {
+        let mut l = LayerNorm::new(exec, bytes.usize(), bytes.usize());
+
+        for values in [&mut l.gains, &mut l.biases] {
+            if let Some(mut values) = values.cpu_borrow() {
+                for i in 0..values.len() {
+                    values[i] = bytes.f32();
+                }
+            }
+            values.updated_cpu();
+        }
+
+        l
+    }
+
+    /// A copy of the current values in the inference form
+    pub(crate) fn frozen(&mut self) -> FrozenLayerNorm {
+        FrozenLayerNorm {
+            size: self.size,
+            input_len: self.input_len,
+            gains: self.gains.cpu_borrow().unwrap().clone(),
+            biases: self.biases.cpu_borrow().unwrap().clone(),
+        }
+    }
+}
+
+impl Layer for LayerNorm {
+    fn forward(&mut self, inputs: &mut Tensor) {
+        let positions = &inputs.row_offsets();
+        let inputs = inputs.data_mut();
+        let batch_size = positions.len();
+        let groups = self.input_len / self.size;
+        self.outputs.resize(batch_size * self.input_len);
+        self.sensitivities.resize(batch_size * self.input_len);
+        self.normalized.resize(batch_size * self.input_len, 0.);
+        self.inv_deviations.resize(batch_size * groups, 0.);
+
+        {
+            let inputs = inputs.cpu_borrow().unwrap();
+            let gains = self.gains.cpu_borrow().unwrap();
+            let biases = self.biases.cpu_borrow().unwrap();
+            let mut outputs = self.outputs.cpu_borrow().unwrap();
+
+            for batch in 0..batch_size {
+                for group in 0..groups {
+                    let x = &inputs[positions[batch] + group * self.size..positions[batch] + (group + 1) * self.size];
+                    let offset = batch * self.input_len + group * self.size;
+
+                    let mean = x.iter().sum::<f32>() / self.size as f32;
+                    let variance = x.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / self.size as f32;
+                    let inv_deviation = 1. / (variance + EPSILON).sqrt();
+                    self.inv_deviations[batch * groups + group] = inv_deviation;
+
+                    for i in 0..self.size {
+                        let normalized = (x[i] - mean) * inv_deviation;
+                        self.normalized[offset + i] = normalized;
+                        outputs[offset + i] = normalized * gains[i] + biases[i];
+                    }
+                }
+            }
+        }
+
+        self.outputs.updated_cpu();
+    }
+
+    fn backward(&mut self, _inputs: &mut Tensor, in_sensitivities: &mut DualVec, optimizer: &Optimizer) {
+        let lr = optimizer.learn_rate();
+        let batch_size = in_sensitivities.len() / self.input_len;
+        let groups = self.input_len / self.size;
+        let n = self.size as f32;
+
+        {
+            let in_sensitivities = in_sensitivities.cpu_borrow().unwrap();
+            let gains = self.gains.cpu_borrow().unwrap();
+            let mut gain_mods = self.gain_mods.cpu_borrow().unwrap();
+            let mut bias_mods = self.bias_mods.cpu_borrow().unwrap();
+            let mut sensitivities = self.sensitivities.cpu_borrow().unwrap();
+
+            for batch in 0..batch_size {
+                for group in 0..groups {
+                    let offset = batch * self.input_len + group * self.size;
+                    let normalized = &self.normalized[offset..offset + self.size];
+
+                    let mut sum = 0.;
+                    let mut weighted = 0.;
+                    for i in 0..self.size {
+                        let gradient = in_sensitivities[offset + i];
+                        gain_mods[i] -= lr * gradient * normalized[i];
+                        bias_mods[i] -= lr * gradient;
+
+                        let gradient = gradient * gains[i];
+                        sum += gradient;
+                        weighted += gradient * normalized[i];
+                    }
+
+                    let inv_deviation = self.inv_deviations[batch * groups + group];
+                    for i in 0..self.size {
+                        let gradient = in_sensitivities[offset + i] * gains[i];
+                        sensitivities[offset + i] = inv_deviation * (gradient - (sum + normalized[i] * weighted) / n);
+                    }
+                }
+            }
+        }
+
+        self.sensitivities.updated_cpu();
+        self.gain_mods.updated_cpu();
+        self.bias_mods.updated_cpu();
+    }
+
+    fn apply_gradients(&mut self, optimizer: &Optimizer, batch_size: usize) {
+        match optimizer {
+            Optimizer::GradientDecent(_) => {
+                let i_batch_size = 1. / batch_size as f32;
+
+                for (values, mods) in [(&mut self.gains, &mut self.gain_mods), (&mut self.biases, &mut self.bias_mods)] {
+                    if let (Some(mut values), Some(mut mods)) = (values.cpu_borrow(), mods.cpu_borrow()) {
+                        for i in 0..values.len() {
+                            values[i] += mods[i] * i_batch_size;
+                        }
+                        mods.fill(0.);
+                    }
+                    values.updated_cpu();
+                    mods.updated_cpu();
+                }
+            }
+        }
+    }
+
+    fn as_bytes(&mut self, bytes: &mut VecWriter) {
+        let gains = self.gains.cpu_borrow().unwrap();
+        let biases = self.biases.cpu_borrow().unwrap();
+        bytes.reserve((2 * 8) + (gains.len() + biases.len()) * 4);
+
+        bytes.usize(self.input_len);
+        bytes.usize(self.size);
+
+        for v in gains.iter().chain(biases.iter()) {
+            bytes.f32(*v);
+        }
+    }
+
+    fn from_bytes(exec: &Execs, bytes: &mut CursorReader) -> Rc<RefCell<dyn Layer>> {
+        Rc::new(RefCell::new(LayerNorm::read(exec, bytes)))
+    }
+
+    fn id(&self) -> usize {
+        5
+    }
+
+    fn exec(&self) -> &Executor {
+        &self.exec
+    }
+
+    fn values(&self) -> Vec<&DualVec> {
+        vec![&self.gains, &self.biases]
+    }
+
+    fn mods(&mut self) -> Result<Vec<&mut DualVec>, Error> {
+        Ok(vec![&mut self.gain_mods, &mut self.bias_mods])
+    }
+
+    fn input_size(&self) -> usize {
+        self.input_len
+    }
+
+    fn output_size(&self) -> usize {
+        self.input_len
+    }
+
+    fn activated_output(&mut self) -> &mut DualVec {
+        &mut self.outputs
+    }
+
+    fn sensitivities(&mut self) -> &mut DualVec {
+        &mut self.sensitivities
+    }
+
+    fn step(&mut self, _position: usize, _cache: &mut KeyValueCache, inputs: &[f32]) -> Result<Vec<f32>, Error> {
+        if !inputs.len().is_multiple_of(self.size) {
+            return Err(Error::Mismatch(MismatchError::Step(inputs.len(), self.size)));
+        }
+
+        let gains = self.gains.cpu_borrow().unwrap();
+        let biases = self.biases.cpu_borrow().unwrap();
+
+        let mut outputs = vec![0.; inputs.len()];
+        for (x, out) in inputs.chunks(self.size).zip(outputs.chunks_mut(self.size)) {
+            let mean = x.iter().sum::<f32>() / self.size as f32;
+            let variance = x.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / self.size as f32;
+            let inv_deviation = 1. / (variance + EPSILON).sqrt();
+
+            for i in 0..self.size {
+                out[i] = (x[i] - mean) * inv_deviation * gains[i] + biases[i];
+            }
+        }
+        Ok(outputs)
+    }
+    fn freeze(&mut self) -> Result<Box<dyn FrozenLayer>, Error> {
+        Ok(Box::new(self.frozen()))
+    }
+}
+
+/// The inference form of [LayerNorm]
+#[derive(Debug)]
+pub struct FrozenLayerNorm {
+    size: usize,
+    input_len: usize,
+    gains: Vec<f32>,
+    biases: Vec<f32>,
+}
+
+impl FrozenLayer for FrozenLayerNorm {
+    fn input_size(&self) -> usize {
+        self.input_len
+    }
+
+    fn output_size(&self) -> usize {
+        self.input_len
+    }
+
+    fn forward(&self, inputs: &[f32], outputs: &mut [f32], _scratch: &mut Scratch) {
+        for (x, out) in inputs.chunks(self.size).zip(outputs.chunks_mut(self.size)) {
+            let mean = x.iter().sum::<f32>() / self.size as f32;
+            let variance = x.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / self.size as f32;
+            let inv_deviation = 1. / (variance + EPSILON).sqrt();
+            for i in 0..self.size {
+                out[i] = (x[i] - mean) * inv_deviation * self.gains[i] + self.biases[i];
+            }
+        }
+    }
+}
@@ -0,0 +1,280 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::{Executor, Execs, Optimizer};
+use std::sync::Arc;
+use crate::dual_vec::DualVec;
+use crate::tensor::Tensor;
+use crate::error::{Error, MismatchError, NetworkError};
+use crate::frozen::{FrozenLayer, Scratch};
+use crate::layer::attention::KeyValueCache;
+use crate::layer::Layer;
+use crate::utils::vec_utils::{CursorReader, VecWriter};
+
+#[derive(Clone, Debug)]
+pub enum Encoding {
+    /// The fixed sine and cosine encoding of "Attention Is All You Need"
+    Sinusoidal,
+    /// A learnable value for every position and characteristic
+    Learned,
+}
+
+impl From<usize> for Encoding {
+    fn from(value: usize) -> Self {
+        match value {
+            1 => Encoding::Learned,
+            _ => Encoding::Sinusoidal,
+        }
+    }
+}
+
+impl From<&Encoding> for usize {
+    fn from(encoding: &Encoding) -> usize {
+        match encoding {
+            Encoding::Sinusoidal => 0,
+            Encoding::Learned => 1,
+        }
+    }
+}
+
+/// The sinusoidal encoding of a single characteristic at a position
+pub fn sinusoid(position: usize, i: usize, characteristics: usize) -> f32 {
+    let angle = position as f32 / 10000f32.powf((2 * (i / 2)) as f32 / characteristics as f32);
+    if i.is_multiple_of(2) {
+        angle.sin()
+    } else {
+        angle.cos()
+    }
+}
+
+/// Adds an encoding of the position to every `[sequence, characteristics]` row of the inputs.
+#[derive(Debug)]
+pub struct PositionalEncoding {
+    exec: Arc<Executor>,
+    encoding: Encoding,
+    characteristics: usize,
+    input_len: usize,
+
+    /// `[sequence, characteristics]`
+    table: DualVec,
+    table_mods: DualVec,
+
+    outputs: DualVec,
+    sensitivities: DualVec,
+}
+
+impl PositionalEncoding {
+    pub fn new(exec: &Execs, inputs: usize, encoding: Encoding, characteristics: usize) -> Self {
+        let c = &*exec.current; // current
+        let p_to_c = exec.p_to_c(); // previous to current
+        let c_to_n = exec.c_to_n(); // current to next
+
+        let mut table = DualVec::from_exec(c, inputs);
+        match encoding {
+            Encoding::Sinusoidal => {
+                if let Some(mut table) = table.cpu_borrow() {
+                    for i in 0..inputs {
+                        table[i] = sinusoid(i / characteristics, i % characteristics, characteristics);
+                    }
+                }
+                table.updated_cpu();
+            }
+            Encoding::Learned => table.randomize(c, (characteristics as f32).sqrt()),
+        }
+
+        PositionalEncoding {
+            exec: exec.current.clone(),
+            encoding,
+            characteristics,
+            input_len: inputs,
+
+            table,
+            table_mods: DualVec::from_exec(c, inputs),
+
+            outputs: DualVec::from_execs(c_to_n, inputs),
+            sensitivities: DualVec::from_execs(p_to_c, inputs),
+        }
+    }
+
+    /// A copy of the current values in the inference form
+    pub(crate) fn frozen(&mut self) -> FrozenPositionalEncoding {
+        FrozenPositionalEncoding {
+            table: self.table.cpu_borrow().unwrap().clone(),
+        }
+    }
+}
+
+impl Layer for PositionalEncoding {
+    fn forward(&mut self, inputs: &mut Tensor) {
+        let positions = &inputs.row_offsets();
+        let inputs = inputs.data_mut();
+        let batch_size = positions.len();
+        self.outputs.resize(batch_size * self.input_len);
+        self.sensitivities.resize(batch_size * self.input_len);
+
+        {
+            let inputs = inputs.cpu_borrow().unwrap();
+            let table = self.table.cpu_borrow().unwrap();
+            let mut outputs = self.outputs.cpu_borrow().unwrap();
+
+            for batch in 0..batch_size {
+                let offset = batch * self.input_len;
+                for i in 0..self.input_len {
+                    outputs[offset + i] = inputs[positions[batch] + i] + table[i];
+                }
+            }
+        }
+
+        self.outputs.updated_cpu();
+    }
+
+    fn backward(&mut self, _inputs: &mut Tensor, in_sensitivities: &mut DualVec, optimizer: &Optimizer) {
+        let lr = optimizer.learn_rate();
+
+        {
+            let in_sensitivities = in_sensitivities.cpu_borrow().unwrap();
+            let mut sensitivities = self.sensitivities.cpu_borrow().unwrap();
+            sensitivities[..in_sensitivities.len()].copy_from_slice(&in_sensitivities);
+
+            if let Encoding::Learned = self.encoding {
+                let mut table_mods = self.table_mods.cpu_borrow().unwrap();
+                for i in 0..in_sensitivities.len() {
+                    table_mods[i % self.input_len] -= lr * in_sensitivities[i];
+                }
+            }
+        }
+
+        self.sensitivities.updated_cpu();
+        self.table_mods.updated_cpu();
+    }
+
+    fn apply_gradients(&mut self, optimizer: &Optimizer, batch_size: usize) {
+        if let Encoding::Sinusoidal = self.encoding {
+            return;
+        }
+
+        match optimizer {
+            Optimizer::GradientDecent(_) => {
+                let i_batch_size = 1. / batch_size as f32;
+
+                if let (Some(mut table), Some(mut mods)) = (self.table.cpu_borrow(), self.table_mods.cpu_borrow()) {
+                    for i in 0..table.len() {
+                        table[i] += mods[i] * i_batch_size;
+                    }
+                    mods.fill(0.);
+                }
+                self.table.updated_cpu();
+                self.table_mods.updated_cpu();
+            }
+        }
+    }
+
+    fn as_bytes(&mut self, bytes: &mut VecWriter) {
+        bytes.usize(self.input_len);
+        bytes.index(&self.encoding);
+        bytes.usize(self.characteristics);
+
+        if let Encoding::Learned = self.encoding {
+            let table = self.table.cpu_borrow().unwrap();
+            bytes.reserve(table.len() * 4);
+            for v in table.iter() {
+                bytes.f32(*v);
+            }
+        }
+    }
+
+    fn from_bytes(exec: &Execs, bytes: &mut CursorReader) -> Rc<RefCell<dyn Layer>> {
+        let mut l = PositionalEncoding::new(exec, bytes.usize(), bytes.indexed(), bytes.usize());
+
+        if let Encoding::Learned = l.encoding {
+            if let Some(mut table) = l.table.cpu_borrow() {
+                for i in 0..table.len() {
+                    table[i] = bytes.f32();
+                }
+            }
+            l.table.updated_cpu();
+        }
+
+        Rc::new(RefCell::new(l))
+    }
+
+    fn id(&self) -> usize {
+        7
+    }
+
+    fn exec(&self) -> &Executor {
+        &self.exec
+    }
+
+    fn values(&self) -> Vec<&DualVec> {
+        match self.encoding {
+            Encoding::Sinusoidal => vec![],
+            Encoding::Learned => vec![&self.table],
+        }
+    }
+
+    fn mods(&mut self) -> Result<Vec<&mut DualVec>, Error> {
+        Ok(match self.encoding {
+            Encoding::Sinusoidal => vec![],
+            Encoding::Learned => vec![&mut self.table_mods],
+        })
+    }
+
+    fn input_size(&self) -> usize {
+        self.input_len
+    }
+
+    fn output_size(&self) -> usize {
+        self.input_len
+    }
+
+    fn activated_output(&mut self) -> &mut DualVec {
+        &mut self.outputs
+    }
+
+    fn sensitivities(&mut self) -> &mut DualVec {
+        &mut self.sensitivities
+    }
+
+    fn step(&mut self, position: usize, _cache: &mut KeyValueCache, inputs: &[f32]) -> Result<Vec<f32>, Error> {
+        if inputs.len() != self.characteristics {
+            return Err(Error::Mismatch(MismatchError::Step(inputs.len(), self.characteristics)));
+        }
+        let sequence = self.input_len / self.characteristics;
+        if position >= sequence {
+            return Err(Error::Network(NetworkError::Position(position, sequence)));
+        }
+
+        let table = self.table.cpu_borrow().unwrap();
+        let row = &table[position * self.characteristics..(position + 1) * self.characteristics];
+        Ok(inputs.iter().zip(row).map(|(x, p)| x + p).collect())
+    }
+    fn freeze(&mut self) -> Result<Box<dyn FrozenLayer>, Error> {
+        Ok(Box::new(self.frozen()))
+    }
+}
+
+/// The inference form of [PositionalEncoding]
+#[derive(Debug)]
+pub struct FrozenPositionalEncoding {
+    /// `[sequence, characteristics]`
+    table: Vec<f32>,
+}
+
+impl FrozenLayer for FrozenPositionalEncoding {
+    fn input_size(&self) -> usize {
+        self.table.len()
+    }
+
+    fn output_size(&self) -> usize {
+        self.table.len()
+    }
+
+    fn forward(&self, inputs: &[f32], outputs: &mut [f32], _scratch: &mut Scratch) {
+        for (x, out) in inputs.chunks(self.table.len()).zip(outputs.chunks_mut(self.table.len())) {
+            for i in 0..self.table.len() {
+                out[i] = x[i] + self.table[i];
+            }
+        }
+    }
+}
@@ -0,0 +1,887 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::{Executor, Execs, Optimizer};
+use std::sync::Arc;
+use crate::dual_vec::DualVec;
+use crate::tensor::Tensor;
+use crate::error::{Error, NetworkError};
+use crate::frozen::{FrozenLayer, Scratch};
+use crate::layer::attention::linear;
+use crate::layer::Layer;
+use crate::utils::vec_utils::{CursorReader, VecWriter};
+
+#[derive(Clone, Debug)]
+pub enum Cell {
+    SimpleRNN,
+    LSTM,
+    GRU,
+}
+
+impl Cell {
+    /// The amount of gates, each of which has `units` values per time step
+    pub fn gates(&self) -> usize {
+        match self {
+            Cell::SimpleRNN => 1,
+            Cell::LSTM => 4,
+            Cell::GRU => 3,
+        }
+    }
+}
+
+impl From<usize> for Cell {
+    fn from(value: usize) -> Self {
+        match value {
+            1 => Cell::LSTM,
+            2 => Cell::GRU,
+            _ => Cell::SimpleRNN,
+        }
+    }
+}
+
+impl From<&Cell> for usize {
+    fn from(cell: &Cell) -> usize {
+        match cell {
+            Cell::SimpleRNN => 0,
+            Cell::LSTM => 1,
+            Cell::GRU => 2,
+        }
+    }
+}
+
+fn sigmoid(x: f32) -> f32 {
+    1. / (1. + (-x).exp())
+}
+
+/// A recurrent layer processing samples of `[timesteps, features]` with backpropagation through time.
+///
+/// The time steps of a sample are sequential, so the layer is computed on the host for
+/// every executor, and the buffers are synchronized through [DualVec].
+#[derive(Debug)]
+pub struct Recurrent {
+    exec: Arc<Executor>,
+    cell: Cell,
+    units: usize,
+    features: usize,
+    timesteps: usize,
+    return_sequences: bool,
+    stateful: bool,
+    /// Processes the time steps from last to first, used by [Bidirectional]
+    reverse: bool,
+
+    /// input weights, `[gates * units, features]`
+    weights: DualVec,
+    /// recurrent weights, `[gates * units, units]`
+    recurrent_weights: DualVec,
+    biases: DualVec,
+
+    weight_mods: DualVec,
+    recurrent_mods: DualVec,
+    bias_mods: DualVec,
+
+    outputs: DualVec,
+    sensitivities: DualVec,
+
+    /// Activated gate values of every processed step
+    gates: Vec<f32>,
+    /// Recurrent weights multiplied by the previous hidden state of every processed step
+    recurrent_products: Vec<f32>,
+    /// Hidden states, including the initial state
+    hidden: Vec<f32>,
+    /// LSTM cell states, including the initial state
+    cells: Vec<f32>,
+
+    /// Final states of the previous batch, which are used as the initial states when stateful
+    state_hidden: Vec<f32>,
+    state_cells: Vec<f32>,
+}
+
+impl Recurrent {
+    pub fn new(exec: &Execs, inputs: usize, cell: Cell, units: usize, timesteps: usize, return_sequences: bool, stateful: bool) -> Self {
+        let c = &*exec.current; // current
+        let p_to_c = exec.p_to_c(); // previous to current
+        let c_to_n = exec.c_to_n(); // current to next
+
+        let features = inputs / timesteps;
+        let gate_len = cell.gates() * units;
+        let output_size = if return_sequences { timesteps * units } else { units };
+
+        let mut r = Recurrent {
+            exec: exec.current.clone(),
+            cell,
+            units,
+            features,
+            timesteps,
+            return_sequences,
+            stateful,
+            reverse: false,
+
+            weights: DualVec::from_exec(c, gate_len * features),
+            recurrent_weights: DualVec::from_exec(c, gate_len * units),
+            biases: DualVec::from_exec(c, gate_len),
+
+            weight_mods: DualVec::from_exec(c, gate_len * features),
+            recurrent_mods: DualVec::from_exec(c, gate_len * units),
+            bias_mods: DualVec::from_exec(c, gate_len),
+
+            outputs: DualVec::from_execs(c_to_n, output_size),
+            sensitivities: DualVec::from_execs(p_to_c, inputs),
+
+            gates: vec![],
+            recurrent_products: vec![],
+            hidden: vec![],
+            cells: vec![],
+            state_hidden: vec![],
+            state_cells: vec![],
+        };
+
+        r.weights.randomize(c, ((features + units) as f32).sqrt());
+        r.recurrent_weights.randomize(c, ((features + units) as f32).sqrt());
+        r.biases.randomize(c, (gate_len as f32).sqrt());
+
+        if let Cell::LSTM = r.cell {
+            // A forget gate bias of one lets the cell state flow through at the start of training
+            if let Some(mut biases) = r.biases.cpu_borrow() {
+                biases[units..2 * units].fill(1.);
+            }
+            r.biases.updated_cpu();
+        }
+
+        r
+    }
+
+    fn output_len(&self) -> usize {
+        if self.return_sequences {
+            self.timesteps * self.units
+        } else {
+            self.units
+        }
+    }
+
+    fn ensure_batch_size(&mut self, batch_size: usize) {
+        let target = batch_size * self.output_len();
+        if self.outputs.len() < target {
+            self.outputs.expand_to(target);
+            self.sensitivities.expand_to(batch_size * self.timesteps * self.features);
+        } else if self.outputs.len() > target {
+            self.outputs.truncate_to(target);
+            self.sensitivities.truncate_to(batch_size * self.timesteps * self.features);
+        }
+
+        let gate_len = self.cell.gates() * self.units;
+        self.gates.resize(batch_size * self.timesteps * gate_len, 0.);
+        self.recurrent_products.resize(batch_size * self.timesteps * gate_len, 0.);
+        self.hidden.resize(batch_size * (self.timesteps + 1) * self.units, 0.);
+        if let Cell::LSTM = self.cell {
+            self.cells.resize(batch_size * (self.timesteps + 1) * self.units, 0.);
+        }
+        // Rows which were not part of the previous batch start from a zero state
+        self.state_hidden.resize(batch_size * self.units, 0.);
+        if let Cell::LSTM = self.cell {
+            self.state_cells.resize(batch_size * self.units, 0.);
+        }
+    }
+
+    /// Clears the states carried between batches when the layer is stateful
+    pub fn reset_states(&mut self) {
+        self.state_hidden.fill(0.);
+        self.state_cells.fill(0.);
+    }
+
+    fn write(&mut self, bytes: &mut VecWriter) {
+        let weights = self.weights.cpu_borrow().unwrap();
+        let recurrent_weights = self.recurrent_weights.cpu_borrow().unwrap();
+        let biases = self.biases.cpu_borrow().unwrap();
+        bytes.reserve((7 * 8) + (weights.len() + recurrent_weights.len() + biases.len()) * 4);
+
+        bytes.usize(self.timesteps * self.features);
+        bytes.index(&self.cell);
+        bytes.usize(self.units);
+        bytes.usize(self.timesteps);
+        bytes.bool(self.return_sequences);
+        bytes.bool(self.stateful);
+        bytes.bool(self.reverse);
+
+        for v in weights.iter().chain(recurrent_weights.iter()).chain(biases.iter()) {
+            bytes.f32(*v);
+        }
+    }
+
+    fn read(exec: &Execs, bytes: &mut CursorReader) -> Self {
+        let mut l = Recurrent::new(exec, bytes.usize(), bytes.indexed(), bytes.usize(), bytes.usize(), bytes.bool(), bytes.bool());
+        l.reverse = bytes.bool();
+
+        for values in [&mut l.weights, &mut l.recurrent_weights, &mut l.biases] {
+            if let Some(mut values) = values.cpu_borrow() {
+                for i in 0..values.len() {
+                    values[i] = bytes.f32();
+                }
+            }
+            values.updated_cpu();
+        }
+
+        l
+    }
+
+    /// A copy of the current values in the inference form, where every sample starts from zeroed states
+    pub(crate) fn frozen(&mut self) -> FrozenRecurrent {
+        FrozenRecurrent {
+            cell: self.cell.clone(),
+            units: self.units,
+            features: self.features,
+            timesteps: self.timesteps,
+            return_sequences: self.return_sequences,
+            reverse: self.reverse,
+            weights: self.weights.cpu_borrow().unwrap().clone(),
+            recurrent_weights: self.recurrent_weights.cpu_borrow().unwrap().clone(),
+            biases: self.biases.cpu_borrow().unwrap().clone(),
+        }
+    }
+}
+
+impl Layer for Recurrent {
+    fn forward(&mut self, inputs: &mut Tensor) {
+        let positions = &inputs.row_offsets();
+        let inputs = inputs.data_mut();
+        self.ensure_batch_size(positions.len());
+
+        let units = self.units;
+        let gate_len = self.cell.gates() * units;
+        let output_len = self.output_len();
+        let steps = self.timesteps;
+        let reverse = self.reverse;
+        // The time index of the nth processed step
+        let time = |step: usize| if reverse { steps - 1 - step } else { step };
+
+        {
+            let inputs = inputs.cpu_borrow().unwrap();
+            let weights = self.weights.cpu_borrow().unwrap();
+            let recurrent_weights = self.recurrent_weights.cpu_borrow().unwrap();
+            let biases = self.biases.cpu_borrow().unwrap();
+            let mut outputs = self.outputs.cpu_borrow().unwrap();
+
+            let mut input_products = vec![0.; gate_len];
+
+            for batch in 0..positions.len() {
+                let hidden_offset = batch * (steps + 1) * units;
+                if self.stateful {
+                    self.hidden[hidden_offset..hidden_offset + units].copy_from_slice(&self.state_hidden[batch * units..(batch + 1) * units]);
+                    if let Cell::LSTM = self.cell {
+                        self.cells[hidden_offset..hidden_offset + units].copy_from_slice(&self.state_cells[batch * units..(batch + 1) * units]);
+                    }
+                } else {
+                    self.hidden[hidden_offset..hidden_offset + units].fill(0.);
+                    if let Cell::LSTM = self.cell {
+                        self.cells[hidden_offset..hidden_offset + units].fill(0.);
+                    }
+                }
+
+                for step in 0..steps {
+                    let t = time(step);
+                    let x = &inputs[positions[batch] + t * self.features..positions[batch] + (t + 1) * self.features];
+                    let gate_offset = (batch * steps + step) * gate_len;
+                    let prev = hidden_offset + step * units;
+                    let next = prev + units;
+
+                    for r in 0..gate_len {
+                        let mut sum = biases[r];
+                        for i in 0..self.features {
+                            sum += weights[r * self.features + i] * x[i];
+                        }
+                        input_products[r] = sum;
+
+                        let mut sum = 0.;
+                        for k in 0..units {
+                            sum += recurrent_weights[r * units + k] * self.hidden[prev + k];
+                        }
+                        self.recurrent_products[gate_offset + r] = sum;
+                    }
+
+                    let gates = &mut self.gates[gate_offset..gate_offset + gate_len];
+                    let products = &self.recurrent_products[gate_offset..gate_offset + gate_len];
+                    match self.cell {
+                        Cell::SimpleRNN => {
+                            for j in 0..units {
+                                gates[j] = (input_products[j] + products[j]).tanh();
+                                self.hidden[next + j] = gates[j];
+                            }
+                        }
+                        Cell::LSTM => {
+                            for j in 0..units {
+                                let i = sigmoid(input_products[j] + products[j]);
+                                let f = sigmoid(input_products[units + j] + products[units + j]);
+                                let g = (input_products[2 * units + j] + products[2 * units + j]).tanh();
+                                let o = sigmoid(input_products[3 * units + j] + products[3 * units + j]);
+                                gates[j] = i;
+                                gates[units + j] = f;
+                                gates[2 * units + j] = g;
+                                gates[3 * units + j] = o;
+
+                                let cell = f * self.cells[prev + j] + i * g;
+                                self.cells[next + j] = cell;
+                                self.hidden[next + j] = o * cell.tanh();
+                            }
+                        }
+                        Cell::GRU => {
+                            for j in 0..units {
+                                let z = sigmoid(input_products[j] + products[j]);
+                                let r = sigmoid(input_products[units + j] + products[units + j]);
+                                let n = (input_products[2 * units + j] + r * products[2 * units + j]).tanh();
+                                gates[j] = z;
+                                gates[units + j] = r;
+                                gates[2 * units + j] = n;
+
+                                self.hidden[next + j] = (1. - z) * n + z * self.hidden[prev + j];
+                            }
+                        }
+                    }
+
+                    if self.return_sequences {
+                        let out = batch * output_len + t * units;
+                        outputs[out..out + units].copy_from_slice(&self.hidden[next..next + units]);
+                    }
+                }
+
+                let last = hidden_offset + steps * units;
+                if !self.return_sequences {
+                    outputs[batch * output_len..(batch + 1) * output_len].copy_from_slice(&self.hidden[last..last + units]);
+                }
+                if self.stateful {
+                    self.state_hidden[batch * units..(batch + 1) * units].copy_from_slice(&self.hidden[last..last + units]);
+                    if let Cell::LSTM = self.cell {
+                        self.state_cells[batch * units..(batch + 1) * units].copy_from_slice(&self.cells[last..last + units]);
+                    }
+                }
+            }
+        }
+
+        self.outputs.updated_cpu();
+    }
+
+    fn backward(&mut self, inputs: &mut Tensor, in_sensitivities: &mut DualVec, optimizer: &Optimizer) {
+        let input_indices = inputs.gather_offsets();
+        let (inputs, input_indices) = (inputs.data_mut(), input_indices.as_ref());
+        let lr = optimizer.learn_rate();
+        let units = self.units;
+        let gate_len = self.cell.gates() * units;
+        let output_len = self.output_len();
+        let input_len = self.timesteps * self.features;
+        let steps = self.timesteps;
+        let batch_size = in_sensitivities.len() / output_len;
+        let reverse = self.reverse;
+        let time = |step: usize| if reverse { steps - 1 - step } else { step };
+
+        {
+            let in_sensitivities = in_sensitivities.cpu_borrow().unwrap();
+            let inputs = inputs.cpu_borrow().unwrap();
+            let weights = self.weights.cpu_borrow().unwrap();
+            let recurrent_weights = self.recurrent_weights.cpu_borrow().unwrap();
+            let mut weight_mods = self.weight_mods.cpu_borrow().unwrap();
+            let mut recurrent_mods = self.recurrent_mods.cpu_borrow().unwrap();
+            let mut bias_mods = self.bias_mods.cpu_borrow().unwrap();
+            let mut sensitivities = self.sensitivities.cpu_borrow().unwrap();
+            sensitivities.fill(0.);
+
+            // gradients of the input and recurrent products before activation
+            let mut input_gradients = vec![0.; gate_len];
+            let mut recurrent_gradients = vec![0.; gate_len];
+            let mut hidden_gradients = vec![0.; units];
+            let mut cell_gradients = vec![0.; units];
+            let mut next_hidden_gradients = vec![0.; units];
+
+            for batch in 0..batch_size {
+                let in_offset = match input_indices {
+                    None => batch * input_len,
+                    Some(indices) => indices[batch],
+                };
+                let hidden_offset = batch * (steps + 1) * units;
+                hidden_gradients.fill(0.);
+                cell_gradients.fill(0.);
+
+                for step in (0..steps).rev() {
+                    let t = time(step);
+                    let gate_offset = (batch * steps + step) * gate_len;
+                    let prev = hidden_offset + step * units;
+                    let next = prev + units;
+                    let gates = &self.gates[gate_offset..gate_offset + gate_len];
+
+                    if self.return_sequences {
+                        for j in 0..units {
+                            hidden_gradients[j] += in_sensitivities[batch * output_len + t * units + j];
+                        }
+                    } else if step == steps - 1 {
+                        for j in 0..units {
+                            hidden_gradients[j] += in_sensitivities[batch * output_len + j];
+                        }
+                    }
+                    next_hidden_gradients.fill(0.);
+
+                    match self.cell {
+                        Cell::SimpleRNN => {
+                            for j in 0..units {
+                                input_gradients[j] = hidden_gradients[j] * (1. - gates[j] * gates[j]);
+                            }
+                            recurrent_gradients.copy_from_slice(&input_gradients);
+                        }
+                        Cell::LSTM => {
+                            for j in 0..units {
+                                let (i, f, g, o) = (gates[j], gates[units + j], gates[2 * units + j], gates[3 * units + j]);
+                                let cell = self.cells[next + j].tanh();
+
+                                let d_cell = cell_gradients[j] + hidden_gradients[j] * o * (1. - cell * cell);
+                                input_gradients[j] = d_cell * g * i * (1. - i);
+                                input_gradients[units + j] = d_cell * self.cells[prev + j] * f * (1. - f);
+                                input_gradients[2 * units + j] = d_cell * i * (1. - g * g);
+                                input_gradients[3 * units + j] = hidden_gradients[j] * cell * o * (1. - o);
+                                cell_gradients[j] = d_cell * f;
+                            }
+                            recurrent_gradients.copy_from_slice(&input_gradients);
+                        }
+                        Cell::GRU => {
+                            let products = &self.recurrent_products[gate_offset..gate_offset + gate_len];
+                            for j in 0..units {
+                                let (z, r, n) = (gates[j], gates[units + j], gates[2 * units + j]);
+
+                                let d_n = hidden_gradients[j] * (1. - z) * (1. - n * n);
+                                let d_r = d_n * products[2 * units + j];
+                                input_gradients[j] = hidden_gradients[j] * (self.hidden[prev + j] - n) * z * (1. - z);
+                                input_gradients[units + j] = d_r * r * (1. - r);
+                                input_gradients[2 * units + j] = d_n;
+
+                                recurrent_gradients[j] = input_gradients[j];
+                                recurrent_gradients[units + j] = input_gradients[units + j];
+                                recurrent_gradients[2 * units + j] = d_n * r;
+
+                                next_hidden_gradients[j] = hidden_gradients[j] * z;
+                            }
+                        }
+                    }
+
+                    let x_offset = in_offset + t * self.features;
+                    let s_offset = batch * input_len + t * self.features;
+                    for r in 0..gate_len {
+                        let input_gradient = input_gradients[r];
+                        let recurrent_gradient = recurrent_gradients[r];
+
+                        bias_mods[r] -= lr * input_gradient;
+                        for i in 0..self.features {
+                            weight_mods[r * self.features + i] -= lr * input_gradient * inputs[x_offset + i];
+                            sensitivities[s_offset + i] += input_gradient * weights[r * self.features + i];
+                        }
+                        for k in 0..units {
+                            recurrent_mods[r * units + k] -= lr * recurrent_gradient * self.hidden[prev + k];
+                            next_hidden_gradients[k] += recurrent_gradient * recurrent_weights[r * units + k];
+                        }
+                    }
+
+                    hidden_gradients.copy_from_slice(&next_hidden_gradients);
+                }
+            }
+        }
+
+        self.sensitivities.updated_cpu();
+        self.weight_mods.updated_cpu();
+        self.recurrent_mods.updated_cpu();
+        self.bias_mods.updated_cpu();
+    }
+
+    fn apply_gradients(&mut self, optimizer: &Optimizer, batch_size: usize) {
+        match optimizer {
+            Optimizer::GradientDecent(_) => {
+                let i_batch_size = 1. / batch_size as f32;
+
+                for (values, mods) in [
+                    (&mut self.weights, &mut self.weight_mods),
+                    (&mut self.recurrent_weights, &mut self.recurrent_mods),
+                    (&mut self.biases, &mut self.bias_mods),
+                ] {
+                    if let (Some(mut values), Some(mut mods)) = (values.cpu_borrow(), mods.cpu_borrow()) {
+                        for i in 0..values.len() {
+                            values[i] += mods[i] * i_batch_size;
+                        }
+                        mods.fill(0.);
+                    }
+                    values.updated_cpu();
+                    mods.updated_cpu();
+                }
+            }
+        }
+    }
+
+    fn as_bytes(&mut self, bytes: &mut VecWriter) {
+        self.write(bytes);
+    }
+
+    fn from_bytes(exec: &Execs, bytes: &mut CursorReader) -> Rc<RefCell<dyn Layer>> {
+        Rc::new(RefCell::new(Recurrent::read(exec, bytes)))
+    }
+
+    fn id(&self) -> usize {
+        3
+    }
+
+    fn exec(&self) -> &Executor {
+        &self.exec
+    }
+
+    fn values(&self) -> Vec<&DualVec> {
+        vec![&self.weights, &self.recurrent_weights, &self.biases]
+    }
+
+    fn mods(&mut self) -> Result<Vec<&mut DualVec>, Error> {
+        Ok(vec![&mut self.weight_mods, &mut self.recurrent_mods, &mut self.bias_mods])
+    }
+
+    fn input_size(&self) -> usize {
+        self.timesteps * self.features
+    }
+
+    fn output_size(&self) -> usize {
+        self.output_len()
+    }
+
+    fn activated_output(&mut self) -> &mut DualVec {
+        &mut self.outputs
+    }
+
+    fn sensitivities(&mut self) -> &mut DualVec {
+        &mut self.sensitivities
+    }
+
+    fn reset_states(&mut self) {
+        Recurrent::reset_states(self);
+    }
+
+    fn freeze(&mut self) -> Result<Box<dyn FrozenLayer>, Error> {
+        // The states carried between batches would be shared by every thread
+        if self.stateful {
+            return Err(Error::Network(NetworkError::Freeze(self.id())));
+        }
+        Ok(Box::new(self.frozen()))
+    }
+}
+
+/// Runs one recurrent layer over the time steps in order and another in reverse,
+/// concatenating both of their outputs for every time step (or the last states).
+#[derive(Debug)]
+pub struct Bidirectional {
+    exec: Arc<Executor>,
+    forward: Recurrent,
+    backward: Recurrent,
+
+    outputs: DualVec,
+    sensitivities: DualVec,
+
+    forward_sensitivities: DualVec,
+    backward_sensitivities: DualVec,
+}
+
+impl Bidirectional {
+    pub fn new(exec: &Execs, inputs: usize, cell: Cell, units: usize, timesteps: usize, return_sequences: bool) -> Self {
+        let inner = Execs::new(&exec.prev, &exec.current, &exec.current);
+        let mut backward = Recurrent::new(&inner, inputs, cell.clone(), units, timesteps, return_sequences, false);
+        backward.reverse = true;
+
+        Self::from_layers(exec, Recurrent::new(&inner, inputs, cell, units, timesteps, return_sequences, false), backward)
+    }
+
+    fn from_layers(exec: &Execs, forward: Recurrent, backward: Recurrent) -> Self {
+        let c = &*exec.current; // current
+        let p_to_c = exec.p_to_c(); // previous to current
+        let c_to_n = exec.c_to_n(); // current to next
+
+        Bidirectional {
+            exec: exec.current.clone(),
+            outputs: DualVec::from_execs(c_to_n, forward.output_len() * 2),
+            sensitivities: DualVec::from_execs(p_to_c, forward.input_size()),
+            forward_sensitivities: DualVec::from_exec(c, forward.output_len()),
+            backward_sensitivities: DualVec::from_exec(c, forward.output_len()),
+            forward,
+            backward,
+        }
+    }
+
+    /// Clears the states of both directions
+    pub fn reset_states(&mut self) {
+        self.forward.reset_states();
+        self.backward.reset_states();
+    }
+}
+
+impl Layer for Bidirectional {
+    fn forward(&mut self, inputs: &mut Tensor) {
+        self.forward.forward(inputs);
+        self.backward.forward(inputs);
+
+        let units = self.forward.units;
+        let direction_len = self.forward.output_len();
+        let batch_size = inputs.batch_size();
+        self.outputs.resize(batch_size * direction_len * 2);
+
+        {
+            let forward = self.forward.outputs.cpu_borrow().unwrap();
+            let backward = self.backward.outputs.cpu_borrow().unwrap();
+            let mut outputs = self.outputs.cpu_borrow().unwrap();
+
+            // Each time step (or the last state) is the forward units followed by the backward units
+            for batch in 0..batch_size {
+                for block in 0..direction_len / units {
+                    let from = batch * direction_len + block * units;
+                    let to = batch * direction_len * 2 + block * units * 2;
+                    outputs[to..to + units].copy_from_slice(&forward[from..from + units]);
+                    outputs[to + units..to + units * 2].copy_from_slice(&backward[from..from + units]);
+                }
+            }
+        }
+
+        self.outputs.updated_cpu();
+    }
+
+    fn backward(&mut self, inputs: &mut Tensor, in_sensitivities: &mut DualVec, optimizer: &Optimizer) {
+        let units = self.forward.units;
+        let direction_len = self.forward.output_len();
+        let batch_size = in_sensitivities.len() / (direction_len * 2);
+
+        self.forward_sensitivities.resize(batch_size * direction_len);
+        self.backward_sensitivities.resize(batch_size * direction_len);
+
+        {
+            let in_sensitivities = in_sensitivities.cpu_borrow().unwrap();
+            let mut forward = self.forward_sensitivities.cpu_borrow().unwrap();
+            let mut backward = self.backward_sensitivities.cpu_borrow().unwrap();
+
+            for batch in 0..batch_size {
+                for block in 0..direction_len / units {
+                    let to = batch * direction_len + block * units;
+                    let from = batch * direction_len * 2 + block * units * 2;
+                    forward[to..to + units].copy_from_slice(&in_sensitivities[from..from + units]);
+                    backward[to..to + units].copy_from_slice(&in_sensitivities[from + units..from + units * 2]);
+                }
+            }
+        }
+        self.forward_sensitivities.updated_cpu();
+        self.backward_sensitivities.updated_cpu();
+
+        self.forward.backward(inputs, &mut self.forward_sensitivities, optimizer);
+        self.backward.backward(inputs, &mut self.backward_sensitivities, optimizer);
+
+        let target = self.forward.sensitivities.len();
+        self.sensitivities.resize(target);
+
+        {
+            let forward = self.forward.sensitivities.cpu_borrow().unwrap();
+            let backward = self.backward.sensitivities.cpu_borrow().unwrap();
+            let mut sensitivities = self.sensitivities.cpu_borrow().unwrap();
+            for i in 0..target {
+                sensitivities[i] = forward[i] + backward[i];
+            }
+        }
+
+        self.sensitivities.updated_cpu();
+    }
+
+    fn apply_gradients(&mut self, optimizer: &Optimizer, batch_size: usize) {
+        self.forward.apply_gradients(optimizer, batch_size);
+        self.backward.apply_gradients(optimizer, batch_size);
+    }
+
+    fn as_bytes(&mut self, bytes: &mut VecWriter) {
+        self.forward.write(bytes);
+        self.backward.write(bytes);
+    }
+
+    fn from_bytes(exec: &Execs, bytes: &mut CursorReader) -> Rc<RefCell<dyn Layer>> {
+        let inner = Execs::new(&exec.prev, &exec.current, &exec.current);
+        let forward = Recurrent::read(&inner, bytes);
+        let backward = Recurrent::read(&inner, bytes);
+
+        Rc::new(RefCell::new(Bidirectional::from_layers(exec, forward, backward)))
+    }
+
+    fn id(&self) -> usize {
+        4
+    }
+
+    fn exec(&self) -> &Executor {
+        &self.exec
+    }
+
+    fn values(&self) -> Vec<&DualVec> {
+        let mut values = self.forward.values();
+        values.extend(self.backward.values());
+        values
+    }
+
+    fn mods(&mut self) -> Result<Vec<&mut DualVec>, Error> {
+        let mut mods = self.forward.mods()?;
+        mods.extend(self.backward.mods()?);
+        Ok(mods)
+    }
+
+    fn input_size(&self) -> usize {
+        self.forward.input_size()
+    }
+
+    fn output_size(&self) -> usize {
+        self.forward.output_len() * 2
+    }
+
+    fn activated_output(&mut self) -> &mut DualVec {
+        &mut self.outputs
+    }
+
+    fn sensitivities(&mut self) -> &mut DualVec {
+        &mut self.sensitivities
+    }
+
+    fn reset_states(&mut self) {
+        Bidirectional::reset_states(self);
+    }
+    fn freeze(&mut self) -> Result<Box<dyn FrozenLayer>, Error> {
+        Ok(Box::new(FrozenBidirectional {
+            forward: self.forward.frozen(),
+            backward: self.backward.frozen(),
+        }))
+    }
+}
+
+/// The inference form of a [Recurrent] layer which isn't stateful
+#[derive(Debug)]
+pub struct FrozenRecurrent {
+    cell: Cell,
+    units: usize,
+    features: usize,
+    timesteps: usize,
+    return_sequences: bool,
+    reverse: bool,
+
+    weights: Vec<f32>,
+    recurrent_weights: Vec<f32>,
+    biases: Vec<f32>,
+}
+
+impl FrozenLayer for FrozenRecurrent {
+    fn input_size(&self) -> usize {
+        self.timesteps * self.features
+    }
+
+    fn output_size(&self) -> usize {
+        if self.return_sequences {
+            self.timesteps * self.units
+        } else {
+            self.units
+        }
+    }
+
+    fn forward(&self, inputs: &[f32], outputs: &mut [f32], scratch: &mut Scratch) {
+        let units = self.units;
+        let gate_len = self.cell.gates() * units;
+        let input_len = self.input_size();
+        let output_len = self.output_size();
+        let steps = self.timesteps;
+
+        let mut hidden = scratch.take(units);
+        let mut cells = scratch.take(units);
+        let mut input_products = scratch.take(gate_len);
+        let mut products = scratch.take(gate_len);
+
+        for batch in 0..inputs.len() / input_len {
+            hidden.fill(0.);
+            cells.fill(0.);
+
+            for step in 0..steps {
+                let t = if self.reverse { steps - 1 - step } else { step };
+                let x = &inputs[batch * input_len + t * self.features..batch * input_len + (t + 1) * self.features];
+
+                linear(x, &self.weights, &self.biases, self.features, &mut input_products);
+                for (r, product) in products.iter_mut().enumerate() {
+                    let weights = &self.recurrent_weights[r * units..(r + 1) * units];
+                    *product = weights.iter().zip(&hidden[..units]).map(|(w, h)| w * h).sum();
+                }
+
+                // Every unit only depends on its own previous state, so the states are updated in place
+                match self.cell {
+                    Cell::SimpleRNN => {
+                        for j in 0..units {
+                            hidden[j] = (input_products[j] + products[j]).tanh();
+                        }
+                    }
+                    Cell::LSTM => {
+                        for j in 0..units {
+                            let i = sigmoid(input_products[j] + products[j]);
+                            let f = sigmoid(input_products[units + j] + products[units + j]);
+                            let g = (input_products[2 * units + j] + products[2 * units + j]).tanh();
+                            let o = sigmoid(input_products[3 * units + j] + products[3 * units + j]);
+
+                            cells[j] = f * cells[j] + i * g;
+                            hidden[j] = o * cells[j].tanh();
+                        }
+                    }
+                    Cell::GRU => {
+                        for j in 0..units {
+                            let z = sigmoid(input_products[j] + products[j]);
+                            let r = sigmoid(input_products[units + j] + products[units + j]);
+                            let n = (input_products[2 * units + j] + r * products[2 * units + j]).tanh();
+
+                            hidden[j] = (1. - z) * n + z * hidden[j];
+                        }
+                    }
+                }
+
+                if self.return_sequences {
+                    let out = batch * output_len + t * units;
+                    outputs[out..out + units].copy_from_slice(&hidden);
+                }
+            }
+
+            if !self.return_sequences {
+                outputs[batch * output_len..(batch + 1) * output_len].copy_from_slice(&hidden);
+            }
+        }
+
+        for buffer in [hidden, cells, input_products, products] {
+            scratch.give(buffer);
+        }
+    }
+}
+
+/// The inference form of [Bidirectional]
+#[derive(Debug)]
+pub struct FrozenBidirectional {
+    forward: FrozenRecurrent,
+    backward: FrozenRecurrent,
+}
+
+impl FrozenLayer for FrozenBidirectional {
+    fn input_size(&self) -> usize {
+        self.forward.input_size()
+    }
+
+    fn output_size(&self) -> usize {
+        self.forward.output_size() * 2
+    }
+
+    fn forward(&self, inputs: &[f32], outputs: &mut [f32], scratch: &mut Scratch) {
+        let samples = inputs.len() / self.input_size();
+        let units = self.forward.units;
+        let direction_len = self.forward.output_size();
+
+        let mut forward = scratch.take(samples * direction_len);
+        let mut backward = scratch.take(samples * direction_len);
+        self.forward.forward(inputs, &mut forward, scratch);
+        self.backward.forward(inputs, &mut backward, scratch);
+
+        // Each time step (or the last state) is the forward units followed by the backward units
+        for batch in 0..samples {
+            for block in 0..direction_len / units {
+                let from = batch * direction_len + block * units;
+                let to = batch * direction_len * 2 + block * units * 2;
+                outputs[to..to + units].copy_from_slice(&forward[from..from + units]);
+                outputs[to + units..to + units * 2].copy_from_slice(&backward[from..from + units]);
+            }
+        }
+
+        scratch.give(forward);
+        scratch.give(backward);
+    }
+}
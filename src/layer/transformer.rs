@@ -0,0 +1,334 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::{Executor, Execs, Optimizer};
+use std::sync::Arc;
+use crate::activation::Activation;
+use crate::dual_vec::DualVec;
+use crate::tensor::Tensor;
+use crate::error::Error;
+use crate::frozen::{FrozenLayer, Scratch};
+use crate::layer::attention::{Attention, FrozenAttention, KeyValueCache};
+use crate::layer::dense::{Dense, FrozenDense};
+use crate::layer::dropout::Dropout;
+use crate::layer::{inputs_of, Layer};
+use crate::layer::norm::{FrozenLayerNorm, LayerNorm};
+use crate::utils::vec_utils::{CursorReader, VecWriter};
+
+/// `out = a + b`, resizing `out` to the length of `a`
+fn add_into(a: &mut DualVec, b: &mut DualVec, out: &mut DualVec) {
+    out.resize(a.len());
+    {
+        let a = a.cpu_borrow().unwrap();
+        let b = b.cpu_borrow().unwrap();
+        let mut out = out.cpu_borrow().unwrap();
+        for i in 0..out.len() {
+            out[i] = a[i] + b[i];
+        }
+    }
+    out.updated_cpu();
+}
+
+/// A post-norm transformer encoder block over samples of `[sequence, characteristics]`:
+///
+/// `x = norm(x + dropout(attention(x)))` followed by `x = norm(x + dropout(dense(dense(x))))`,
+/// where both dense layers are applied to every position separately.
+#[derive(Debug)]
+pub struct TransformerEncoder {
+    exec: Arc<Executor>,
+    heads: usize,
+    characteristics: usize,
+    feed_forward_size: usize,
+    dropout: f32,
+    input_len: usize,
+
+    attention: Attention,
+    attention_dropout: Dropout,
+    attention_norm: LayerNorm,
+    hidden: Dense,
+    projection: Dense,
+    feed_forward_dropout: Dropout,
+    feed_forward_norm: LayerNorm,
+
+    inputs: DualVec,
+    attention_residual: DualVec,
+    feed_forward_residual: DualVec,
+    residual_sensitivities: DualVec,
+
+    sensitivities: DualVec,
+}
+
+impl TransformerEncoder {
+    pub fn new(exec: &Execs, inputs: usize, heads: usize, characteristics: usize, feed_forward_size: usize, dropout: f32, causal: bool) -> Self {
+        let first = Execs::new(&exec.prev, &exec.current, &exec.current);
+        let inner = Execs::all(&exec.current);
+        let last = Execs::new(&exec.current, &exec.current, &exec.next);
+
+        Self::from_layers(
+            exec, dropout,
+            Attention::new(&first, inputs, heads, characteristics / heads, characteristics, characteristics, causal),
+            LayerNorm::new(&inner, inputs, characteristics),
+            Dense::new(&inner, characteristics, feed_forward_size, Activation::ReLU),
+            Dense::new(&inner, feed_forward_size, characteristics, Activation::Linear),
+            LayerNorm::new(&last, inputs, characteristics),
+        )
+    }
+
+    /// An encoder of the given layers, taking its sizes from them
+    fn from_layers(
+        exec: &Execs, dropout: f32,
+        attention: Attention, attention_norm: LayerNorm, hidden: Dense, projection: Dense, feed_forward_norm: LayerNorm,
+    ) -> Self {
+        let c = &*exec.current; // current
+        let p_to_c = exec.p_to_c(); // previous to current
+        let inputs = attention.input_size();
+
+        TransformerEncoder {
+            exec: exec.current.clone(),
+            heads: attention.heads(),
+            characteristics: hidden.input_size(),
+            feed_forward_size: hidden.output_size(),
+            dropout,
+            input_len: inputs,
+
+            attention,
+            attention_dropout: Dropout::new(&Execs::all(&exec.current), inputs, dropout),
+            attention_norm,
+            hidden,
+            projection,
+            feed_forward_dropout: Dropout::new(&Execs::all(&exec.current), inputs, dropout),
+            feed_forward_norm,
+
+            inputs: DualVec::from_execs(p_to_c, inputs),
+            attention_residual: DualVec::from_exec(c, inputs),
+            feed_forward_residual: DualVec::from_exec(c, inputs),
+            residual_sensitivities: DualVec::from_exec(c, inputs),
+
+            sensitivities: DualVec::from_execs(p_to_c, inputs),
+        }
+    }
+
+    /// The forward pass, where `lengths` is the amount of positions of each sample which aren't padding
+    fn encode(&mut self, inputs: &mut Tensor, lengths: Option<&[usize]>) {
+        // The inputs are needed again for the residual connection, so the batch is gathered first
+        let positions = inputs.row_offsets();
+        self.inputs.resize(positions.len() * self.input_len);
+        {
+            let inputs = inputs.data_mut().cpu_borrow().unwrap();
+            let mut gathered = self.inputs.cpu_borrow().unwrap();
+            for batch in 0..positions.len() {
+                gathered[batch * self.input_len..(batch + 1) * self.input_len]
+                    .copy_from_slice(&inputs[positions[batch]..positions[batch] + self.input_len]);
+            }
+        }
+        self.inputs.updated_cpu();
+
+        let mut gathered = inputs_of(&self.attention, &self.inputs);
+        match lengths {
+            Some(lengths) => self.attention.padded_forward(&mut gathered, lengths),
+            None => self.attention.forward(&mut gathered),
+        }
+        self.attention_dropout.forward(&mut inputs_of(&self.attention_dropout, self.attention.activated_output()));
+        add_into(&mut self.inputs, self.attention_dropout.activated_output(), &mut self.attention_residual);
+        self.attention_norm.forward(&mut inputs_of(&self.attention_norm, &self.attention_residual));
+
+        self.hidden.forward(&mut inputs_of(&self.hidden, self.attention_norm.activated_output()));
+        self.projection.forward(&mut inputs_of(&self.projection, self.hidden.activated_output()));
+        self.feed_forward_dropout.forward(&mut inputs_of(&self.feed_forward_dropout, self.projection.activated_output()));
+        add_into(self.attention_norm.activated_output(), self.feed_forward_dropout.activated_output(), &mut self.feed_forward_residual);
+        self.feed_forward_norm.forward(&mut inputs_of(&self.feed_forward_norm, &self.feed_forward_residual));
+    }
+
+    /// A copy of the current values in the inference form, which leaves out the dropout
+    pub(crate) fn frozen(&mut self) -> FrozenTransformerEncoder {
+        FrozenTransformerEncoder {
+            input_len: self.input_len,
+            characteristics: self.characteristics,
+            feed_forward_size: self.feed_forward_size,
+            attention: self.attention.frozen(),
+            attention_norm: self.attention_norm.frozen(),
+            hidden: self.hidden.frozen(),
+            projection: self.projection.frozen(),
+            feed_forward_norm: self.feed_forward_norm.frozen(),
+        }
+    }
+}
+
+impl Layer for TransformerEncoder {
+    fn prepare(&mut self, inputs: &mut Tensor) -> Result<(), Error> {
+        self.hidden.build_program()?;
+        self.projection.build_program()
+    }
+
+    fn forward(&mut self, inputs: &mut Tensor) {
+        self.encode(inputs, None);
+    }
+
+    fn padded_forward(&mut self, inputs: &mut Tensor, lengths: &[usize]) {
+        self.encode(inputs, Some(lengths));
+    }
+
+    fn backward(&mut self, _inputs: &mut Tensor, in_sensitivities: &mut DualVec, optimizer: &Optimizer) {
+        self.feed_forward_norm.backward(&mut inputs_of(&self.feed_forward_norm, &self.feed_forward_residual), in_sensitivities, optimizer);
+        self.feed_forward_dropout.backward(&mut inputs_of(&self.feed_forward_dropout, self.projection.activated_output()), self.feed_forward_norm.sensitivities(), optimizer);
+        self.projection.backward(&mut inputs_of(&self.projection, self.hidden.activated_output()), self.feed_forward_dropout.sensitivities(), optimizer);
+        self.hidden.backward(&mut inputs_of(&self.hidden, self.attention_norm.activated_output()), self.projection.sensitivities(), optimizer);
+        // The residual connection passes the gradients of the block's output straight through
+        add_into(self.feed_forward_norm.sensitivities(), self.hidden.sensitivities(), &mut self.residual_sensitivities);
+
+        self.attention_norm.backward(&mut inputs_of(&self.attention_norm, &self.attention_residual), &mut self.residual_sensitivities, optimizer);
+        self.attention_dropout.backward(&mut inputs_of(&self.attention_dropout, self.attention.activated_output()), self.attention_norm.sensitivities(), optimizer);
+        self.attention.backward(&mut inputs_of(&self.attention, &self.inputs), self.attention_dropout.sensitivities(), optimizer);
+        add_into(self.attention_norm.sensitivities(), self.attention.sensitivities(), &mut self.sensitivities);
+    }
+
+    fn apply_gradients(&mut self, optimizer: &Optimizer, batch_size: usize) {
+        self.attention.apply_gradients(optimizer, batch_size);
+        self.attention_norm.apply_gradients(optimizer, batch_size);
+        self.hidden.apply_gradients(optimizer, batch_size);
+        self.projection.apply_gradients(optimizer, batch_size);
+        self.feed_forward_norm.apply_gradients(optimizer, batch_size);
+    }
+
+    fn as_bytes(&mut self, bytes: &mut VecWriter) {
+        bytes.usize(self.input_len);
+        bytes.usize(self.heads);
+        bytes.usize(self.characteristics);
+        bytes.usize(self.feed_forward_size);
+        bytes.f32(self.dropout);
+
+        self.attention.as_bytes(bytes);
+        self.attention_norm.as_bytes(bytes);
+        self.hidden.as_bytes(bytes);
+        self.projection.as_bytes(bytes);
+        self.feed_forward_norm.as_bytes(bytes);
+    }
+
+    fn from_bytes(exec: &Execs, bytes: &mut CursorReader) -> Rc<RefCell<dyn Layer>> {
+        let inner = Execs::all(&exec.current);
+        // the input, head, characteristic and feed forward sizes are those of the layers read after them
+        let (_, _, _, _, dropout) = (bytes.usize(), bytes.usize(), bytes.usize(), bytes.usize(), bytes.f32());
+
+        let attention = Attention::read(&Execs::new(&exec.prev, &exec.current, &exec.current), bytes);
+        let attention_norm = LayerNorm::read(&inner, bytes);
+        let hidden = Dense::read(&inner, bytes);
+        let projection = Dense::read(&inner, bytes);
+        let feed_forward_norm = LayerNorm::read(&Execs::new(&exec.current, &exec.current, &exec.next), bytes);
+
+        Rc::new(RefCell::new(TransformerEncoder::from_layers(
+            exec, dropout,
+            attention, attention_norm, hidden, projection, feed_forward_norm,
+        )))
+    }
+
+    fn id(&self) -> usize {
+        8
+    }
+
+    fn exec(&self) -> &Executor {
+        &self.exec
+    }
+
+    fn values(&self) -> Vec<&DualVec> {
+        let mut values = self.attention.values();
+        values.extend(self.attention_norm.values());
+        values.extend(self.hidden.values());
+        values.extend(self.projection.values());
+        values.extend(self.feed_forward_norm.values());
+        values
+    }
+
+    fn mods(&mut self) -> Result<Vec<&mut DualVec>, Error> {
+        let mut mods = self.attention.mods()?;
+        mods.extend(self.attention_norm.mods()?);
+        mods.extend(self.hidden.mods()?);
+        mods.extend(self.projection.mods()?);
+        mods.extend(self.feed_forward_norm.mods()?);
+        Ok(mods)
+    }
+
+    fn input_size(&self) -> usize {
+        self.input_len
+    }
+
+    fn output_size(&self) -> usize {
+        self.input_len
+    }
+
+    fn activated_output(&mut self) -> &mut DualVec {
+        self.feed_forward_norm.activated_output()
+    }
+
+    fn sensitivities(&mut self) -> &mut DualVec {
+        &mut self.sensitivities
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.attention_dropout.set_training(training);
+        self.feed_forward_dropout.set_training(training);
+    }
+
+    fn step(&mut self, position: usize, cache: &mut KeyValueCache, inputs: &[f32]) -> Result<Vec<f32>, Error> {
+        // Dropout is inactive at inference, and only the attention keeps values of earlier positions
+        let attended = self.attention.step(position, cache, inputs)?;
+        let residual: Vec<f32> = inputs.iter().zip(&attended).map(|(x, a)| x + a).collect();
+        let normalized = self.attention_norm.step(position, cache, &residual)?;
+
+        let hidden = self.hidden.step(position, cache, &normalized)?;
+        let projected = self.projection.step(position, cache, &hidden)?;
+        let residual: Vec<f32> = normalized.iter().zip(&projected).map(|(x, p)| x + p).collect();
+        self.feed_forward_norm.step(position, cache, &residual)
+    }
+
+    fn freeze(&mut self) -> Result<Box<dyn FrozenLayer>, Error> {
+        Ok(Box::new(self.frozen()))
+    }
+}
+
+/// The inference form of [TransformerEncoder]
+#[derive(Debug)]
+pub struct FrozenTransformerEncoder {
+    input_len: usize,
+    characteristics: usize,
+    feed_forward_size: usize,
+
+    attention: FrozenAttention,
+    attention_norm: FrozenLayerNorm,
+    hidden: FrozenDense,
+    projection: FrozenDense,
+    feed_forward_norm: FrozenLayerNorm,
+}
+
+impl FrozenLayer for FrozenTransformerEncoder {
+    fn input_size(&self) -> usize {
+        self.input_len
+    }
+
+    fn output_size(&self) -> usize {
+        self.input_len
+    }
+
+    fn forward(&self, inputs: &[f32], outputs: &mut [f32], scratch: &mut Scratch) {
+        let rows = inputs.len() / self.characteristics;
+
+        let mut residual = scratch.take(inputs.len());
+        self.attention.forward(inputs, &mut residual, scratch);
+        for i in 0..residual.len() {
+            residual[i] += inputs[i];
+        }
+        let mut normalized = scratch.take(inputs.len());
+        self.attention_norm.forward(&residual, &mut normalized, scratch);
+
+        let mut hidden = scratch.take(rows * self.feed_forward_size);
+        self.hidden.forward(&normalized, &mut hidden, scratch);
+        self.projection.forward(&hidden, &mut residual, scratch);
+        for i in 0..residual.len() {
+            residual[i] += normalized[i];
+        }
+        self.feed_forward_norm.forward(&residual, outputs, scratch);
+
+        for buffer in [residual, normalized, hidden] {
+            scratch.give(buffer);
+        }
+    }
+}
@@ -1,29 +1,112 @@
 #![allow(unused)]
 
-use ocl::ProQue;
+use std::sync::Arc;
+use std::thread;
 
-use crate::Executor::GPU;
+use crate::gpu::{Gpu, GpuBuilder};
+use crate::pool::{HOST_POOL, MemoryPool};
+use crate::profiler::{Profiler, Report};
+use crate::Executor::{CPU, GPU, Threaded};
 
 pub mod dual_vec;
 pub mod layer;
 pub mod utils;
 pub mod network;
+pub mod builder;
+pub mod shape;
+pub mod tensor;
+pub mod ops;
+pub mod autograd;
+pub mod precision;
+pub mod graph;
+pub mod data_parallel;
+pub mod format;
+pub mod frozen;
+pub mod gpu;
+pub mod profiler;
+pub mod pool;
 pub mod loss;
+pub mod metric;
 pub mod activation;
 pub mod error;
 
 
+// executors are created once and shared, so the size of the GPU doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 pub enum Executor {
-    GPU(ProQue),
+    GPU(Gpu),
     CPU,
+    /// The CPU, splitting the work of a layer between the given amount of threads
+    Threaded(usize),
 }
 
 impl Executor {
+    /// A threaded CPU executor with a thread for every core that is available to the process
+    pub fn threaded() -> Self {
+        Threaded(thread::available_parallelism().map(|n| n.get()).unwrap_or(1))
+    }
+
+    /// The amount of threads the work of a layer can be split between
+    pub fn threads(&self) -> usize {
+        match self {
+            Threaded(threads) => (*threads).max(1),
+            _ => 1,
+        }
+    }
+
+
+    /// Whether the values used by the executor are kept on a device rather than on the host
+    pub fn is_gpu(&self) -> bool {
+        matches!(self, GPU(_))
+    }
+
+    /// The pool the memory of the executor is allocated from, which is shared by all CPU executors
+    pub fn pool(&self) -> &Arc<MemoryPool> {
+        match self {
+            GPU(gpu) => gpu.pool(),
+            CPU | Threaded(_) => &HOST_POOL,
+        }
+    }
+
+    /// Starts recording the time spent in every layer run on the executor, and for GPUs the
+    /// host and device copies of every [DualVec] and the kernels launched when built with
+    /// [GpuBuilder::profiling]. Training then produces a [Report] at the end of every epoch,
+    /// which is logged and kept until [Executor::take_reports].
+    ///
+    /// The CPU executors share their profiling on every thread, so a run on another thread isn't
+    /// recorded by this one.
+    ///
+    /// [DualVec]: crate::dual_vec::DualVec
+    pub fn enable_profiling(&self) {
+        profiler::with(self, Profiler::enable);
+    }
+
+    pub fn disable_profiling(&self) {
+        profiler::with(self, Profiler::disable);
+    }
+
+    pub fn profiling(&self) -> bool {
+        profiler::with(self, Profiler::enabled)
+    }
+
+    /// The reports of the epochs trained on the executor since the last call
+    pub fn take_reports(&self) -> Vec<Report> {
+        profiler::with(self, Profiler::take_reports)
+    }
+
     pub fn gpu() -> Self {
-        let src = include_str!("kernels.c");
-        match ProQue::builder().src(src).build() {
-            Ok(pro_que) => GPU(pro_que),
+        Self::gpu_with(Gpu::builder())
+    }
+
+    /// A GPU executor which sums in a fixed order, so training is reproducible run to run
+    pub fn gpu_deterministic() -> Self {
+        Self::gpu_with(Gpu::builder().deterministic(true))
+    }
+
+    fn gpu_with(builder: GpuBuilder) -> Self {
+        match builder.build() {
+            Ok(gpu) => GPU(gpu),
             Err(err) => {
                 eprint!("{}", err);
                 panic!();
@@ -32,6 +115,51 @@ impl Executor {
     }
 }
 
+/// The executors of the previous, current and next layers, which decide where the
+/// buffers passed between the layers are kept
+#[derive(Clone, Debug)]
+pub struct Execs {
+    pub prev: Arc<Executor>,
+    pub current: Arc<Executor>,
+    pub next: Arc<Executor>,
+}
+
+impl Execs {
+    pub fn new(prev: &Arc<Executor>, current: &Arc<Executor>, next: &Arc<Executor>) -> Self {
+        Execs {
+            prev: prev.clone(),
+            current: current.clone(),
+            next: next.clone(),
+        }
+    }
+
+    /// The same executor for all three
+    pub fn all(exec: &Arc<Executor>) -> Self {
+        Self::new(exec, exec, exec)
+    }
+
+    /// The executors around the layer `i` of a sequence of layers, where the inputs
+    /// of the first layer and the outputs of the last one are kept on the CPU
+    pub fn of(execs: &[Arc<Executor>], i: usize) -> Self {
+        let cpu = Arc::new(CPU);
+        Execs {
+            prev: if i > 0 { execs[i - 1].clone() } else { cpu.clone() },
+            current: execs[i].clone(),
+            next: if i + 1 < execs.len() { execs[i + 1].clone() } else { cpu },
+        }
+    }
+
+    /// previous to current
+    pub fn p_to_c(&self) -> (&Executor, &Executor) {
+        (&self.prev, &self.current)
+    }
+
+    /// current to next
+    pub fn c_to_n(&self) -> (&Executor, &Executor) {
+        (&self.current, &self.next)
+    }
+}
+
 pub enum Optimizer {
     GradientDecent(f32),
 }
@@ -1,76 +1,134 @@
-use std::time::Instant;
+use std::rc::Rc;
+
+use crate::autograd::{Tape, Var};
 use crate::dual_vec::DualVec;
 use crate::error::Error;
 use crate::error::Error::UnavailableBuffer;
 use crate::Executor;
 use crate::Executor::{CPU, GPU};
+use crate::tensor::Tensor;
+
+/// The losses of the outputs (the first variable) against the targets (the second) recorded on a tape
+pub type LossFn = dyn Fn(&mut Tape<'_>, Var, Var) -> Result<Var, Error>;
 
 pub enum Loss {
     Categorical,
     MeanSquared,
+    /// The gradients are those of the sum of the recorded losses. Custom losses are computed on the
+    /// host, like the others.
+    Custom(Rc<LossFn>),
 }
 
 impl Loss {
-    pub fn calculate(&self, exec: &Executor, actual: &mut DualVec, output_size: usize, target: &mut DualVec, target_indices: &Vec<usize>, batch_size: usize) -> Result<DualVec, Error> {
+    /// The loss of every sample of `actual` against the sample of `target` at the same position
+    pub fn calculate(&self, exec: &Executor, actual: &mut Tensor, target: &mut Tensor) -> Result<DualVec, Error> {
+        let batch_size = actual.batch_size();
+        self.part_calculate(exec, actual, target, batch_size)
+    }
+
+    /// [Loss::calculate] of samples which are part of a batch of `batch_size` samples, where losses
+    /// which are means over the batch are taken over the whole batch, so that the losses of the
+    /// parts add up to those of the batch
+    pub(crate) fn part_calculate(&self, exec: &Executor, actual: &mut Tensor, target: &mut Tensor, batch_size: usize) -> Result<DualVec, Error> {
         match self {
             Loss::Categorical => match exec {
                 GPU(_) => todo!(),
-                Executor::CPU => {
+                Executor::CPU | Executor::Threaded(_) => {
                     todo!()
                 }
             },
-            Loss::MeanSquared => match exec {
-                GPU(_) => todo!(),
-                Executor::CPU => {
-                    if let (Some(actual), Some(target)) = (actual.cpu_borrow(), target.cpu_borrow()) {
-                        let mut losses = Vec::with_capacity(actual.len());
-                        for batch in 0..batch_size {
-                            let mut error = 0.;
-                            for i in 0..output_size {
-                                error += (actual[i + batch * output_size] - target[i + target_indices[batch]]).powf(2.0);
+            Loss::MeanSquared => {
+                let (output_size, samples) = (actual.sample_size(), actual.batch_size());
+                let target_indices = target.row_offsets();
+                let (actual, target) = (actual.data_mut(), target.data_mut());
+
+                match exec {
+                    GPU(_) => todo!(),
+                    Executor::CPU | Executor::Threaded(_) => {
+                        if let (Some(actual), Some(target)) = (actual.cpu_borrow(), target.cpu_borrow()) {
+                            let mut losses = Vec::with_capacity(actual.len());
+                            for batch in 0..samples {
+                                let mut error = 0.;
+                                for i in 0..output_size {
+                                    error += (actual[i + batch * output_size] - target[i + target_indices[batch]]).powf(2.0);
+                                }
+                                losses.push(error / (batch_size * output_size) as f32);
                             }
-                            losses.push(error / actual.len() as f32);
+                            Ok(DualVec::from_vec((&CPU, exec), losses))
+                        } else {
+                            Err(UnavailableBuffer("CPU buffer was not available when calculating error".to_string()))
                         }
-                        Ok(DualVec::from_vec((&CPU, exec), losses))
-                    } else {
-                        Err(UnavailableBuffer("CPU buffer was not available when calculating error".to_string()))
                     }
                 }
             }
+            Loss::Custom(loss) => {
+                let mut tape = Tape::new(&CPU);
+                let (actual, target) = (tape.var(actual.clone()), tape.var(target.clone()));
+                let losses = loss(&mut tape, actual, target)?;
+                Ok(DualVec::from_vec((&CPU, exec), tape.value(losses).clone().to_vec()))
+            }
         }
     }
 
-    pub fn dynamic_derivative(&self, exec: &Executor, actual: &mut DualVec, target: &mut DualVec, target_indices: &Vec<usize>, out: &mut DualVec) {
+    pub fn dynamic_derivative(&self, exec: &Executor, actual: &mut Tensor, target: &mut Tensor, out: &mut DualVec) -> Result<(), Error> {
+        let batch_size = actual.batch_size();
+        self.part_derivative(exec, actual, target, batch_size, out)
+    }
 
+    /// [Loss::dynamic_derivative] of samples which are part of a batch of `batch_size` samples,
+    /// where losses which are means over the batch are taken over the whole batch
+    pub(crate) fn part_derivative(&self, exec: &Executor, actual: &mut Tensor, target: &mut Tensor, batch_size: usize, out: &mut DualVec) -> Result<(), Error> {
         match self {
             Loss::Categorical => match exec {
                 GPU(_) => todo!(),
-                Executor::CPU => todo!(),
+                Executor::CPU | Executor::Threaded(_) => todo!(),
             }
-            Loss::MeanSquared => match exec {
-                GPU(pq) => {
-                    // let st = Instant::now();
-                    if let (Some(mut gradients), Some(actual), Some(target)) =
-                        (out.cpu_borrow(), actual.cpu_borrow(), target.cpu_borrow()) {
-                        for i in 0..actual.len() {
-                            gradients[i] = (2. * (actual[i] - target[target_indices[i]+i]) / actual.len() as f32);
+            Loss::MeanSquared => {
+                let target_indices = target.row_offsets();
+                let (actual, target) = (actual.data_mut(), target.data_mut());
+
+                match exec {
+                    GPU(pq) => {
+                        if let (Some(mut gradients), Some(actual), Some(target)) =
+                            (out.cpu_borrow(), actual.cpu_borrow(), target.cpu_borrow()) {
+                            let output_size = actual.len() / target_indices.len();
+                            for i in 0..actual.len() {
+                                gradients[i] = (2. * (actual[i] - target[target_indices[i / output_size] + i % output_size]) / (batch_size * output_size) as f32);
+                            }
+                        }
+                        out.updated_cpu();
+                    },
+                    Executor::CPU | Executor::Threaded(_) => {
+                        if let (Some(mut gradients), Some(actual), Some(target)) =
+                            (out.cpu_borrow(), actual.cpu_borrow(), target.cpu_borrow()) {
+                            let output_size = actual.len() / target_indices.len();
+                            for i in 0..actual.len() {
+                                gradients[i] = (2. * (actual[i] - target[target_indices[i / output_size] + i % output_size]) / (batch_size * output_size) as f32);
+                            }
                         }
+                        out.updated_cpu();
                     }
-                    out.updated_cpu();
-                    // out.gpu();
-                    // pq.finish();
-                    // let d = st.elapsed();
-                    // println!("{:?}", d);
-                },
-                Executor::CPU => {
-                    if let (Some(mut gradients), Some(actual), Some(target)) =
-                        (out.cpu_borrow(), actual.cpu_borrow(), target.cpu_borrow()) {
-                        for i in 0..actual.len() {
-                            gradients[i] = (2. * (actual[i] - target[target_indices[i]+i]) / actual.len() as f32);
+                }
+            }
+            Loss::Custom(loss) => {
+                let mut tape = Tape::new(&CPU);
+                let (actual, target) = (tape.var(actual.clone()), tape.var(target.clone()));
+                let losses = loss(&mut tape, actual, target)?;
+                let gradients = tape.backward(losses, None)?.take(actual);
+
+                if let Some(mut out) = out.cpu_borrow() {
+                    match gradients {
+                        Some(mut gradients) => {
+                            let gradients = gradients.to_vec();
+                            out[..gradients.len()].copy_from_slice(&gradients);
                         }
+                        // the losses don't depend on the outputs
+                        None => out.fill(0.),
                     }
                 }
+                out.updated_cpu();
             }
         }
+        Ok(())
     }
-}
\ No newline at end of file
+}
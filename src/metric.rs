@@ -0,0 +1,108 @@
+use crate::tensor::Tensor;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Metric {
+    MeanAbsoluteError,
+    /// The fraction of samples where the largest output matches the largest target,
+    /// or where both are on the same side of 0.5 for single outputs
+    Accuracy,
+}
+
+impl Metric {
+    /// The metric over a batch of outputs against the targets of the same samples
+    pub fn calculate(&self, actual: &mut Tensor, target: &mut Tensor) -> f32 {
+        let (output_size, target_indices) = (actual.sample_size(), target.row_offsets());
+        let (Some(actual), Some(target)) = (actual.data_mut().cpu_borrow(), target.data_mut().cpu_borrow()) else {
+            return f32::NAN;
+        };
+        let batch_size = target_indices.len();
+
+        match self {
+            Metric::MeanAbsoluteError => {
+                let mut error = 0.;
+                for batch in 0..batch_size {
+                    for i in 0..output_size {
+                        error += (actual[i + batch * output_size] - target[i + target_indices[batch]]).abs();
+                    }
+                }
+                error / (batch_size * output_size) as f32
+            }
+            Metric::Accuracy => {
+                let mut correct = 0;
+                for batch in 0..batch_size {
+                    let actual = &actual[batch * output_size..(batch + 1) * output_size];
+                    let target = &target[target_indices[batch]..target_indices[batch] + output_size];
+                    let matches = if output_size == 1 {
+                        (actual[0] >= 0.5) == (target[0] >= 0.5)
+                    } else {
+                        argmax(actual) == argmax(target)
+                    };
+                    if matches {
+                        correct += 1;
+                    }
+                }
+                correct as f32 / batch_size as f32
+            }
+        }
+    }
+}
+
+fn argmax(values: &[f32]) -> usize {
+    let mut max = 0;
+    for i in 1..values.len() {
+        if values[i] > values[max] {
+            max = i;
+        }
+    }
+    max
+}
+
+/// The losses and metrics of an output head for every epoch
+#[derive(Clone, Debug)]
+pub struct HeadHistory {
+    pub output: String,
+    pub loss: Vec<f32>,
+    pub metrics: Vec<(Metric, Vec<f32>)>,
+}
+
+impl HeadHistory {
+    pub fn metric(&self, metric: &Metric) -> Option<&Vec<f32>> {
+        self.metrics.iter().find(|(m, _)| m == metric).map(|(_, values)| values)
+    }
+}
+
+/// The averages over the batches of every epoch of training
+#[derive(Clone, Debug)]
+pub struct History {
+    /// The weighted sum of the losses of every head
+    pub loss: Vec<f32>,
+    pub heads: Vec<HeadHistory>,
+}
+
+impl History {
+    pub(crate) fn new(heads: &[(String, Vec<Metric>)]) -> Self {
+        History {
+            loss: vec![],
+            heads: heads.iter().map(|(output, metrics)| HeadHistory {
+                output: output.clone(),
+                loss: vec![],
+                metrics: metrics.iter().map(|m| (m.clone(), vec![])).collect(),
+            }).collect(),
+        }
+    }
+
+    pub(crate) fn push(&mut self, loss: f32, head_losses: &[f32], head_metrics: &[Vec<f32>]) {
+        self.loss.push(loss);
+        for (h, head) in self.heads.iter_mut().enumerate() {
+            head.loss.push(head_losses[h]);
+            for (m, (_, values)) in head.metrics.iter_mut().enumerate() {
+                values.push(head_metrics[h][m]);
+            }
+        }
+    }
+
+    /// The history of the head of the output node called `output`
+    pub fn head(&self, output: &str) -> Option<&HeadHistory> {
+        self.heads.iter().find(|h| h.output == output)
+    }
+}
@@ -1,49 +1,124 @@
 use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::rc::Rc;
+use std::slice::ChunksExact;
+use std::sync::Arc;
 
-use rand::random;
+use rand::seq::SliceRandom;
+use rand::thread_rng;
 
-use crate::{Executor, Optimizer};
+use crate::{Executor, Execs, Optimizer};
 use crate::dual_vec::DualVec;
-use crate::error::{DecodeError, Error, MismatchError, NetworkError};
-use crate::Executor::{CPU, GPU};
+use crate::error::{Error, MismatchError, NetworkError};
+use crate::format::{Kind, LayerSection, ModelFile, VERSION};
+use crate::frozen::FrozenNetwork;
+use crate::Executor::{CPU, GPU, Threaded};
+use crate::layer;
 use crate::layer::{Layer, LayerType};
-use crate::layer::attention::Attention;
-use crate::layer::dense::Dense;
+use crate::layer::attention::KeyValueCache;
 use crate::loss::Loss;
+use crate::pool;
+use crate::profiler;
+use crate::tensor::{DType, Tensor};
 use crate::utils::cl_utils;
 use crate::utils::vec_utils::{CursorReader, VecWriter};
 
-pub struct Network<'a> {
-    layers: Vec<Rc<RefCell<dyn Layer<'a> + 'a>>>,
+/// The state of a single sequence processed one position at a time through [Network::step]
+#[derive(Clone, Debug)]
+pub struct IncrementalState {
+    position: usize,
+    caches: Vec<KeyValueCache>,
+}
+
+impl IncrementalState {
+    /// The amount of positions processed so far
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Clears the cached keys and values, starting a new sequence
+    pub fn reset(&mut self) {
+        self.position = 0;
+        for cache in &mut self.caches {
+            cache.clear();
+        }
+    }
+
+    /// A copy of the state that continues separately, such as for every beam of a beam search
+    pub fn fork(&self) -> Self {
+        self.clone()
+    }
+}
+
+/// The samples of the batches of every epoch of training, which are shuffled at the start of
+/// every epoch so that no sample is used twice within an epoch
+pub(crate) struct Batches {
+    order: Vec<usize>,
+    batch_size: usize,
+}
+
+impl Batches {
+    /// Batches of `batch_size` out of `samples` samples, or a single batch of every sample when
+    /// there are fewer samples than that
+    pub(crate) fn new(samples: usize, batch_size: usize) -> Self {
+        Batches {
+            order: (0..samples).collect(),
+            batch_size: batch_size.min(samples).max(1),
+        }
+    }
+
+    pub(crate) fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    /// The amount of batches of every epoch, where the samples beyond the last full batch are left out
+    pub(crate) fn count(&self) -> usize {
+        self.order.len() / self.batch_size
+    }
+
+    /// The samples of every batch of the next epoch
+    pub(crate) fn epoch(&mut self) -> ChunksExact<'_, usize> {
+        self.order.shuffle(&mut thread_rng());
+        self.order.chunks_exact(self.batch_size)
+    }
+}
+
+/// Inputs where only the first `lengths[sample]` positions of every sample aren't padding
+pub struct Padded<'p> {
+    inputs: &'p mut DualVec,
+    lengths: Option<&'p [usize]>,
+}
+
+impl<'p> Padded<'p> {
+    pub fn new(inputs: &'p mut DualVec, lengths: &'p [usize]) -> Self {
+        Padded {
+            inputs,
+            lengths: Some(lengths),
+        }
+    }
+}
+
+pub struct Network {
+    layers: Vec<Rc<RefCell<dyn Layer>>>,
+    /// The executor of every layer
+    execs: Vec<Arc<Executor>>,
     output_size: usize,
+    metadata: BTreeMap<String, String>,
 }
 
-impl<'a> Network<'a> {
+impl Network {
 
-    pub fn new(mut input_size: usize, layers_types: &'a Vec<(&'a Executor, LayerType)>) -> Result<Self, Error> {
-        if layers_types.len() == 0 {
+    pub fn new(mut input_size: usize, layers_types: &[(Arc<Executor>, LayerType)]) -> Result<Self, Error> {
+        if layers_types.is_empty() {
             return Err(Error::Network(NetworkError::ZeroLayers))
         }
+        let execs: Vec<Arc<Executor>> = layers_types.iter().map(|(exec, _)| exec.clone()).collect();
         let mut layers = vec![];
         let mut network_output_size = 0;
-        for i in 0..layers_types.len() {
-            let l_type = &layers_types[i].1;
-            let current_exec= layers_types[i].0;
-            let prev_exec =
-                if i > 0 {
-                    layers_types[i-1].0
-                } else {
-                    &CPU
-                };
-            let next_exec =
-                if i < layers_types.len() - 1 {
-                    layers_types[i+1].0
-                } else {
-                    &CPU
-                };
-
-            let (layer, output_size) = l_type.layer((prev_exec, current_exec, next_exec), input_size);
+        for (i, (_, layer_type)) in layers_types.iter().enumerate() {
+            layer_type.check(input_size)?;
+            let owner = format!("{} {}", i, layer::layer_name(layer_type.id()));
+            let (layer, output_size) = pool::owned_by(owner, || layer_type.layer(&Execs::of(&execs, i), input_size));
 
             network_output_size = output_size;
             input_size = output_size;
@@ -52,20 +127,105 @@ impl<'a> Network<'a> {
 
         Ok(Network {
             layers,
-            output_size: network_output_size
+            execs,
+            output_size: network_output_size,
+            metadata: BTreeMap::new(),
         })
     }
 
-    pub fn predict(&mut self, inputs: &mut DualVec) -> DualVec {
-        let mut batch_size = self.layers[0].borrow_mut().forward(inputs);
-        for i in 1..self.layers.len() {
-            let layer = self.layers[i].clone();
-            layer.borrow_mut().forward(self.layers[i-1].borrow_mut().activated_output());
+    pub fn predict(&mut self, inputs: &mut DualVec) -> Result<DualVec, Error> {
+        let input_size = self.layers[0].borrow().input_size();
+        self.forward_batch(&mut Tensor::batch(inputs.clone(), input_size), None)?;
+        Ok(self.layers.last().unwrap().borrow_mut().activated_output().clone())
+    }
+
+    /// Predicts samples where only the first `lengths[sample]` positions aren't padding
+    pub fn predict_padded(&mut self, inputs: &mut DualVec, lengths: &[usize]) -> Result<DualVec, Error> {
+        let input_size = self.layers[0].borrow().input_size();
+        self.forward_batch(&mut Tensor::batch(inputs.clone(), input_size), Some(lengths))?;
+        Ok(self.layers.last().unwrap().borrow_mut().activated_output().clone())
+    }
+
+    /// The forward pass of the layer `i` after preparing it, passing the padding of every
+    /// sample along when there is any
+    fn layer_forward(&mut self, i: usize, inputs: &mut Tensor, lengths: Option<&[usize]>) -> Result<(), Error> {
+        let layer = self.layers[i].clone();
+        layer.borrow_mut().prepare(inputs)?;
+        profiler::time_layer(&self.execs[i], || self.label(i, "forward"), || match lengths {
+            Some(lengths) => layer.borrow_mut().padded_forward(inputs, lengths),
+            None => layer.borrow_mut().forward(inputs),
+        });
+        Ok(())
+    }
+
+    /// The name of a pass of the layer `i` in profiling reports
+    fn label(&self, i: usize, pass: &str) -> String {
+        format!("{} {} {}", i, layer::layer_name(self.layers[i].borrow().id()), pass)
+    }
+
+    /// The outputs of the layer `i - 1` as the inputs of the layer `i`
+    fn inputs_of(&self, i: usize) -> Tensor {
+        let mut outputs = self.layers[i - 1].borrow_mut().output();
+        self.cross(i - 1, i, outputs.data_mut());
+        outputs
+    }
+
+    /// The sensitivities of the layer `i + 1` as the gradients of the outputs of the layer `i`
+    fn gradients_of(&self, i: usize) -> DualVec {
+        let mut sensitivities = self.layers[i + 1].borrow_mut().sensitivities().clone();
+        self.cross(i + 1, i, &mut sensitivities);
+        sensitivities
+    }
+
+    /// Copies `values` passed from the layer `from` to the layer `to` to the side of `to` when only
+    /// one of them is on a GPU, so the copy is made and recorded at the boundary between the layers
+    fn cross(&self, from: usize, to: usize, values: &mut DualVec) {
+        if self.execs[from].is_gpu() != self.execs[to].is_gpu() {
+            let (first, second) = (from.min(to), from.max(to));
+            let gpu = if self.execs[from].is_gpu() { &self.execs[from] } else { &self.execs[to] };
+            profiler::boundary(
+                gpu,
+                || format!("{} {} -> {} {}", first, layer::layer_name(self.layers[first].borrow().id()), second, layer::layer_name(self.layers[second].borrow().id())),
+                || values.sync_to(&self.execs[to]),
+            );
         }
-        self.layers.last().unwrap().borrow_mut().activated_output().clone()
     }
 
-    pub fn train(&mut self, mut inputs: &mut DualVec, targets: &mut DualVec, optimizer: Optimizer, loss: Loss, epochs: u32, mut batch_size: usize) -> Result<f32, Error> {
+    /// An empty state for a new sequence to [step](Network::step) through
+    pub fn incremental_state(&self) -> IncrementalState {
+        IncrementalState {
+            position: 0,
+            caches: vec![KeyValueCache::default(); self.layers.len()],
+        }
+    }
+
+    /// Predicts the next position of the sequence of `state` from the values of a single position.
+    ///
+    /// Attention layers only compute the new position, attending to the keys and values cached
+    /// for the previous positions, so every layer has to handle one position at a time.
+    pub fn step(&mut self, state: &mut IncrementalState, inputs: &mut DualVec) -> Result<DualVec, Error> {
+        let mut values = inputs.cpu_borrow().unwrap().clone();
+        for i in 0..self.layers.len() {
+            values = self.layers[i].borrow_mut().step(state.position, &mut state.caches[i], &values)?;
+        }
+        state.position += 1;
+
+        Ok(DualVec::from_vec((&CPU, &CPU), values))
+    }
+
+    /// Trains on `epochs` epochs of batches of `batch_size` samples. Returns the loss of the last
+    /// epoch, the mean over its batches of the summed losses of a batch.
+    pub fn train(&mut self, inputs: &mut DualVec, targets: &mut DualVec, optimizer: Optimizer, loss: Loss, epochs: u32, batch_size: usize) -> Result<f32, Error> {
+        self.fit(Padded { inputs, lengths: None }, targets, optimizer, loss, epochs, batch_size)
+    }
+
+    /// Trains on samples of which only some positions aren't padding
+    pub fn train_padded(&mut self, inputs: Padded, targets: &mut DualVec, optimizer: Optimizer, loss: Loss, epochs: u32, batch_size: usize) -> Result<f32, Error> {
+        self.fit(inputs, targets, optimizer, loss, epochs, batch_size)
+    }
+
+    fn fit(&mut self, inputs: Padded, targets: &mut DualVec, optimizer: Optimizer, loss: Loss, epochs: u32, batch_size: usize) -> Result<f32, Error> {
+        let Padded { inputs, lengths } = inputs;
         let input_size = self.layers.first().unwrap().borrow().input_size();
         let output_size = self.layers.last().unwrap().borrow().output_size();
 
@@ -73,153 +233,222 @@ impl<'a> Network<'a> {
         if (targets.len() / output_size != samples) {
             return Err(Error::Mismatch(MismatchError::Sample(samples, targets.len() / output_size)))
         }
-        if batch_size > samples {
-            batch_size = samples;
+        if let Some(lengths) = lengths
+            && lengths.len() != samples {
+            return Err(Error::Mismatch(MismatchError::Sample(samples, lengths.len())))
         }
-        let mut input_indices = Vec::with_capacity(batch_size);
-        let mut output_indices = Vec::with_capacity(batch_size);
+        let mut batches = Batches::new(samples, batch_size);
+        let batch_size = batches.batch_size();
+        let batch_count = batches.count();
+        let mut batch_lengths = vec![0; batch_size];
 
-        input_indices.resize(batch_size, 0);
-        output_indices.resize(batch_size, 0);
+        let input_samples = Tensor::batch(inputs.clone(), input_size);
+        let target_samples = Tensor::batch(targets.clone(), output_size);
 
         let mut last_loss = f32::INFINITY;
         let mut output_sensitivities = DualVec::from_exec(self.layers.last().unwrap().borrow().exec(), self.output_size * batch_size);
+        self.set_training(true);
         for epoch in 0..epochs {
-            let mut avg = 0.;
-            for i in 0..samples / batch_size {
-                for batch in 0..batch_size {
-                    // TODO This should probably not be just random, and shouldn't pick the same sample more than once within the same batch
-                    let sample = (random::<f64>() * (samples-batch_size) as f64) as usize;
-
-                    input_indices[batch] = (sample * input_size);
-                    output_indices[batch] = (sample * output_size);
-                }
-
-                // Forward pass through all layers
-                self.layers[0].borrow_mut()
-                    .dynamic_forward(&input_indices, inputs);
-                for i in 1..self.layers.len() {
-                    let layer = self.layers[i].clone();
-                    let mut layer = layer.borrow_mut();
-                    layer.forward(self.layers[i - 1].borrow_mut().activated_output());
-                }
-                let mut batch_output = self.layers.last().unwrap().borrow_mut().activated_output().clone();
-
-                match loss.calculate(&CPU, &mut batch_output, output_size, targets, &output_indices, batch_size) {
-                    Ok(mut res) => {
-                        for i in 0..res.len() {
-                            avg += res.cpu_borrow().unwrap()[i];
-                        }
-                        last_loss = avg;
-
-                        // Backward pass through all layers
-                        // TODO implement this properly in some way where the behavior of the last and first layers is not hard coded
-                        loss.dynamic_derivative(self.layers.last().unwrap().borrow().exec(), &mut batch_output, targets, &output_indices, &mut output_sensitivities);
-                        {
-                            let prev = self.layers[self.layers.len()-2].clone();
-                            self.layers.last().unwrap().borrow_mut().backward(prev.borrow_mut().activated_output(), None, &mut output_sensitivities, &optimizer);
-                        }
-                        for i in (1..self.layers.len()-1).rev() {
-                            let layer = self.layers[i].clone();
-                            let layer_p = self.layers[i-1].clone();
-                            layer.borrow_mut().backward(layer_p.borrow_mut().activated_output(), None, self.layers[i+1].borrow_mut().sensitivities(), &optimizer);
-                        }
-                        {
-                            let layer = self.layers[0].clone();
-                            layer.borrow_mut().backward(&mut inputs, Some(&input_indices), self.layers[1].borrow_mut().sensitivities(), &optimizer);
-                        }
-
-                        // Apply calculated gradients
-                        for i in (0..self.layers.len()).rev() {
-                            let layer = self.layers[i].clone();
-                            layer.borrow_mut().apply_gradients(&optimizer, batch_size);
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("{e}");
+            let mut total = 0.;
+            for batch_samples in batches.epoch() {
+                if let Some(lengths) = lengths {
+                    for (length, sample) in batch_lengths.iter_mut().zip(batch_samples) {
+                        *length = lengths[*sample];
                     }
                 }
+                let mut batch_inputs = input_samples.gather(batch_samples)?;
+                let mut batch_targets = target_samples.gather(batch_samples)?;
+
+                let mut batch_output = self.forward_batch(&mut batch_inputs, lengths.map(|_| &batch_lengths[..]))?;
+                let mut losses = loss.calculate(&CPU, &mut batch_output, &mut batch_targets)?;
+                total += losses.cpu_borrow().unwrap().iter().sum::<f32>();
+
+                loss.dynamic_derivative(self.layers.last().unwrap().borrow().exec(), &mut batch_output, &mut batch_targets, &mut output_sensitivities)?;
+                self.backward_batch(&mut batch_inputs, &mut output_sensitivities, &optimizer);
+                self.apply_gradients(&optimizer, batch_size);
             }
-            avg /= (batch_size * output_size) as f32;
-            if epoch % 10 == 0 {
-                println!("{epoch},{avg}");
-                avg = 0.;
-            }
+            profiler::finish_epoch(&self.execs, epoch);
+            last_loss = total / batch_count as f32;
         }
+        self.set_training(false);
 
-        for l in &self.layers {
-            println!("{:?}", l.borrow_mut().activated_output().cpu_borrow());
+        Ok(last_loss)
+    }
+
+    /// The forward pass of a batch, returning the outputs of the last layer
+    pub(crate) fn forward_batch(&mut self, batch_inputs: &mut Tensor, lengths: Option<&[usize]>) -> Result<Tensor, Error> {
+        self.layer_forward(0, batch_inputs, lengths)?;
+        for i in 1..self.layers.len() {
+            self.layer_forward(i, &mut self.inputs_of(i), lengths)?;
         }
+        Ok(self.layers.last().unwrap().borrow_mut().output())
+    }
 
-        Ok(last_loss)
+    /// The backward pass of a batch from the gradients of the outputs of the last layer, accumulating
+    /// the modifications of every layer without applying them
+    pub(crate) fn backward_batch(&mut self, batch_inputs: &mut Tensor, output_sensitivities: &mut DualVec, optimizer: &Optimizer) {
+        // TODO implement this properly in some way where the behavior of the last and first layers is not hard coded
+        let last = self.layers.len() - 1;
+        if last == 0 {
+            // the only layer is both the first and the last
+            profiler::time_layer(&self.execs[0], || self.label(0, "backward"), || {
+                self.layers[0].borrow_mut().backward(batch_inputs, output_sensitivities, optimizer);
+            });
+            return;
+        }
+        profiler::time_layer(&self.execs[last], || self.label(last, "backward"), || {
+            self.layers[last].borrow_mut().backward(&mut self.inputs_of(last), output_sensitivities, optimizer);
+        });
+        for i in (1..self.layers.len()-1).rev() {
+            let layer = self.layers[i].clone();
+            profiler::time_layer(&self.execs[i], || self.label(i, "backward"), || {
+                layer.borrow_mut().backward(&mut self.inputs_of(i), &mut self.gradients_of(i), optimizer);
+            });
+        }
+        profiler::time_layer(&self.execs[0], || self.label(0, "backward"), || {
+            let layer = self.layers[0].clone();
+            layer.borrow_mut().backward(batch_inputs, &mut self.gradients_of(0), optimizer);
+        });
     }
 
-    pub fn as_bytes(&mut self) -> Vec<u8> {
-        let mut writer = VecWriter::new();
+    /// Applies the modifications accumulated over a batch of `batch_size` samples to every layer
+    pub(crate) fn apply_gradients(&mut self, optimizer: &Optimizer, batch_size: usize) {
+        for i in (0..self.layers.len()).rev() {
+            let layer = self.layers[i].clone();
+            profiler::time_layer(&self.execs[i], || self.label(i, "apply"), || {
+                layer.borrow_mut().apply_gradients(optimizer, batch_size);
+            });
+        }
+    }
 
-        writer.usize(self.layers.len());
+    pub(crate) fn layers(&self) -> &[Rc<RefCell<dyn Layer>>] {
+        &self.layers
+    }
 
+    pub(crate) fn set_training(&mut self, training: bool) {
         for l in &self.layers {
-            let mut layer = l.borrow_mut();
-            writer.usize(layer.id());
+            l.borrow_mut().set_training(training);
+        }
+    }
 
-            // Store the executor type as well
-            match layer.exec() {
-                CPU => writer.usize(0),
-                GPU(_) => writer.usize(1),
-            }
+    /// Clears the states that stateful layers carry between batches
+    pub fn reset_states(&mut self) {
+        for l in &self.layers {
+            l.borrow_mut().reset_states();
         }
+    }
 
+    /// Copies the current values of every layer into an immutable network for inference, which
+    /// can be shared between threads. Training the network afterwards doesn't affect the copy.
+    pub fn freeze(&self) -> Result<FrozenNetwork, Error> {
+        let mut layers = vec![];
         for l in &self.layers {
-            l.borrow_mut().as_bytes(&mut writer);
+            layers.push(l.borrow_mut().freeze()?);
         }
+        Ok(FrozenNetwork::new(layers))
+    }
 
-        writer.vec()
+    /// The executor the layer `layer` runs on
+    pub fn executor(&self, layer: usize) -> &Arc<Executor> {
+        &self.execs[layer]
     }
 
-    pub fn from_bytes(gpu_executor: Option<&'a Executor>, bytes: Vec<u8>) -> Result<Network<'a>, Error> {
-        let mut reader = CursorReader::new(bytes.as_slice());
+    /// Runs the layer `layer` on `exec` from now on, keeping its values and precision.
+    ///
+    /// The layer is read again from its bytes on the new executor, along with its neighbours, whose
+    /// outputs and sensitivities are kept on the sides of the executors on both sides of them. The
+    /// gradients of a batch in progress are dropped, so layers should be moved between training runs.
+    pub fn move_layer(&mut self, layer: usize, exec: &Arc<Executor>) -> Result<(), Error> {
+        let mut execs = self.execs.clone();
+        execs[layer] = exec.clone();
+
+        let neighbours = layer.saturating_sub(1)..(layer + 2).min(self.layers.len());
+        let mut moved = vec![];
+        for i in neighbours.clone() {
+            let mut old = self.layers[i].borrow_mut();
+            let mut writer = VecWriter::new();
+            old.as_bytes(&mut writer);
+            let bytes = writer.vec();
+
+            let owner = format!("{} {}", i, layer::layer_name(old.id()));
+            let new = pool::owned_by(owner, || layer::read_layer(old.id(), &Execs::of(&execs, i), &mut CursorReader::new(&bytes)))?;
+            new.borrow_mut().set_precision(old.precision())?;
+            moved.push(new);
+        }
 
-        let layer_count = reader.usize();
+        for (i, new) in neighbours.zip(moved) {
+            self.layers[i] = new;
+        }
+        self.execs = execs;
+        Ok(())
+    }
 
-        let cpu_exec = &CPU;
-        let gpu_exec = gpu_executor.unwrap_or_else(|| &CPU); // If no GPU executor is provided, then default to using the CPU
+    /// The precision the values of the layer `layer` are kept in
+    pub fn precision(&self, layer: usize) -> DType {
+        self.layers[layer].borrow().precision()
+    }
+
+    /// Keeps the values of the layer `layer` in `dtype`, see [Layer::set_precision]
+    pub fn set_precision(&mut self, layer: usize, dtype: DType) -> Result<(), Error> {
+        self.layers[layer].borrow_mut().set_precision(dtype)
+    }
 
-        let mut layer_types = Vec::new();
-        for i in 0..layer_count {
-            let layer_type = reader.usize();
-            let executor = match reader.usize() {
-                1 => gpu_exec,
-                _ => gpu_exec, // default should be CPU
-            };
-            layer_types.push((layer_type, executor));
+    /// The free-form metadata written with the network, such as the training configuration
+    pub fn metadata(&self) -> &BTreeMap<String, String> {
+        &self.metadata
+    }
+
+    pub fn metadata_mut(&mut self) -> &mut BTreeMap<String, String> {
+        &mut self.metadata
+    }
+
+    /// Writes the network as a [ModelFile]
+    pub fn as_bytes(&mut self) -> Vec<u8> {
+        ModelFile {
+            kind: Kind::Sequential,
+            version: VERSION,
+            metadata: self.metadata.clone(),
+            topology: vec![],
+            layers: self.layers.iter().map(|l| LayerSection::of(&mut *l.borrow_mut())).collect(),
+        }.as_bytes()
+    }
+
+    /// Reads a network written by [Network::as_bytes], placing every layer on the same kind of
+    /// executor it was written from. GPU layers are placed on `gpu_executor`, or on the CPU when
+    /// there is none, and threaded layers use the threads they were written with.
+    pub fn from_bytes(gpu_executor: Option<Arc<Executor>>, bytes: Vec<u8>) -> Result<Network, Error> {
+        let cpu = Arc::new(CPU);
+        let gpu_exec = gpu_executor.unwrap_or_else(|| cpu.clone()); // If no GPU executor is provided, then default to using the CPU
+
+        Self::read(&bytes, |section| section.executor(&gpu_exec, &cpu))
+    }
+
+    /// A copy of the network with the same values and precisions, with every layer on `exec`
+    pub fn replicate(&mut self, exec: &Arc<Executor>) -> Result<Network, Error> {
+        Self::read(&self.as_bytes(), |_| exec.clone())
+    }
+
+    /// Reads a network written by [Network::as_bytes], placing every layer on the executor
+    /// `placement` gives for its section
+    fn read(bytes: &[u8], placement: impl Fn(&LayerSection) -> Arc<Executor>) -> Result<Network, Error> {
+        let file = ModelFile::from_bytes(bytes, Kind::Sequential)?;
+        if file.layers.is_empty() {
+            return Err(Error::Network(NetworkError::ZeroLayers))
         }
+        let execs: Vec<Arc<Executor>> = file.layers.iter().map(&placement).collect();
 
         let mut layers = Vec::new();
-        let mut last_exec = cpu_exec;
-        let mut output_size = 0;
-        for i in 0..layer_count {
-            let current_exec = layer_types[i].1;
-            let next_exec = if i+1 >= layer_count {
-                &CPU
-            } else {
-                layer_types[i+1].1
-            };
-            let layer = match layer_types[i].0 {
-                0 => Dense::from_bytes((last_exec, current_exec, next_exec), &mut reader),
-                1 => Attention::from_bytes((last_exec, current_exec, next_exec), &mut reader),
-                v => {
-                    return Err(Error::Decode(DecodeError::InvalidLayerType(v)));
-                }
-            };
-            output_size = layer.borrow_mut().output_size();
-            layers.push(layer);
-            last_exec = current_exec;
+        for (i, section) in file.layers.iter().enumerate() {
+            let owner = format!("{} {}", i, layer::layer_name(section.id));
+            layers.push(pool::owned_by(owner, || section.read(i, &Execs::of(&execs, i)))?);
         }
+        let output_size = layers.last().unwrap().borrow().output_size();
 
         Ok(Self {
             layers,
+            execs,
             output_size,
+            metadata: file.metadata,
         })
     }
 }
\ No newline at end of file
@@ -0,0 +1,519 @@
+use std::cell::Ref;
+
+use ocl::{Buffer, Kernel, ProQue, SpatialDims};
+
+use crate::dual_vec::DualVec;
+use crate::error::{Error, TensorError};
+use crate::Executor;
+use crate::Executor::{CPU, GPU, Threaded};
+use crate::gpu::Gpu;
+use crate::tensor::Tensor;
+use crate::utils::cl_utils;
+use crate::utils::gemm::{gemm, Matrix};
+use crate::utils::parallel;
+
+/// The element-wise operations of two tensors, numbered like the `op` of the `op_binary` kernel
+#[derive(Clone, Copy, Debug)]
+enum Binary {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl Binary {
+    fn apply(self, x: f32, y: f32) -> f32 {
+        match self {
+            Binary::Add => x + y,
+            Binary::Sub => x - y,
+            Binary::Mul => x * y,
+            Binary::Div => x / y,
+        }
+    }
+}
+
+/// The element-wise operations of a single tensor, numbered like the `op` of the `op_unary` kernel
+#[derive(Clone, Copy, Debug)]
+enum Unary {
+    Copy,
+    Scale(f32),
+    Exp,
+    Log,
+}
+
+impl Unary {
+    fn id(self) -> u64 {
+        match self {
+            Unary::Copy => 0,
+            Unary::Scale(_) => 1,
+            Unary::Exp => 2,
+            Unary::Log => 3,
+        }
+    }
+
+    fn apply(self, x: f32) -> f32 {
+        match self {
+            Unary::Copy => x,
+            Unary::Scale(factor) => x * factor,
+            Unary::Exp => x.exp(),
+            Unary::Log => x.ln(),
+        }
+    }
+}
+
+/// The reductions along a dimension, numbered like the `op` of the `op_reduce` kernel
+#[derive(Clone, Copy, Debug)]
+enum Reduction {
+    Sum,
+    Mean,
+    Max,
+    Argmax,
+}
+
+/// `a + b`, where the shapes are broadcast together like NumPy does: they are aligned by their last
+/// dimension, and dimensions of size 1 or missing from one of them are repeated to match the other.
+pub fn add(exec: &Executor, a: &mut Tensor, b: &mut Tensor) -> Result<Tensor, Error> {
+    binary(exec, a, b, Binary::Add)
+}
+
+/// `a - b`, broadcast like [add]
+pub fn sub(exec: &Executor, a: &mut Tensor, b: &mut Tensor) -> Result<Tensor, Error> {
+    binary(exec, a, b, Binary::Sub)
+}
+
+/// `a * b` element-wise, broadcast like [add]
+pub fn mul(exec: &Executor, a: &mut Tensor, b: &mut Tensor) -> Result<Tensor, Error> {
+    binary(exec, a, b, Binary::Mul)
+}
+
+/// `a / b` element-wise, broadcast like [add]
+pub fn div(exec: &Executor, a: &mut Tensor, b: &mut Tensor) -> Result<Tensor, Error> {
+    binary(exec, a, b, Binary::Div)
+}
+
+pub fn scale(exec: &Executor, a: &mut Tensor, factor: f32) -> Result<Tensor, Error> {
+    unary(exec, a, Unary::Scale(factor))
+}
+
+pub fn exp(exec: &Executor, a: &mut Tensor) -> Result<Tensor, Error> {
+    unary(exec, a, Unary::Exp)
+}
+
+/// The natural logarithm of every value
+pub fn log(exec: &Executor, a: &mut Tensor) -> Result<Tensor, Error> {
+    unary(exec, a, Unary::Log)
+}
+
+/// A copy of the values of any view of a tensor, laid out in row major order from the start of a
+/// new vector
+pub fn contiguous(exec: &Executor, a: &mut Tensor) -> Result<Tensor, Error> {
+    unary(exec, a, Unary::Copy)
+}
+
+/// A copy of `a` with the dimensions `x` and `y` swapped
+pub fn transpose(exec: &Executor, a: &mut Tensor, x: usize, y: usize) -> Result<Tensor, Error> {
+    contiguous(exec, &mut strided(exec, a)?.transpose(x, y)?)
+}
+
+/// The sums along the dimension `axis`, which is removed from the shape
+pub fn sum(exec: &Executor, a: &mut Tensor, axis: usize) -> Result<Tensor, Error> {
+    reduce(exec, a, axis, Reduction::Sum)
+}
+
+/// The means along the dimension `axis`, which is removed from the shape
+pub fn mean(exec: &Executor, a: &mut Tensor, axis: usize) -> Result<Tensor, Error> {
+    reduce(exec, a, axis, Reduction::Mean)
+}
+
+/// The largest values along the dimension `axis`, which is removed from the shape
+pub fn max(exec: &Executor, a: &mut Tensor, axis: usize) -> Result<Tensor, Error> {
+    reduce(exec, a, axis, Reduction::Max)
+}
+
+/// The positions of the largest values along the dimension `axis`, the first one for ties. The
+/// positions are stored as values of the tensor, which represent them exactly up to 2^24.
+pub fn argmax(exec: &Executor, a: &mut Tensor, axis: usize) -> Result<Tensor, Error> {
+    reduce(exec, a, axis, Reduction::Argmax)
+}
+
+/// The matrix product of `a` of shape `[m, k]` and `b` of shape `[k, n]`. Transposed views are
+/// multiplied without copying them first.
+pub fn matmul(exec: &Executor, a: &mut Tensor, b: &mut Tensor) -> Result<Tensor, Error> {
+    if a.shape().len() != 2 || b.shape().len() != 2 || a.shape()[1] != b.shape()[0] {
+        return Err(Error::Tensor(TensorError::Product(a.shape().to_vec(), b.shape().to_vec())));
+    }
+    let (mut a, mut b) = (strided(exec, a)?, strided(exec, b)?);
+    let (m, k, n) = (a.shape()[0], a.shape()[1], b.shape()[1]);
+    let layout = [a.strides()[0], a.strides()[1], b.strides()[0], b.strides()[1]];
+    let (a_offset, b_offset) = (a.offset(), b.offset());
+
+    match exec {
+        GPU(gpu) => {
+            let mut out = DualVec::from_exec(exec, m * n);
+            {
+                let (a_buf, b_buf) = (device(&mut a)?, device(&mut b)?);
+                let out_buf = out.gpu_borrow().unwrap();
+                let layout = cl_utils::indices_buffer(gpu, &layout);
+                launch(gpu, "op_matmul", |pq| pq.kernel_builder("op_matmul")
+                    .arg(None::<&Buffer<f32>>)
+                    .arg(0u64)
+                    .arg(None::<&Buffer<f32>>)
+                    .arg(0u64)
+                    .arg(None::<&Buffer<f32>>)
+                    .arg(None::<&Buffer<u64>>)
+                    .arg(0u64)
+                    .arg(0u64)
+                    .build(), |kernel| {
+                    kernel.set_arg(0, &*a_buf)?;
+                    kernel.set_arg(1, a_offset as u64)?;
+                    kernel.set_arg(2, &*b_buf)?;
+                    kernel.set_arg(3, b_offset as u64)?;
+                    kernel.set_arg(4, &*out_buf)?;
+                    kernel.set_arg(5, &layout)?;
+                    kernel.set_arg(6, k as u64)?;
+                    kernel.set_arg(7, n as u64)
+                }, (m, n));
+            }
+            out.updated_gpu();
+            Tensor::new(out, &[m, n])
+        }
+        CPU | Threaded(_) => {
+            let mut values = vec![0.; m * n];
+            {
+                let (a_values, b_values) = (host(&mut a)?, host(&mut b)?);
+                let a_matrix = Matrix::strided(&a_values[a_offset..], m, k, layout[0], layout[1]);
+                let b_matrix = Matrix::strided(&b_values[b_offset..], k, n, layout[2], layout[3]);
+                gemm(exec.threads(), 1., a_matrix, b_matrix, 0., &mut values);
+            }
+            Tensor::new(DualVec::from_vec((exec, exec), values), &[m, n])
+        }
+    }
+}
+
+/// Adds `values * factor` to `target` in place, such as the gradients of an optimizer step
+pub fn add_scaled(exec: &Executor, target: &mut DualVec, values: &mut DualVec, factor: f32) -> Result<(), Error> {
+    let len = target.len().min(values.len());
+    match exec {
+        GPU(gpu) => {
+            {
+                let target = target.gpu().ok_or_else(|| unavailable("GPU"))?.borrow();
+                let values = values.gpu().ok_or_else(|| unavailable("GPU"))?.borrow();
+                launch(gpu, "mult_second_and_add", |pq| pq.kernel_builder("mult_second_and_add")
+                    .arg(None::<&Buffer<f32>>)
+                    .arg(None::<&Buffer<f32>>)
+                    .arg(0f32)
+                    .build(), |kernel| {
+                    kernel.set_arg(0, &*target)?;
+                    kernel.set_arg(1, &*values)?;
+                    kernel.set_arg(2, factor)
+                }, len);
+            }
+            target.updated_gpu();
+        }
+        CPU | Threaded(_) => {
+            {
+                let values = values.cpu().ok_or_else(|| unavailable("CPU"))?.borrow();
+                let mut target = target.cpu_borrow().ok_or_else(|| unavailable("CPU"))?;
+                for (t, v) in target[..len].iter_mut().zip(&values[..len]) {
+                    *t += v * factor;
+                }
+            }
+            target.updated_cpu();
+        }
+    }
+    Ok(())
+}
+
+/// The shape `a` and `b` are broadcast to by the element-wise operations, see [add]
+pub fn broadcast_shape(a: &[usize], b: &[usize]) -> Result<Vec<usize>, Error> {
+    let rank = a.len().max(b.len());
+    let dim = |shape: &[usize], d: usize| (d + shape.len()).checked_sub(rank).map_or(1, |d| shape[d]);
+
+    (0..rank).map(|d| match (dim(a, d), dim(b, d)) {
+        (x, y) if x == y || y == 1 => Ok(x),
+        (1, y) => Ok(y),
+        _ => Err(Error::Tensor(TensorError::Broadcast(a.to_vec(), b.to_vec()))),
+    }).collect()
+}
+
+fn binary(exec: &Executor, a: &mut Tensor, b: &mut Tensor, op: Binary) -> Result<Tensor, Error> {
+    let shape = broadcast_shape(a.shape(), b.shape())?;
+    let (mut a, mut b) = (strided(exec, a)?, strided(exec, b)?);
+    let (a_strides, b_strides) = (broadcast(&a, &shape), broadcast(&b, &shape));
+    let (a_offset, b_offset) = (a.offset(), b.offset());
+    let len = shape.iter().product();
+
+    match exec {
+        GPU(gpu) => {
+            let mut out = DualVec::from_exec(exec, len);
+            {
+                let (a_buf, b_buf) = (device(&mut a)?, device(&mut b)?);
+                let out_buf = out.gpu_borrow().unwrap();
+                let layout = cl_utils::indices_buffer(gpu, &[&shape[..], &a_strides, &b_strides].concat());
+                launch(gpu, "op_binary", |pq| pq.kernel_builder("op_binary")
+                    .arg(None::<&Buffer<f32>>)
+                    .arg(0u64)
+                    .arg(None::<&Buffer<f32>>)
+                    .arg(0u64)
+                    .arg(None::<&Buffer<f32>>)
+                    .arg(None::<&Buffer<u64>>)
+                    .arg(0i32)
+                    .arg(0u64)
+                    .build(), |kernel| {
+                    kernel.set_arg(0, &*a_buf)?;
+                    kernel.set_arg(1, a_offset as u64)?;
+                    kernel.set_arg(2, &*b_buf)?;
+                    kernel.set_arg(3, b_offset as u64)?;
+                    kernel.set_arg(4, &*out_buf)?;
+                    kernel.set_arg(5, &layout)?;
+                    kernel.set_arg(6, shape.len() as i32)?;
+                    kernel.set_arg(7, op as u64)
+                }, len);
+            }
+            out.updated_gpu();
+            Tensor::new(out, &shape)
+        }
+        CPU | Threaded(_) => {
+            let mut values = vec![0.; len];
+            {
+                let (a_values, b_values) = (host(&mut a)?, host(&mut b)?);
+                let (a_values, b_values): (&[f32], &[f32]) = (&a_values, &b_values);
+                parallel::for_chunks(exec.threads(), &mut values, 1, |start, chunk| {
+                    for (i, value) in chunk.iter_mut().enumerate() {
+                        let x = a_values[index(start + i, &shape, &a_strides, a_offset)];
+                        let y = b_values[index(start + i, &shape, &b_strides, b_offset)];
+                        *value = op.apply(x, y);
+                    }
+                });
+            }
+            Tensor::new(DualVec::from_vec((exec, exec), values), &shape)
+        }
+    }
+}
+
+fn unary(exec: &Executor, a: &mut Tensor, op: Unary) -> Result<Tensor, Error> {
+    let mut a = strided(exec, a)?;
+    let (shape, strides, a_offset) = (a.shape().to_vec(), a.strides().to_vec(), a.offset());
+    let len = a.len();
+
+    match exec {
+        GPU(gpu) => {
+            let mut out = DualVec::from_exec(exec, len);
+            {
+                let a_buf = device(&mut a)?;
+                let out_buf = out.gpu_borrow().unwrap();
+                let layout = cl_utils::indices_buffer(gpu, &[&shape[..], &strides].concat());
+                let factor = match op {
+                    Unary::Scale(factor) => factor,
+                    _ => 1.,
+                };
+                launch(gpu, "op_unary", |pq| pq.kernel_builder("op_unary")
+                    .arg(None::<&Buffer<f32>>)
+                    .arg(0u64)
+                    .arg(None::<&Buffer<f32>>)
+                    .arg(None::<&Buffer<u64>>)
+                    .arg(0i32)
+                    .arg(0u64)
+                    .arg(0f32)
+                    .build(), |kernel| {
+                    kernel.set_arg(0, &*a_buf)?;
+                    kernel.set_arg(1, a_offset as u64)?;
+                    kernel.set_arg(2, &*out_buf)?;
+                    kernel.set_arg(3, &layout)?;
+                    kernel.set_arg(4, shape.len() as i32)?;
+                    kernel.set_arg(5, op.id())?;
+                    kernel.set_arg(6, factor)
+                }, len);
+            }
+            out.updated_gpu();
+            Tensor::new(out, &shape)
+        }
+        CPU | Threaded(_) => {
+            let mut values = vec![0.; len];
+            {
+                let a_values = host(&mut a)?;
+                let a_values: &[f32] = &a_values;
+                parallel::for_chunks(exec.threads(), &mut values, 1, |start, chunk| {
+                    for (i, value) in chunk.iter_mut().enumerate() {
+                        *value = op.apply(a_values[index(start + i, &shape, &strides, a_offset)]);
+                    }
+                });
+            }
+            Tensor::new(DualVec::from_vec((exec, exec), values), &shape)
+        }
+    }
+}
+
+fn reduce(exec: &Executor, a: &mut Tensor, axis: usize, op: Reduction) -> Result<Tensor, Error> {
+    if axis >= a.shape().len() {
+        return Err(Error::Tensor(TensorError::Dimension(axis, a.shape().len())));
+    }
+    let mut a = strided(exec, a)?;
+    let (reduced, stride, a_offset) = (a.shape()[axis], a.strides()[axis], a.offset());
+
+    // the output keeps the other dimensions, walked with the strides of the input
+    let mut shape = a.shape().to_vec();
+    let mut strides = a.strides().to_vec();
+    shape.remove(axis);
+    strides.remove(axis);
+    let len = shape.iter().product();
+
+    let out = match exec {
+        GPU(gpu) => {
+            let mut out = DualVec::from_exec(exec, len);
+            {
+                let a_buf = device(&mut a)?;
+                let out_buf = out.gpu_borrow().unwrap();
+                let layout = cl_utils::indices_buffer(gpu, &[&shape[..], &strides].concat());
+                launch(gpu, "op_reduce", |pq| pq.kernel_builder("op_reduce")
+                    .arg(None::<&Buffer<f32>>)
+                    .arg(0u64)
+                    .arg(None::<&Buffer<f32>>)
+                    .arg(None::<&Buffer<u64>>)
+                    .arg(0i32)
+                    .arg(0u64)
+                    .arg(0u64)
+                    .arg(0u64)
+                    .build(), |kernel| {
+                    kernel.set_arg(0, &*a_buf)?;
+                    kernel.set_arg(1, a_offset as u64)?;
+                    kernel.set_arg(2, &*out_buf)?;
+                    kernel.set_arg(3, &layout)?;
+                    kernel.set_arg(4, shape.len() as i32)?;
+                    kernel.set_arg(5, reduced as u64)?;
+                    kernel.set_arg(6, stride as u64)?;
+                    kernel.set_arg(7, op as u64)
+                }, len);
+            }
+            out.updated_gpu();
+            out
+        }
+        CPU | Threaded(_) => {
+            let mut values = vec![0.; len];
+            {
+                let a_values = host(&mut a)?;
+                let a_values: &[f32] = &a_values;
+                parallel::for_chunks(exec.threads(), &mut values, reduced, |start, chunk| {
+                    for (i, value) in chunk.iter_mut().enumerate() {
+                        let first = index(start + i, &shape, &strides, a_offset);
+                        let along = (0..reduced).map(|j| a_values[first + j * stride]);
+                        *value = match op {
+                            Reduction::Sum => along.sum(),
+                            Reduction::Mean => along.sum::<f32>() / reduced as f32,
+                            Reduction::Max => along.fold(f32::NEG_INFINITY, f32::max),
+                            // the first of equal values is kept, like on the device
+                            Reduction::Argmax => along.enumerate()
+                                .fold((0, f32::NEG_INFINITY), |best, (j, v)| if v > best.1 { (j, v) } else { best })
+                                .0 as f32,
+                        };
+                    }
+                });
+            }
+            DualVec::from_vec((exec, exec), values)
+        }
+    };
+
+    // reducing the only dimension leaves a single value
+    if shape.is_empty() {
+        shape.push(1);
+    }
+    Tensor::new(out, &shape)
+}
+
+/// A view of `a` which can be described by its offset and strides alone, which needs the rows
+/// picked by [Tensor::gather] to be copied together first
+fn strided(exec: &Executor, a: &mut Tensor) -> Result<Tensor, Error> {
+    let Some(rows) = a.rows().map(|rows| rows.to_vec()) else {
+        return Ok(a.clone());
+    };
+    let (shape, strides) = (a.shape().to_vec(), a.strides().to_vec());
+    let (sample_shape, sample_strides) = (&shape[1..], &strides[1..]);
+    let sample = a.sample_size();
+    let len = a.len();
+
+    match exec {
+        GPU(gpu) => {
+            let mut out = DualVec::from_exec(exec, len);
+            {
+                let a_buf = a.data_mut().gpu().ok_or_else(|| unavailable("GPU"))?.borrow();
+                let out_buf = out.gpu_borrow().unwrap();
+                let rows = cl_utils::indices_buffer(gpu, &rows);
+                let layout = cl_utils::indices_buffer(gpu, &[sample_shape, sample_strides].concat());
+                launch(gpu, "op_gather", |pq| pq.kernel_builder("op_gather")
+                    .arg(None::<&Buffer<f32>>)
+                    .arg(None::<&Buffer<u64>>)
+                    .arg(None::<&Buffer<f32>>)
+                    .arg(None::<&Buffer<u64>>)
+                    .arg(0i32)
+                    .arg(0u64)
+                    .build(), |kernel| {
+                    kernel.set_arg(0, &*a_buf)?;
+                    kernel.set_arg(1, &rows)?;
+                    kernel.set_arg(2, &*out_buf)?;
+                    kernel.set_arg(3, &layout)?;
+                    kernel.set_arg(4, sample_shape.len() as i32)?;
+                    kernel.set_arg(5, sample as u64)
+                }, len);
+            }
+            out.updated_gpu();
+            Tensor::new(out, &shape)
+        }
+        CPU | Threaded(_) => {
+            let mut values = vec![0.; len];
+            {
+                let a_values = a.data_mut().cpu().ok_or_else(|| unavailable("CPU"))?.borrow();
+                for (i, value) in values.iter_mut().enumerate() {
+                    *value = a_values[index(i % sample, sample_shape, sample_strides, rows[i / sample])];
+                }
+            }
+            Tensor::new(DualVec::from_vec((exec, exec), values), &shape)
+        }
+    }
+}
+
+/// The strides of `a` over the shape it is broadcast to, where repeated dimensions have a stride of 0
+fn broadcast(a: &Tensor, shape: &[usize]) -> Vec<usize> {
+    let skip = shape.len() - a.shape().len();
+    let mut strides = vec![0; shape.len()];
+    for (d, (size, stride)) in a.shape().iter().zip(a.strides()).enumerate() {
+        if *size == shape[skip + d] {
+            strides[skip + d] = *stride;
+        }
+    }
+    strides
+}
+
+/// The position in the vector of the value `i` of a view in row major order
+fn index(mut i: usize, shape: &[usize], strides: &[usize], offset: usize) -> usize {
+    let mut index = offset;
+    for d in (0..shape.len()).rev() {
+        index += i % shape[d] * strides[d];
+        i /= shape[d];
+    }
+    index
+}
+
+fn host(a: &mut Tensor) -> Result<Ref<'_, Vec<f32>>, Error> {
+    Ok(a.data_mut().cpu().ok_or_else(|| unavailable("CPU"))?.borrow())
+}
+
+fn device(a: &mut Tensor) -> Result<Ref<'_, Buffer<f32>>, Error> {
+    Ok(a.data_mut().gpu().ok_or_else(|| unavailable("GPU"))?.borrow())
+}
+
+fn unavailable(side: &str) -> Error {
+    Error::UnavailableBuffer(format!("The {} buffer of an operand was not available", side))
+}
+
+/// Runs the cached kernel `name` over `size` once `args` has set its arguments
+fn launch<S: Into<SpatialDims>>(gpu: &Gpu, name: &'static str, build: impl FnOnce(&ProQue) -> ocl::Result<Kernel>, args: impl FnOnce(&Kernel) -> ocl::Result<()>, size: S) {
+    let size = size.into();
+    if size.to_len() == 0 {
+        return;
+    }
+
+    gpu.with_kernel(name, |pq| build(pq).expect("Failed to build ops kernel"), |kernel| {
+        args(kernel).expect("Failed to set ops kernel arguments");
+        unsafe { cl_utils::execute_kernel(gpu, kernel, size) };
+    });
+}
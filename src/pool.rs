@@ -0,0 +1,158 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex};
+
+use ocl::{Buffer, Queue};
+
+/// The pool of the CPU executors, which all allocate from the memory of the host
+pub(crate) static HOST_POOL: LazyLock<Arc<MemoryPool>> = LazyLock::new(|| Arc::new(MemoryPool::default()));
+
+/// The owner of the memory allocated outside of [owned_by]
+const OTHER: &str = "Other";
+
+thread_local! {
+    /// The owner of the [DualVec](crate::dual_vec::DualVec)s created on this thread
+    static OWNER: RefCell<Option<Arc<str>>> = const { RefCell::new(None) };
+}
+
+/// Recycles the device buffers and host vectors of the [DualVec](crate::dual_vec::DualVec)s of an
+/// executor. Allocations are rounded up to a size class, and when freed they are kept to be reused
+/// by the next allocation of the same class, rather than allocated again every time a batch grows
+/// or a temporary is made.
+///
+/// The memory in use is recorded by the layer which allocated it, see [MemoryPool::usage].
+#[derive(Debug, Default)]
+pub struct MemoryPool {
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    buffers: HashMap<usize, Vec<Buffer<f32>>>,
+    vecs: HashMap<usize, Vec<Vec<f32>>>,
+    usage: HashMap<Arc<str>, MemoryUsage>,
+}
+
+/// The bytes allocated by a single owner, a layer or [Other](OTHER) for everything else
+#[derive(Clone, Debug, Default)]
+pub struct MemoryUsage {
+    pub owner: String,
+    pub current: usize,
+    pub peak: usize,
+}
+
+impl MemoryPool {
+    /// The memory currently in use and the most that was ever in use, by every owner
+    pub fn usage(&self) -> Vec<MemoryUsage> {
+        let mut usage: Vec<MemoryUsage> = self.state.lock().unwrap().usage.values().cloned().collect();
+        usage.sort_by(|a, b| a.owner.cmp(&b.owner));
+        usage
+    }
+
+    /// The bytes of the freed allocations kept for reuse
+    pub fn free(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        let buffers: usize = state.buffers.iter().map(|(class, free)| class * free.len()).sum();
+        let vecs: usize = state.vecs.iter().map(|(class, free)| class * free.len()).sum();
+        (buffers + vecs) * size_of::<f32>()
+    }
+
+    /// Releases the freed allocations kept for reuse
+    pub fn trim(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.buffers.clear();
+        state.vecs.clear();
+    }
+
+    /// A device buffer of at least `len` zeroes on `queue`
+    pub(crate) fn buffer(&self, queue: &Queue, owner: &Arc<str>, len: usize) -> Buffer<f32> {
+        let class = size_class(len);
+        let mut state = self.state.lock().unwrap();
+        state.allocated(owner, class);
+
+        match state.buffers.get_mut(&class).and_then(|free| free.pop()) {
+            Some(buffer) => {
+                buffer.cmd().queue(queue).fill(0., None).enq().expect("Failed to clear buffer");
+                buffer
+            }
+            None => Buffer::builder()
+                .queue(queue.clone())
+                .len(class)
+                .fill_val(0.)
+                .build()
+                .expect("Failed to create buffer"),
+        }
+    }
+
+    /// A host vector of `len` zeroes, with the capacity of its size class
+    pub(crate) fn vec(&self, owner: &Arc<str>, len: usize) -> Vec<f32> {
+        let class = size_class(len);
+        let mut state = self.state.lock().unwrap();
+        state.allocated(owner, class);
+
+        let mut vec = state.vecs.get_mut(&class).and_then(|free| free.pop())
+            .unwrap_or_else(|| Vec::with_capacity(class));
+        vec.clear();
+        vec.resize(len, 0.);
+        vec
+    }
+
+    /// Records a vector allocated outside of the pool, which is recycled like the others once freed
+    pub(crate) fn adopt_vec(&self, owner: &Arc<str>, vec: &Vec<f32>) {
+        self.state.lock().unwrap().allocated(owner, vec.capacity());
+    }
+
+    pub(crate) fn recycle_buffer(&self, owner: &Arc<str>, buffer: Buffer<f32>) {
+        let mut state = self.state.lock().unwrap();
+        state.freed(owner, buffer.len());
+        // buffers which aren't of a size class can't be handed out again
+        if size_class(buffer.len()) == buffer.len() {
+            state.buffers.entry(buffer.len()).or_default().push(buffer);
+        }
+    }
+
+    pub(crate) fn recycle_vec(&self, owner: &Arc<str>, vec: Vec<f32>) {
+        let mut state = self.state.lock().unwrap();
+        state.freed(owner, vec.capacity());
+        if size_class(vec.capacity()) == vec.capacity() {
+            state.vecs.entry(vec.capacity()).or_default().push(vec);
+        }
+    }
+}
+
+impl State {
+    fn allocated(&mut self, owner: &Arc<str>, len: usize) {
+        let usage = self.usage.entry(owner.clone())
+            .or_insert_with(|| MemoryUsage { owner: owner.to_string(), ..Default::default() });
+        usage.current += len * size_of::<f32>();
+        usage.peak = usage.peak.max(usage.current);
+    }
+
+    fn freed(&mut self, owner: &Arc<str>, len: usize) {
+        if let Some(usage) = self.usage.get_mut(owner) {
+            usage.current = usage.current.saturating_sub(len * size_of::<f32>());
+        }
+    }
+}
+
+/// The length allocations of `len` values are rounded up to. Between two powers of two there are
+/// four classes, so at most a fifth of an allocation is unused.
+pub fn size_class(len: usize) -> usize {
+    let len = len.max(1);
+    let step = (len.next_power_of_two() / 8).max(1);
+    len.next_multiple_of(step)
+}
+
+/// Runs `f`, recording the memory of every [DualVec](crate::dual_vec::DualVec) created in it as
+/// owned by `owner`
+pub(crate) fn owned_by<T>(owner: String, f: impl FnOnce() -> T) -> T {
+    let previous = OWNER.with(|o| o.replace(Some(owner.into())));
+    let result = f();
+    OWNER.with(|o| *o.borrow_mut() = previous);
+    result
+}
+
+/// The owner of the memory allocated now
+pub(crate) fn owner() -> Arc<str> {
+    OWNER.with(|o| o.borrow().clone()).unwrap_or_else(|| OTHER.into())
+}
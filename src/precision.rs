@@ -0,0 +1,131 @@
+use crate::tensor::DType;
+
+/// The bits of `values` in the half precision `dtype`, rounded to the nearest even value like the
+/// conversions of the kernels
+pub fn to_half(dtype: DType, values: &[f32]) -> Vec<u16> {
+    match dtype {
+        DType::BF16 => values.iter().map(|v| bf16_bits(*v)).collect(),
+        _ => values.iter().map(|v| f16_bits(*v)).collect(),
+    }
+}
+
+/// The values of the half precision `bits` of `dtype`
+pub fn from_half(dtype: DType, bits: &[u16]) -> Vec<f32> {
+    match dtype {
+        DType::BF16 => bits.iter().map(|b| f32::from_bits((*b as u32) << 16)).collect(),
+        _ => bits.iter().map(|b| f16_value(*b)).collect(),
+    }
+}
+
+/// The nearest value of `dtype` to `value`
+pub fn round(dtype: DType, value: f32) -> f32 {
+    match dtype {
+        DType::F16 | DType::BF16 => from_half(dtype, &to_half(dtype, &[value]))[0],
+        DType::F32 | DType::F64 => value,
+    }
+}
+
+fn f16_bits(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+
+    if exponent == 0xff {
+        // infinities stay infinite and NaNs stay NaNs
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+
+    let exponent = exponent - 127 + 15;
+    if exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+    if exponent <= 0 {
+        // too small for a normal half, so the implicit bit is shifted into the mantissa
+        if exponent < -10 {
+            return sign;
+        }
+        return sign | round_shifted(mantissa | 0x80_0000, (14 - exponent) as u32);
+    }
+
+    // the rounding can carry into the exponent, up to infinity
+    sign | (((exponent as u32) << 10) as u16 + round_shifted(mantissa, 13))
+}
+
+/// `value >> shift`, rounded to the nearest even value
+fn round_shifted(value: u32, shift: u32) -> u16 {
+    let (shifted, rest, half) = (value >> shift, value & ((1 << shift) - 1), 1 << (shift - 1));
+    let rounded = if rest > half || (rest == half && shifted & 1 == 1) { shifted + 1 } else { shifted };
+    rounded as u16
+}
+
+fn f16_value(bits: u16) -> f32 {
+    let sign = ((bits & 0x8000) as u32) << 16;
+    let exponent = ((bits >> 10) & 0x1f) as u32;
+    let mantissa = (bits & 0x3ff) as u32;
+
+    match exponent {
+        0 => {
+            let value = mantissa as f32 * 2f32.powi(-24);
+            if sign != 0 { -value } else { value }
+        }
+        0x1f => f32::from_bits(sign | 0x7f80_0000 | (mantissa << 13)),
+        _ => f32::from_bits(sign | ((exponent + 112) << 23) | (mantissa << 13)),
+    }
+}
+
+fn bf16_bits(value: f32) -> u16 {
+    let bits = value.to_bits();
+    if value.is_nan() {
+        return ((bits >> 16) | 0x40) as u16;
+    }
+    // rounding up the largest values gives infinity, like an overflow
+    ((bits + 0x7fff + ((bits >> 16) & 1)) >> 16) as u16
+}
+
+/// Scales the gradients kept in half precision, so the small ones aren't flushed to zero.
+///
+/// The gradients are linear in the loss, so scaling them is the same as scaling the loss. When a
+/// scaled gradient overflows the step is skipped and the scale is halved, and after `interval`
+/// steps in a row without an overflow it is doubled again.
+#[derive(Clone, Debug)]
+pub struct LossScale {
+    scale: f32,
+    interval: usize,
+    /// The steps since the last overflow or growth of the scale
+    steps: usize,
+}
+
+impl LossScale {
+    pub fn new(scale: f32, interval: usize) -> Self {
+        LossScale {
+            scale,
+            interval,
+            steps: 0,
+        }
+    }
+
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+
+    /// Records whether the gradients of the last step overflowed
+    pub fn update(&mut self, overflowed: bool) {
+        if overflowed {
+            self.scale = (self.scale / 2.).max(1.);
+            self.steps = 0;
+        } else {
+            self.steps += 1;
+            if self.steps >= self.interval {
+                self.scale *= 2.;
+                self.steps = 0;
+            }
+        }
+    }
+}
+
+impl Default for LossScale {
+    fn default() -> Self {
+        LossScale::new(32768., 2000)
+    }
+}
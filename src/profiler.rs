@@ -0,0 +1,266 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::info;
+use ocl::enums::{ProfilingInfo, ProfilingInfoResult};
+use ocl::Event;
+
+use crate::Executor;
+
+thread_local! {
+    /// The boundary between executors the copies made on this thread are recorded under
+    static BOUNDARY: RefCell<Option<String>> = const { RefCell::new(None) };
+    /// The profiler of the CPU executors, which can only be told apart by the thread running them
+    static HOST: Profiler = Profiler::default();
+}
+
+/// The profiling mode of an executor, which every GPU has its own of. The layers of a network
+/// stay on the thread that runs it, so the CPU executors share one on every thread.
+#[derive(Debug, Default)]
+pub(crate) struct Profiler {
+    /// The recordings since the last report, `None` while profiling is disabled
+    profile: Mutex<Option<Profile>>,
+    /// The reports of the epochs trained while profiling was enabled
+    reports: Mutex<Vec<Report>>,
+}
+
+/// Runs `f` with the profiler of `exec`
+pub(crate) fn with<T>(exec: &Executor, f: impl FnOnce(&Profiler) -> T) -> T {
+    match exec {
+        Executor::GPU(gpu) => f(gpu.profiler()),
+        Executor::CPU | Executor::Threaded(_) => HOST.with(f),
+    }
+}
+
+impl Profiler {
+    pub(crate) fn enable(&self) {
+        self.profile.lock().unwrap().get_or_insert_with(Profile::default);
+    }
+
+    pub(crate) fn disable(&self) {
+        self.profile.lock().unwrap().take();
+    }
+
+    pub(crate) fn enabled(&self) -> bool {
+        self.profile.lock().unwrap().is_some()
+    }
+
+    pub(crate) fn take_reports(&self) -> Vec<Report> {
+        std::mem::take(&mut self.reports.lock().unwrap())
+    }
+
+    /// Records the event of a kernel, whose time is read once it has completed
+    pub(crate) fn kernel(&self, name: String, event: Event) {
+        if let Some(profile) = self.profile.lock().unwrap().as_mut() {
+            profile.pending.push((name, event));
+        }
+    }
+
+    pub(crate) fn transfer(&self, direction: Direction, bytes: usize, elapsed: Duration) {
+        if let Some(profile) = self.profile.lock().unwrap().as_mut() {
+            let boundary = BOUNDARY.with(|boundary| boundary.borrow().clone()).map(|name| {
+                profile.boundaries.entry(name.clone()).or_insert_with(|| Boundary { name, ..Default::default() })
+            });
+            let (total, boundary) = match direction {
+                Direction::ToDevice => (&mut profile.to_device, boundary.map(|b| &mut b.to_device)),
+                Direction::ToHost => (&mut profile.to_host, boundary.map(|b| &mut b.to_host)),
+            };
+            for transfers in std::iter::once(total).chain(boundary) {
+                transfers.count += 1;
+                transfers.bytes += bytes;
+                transfers.total += elapsed;
+            }
+        }
+    }
+
+    /// Makes the report of everything recorded since the last epoch, waiting for the recorded kernels to complete
+    fn finish_epoch(&self, epoch: u32) {
+        let profile = match self.profile.lock().unwrap().as_mut() {
+            Some(profile) => std::mem::take(profile),
+            None => return,
+        };
+
+        let mut kernels = profile.kernels;
+        for (name, event) in profile.pending {
+            // queues without profiling enabled don't have the times of their events
+            if let Some(elapsed) = event_time(&event) {
+                add(&mut kernels, name, elapsed);
+            }
+        }
+
+        let mut boundaries: Vec<Boundary> = profile.boundaries.into_values().collect();
+        boundaries.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let report = Report {
+            epoch,
+            layers: sorted(profile.layers),
+            kernels: sorted(kernels),
+            to_device: profile.to_device,
+            to_host: profile.to_host,
+            boundaries,
+        };
+        info!("{}", report);
+        self.reports.lock().unwrap().push(report);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Profile {
+    layers: HashMap<String, Timing>,
+    kernels: HashMap<String, Timing>,
+    /// Kernels which may still be running, whose times are read when the report is made
+    pending: Vec<(String, Event)>,
+    to_device: Transfers,
+    to_host: Transfers,
+    boundaries: HashMap<String, Boundary>,
+}
+
+/// The total time of everything recorded under the same name
+#[derive(Clone, Debug, Default)]
+pub struct Timing {
+    pub name: String,
+    pub calls: usize,
+    pub total: Duration,
+}
+
+/// The copies between the host and the device in a single direction
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Transfers {
+    pub count: usize,
+    pub bytes: usize,
+    /// The time the host spent on the copies, which for asynchronous copies is only the time
+    /// taken to start them
+    pub total: Duration,
+}
+
+/// The copies made where the values pass between layers on the host and layers on a device
+#[derive(Clone, Debug, Default)]
+pub struct Boundary {
+    /// The layers on either side, in the direction of the forward pass
+    pub name: String,
+    pub to_device: Transfers,
+    pub to_host: Transfers,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Direction {
+    ToDevice,
+    ToHost,
+}
+
+/// Where the time of a single epoch was spent, with the timings sorted from the longest
+#[derive(Clone, Debug)]
+pub struct Report {
+    pub epoch: u32,
+    /// The time the host spent in every layer. The kernels of GPU layers run asynchronously, so
+    /// for those this is mostly the time taken to launch them.
+    pub layers: Vec<Timing>,
+    /// The time the device spent running every kernel
+    pub kernels: Vec<Timing>,
+    pub to_device: Transfers,
+    pub to_host: Transfers,
+    /// The copies of [to_device](Report::to_device) and [to_host](Report::to_host) made between
+    /// layers on different executors, sorted by name
+    pub boundaries: Vec<Boundary>,
+}
+
+/// Runs `f`, adding its time to the layer called `name` when `exec` is profiled
+pub(crate) fn time_layer<T>(exec: &Executor, name: impl FnOnce() -> String, f: impl FnOnce() -> T) -> T {
+    if !with(exec, Profiler::enabled) {
+        return f();
+    }
+
+    let start = Instant::now();
+    let result = f();
+    let elapsed = start.elapsed();
+    with(exec, |profiler| {
+        if let Some(profile) = profiler.profile.lock().unwrap().as_mut() {
+            add(&mut profile.layers, name(), elapsed);
+        }
+    });
+    result
+}
+
+/// Runs `f`, recording the copies it makes under the boundary called `name` as well when `gpu`,
+/// the executor on the device side of the boundary, is profiled
+pub(crate) fn boundary<T>(gpu: &Executor, name: impl FnOnce() -> String, f: impl FnOnce() -> T) -> T {
+    if !with(gpu, Profiler::enabled) {
+        return f();
+    }
+
+    let previous = BOUNDARY.with(|boundary| boundary.replace(Some(name())));
+    let result = f();
+    BOUNDARY.with(|boundary| boundary.replace(previous));
+    result
+}
+
+/// Makes the report of the epoch of every profiled executor of `execs`, once for executors
+/// sharing a profiler
+pub(crate) fn finish_epoch(execs: &[Arc<Executor>], epoch: u32) {
+    let mut finished: Vec<*const Profiler> = Vec::new();
+    for exec in execs {
+        with(exec, |profiler| {
+            if !finished.contains(&(profiler as *const Profiler)) {
+                finished.push(profiler);
+                profiler.finish_epoch(epoch);
+            }
+        });
+    }
+}
+
+fn event_time(event: &Event) -> Option<Duration> {
+    event.wait_for().ok()?;
+    match (event.profiling_info(ProfilingInfo::Start), event.profiling_info(ProfilingInfo::End)) {
+        (Ok(ProfilingInfoResult::Start(start)), Ok(ProfilingInfoResult::End(end))) => {
+            Some(Duration::from_nanos(end.saturating_sub(start)))
+        }
+        _ => None,
+    }
+}
+
+fn add(timings: &mut HashMap<String, Timing>, name: String, elapsed: Duration) {
+    let timing = timings.entry(name.clone()).or_insert_with(|| Timing { name, ..Default::default() });
+    timing.calls += 1;
+    timing.total += elapsed;
+}
+
+fn sorted(timings: HashMap<String, Timing>) -> Vec<Timing> {
+    let mut timings: Vec<Timing> = timings.into_values().collect();
+    timings.sort_by(|a, b| b.total.cmp(&a.total).then_with(|| a.name.cmp(&b.name)));
+    timings
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Epoch {}", self.epoch)?;
+        for (title, timings) in [("Layers", &self.layers), ("Kernels", &self.kernels)] {
+            if timings.is_empty() {
+                continue;
+            }
+            let total: Duration = timings.iter().map(|t| t.total).sum();
+            writeln!(f, "  {} ({:?})", title, total)?;
+            for timing in timings {
+                let share = timing.total.as_secs_f64() / total.as_secs_f64().max(f64::MIN_POSITIVE) * 100.;
+                writeln!(f, "    {:<32} {:>8} calls {:>12?} {:>5.1}%", timing.name, timing.calls, timing.total, share)?;
+            }
+        }
+        for (title, transfers) in [("Host to device", &self.to_device), ("Device to host", &self.to_host)] {
+            writeln!(f, "  {:<34} {:>8} copies {:>12} bytes {:>12?}", title, transfers.count, transfers.bytes, transfers.total)?;
+        }
+        if !self.boundaries.is_empty() {
+            writeln!(f, "  Boundaries")?;
+        }
+        for boundary in &self.boundaries {
+            for (direction, transfers) in [("to device", &boundary.to_device), ("to host", &boundary.to_host)] {
+                if transfers.count > 0 {
+                    let title = format!("{} {}", boundary.name, direction);
+                    writeln!(f, "    {:<32} {:>8} copies {:>12} bytes {:>12?}", title, transfers.count, transfers.bytes, transfers.total)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
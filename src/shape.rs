@@ -0,0 +1,51 @@
+/// The dimensions of a single sample, from the outermost to the innermost.
+///
+/// Samples are stored flat, so the shape only decides how layers such as attention
+/// split a sample into positions of characteristics.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Shape(Vec<usize>);
+
+impl Shape {
+    pub fn dims(&self) -> &[usize] {
+        &self.0
+    }
+
+    /// The amount of values of a sample
+    pub fn size(&self) -> usize {
+        self.0.iter().product()
+    }
+
+    /// The innermost dimension, such as the characteristics of every position of a sequence
+    pub fn last(&self) -> usize {
+        *self.0.last().unwrap_or(&1)
+    }
+
+    /// The amount of positions of size [Shape::last], which is 0 when that is 0
+    pub fn positions(&self) -> usize {
+        self.size().checked_div(self.last()).unwrap_or(0)
+    }
+}
+
+impl From<usize> for Shape {
+    fn from(value: usize) -> Self {
+        Shape(vec![value])
+    }
+}
+
+impl<const N: usize> From<[usize; N]> for Shape {
+    fn from(value: [usize; N]) -> Self {
+        Shape(value.to_vec())
+    }
+}
+
+impl From<Vec<usize>> for Shape {
+    fn from(value: Vec<usize>) -> Self {
+        Shape(value)
+    }
+}
+
+impl From<&[usize]> for Shape {
+    fn from(value: &[usize]) -> Self {
+        Shape(value.to_vec())
+    }
+}
@@ -0,0 +1,304 @@
+use std::ops::Range;
+use std::rc::Rc;
+
+use crate::dual_vec::DualVec;
+use crate::error::{Error, TensorError};
+use crate::shape::Shape;
+
+/// The type of the values of a tensor, and the precision the values of a layer are kept in, see
+/// [Layer::set_precision](crate::layer::Layer::set_precision)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DType {
+    F32,
+    /// Double precision, computed on the host
+    F64,
+    /// IEEE half precision, stored on devices with `cl_khr_fp16`
+    F16,
+    /// The upper half of an f32, with its range but fewer significant bits
+    BF16,
+}
+
+impl DType {
+    /// The bytes of a single value
+    pub fn size(&self) -> usize {
+        match self {
+            DType::F32 => size_of::<f32>(),
+            DType::F64 => size_of::<f64>(),
+            DType::F16 | DType::BF16 => size_of::<u16>(),
+        }
+    }
+
+    /// Whether the values are stored in 16 bits
+    pub fn is_half(&self) -> bool {
+        matches!(self, DType::F16 | DType::BF16)
+    }
+}
+
+impl From<usize> for DType {
+    fn from(value: usize) -> Self {
+        match value {
+            1 => DType::F64,
+            2 => DType::F16,
+            3 => DType::BF16,
+            _ => DType::F32,
+        }
+    }
+}
+
+impl From<DType> for usize {
+    fn from(value: DType) -> Self {
+        match value {
+            DType::F32 => 0,
+            DType::F64 => 1,
+            DType::F16 => 2,
+            DType::BF16 => 3,
+        }
+    }
+}
+
+/// A shaped view of the values of a [DualVec], kept on the host, the device or both like the
+/// vector itself.
+///
+/// The first dimension is the batch. Its rows are either spaced evenly by the stride of the
+/// dimension, or are gathered from any offsets of the vector by [Tensor::gather], so a batch of
+/// samples picked from a dataset is passed to the layers without being copied. Views share the
+/// values and their residency with the tensor they were made from.
+#[derive(Clone, Debug)]
+pub struct Tensor {
+    data: DualVec,
+    dtype: DType,
+    shape: Vec<usize>,
+    strides: Vec<usize>,
+    offset: usize,
+    /// The offsets of the rows picked by [Tensor::gather], which replace the stride of the batch
+    rows: Option<Rc<[usize]>>,
+}
+
+impl Tensor {
+    /// A tensor of `shape` over the values of `data`, laid out in row major order
+    pub fn new(data: DualVec, shape: &[usize]) -> Result<Self, Error> {
+        let len = shape.iter().product::<usize>();
+        if shape.is_empty() || len > data.len() {
+            return Err(Error::Tensor(TensorError::Shape(shape.to_vec(), data.len())));
+        }
+
+        Ok(Tensor {
+            data,
+            dtype: DType::F32,
+            strides: row_major(shape),
+            shape: shape.to_vec(),
+            offset: 0,
+            rows: None,
+        })
+    }
+
+    /// A batch of as many samples of `sample` as `data` holds
+    pub fn batch(data: DualVec, sample: impl Into<Shape>) -> Self {
+        let sample = sample.into();
+        let mut shape = vec![data.len() / sample.size().max(1)];
+        shape.extend_from_slice(sample.dims());
+
+        Tensor {
+            data,
+            dtype: DType::F32,
+            strides: row_major(&shape),
+            shape,
+            offset: 0,
+            rows: None,
+        }
+    }
+
+    /// A batch of the samples of `sample` starting at every one of `offsets` in `data`
+    pub fn gathered(data: DualVec, sample: impl Into<Shape>, offsets: Vec<usize>) -> Result<Self, Error> {
+        let sample = sample.into();
+        if let Some(offset) = offsets.iter().find(|offset| *offset + sample.size() > data.len()) {
+            return Err(Error::Tensor(TensorError::Offset(*offset, data.len())));
+        }
+
+        let mut shape = vec![offsets.len()];
+        shape.extend_from_slice(sample.dims());
+        Ok(Tensor {
+            data,
+            dtype: DType::F32,
+            strides: row_major(&shape),
+            shape,
+            offset: 0,
+            rows: Some(offsets.into()),
+        })
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    /// The values between two consecutive elements of every dimension. The stride of the batch
+    /// doesn't apply to gathered rows.
+    pub fn strides(&self) -> &[usize] {
+        &self.strides
+    }
+
+    pub fn dtype(&self) -> DType {
+        self.dtype
+    }
+
+    /// The amount of values in the tensor
+    pub fn len(&self) -> usize {
+        self.shape.iter().product()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The amount of samples, the size of the first dimension
+    pub fn batch_size(&self) -> usize {
+        self.shape[0]
+    }
+
+    /// The amount of values of every sample
+    pub fn sample_size(&self) -> usize {
+        self.shape[1..].iter().product()
+    }
+
+    pub fn data(&self) -> &DualVec {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut DualVec {
+        &mut self.data
+    }
+
+    pub fn into_data(self) -> DualVec {
+        self.data
+    }
+
+    /// The position of the first value in the vector, which gathered rows don't use
+    pub(crate) fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// The offsets of the rows picked by [Tensor::gather]
+    pub(crate) fn rows(&self) -> Option<&[usize]> {
+        self.rows.as_deref()
+    }
+
+    /// Whether the values are laid out in row major order without gaps from the start of the vector
+    pub fn is_packed(&self) -> bool {
+        self.rows.is_none() && self.offset == 0 && self.is_contiguous()
+    }
+
+    /// Whether the values are laid out in row major order without gaps
+    pub fn is_contiguous(&self) -> bool {
+        self.rows.is_none() && self.strides == row_major(&self.shape)
+    }
+
+    /// The same values in another shape, which needs the values to be contiguous
+    pub fn reshape(&self, shape: &[usize]) -> Result<Tensor, Error> {
+        if !self.is_contiguous() {
+            return Err(Error::Tensor(TensorError::NotContiguous));
+        }
+        if shape.is_empty() || shape.iter().product::<usize>() != self.len() {
+            return Err(Error::Tensor(TensorError::Shape(shape.to_vec(), self.len())));
+        }
+
+        Ok(Tensor {
+            shape: shape.to_vec(),
+            strides: row_major(shape),
+            ..self.clone()
+        })
+    }
+
+    /// A view with the dimensions `a` and `b` swapped. The batch can't be swapped once its rows
+    /// are gathered.
+    pub fn transpose(&self, a: usize, b: usize) -> Result<Tensor, Error> {
+        let dims = self.shape.len();
+        if a >= dims || b >= dims {
+            return Err(Error::Tensor(TensorError::Dimension(a.max(b), dims)));
+        }
+        if self.rows.is_some() && (a == 0 || b == 0) {
+            return Err(Error::Tensor(TensorError::NotContiguous));
+        }
+
+        let mut view = self.clone();
+        view.shape.swap(a, b);
+        view.strides.swap(a, b);
+        Ok(view)
+    }
+
+    /// A view of the samples in `range`
+    pub fn slice_rows(&self, range: Range<usize>) -> Result<Tensor, Error> {
+        if range.start > range.end || range.end > self.batch_size() {
+            return Err(Error::Tensor(TensorError::Row(range.end, self.batch_size())));
+        }
+
+        let mut view = self.clone();
+        match &self.rows {
+            Some(rows) => view.rows = Some(rows[range.clone()].into()),
+            None => view.offset += range.start * self.strides[0],
+        }
+        view.shape[0] = range.len();
+        Ok(view)
+    }
+
+    /// A view of the samples at `indices`, in their order
+    pub fn gather(&self, indices: &[usize]) -> Result<Tensor, Error> {
+        if let Some(index) = indices.iter().find(|index| **index >= self.batch_size()) {
+            return Err(Error::Tensor(TensorError::Row(*index, self.batch_size())));
+        }
+
+        let mut view = self.clone();
+        view.rows = Some(indices.iter().map(|index| self.row_offset(*index)).collect());
+        view.shape[0] = indices.len();
+        Ok(view)
+    }
+
+    /// The offset of the first value of the sample `row` in the vector
+    pub fn row_offset(&self, row: usize) -> usize {
+        match &self.rows {
+            Some(rows) => rows[row],
+            None => self.offset + row * self.strides[0],
+        }
+    }
+
+    /// The offsets of every sample in the vector, where the layers expect the values of every
+    /// sample to be contiguous
+    pub fn row_offsets(&self) -> Vec<usize> {
+        debug_assert!(self.strides[1..] == row_major(&self.shape[1..]), "The samples aren't contiguous");
+        (0..self.batch_size()).map(|row| self.row_offset(row)).collect()
+    }
+
+    /// The offsets of every sample, or `None` when the samples are packed from the start of the
+    /// vector, where the layers compute the offsets themselves
+    pub fn gather_offsets(&self) -> Option<Vec<usize>> {
+        (!self.is_packed()).then(|| self.row_offsets())
+    }
+
+    /// Copies the values of the view into a new vector in row major order
+    pub fn to_vec(&mut self) -> Vec<f32> {
+        let offsets: Vec<usize> = (0..self.batch_size()).map(|row| self.row_offset(row)).collect();
+        // the offset of every value of a sample from the start of the sample
+        let inner: Vec<usize> = (0..self.sample_size()).map(|mut rest| {
+            let mut offset = 0;
+            for dim in (1..self.shape.len()).rev() {
+                offset += rest % self.shape[dim] * self.strides[dim];
+                rest /= self.shape[dim];
+            }
+            offset
+        }).collect();
+
+        let values = self.data.cpu_borrow().unwrap();
+        let values = &values;
+        offsets.iter()
+            .flat_map(|row| inner.iter().map(move |i| values[row + i]))
+            .collect()
+    }
+}
+
+/// The strides of `shape` laid out in row major order
+fn row_major(shape: &[usize]) -> Vec<usize> {
+    let mut strides = vec![1; shape.len()];
+    for i in (0..shape.len().saturating_sub(1)).rev() {
+        strides[i] = strides[i + 1] * shape[i + 1];
+    }
+    strides
+}
@@ -1,8 +1,12 @@
-use ocl::{Buffer, Kernel, ProQue, SpatialDims};
+use ocl::{Buffer, Device, Event, Kernel, ProQue, SpatialDims};
+use ocl::builders::KernelCmd;
+use ocl::enums::{DeviceInfo, DeviceInfoResult, KernelWorkGroupInfo, KernelWorkGroupInfoResult};
 use ocl::enums::WriteSrc;
 use ocl::traits::WorkDims;
 use rand::random;
 
+use crate::gpu::Gpu;
+
 pub fn new_buffer<T: ocl::OclPrm>(pro_que: &ProQue, size: usize) -> Buffer<T> {
     new_buffer_f(pro_que, size, T::default())
 }
@@ -31,8 +35,8 @@ pub fn buf_write<'c, 'd, T, W>(buffer: &Buffer<T>, values: W) where 'd: 'c, W: I
     buffer.write(values).enq().expect("Failed to write network_old inputs");
 }
 
-pub fn randomize_buffer(buffer: &Buffer<f32>, max_work_size: u32, div: f32, pro_que: &ProQue) {
-    let rnd_kernel = pro_que
+pub fn randomize_buffer(buffer: &Buffer<f32>, max_work_size: u32, div: f32, gpu: &Gpu) {
+    let rnd_kernel = gpu
         .kernel_builder("random_buf")
         .arg(buffer)
         .arg(random::<u64>())
@@ -45,7 +49,7 @@ pub fn randomize_buffer(buffer: &Buffer<f32>, max_work_size: u32, div: f32, pro_
             .cmd()
             .global_work_size(buffer.len())
             .local_work_size(calc_ws(max_work_size as usize, buffer.len()))
-            .enq()
+            .enq_profiled(&rnd_kernel, gpu)
             .expect("Failed to enq rnd_kernel")
     }
 }
@@ -62,8 +66,15 @@ pub fn calc_ws(max: usize, size: usize) -> usize {
     calc
 }
 
-pub unsafe fn execute_kernel<SD: Into<SpatialDims>>(pro_que: &ProQue, kernel: &Kernel, size: SD) {
-    let max_wg = pro_que.max_wg_size().expect("Failed to get max workgroup size");
+/// Runs `kernel` over `size` in the largest work groups which divide it
+///
+/// # Safety
+///
+/// Every argument of `kernel` must be set, and the buffers it uses must hold as many values as
+/// the kernel reads and writes over `size`. The kernel runs asynchronously, so they must not be
+/// released before it completes.
+pub unsafe fn execute_kernel<SD: Into<SpatialDims>>(gpu: &Gpu, kernel: &Kernel, size: SD) {
+    let max_wg = kernel_wg_size(gpu, kernel);
     let size = size.into();
     let sizes = size.to_work_size().expect("Failed to convert SpatialDims to work sizes");
 
@@ -87,7 +98,171 @@ pub unsafe fn execute_kernel<SD: Into<SpatialDims>>(pro_que: &ProQue, kernel: &K
             .cmd()
             .global_work_size(size)
             .local_work_size(wg_size)
-            .enq()
+            .enq_profiled(kernel, gpu)
             .expect("Failed to enqueue activation kernel");
     }
-}
\ No newline at end of file
+}
+
+/// The limits of a device which decide the sizes of the work groups of the kernels
+#[derive(Clone, Copy, Debug)]
+pub struct DeviceLimits {
+    pub max_wg_size: usize,
+    /// The most work items of a work group in the first two dimensions
+    pub max_item_sizes: [usize; 2],
+    /// The bytes of `__local` memory available to a work group
+    pub local_mem_size: usize,
+}
+
+impl DeviceLimits {
+    pub fn of(device: &Device) -> Result<Self, ocl::Error> {
+        let max_item_sizes = match device.info(DeviceInfo::MaxWorkItemSizes)? {
+            DeviceInfoResult::MaxWorkItemSizes(sizes) => [sizes[0], *sizes.get(1).unwrap_or(&1)],
+            _ => [1, 1],
+        };
+        let local_mem_size = match device.info(DeviceInfo::LocalMemSize)? {
+            DeviceInfoResult::LocalMemSize(size) => size as usize,
+            _ => 0,
+        };
+
+        Ok(DeviceLimits {
+            max_wg_size: device.max_wg_size()?,
+            max_item_sizes,
+            local_mem_size,
+        })
+    }
+
+    /// The width of the square work groups of the matrix product kernels. It is the largest power of
+    /// two up to 32 where the work group and its two local tiles of `tile * (tile + 1)` values fit the device.
+    pub fn tile(&self) -> usize {
+        let mut tile = 32;
+        while tile > 1 && (tile * tile > self.max_wg_size
+            || tile > self.max_item_sizes[0].min(self.max_item_sizes[1])
+            || tile_bytes(tile) > self.local_mem_size) {
+            tile /= 2;
+        }
+        tile
+    }
+
+    /// The work items of the tree reductions, the largest power of two up to 256 where the work group
+    /// and its local sums fit the device
+    pub fn reduction(&self) -> usize {
+        let mut width = 256;
+        while width > 1 && (width > self.max_wg_size.min(self.max_item_sizes[0])
+            || width * size_of::<f32>() > self.local_mem_size) {
+            width /= 2;
+        }
+        width
+    }
+
+    /// The [DeviceLimits::tile] made smaller until its work group fits a kernel which can run at most
+    /// `kernel_wg_size` work items at once
+    pub fn kernel_tile(&self, kernel_wg_size: usize) -> usize {
+        let mut tile = self.tile();
+        while tile > 1 && tile * tile > kernel_wg_size {
+            tile /= 2;
+        }
+        tile
+    }
+
+    /// The [DeviceLimits::reduction] made smaller until it fits a kernel which can run at most
+    /// `kernel_wg_size` work items at once
+    pub fn kernel_reduction(&self, kernel_wg_size: usize) -> usize {
+        let mut reduction = self.reduction();
+        while reduction > 1 && reduction > kernel_wg_size {
+            reduction /= 2;
+        }
+        reduction
+    }
+}
+
+/// The bytes of `__local` memory used by the two tiles of a matrix product kernel
+fn tile_bytes(tile: usize) -> usize {
+    2 * tile * (tile + 1) * size_of::<f32>()
+}
+
+/// The most work items `kernel` can be run with in a work group, which can be less than the
+/// limit of the device when the kernel needs a lot of registers
+pub fn kernel_wg_size(pro_que: &ProQue, kernel: &Kernel) -> usize {
+    let max_wg = pro_que.max_wg_size().expect("Failed to get max workgroup size");
+    match kernel.wg_info(pro_que.device(), KernelWorkGroupInfo::WorkGroupSize) {
+        Ok(KernelWorkGroupInfoResult::WorkGroupSize(size)) => size.min(max_wg),
+        _ => max_wg,
+    }
+}
+
+/// Runs `kernel` over `size` rounded up to whole square work groups of [Gpu::tile], where the kernel
+/// has to skip the work items outside of `size`. The tiles are made smaller when the kernel can't
+/// run work groups that large, so its local buffers sized for [Gpu::tile] are always big enough.
+///
+/// # Safety
+///
+/// Every argument of `kernel` must be set, and the buffers it uses must hold as many values as
+/// the kernel reads and writes over `size`. The kernel runs asynchronously, so they must not be
+/// released before it completes.
+pub unsafe fn execute_tiled(gpu: &Gpu, kernel: &Kernel, size: (usize, usize)) {
+    let tile = gpu.limits().kernel_tile(kernel_wg_size(gpu, kernel));
+
+    unsafe {
+        kernel
+            .cmd()
+            .global_work_size((size.0.next_multiple_of(tile), size.1.next_multiple_of(tile)))
+            .local_work_size((tile, tile))
+            .enq_profiled(kernel, gpu)
+            .expect("Failed to enqueue tiled kernel");
+    }
+}
+
+/// Runs `kernel` with a work group of at most [Gpu::reduction] work items for every one of the
+/// `groups` sums, repeated for `width` columns
+///
+/// # Safety
+///
+/// Every argument of `kernel` must be set, and the buffers it uses must hold as many values as
+/// the kernel reads and writes for every sum. The kernel runs asynchronously, so they must not be
+/// released before it completes.
+pub unsafe fn execute_reduction(gpu: &Gpu, kernel: &Kernel, groups: usize, width: usize) {
+    let reduction = gpu.limits().kernel_reduction(kernel_wg_size(gpu, kernel));
+
+    unsafe {
+        kernel
+            .cmd()
+            .global_work_size((groups * reduction, width))
+            .local_work_size((reduction, 1))
+            .enq_profiled(kernel, gpu)
+            .expect("Failed to enqueue reduction kernel");
+    }
+}
+
+/// A device buffer of the offsets of every sample of a batch, so kernels can gather the samples themselves
+pub fn positions_buffer(pro_que: &ProQue, positions: &[usize]) -> Buffer<u64> {
+    indices_buffer(pro_que, positions)
+}
+
+/// A device buffer of indices, which always holds at least one value as buffers can't be empty
+pub fn indices_buffer(pro_que: &ProQue, indices: &[usize]) -> Buffer<u64> {
+    let indices: Vec<u64> = indices.iter().map(|i| *i as u64).collect();
+    let buffer = new_buffer(pro_que, indices.len().max(1));
+    if !indices.is_empty() {
+        buf_write(&buffer, &indices);
+    }
+    buffer
+}
+
+/// Enqueues kernel commands, recording the time the device spends running them while `gpu`
+/// is [profiled](crate::Executor::enable_profiling)
+pub(crate) trait ProfiledEnq {
+    unsafe fn enq_profiled(self, kernel: &Kernel, gpu: &Gpu) -> ocl::Result<()>;
+}
+
+impl ProfiledEnq for KernelCmd<'_> {
+    unsafe fn enq_profiled(self, kernel: &Kernel, gpu: &Gpu) -> ocl::Result<()> {
+        if !gpu.profiler().enabled() {
+            return unsafe { self.enq() };
+        }
+
+        let mut event = Event::empty();
+        unsafe { self.enew(&mut event).enq()? };
+        gpu.profiler().kernel(kernel.name().unwrap_or_default(), event);
+        Ok(())
+    }
+}
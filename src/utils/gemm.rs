@@ -0,0 +1,314 @@
+use std::cell::RefCell;
+use std::sync::OnceLock;
+
+use crate::utils::parallel;
+
+/// Rows of `a` computed together by the micro kernel
+const MR: usize = 4;
+/// Columns of `b` computed together by the micro kernel
+const NR: usize = 16;
+/// The depth of the panels, chosen so a panel of `b` stays in the L1 cache
+const KC: usize = 256;
+/// Rows of `a` packed at once, chosen so the packed block stays in the L2 cache
+const MC: usize = 64;
+/// Columns of `b` packed at once
+const NC: usize = 1024;
+
+/// A read only view of a matrix of any layout, so transposed matrices can be multiplied
+/// without copying them first.
+#[derive(Clone, Copy, Debug)]
+pub struct Matrix<'a> {
+    values: &'a [f32],
+    rows: usize,
+    cols: usize,
+    row_stride: usize,
+    col_stride: usize,
+}
+
+impl<'a> Matrix<'a> {
+    /// A row major matrix
+    pub fn new(values: &'a [f32], rows: usize, cols: usize) -> Self {
+        Matrix {
+            values,
+            rows,
+            cols,
+            row_stride: cols,
+            col_stride: 1,
+        }
+    }
+
+    /// A matrix of any layout, where the value at `(row, col)` is at `row * row_stride + col * col_stride`
+    pub fn strided(values: &'a [f32], rows: usize, cols: usize, row_stride: usize, col_stride: usize) -> Self {
+        Matrix {
+            values,
+            rows,
+            cols,
+            row_stride,
+            col_stride,
+        }
+    }
+
+    /// The transposed matrix, which views the same values
+    pub fn t(self) -> Self {
+        Matrix {
+            values: self.values,
+            rows: self.cols,
+            cols: self.rows,
+            row_stride: self.col_stride,
+            col_stride: self.row_stride,
+        }
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    /// The rows starting at `first`
+    fn skip_rows(self, first: usize) -> Self {
+        let offset = (first * self.row_stride).min(self.values.len());
+        Matrix {
+            values: &self.values[offset..],
+            rows: self.rows - first,
+            ..self
+        }
+    }
+
+    fn at(&self, row: usize, col: usize) -> f32 {
+        self.values[row * self.row_stride + col * self.col_stride]
+    }
+}
+
+/// The micro kernel used for the products, chosen from the features of the CPU at runtime
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kernel {
+    Scalar,
+    #[cfg(target_arch = "x86_64")]
+    Avx2,
+    #[cfg(target_arch = "aarch64")]
+    Neon,
+}
+
+impl Kernel {
+    /// The fastest kernel the CPU supports
+    pub fn detect() -> Kernel {
+        static KERNEL: OnceLock<Kernel> = OnceLock::new();
+        *KERNEL.get_or_init(|| {
+            #[cfg(target_arch = "x86_64")]
+            if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+                return Kernel::Avx2;
+            }
+            #[cfg(target_arch = "aarch64")]
+            if std::arch::is_aarch64_feature_detected!("neon") {
+                return Kernel::Neon;
+            }
+            Kernel::Scalar
+        })
+    }
+
+    /// Every kernel the CPU supports, such as to compare the SIMD kernel with the scalar one
+    pub fn available() -> Vec<Kernel> {
+        let mut kernels = vec![Kernel::Scalar];
+        if Kernel::detect() != Kernel::Scalar {
+            kernels.push(Kernel::detect());
+        }
+        kernels
+    }
+
+    /// `acc = a * b` for a packed `[kc, MR]` panel of `a` and a packed `[kc, NR]` panel of `b`
+    fn run(self, kc: usize, a: &[f32], b: &[f32], acc: &mut [f32; MR * NR]) {
+        match self {
+            Kernel::Scalar => kernel_scalar(kc, a, b, acc),
+            // The features were detected before choosing the kernel
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx2 => unsafe { kernel_avx2(kc, a, b, acc) },
+            #[cfg(target_arch = "aarch64")]
+            Kernel::Neon => unsafe { kernel_neon(kc, a, b, acc) },
+        }
+    }
+}
+
+fn kernel_scalar(kc: usize, a: &[f32], b: &[f32], acc: &mut [f32; MR * NR]) {
+    acc.fill(0.);
+    for p in 0..kc {
+        let a = &a[p * MR..(p + 1) * MR];
+        let b = &b[p * NR..(p + 1) * NR];
+        for i in 0..MR {
+            for j in 0..NR {
+                acc[i * NR + j] += a[i] * b[j];
+            }
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2,fma")]
+fn kernel_avx2(kc: usize, a: &[f32], b: &[f32], acc: &mut [f32; MR * NR]) {
+    use std::arch::x86_64::*;
+
+    assert!(a.len() >= kc * MR && b.len() >= kc * NR);
+    let mut c = [[_mm256_setzero_ps(); 2]; MR];
+    for p in 0..kc {
+        // The panels hold `kc` rows, which was checked above
+        let (b0, b1) = unsafe {
+            let b = b.as_ptr().add(p * NR);
+            (_mm256_loadu_ps(b), _mm256_loadu_ps(b.add(8)))
+        };
+        for i in 0..MR {
+            let a = _mm256_set1_ps(a[p * MR + i]);
+            c[i][0] = _mm256_fmadd_ps(a, b0, c[i][0]);
+            c[i][1] = _mm256_fmadd_ps(a, b1, c[i][1]);
+        }
+    }
+    for (i, row) in c.iter().enumerate() {
+        unsafe {
+            let acc = acc.as_mut_ptr().add(i * NR);
+            _mm256_storeu_ps(acc, row[0]);
+            _mm256_storeu_ps(acc.add(8), row[1]);
+        }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+fn kernel_neon(kc: usize, a: &[f32], b: &[f32], acc: &mut [f32; MR * NR]) {
+    use std::arch::aarch64::*;
+
+    assert!(a.len() >= kc * MR && b.len() >= kc * NR);
+    let mut c = [[vdupq_n_f32(0.); NR / 4]; MR];
+    for p in 0..kc {
+        let mut bs = [vdupq_n_f32(0.); NR / 4];
+        for q in 0..NR / 4 {
+            // The panels hold `kc` rows, which was checked above
+            bs[q] = unsafe { vld1q_f32(b.as_ptr().add(p * NR + q * 4)) };
+        }
+        for i in 0..MR {
+            let a = a[p * MR + i];
+            for q in 0..NR / 4 {
+                c[i][q] = vfmaq_n_f32(c[i][q], bs[q], a);
+            }
+        }
+    }
+    for (i, row) in c.iter().enumerate() {
+        for (q, values) in row.iter().enumerate() {
+            unsafe { vst1q_f32(acc.as_mut_ptr().add(i * NR + q * 4), *values) };
+        }
+    }
+}
+
+/// Packs `[kc, mc]` values of `a` into panels of `MR` rows, padding the last panel with zeros
+fn pack_a(a: &Matrix, row: usize, mc: usize, depth: usize, kc: usize, packed: &mut [f32]) {
+    for panel in 0..mc.div_ceil(MR) {
+        let packed = &mut packed[panel * kc * MR..(panel + 1) * kc * MR];
+        for p in 0..kc {
+            for i in 0..MR {
+                let r = panel * MR + i;
+                packed[p * MR + i] = if r < mc { a.at(row + r, depth + p) } else { 0. };
+            }
+        }
+    }
+}
+
+/// Packs `[kc, nc]` values of `b` into panels of `NR` columns, padding the last panel with zeros
+fn pack_b(b: &Matrix, depth: usize, kc: usize, col: usize, nc: usize, packed: &mut [f32]) {
+    for panel in 0..nc.div_ceil(NR) {
+        let packed = &mut packed[panel * kc * NR..(panel + 1) * kc * NR];
+        for p in 0..kc {
+            for j in 0..NR {
+                let c = panel * NR + j;
+                packed[p * NR + j] = if c < nc { b.at(depth + p, col + c) } else { 0. };
+            }
+        }
+    }
+}
+
+thread_local! {
+    /// The packed blocks of `a` and `b` of every thread, kept between products so small products,
+    /// such as those of a single position, don't allocate them every time
+    static PACKED: RefCell<(Vec<f32>, Vec<f32>)> = const { RefCell::new((Vec::new(), Vec::new())) };
+}
+
+/// `c += alpha * a * b` for the rows of `c` given to a single thread
+fn gemm_block(kernel: Kernel, alpha: f32, a: Matrix, b: Matrix, c: &mut [f32]) {
+    PACKED.with_borrow_mut(|(packed_a, packed_b)| {
+        // every value of the blocks used is packed before it is read, so the buffers are only grown
+        let n = b.cols;
+        if packed_a.len() < MC * KC {
+            packed_a.resize(MC * KC, 0.);
+        }
+        let b_len = KC * NC.min(n.next_multiple_of(NR));
+        if packed_b.len() < b_len {
+            packed_b.resize(b_len, 0.);
+        }
+        gemm_packed(kernel, alpha, a, b, c, packed_a, packed_b);
+    });
+}
+
+fn gemm_packed(kernel: Kernel, alpha: f32, a: Matrix, b: Matrix, c: &mut [f32], packed_a: &mut [f32], packed_b: &mut [f32]) {
+    let (n, k) = (b.cols, a.cols);
+    let m = c.len() / n;
+    let mut acc = [0.; MR * NR];
+
+    for jc in (0..n).step_by(NC) {
+        let nc = NC.min(n - jc);
+        for pc in (0..k).step_by(KC) {
+            let kc = KC.min(k - pc);
+            pack_b(&b, pc, kc, jc, nc, packed_b);
+
+            for ic in (0..m).step_by(MC) {
+                let mc = MC.min(m - ic);
+                pack_a(&a, ic, mc, pc, kc, packed_a);
+
+                for jr in (0..nc).step_by(NR) {
+                    let b_panel = &packed_b[(jr / NR) * kc * NR..(jr / NR + 1) * kc * NR];
+                    for ir in (0..mc).step_by(MR) {
+                        let a_panel = &packed_a[(ir / MR) * kc * MR..(ir / MR + 1) * kc * MR];
+                        kernel.run(kc, a_panel, b_panel, &mut acc);
+
+                        for i in 0..MR.min(mc - ir) {
+                            let row = &mut c[(ic + ir + i) * n + jc + jr..];
+                            for j in 0..NR.min(nc - jr) {
+                                row[j] += alpha * acc[i * NR + j];
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// `c = alpha * a * b + beta * c`, where `c` is a row major `[a.rows(), b.cols()]` matrix.
+///
+/// The products are computed in blocks that stay in the caches, with a micro kernel using the
+/// SIMD instructions available at runtime. The rows of `c` are split between `threads` threads,
+/// and every value is summed in the same order for any amount of threads.
+pub fn gemm(threads: usize, alpha: f32, a: Matrix, b: Matrix, beta: f32, c: &mut [f32]) {
+    gemm_with(Kernel::detect(), threads, alpha, a, b, beta, c)
+}
+
+/// [gemm] with the micro kernel `kernel`, which has to be one of [Kernel::available]
+pub fn gemm_with(kernel: Kernel, threads: usize, alpha: f32, a: Matrix, b: Matrix, beta: f32, c: &mut [f32]) {
+    assert!(kernel == Kernel::Scalar || kernel == Kernel::detect(), "The CPU does not support the {:?} kernel", kernel);
+    assert_eq!(a.cols, b.rows, "The inner dimensions of the matrices do not match");
+    let (m, n, k) = (a.rows, b.cols, a.cols);
+    let c = &mut c[..m * n];
+
+    if beta == 0. {
+        c.fill(0.);
+    } else if beta != 1. {
+        for v in c.iter_mut() {
+            *v *= beta;
+        }
+    }
+    if m == 0 || n == 0 || k == 0 {
+        return;
+    }
+
+    parallel::for_rows(threads, c, n, MR, k, |row, c| {
+        gemm_block(kernel, alpha, a.skip_rows(row), b, c);
+    });
+}
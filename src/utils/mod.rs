@@ -1,3 +1,4 @@
 pub mod cl_utils;
-pub mod gpu_math;
+pub mod gemm;
+pub mod parallel;
 pub mod vec_utils;
\ No newline at end of file
@@ -0,0 +1,33 @@
+use std::thread;
+
+/// The least amount of work, in multiply-adds, that is worth starting another thread for
+const MIN_THREAD_WORK: usize = 1 << 14;
+
+/// Splits `values` into a contiguous chunk per thread, calling `f` with the index of the first
+/// value of every chunk along with the chunk. `cost` is the work needed for every value, which
+/// limits the amount of threads for small layers.
+///
+/// Every value is computed by a single thread, so the results don't depend on the thread count.
+pub fn for_chunks<T: Send, F>(threads: usize, values: &mut [T], cost: usize, f: F) where F: Fn(usize, &mut [T]) + Sync {
+    for_rows(threads, values, 1, 1, cost, f)
+}
+
+/// Like [for_chunks], but only splits `values` between whole rows of `row_len` values, and gives
+/// every thread a multiple of `align` rows. `f` is called with the index of the first row.
+pub fn for_rows<T: Send, F>(threads: usize, values: &mut [T], row_len: usize, align: usize, cost: usize, f: F) where F: Fn(usize, &mut [T]) + Sync {
+    let rows = values.len() / row_len.max(1);
+    let work = values.len() * cost.max(1);
+    let threads = threads.min(work / MIN_THREAD_WORK).min(rows.div_ceil(align.max(1))).max(1);
+    if threads == 1 {
+        f(0, values);
+        return;
+    }
+
+    let chunk = rows.div_ceil(threads).next_multiple_of(align.max(1));
+    thread::scope(|scope| {
+        let f = &f;
+        for (i, values) in values.chunks_mut(chunk * row_len).enumerate() {
+            scope.spawn(move || f(i * chunk, values));
+        }
+    });
+}
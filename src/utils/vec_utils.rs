@@ -1,34 +1,92 @@
 use std::io::{Cursor, Read};
 
 pub struct CursorReader<'a> {
-    cursor: Cursor<&'a [u8]>
+    cursor: Cursor<&'a [u8]>,
+    /// The bytes read beyond the end, which read as zeros
+    past_end: usize,
 }
 
 impl<'a> CursorReader<'a> {
     pub fn new(bytes: &'a [u8]) -> Self {
         Self {
             cursor: Cursor::new(bytes),
+            past_end: 0,
+        }
+    }
+
+    fn read(&mut self, b: &mut [u8]) {
+        let left = self.cursor.get_ref().len().saturating_sub(self.pos());
+        if self.cursor.read_exact(b).is_err() {
+            self.past_end += b.len() - left;
         }
     }
 
     pub fn f32(&mut self) -> f32 {
         let mut b = [0u8; 4];
-        self.cursor.read_exact(&mut b);
+        self.read(&mut b);
         f32::from_be_bytes(b)
     }
 
     pub fn usize(&mut self) -> usize {
         let mut b = [0u8; 8];
-        self.cursor.read_exact(&mut b);
+        self.read(&mut b);
         usize::from_be_bytes(b)
     }
 
     pub fn i32(&mut self) -> i32 {
         let mut b = [0u8; 4];
-        self.cursor.read_exact(&mut b);
+        self.read(&mut b);
         i32::from_be_bytes(b)
     }
 
+    pub fn u32(&mut self) -> u32 {
+        let mut b = [0u8; 4];
+        self.read(&mut b);
+        u32::from_be_bytes(b)
+    }
+
+    pub fn bool(&mut self) -> bool {
+        self.usize() != 0
+    }
+
+    /// A string written by [VecWriter::string], which is empty when there are fewer bytes left than
+    /// its length, so that a damaged length isn't allocated
+    pub fn string(&mut self) -> String {
+        let len = self.usize();
+        let left = self.cursor.get_ref().len().saturating_sub(self.pos());
+        if len > left {
+            self.cursor.set_position(self.cursor.get_ref().len() as u64);
+            self.past_end += len - left;
+            return String::new();
+        }
+        let mut b = vec![0u8; len];
+        self.read(&mut b);
+        String::from_utf8_lossy(&b).into_owned()
+    }
+
+    /// Bytes written by [VecWriter::bytes], or `None` when there are fewer bytes left than their length
+    pub fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = self.usize();
+        let all: &'a [u8] = self.cursor.get_ref();
+        let bytes = all.get(self.pos()..self.pos().checked_add(len)?)?;
+        self.cursor.set_position((self.pos() + len) as u64);
+        Some(bytes)
+    }
+
+    /// Reads a count followed by that many values, stopping at the end of the bytes so that a count
+    /// read from damaged bytes doesn't run on
+    pub fn counted<T>(&mut self, mut read: impl FnMut(&mut Self) -> T) -> Vec<T> {
+        let mut values = vec![];
+        for _ in 0..self.usize() {
+            let value = read(self);
+            if self.past_end > 0 {
+                break;
+            }
+            values.push(value);
+        }
+        values
+    }
+
     pub fn indexed<T: From<usize>>(&mut self) -> T {
         T::from(self.usize())
     }
@@ -40,6 +98,11 @@ impl<'a> CursorReader<'a> {
     pub fn pos(&self) -> usize {
         self.cursor.position() as usize
     }
+
+    /// The amount of bytes read beyond the end of the bytes
+    pub fn past_end(&self) -> usize {
+        self.past_end
+    }
 }
 
 pub struct VecWriter {
@@ -75,6 +138,26 @@ impl VecWriter {
         self.write(&v.to_be_bytes())
     }
 
+    pub fn u32(&mut self, v: u32) {
+        self.write(&v.to_be_bytes())
+    }
+
+    pub fn bool(&mut self, v: bool) {
+        self.usize(v as usize)
+    }
+
+    /// Writes the length of the bytes followed by the bytes
+    pub fn bytes(&mut self, v: &[u8]) {
+        self.usize(v.len());
+        self.vec.extend_from_slice(v)
+    }
+
+    /// Writes the length of the string followed by its UTF-8 bytes
+    pub fn string(&mut self, v: &str) {
+        self.usize(v.len());
+        self.write(v.as_bytes())
+    }
+
     pub fn index<T: Into<usize>>(&mut self, v: T) {
         let v= v.into();
         self.usize(v);
@@ -84,6 +167,11 @@ impl VecWriter {
         self.vec.len()
     }
 
+    /// The bytes written so far
+    pub fn as_slice(&self) -> &[u8] {
+        &self.vec
+    }
+
     pub fn reserve(&mut self, len: usize) {
         self.vec.reserve(len);
     }
@@ -0,0 +1,92 @@
+mod common;
+
+use std::sync::Arc;
+
+use neurox::builder::NetworkBuilder;
+use neurox::dual_vec::DualVec;
+use neurox::error::{Error, MismatchError, NetworkError};
+use neurox::layer::attention::Attention;
+use neurox::loss::Loss;
+use neurox::network::{Network, Padded};
+use neurox::shape::Shape;
+use neurox::Executor::CPU;
+use neurox::{Execs, Optimizer};
+use common::{assert_close, gradient_error, predictions};
+
+const SEQUENCE: usize = 3;
+const CHARACTERISTICS: usize = 4;
+
+fn attention(causal: bool) -> Network {
+    NetworkBuilder::new([SEQUENCE, CHARACTERISTICS]).attention(2, 3, 5, causal).build().unwrap()
+}
+
+fn sequence() -> Vec<f32> {
+    (0..SEQUENCE * CHARACTERISTICS).map(|i| ((i * 5 % 7) as f32 - 3.) / 3.).collect()
+}
+
+#[test]
+fn outputs_have_a_row_per_position() {
+    let builder = NetworkBuilder::new([SEQUENCE, CHARACTERISTICS]).attention(2, 3, 5, false);
+    assert_eq!(builder.shape(), &Shape::from([SEQUENCE, 5]));
+
+    let mut network = builder.build().unwrap();
+    let mut inputs: Vec<f32> = sequence();
+    inputs.extend(sequence().iter().rev());
+    assert_eq!(predictions(&mut network, &mut DualVec::from_vec((&CPU, &CPU), inputs)).len(), 2 * SEQUENCE * 5);
+}
+
+#[test]
+fn gradients_match_finite_differences() {
+    let mut inputs = sequence();
+    inputs.extend(sequence().iter().map(|v| v * -0.5));
+    for causal in [false, true] {
+        let mut layer = Attention::new(&Execs::all(&Arc::new(CPU)), SEQUENCE * CHARACTERISTICS, 2, 3, CHARACTERISTICS, 5, causal);
+        let error = gradient_error(&mut layer, &inputs);
+        assert!(error < 1e-2, "The gradients of causal ({}) attention differ from finite differences by {}", causal, error);
+    }
+}
+
+#[test]
+fn steps_match_the_causal_sequence() {
+    let mut network = attention(true);
+    let inputs = sequence();
+    let expected = predictions(&mut network, &mut DualVec::from_vec((&CPU, &CPU), inputs.clone()));
+
+    let mut state = network.incremental_state();
+    let mut stepped = vec![];
+    for position in inputs.chunks(CHARACTERISTICS) {
+        let mut outputs = network.step(&mut state, &mut DualVec::from_vec((&CPU, &CPU), position.to_vec())).unwrap();
+        stepped.extend(outputs.cpu_borrow().unwrap().iter());
+    }
+    assert_close(&stepped, &expected, 1e-5);
+}
+
+#[test]
+fn steps_beyond_the_sequence_are_rejected() {
+    let mut network = attention(true);
+    let mut state = network.incremental_state();
+    for position in sequence().chunks(CHARACTERISTICS) {
+        network.step(&mut state, &mut DualVec::from_vec((&CPU, &CPU), position.to_vec())).unwrap();
+    }
+
+    let stepped = network.step(&mut state, &mut DualVec::from_vec((&CPU, &CPU), vec![0.; CHARACTERISTICS]));
+    assert!(matches!(stepped, Err(Error::Network(NetworkError::Position(SEQUENCE, SEQUENCE)))));
+}
+
+#[test]
+fn attention_which_isnt_causal_cant_step() {
+    let mut network = attention(false);
+    let stepped = network.step(&mut network.incremental_state(), &mut DualVec::from_vec((&CPU, &CPU), vec![0.; CHARACTERISTICS]));
+    assert!(matches!(stepped, Err(Error::Network(NetworkError::NotCausal))));
+}
+
+#[test]
+fn padded_samples_need_a_length_each() {
+    let mut network = attention(false);
+    let mut inputs = DualVec::from_vec((&CPU, &CPU), [sequence(), sequence()].concat());
+    let mut targets = DualVec::from_vec((&CPU, &CPU), vec![0.5; 2 * SEQUENCE * 5]);
+
+    let trained = network.train_padded(Padded::new(&mut inputs, &[SEQUENCE]), &mut targets, Optimizer::GradientDecent(0.1), Loss::MeanSquared, 1, 2);
+    assert!(matches!(trained, Err(Error::Mismatch(MismatchError::Sample(2, 1)))));
+    network.train_padded(Padded::new(&mut inputs, &[SEQUENCE, 1]), &mut targets, Optimizer::GradientDecent(0.1), Loss::MeanSquared, 1, 2).unwrap();
+}
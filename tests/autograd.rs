@@ -0,0 +1,105 @@
+use std::rc::Rc;
+
+use neurox::activation::Activation::TanH;
+use neurox::autograd::{check_gradients, Tape, Var};
+use neurox::builder::NetworkBuilder;
+use neurox::dual_vec::DualVec;
+use neurox::error::{DecodeError, Error};
+use neurox::layer::autograd::Definition;
+use neurox::loss::Loss;
+use neurox::network::Network;
+use neurox::tensor::Tensor;
+use neurox::Executor::CPU;
+use neurox::Optimizer;
+
+fn tensor(values: &[f32], shape: &[usize]) -> Tensor {
+    Tensor::new(DualVec::from_vec((&CPU, &CPU), values.to_vec()), shape).unwrap()
+}
+
+/// A dense layer without an activation, `inputs * weights + biases`
+#[derive(Debug)]
+struct Linear(usize);
+
+impl Definition for Linear {
+    fn parameters(&self, inputs: usize) -> Vec<Vec<usize>> {
+        vec![vec![inputs, self.0], vec![self.0]]
+    }
+
+    fn output_size(&self, _inputs: usize) -> usize {
+        self.0
+    }
+
+    fn forward(&self, tape: &mut Tape, inputs: Var, parameters: &[Var]) -> Result<Var, Error> {
+        let product = tape.matmul(inputs, parameters[0])?;
+        tape.add(product, parameters[1])
+    }
+}
+
+#[test]
+fn gradients_match_finite_differences() {
+    let x = tensor(&[0.5, -0.3, 0.8, 0.1, -0.7, 0.4], &[2, 3]);
+    let w = tensor(&[0.2, -0.1, 0.4, 0.3, -0.5, 0.6], &[3, 2]);
+    let b = tensor(&[0.1, -0.2], &[2]);
+
+    let error = check_gradients(&CPU, &[x, w, b], 1e-2, |tape, vars| {
+        let product = tape.matmul(vars[0], vars[1])?;
+        let shifted = tape.add(product, vars[2])?;
+        let exp = tape.exp(shifted)?;
+        let positive = tape.scale(exp, 2.)?;
+        let log = tape.log(positive)?;
+        let ratio = tape.div(log, positive)?;
+        let squared = tape.mul(ratio, ratio)?;
+        let swapped = tape.transpose(squared, 0, 1)?;
+        let mean = tape.mean(swapped, 1)?;
+        let difference = tape.sub(mean, vars[2])?;
+        tape.sum(difference, 0)
+    }).unwrap();
+
+    assert!(error < 1e-2, "The gradients differ from finite differences by {}", error);
+}
+
+#[test]
+fn gradients_of_broadcast_values_are_summed() {
+    let x = tensor(&[1., 2., 3., 4., 5., 6.], &[2, 3]);
+    let row = tensor(&[0.5, 0.25, 2.], &[1, 3]);
+
+    let mut tape = Tape::new(&CPU);
+    let (x, row) = (tape.var(x), tape.var(row));
+    let product = tape.mul(x, row).unwrap();
+    let mut gradients = tape.backward(product, None).unwrap();
+
+    let mut gradient = gradients.take(row).unwrap();
+    assert_eq!(gradient.shape(), &[1, 3]);
+    assert_eq!(gradient.to_vec(), vec![5., 7., 9.]);
+}
+
+#[test]
+fn autograd_layers_and_losses_train() {
+    let squared_error = Loss::Custom(Rc::new(|tape: &mut Tape, actual: Var, target: Var| {
+        let difference = tape.sub(actual, target)?;
+        let squared = tape.mul(difference, difference)?;
+        tape.mean(squared, 1)
+    }));
+
+    let mut network = NetworkBuilder::new(1)
+        .autograd(Linear(16))
+        .dense(1, TanH)
+        .build()
+        .unwrap();
+
+    let inputs: Vec<f32> = (0..32).map(|i| i as f32 / 32.).collect();
+    let targets: Vec<f32> = inputs.iter().map(|v| v * 0.8 - 0.4).collect();
+    let mut inputs = DualVec::from_vec((&CPU, &CPU), inputs);
+    let mut targets = DualVec::from_vec((&CPU, &CPU), targets);
+
+    let history = network.train(&mut inputs, &mut targets, Optimizer::GradientDecent(0.1), squared_error, 1, 4).unwrap();
+    let trained = network.train(&mut inputs, &mut targets, Optimizer::GradientDecent(0.1), Loss::MeanSquared, 200, 4).unwrap();
+    assert!(trained < history.max(1e-3), "The loss didn't decrease from {} to {}", history, trained);
+}
+
+#[test]
+fn autograd_layers_cant_be_read_from_bytes() {
+    let mut network = NetworkBuilder::new(2).autograd(Linear(3)).build().unwrap();
+    let read = Network::from_bytes(None, network.as_bytes());
+    assert!(matches!(read, Err(Error::Decode(DecodeError::Autograd))));
+}
@@ -0,0 +1,38 @@
+mod common;
+
+use neurox::activation::Activation::{Linear, TanH};
+use neurox::builder::NetworkBuilder;
+use neurox::dual_vec::DualVec;
+use neurox::error::{Error, NetworkError};
+use neurox::layer::recurrent::Cell;
+use neurox::layer::LayerType;
+use neurox::Executor::CPU;
+use common::predictions;
+
+#[test]
+fn shapes_follow_the_layers() {
+    let builder = NetworkBuilder::new([3, 4]).attention(2, 4, 5, false).layer_norm();
+    assert_eq!(builder.shape().dims(), &[3, 5]);
+    let builder = builder.recurrent(Cell::GRU, 6, false, false);
+    assert_eq!(builder.shape().dims(), &[6]);
+
+    let mut network = builder.dense(2, TanH).build().unwrap();
+    let outputs = predictions(&mut network, &mut DualVec::from_vec((&CPU, &CPU), vec![0.1; 2 * 12]));
+    assert_eq!(outputs.len(), 2 * 2);
+}
+
+#[test]
+fn layers_must_fit_their_inputs() {
+    let recurrent = LayerType::Recurrent { cell: Cell::SimpleRNN, units: 2, timesteps: 3, return_sequences: false, stateful: false };
+    let network = NetworkBuilder::new(4).layer(recurrent).build();
+    assert!(matches!(network, Err(Error::Network(NetworkError::Timesteps(4, 3)))));
+}
+
+#[test]
+fn dimensions_of_0_are_rejected() {
+    let network = NetworkBuilder::new([3, 0]).attention(1, 2, 2, false).dense(1, Linear).build();
+    assert!(matches!(network, Err(Error::Network(NetworkError::ZeroDimension(dims))) if dims == [3, 0]));
+
+    let network = NetworkBuilder::new(4).dense(0, Linear).recurrent(Cell::LSTM, 2, false, false).build();
+    assert!(matches!(network, Err(Error::Network(NetworkError::ZeroDimension(dims))) if dims == [0]));
+}
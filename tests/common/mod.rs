@@ -0,0 +1,107 @@
+//! Helpers shared by the integration tests.
+//!
+//! Tests which need an OpenCL device are marked `#[ignore]`, so they are reported as ignored rather
+//! than passing when there is no device. They are run with `cargo test -- --ignored`, and fail when
+//! no device is available then. Tests running on a CPU OpenCL device, such as pocl, use [cpu_device].
+
+// every test binary only uses some of the helpers
+#![allow(dead_code)]
+
+use std::sync::Arc;
+
+use neurox::dual_vec::DualVec;
+use neurox::gpu::{Gpu, GpuBuilder};
+use neurox::layer::Layer;
+use neurox::network::Network;
+use neurox::tensor::Tensor;
+use neurox::{Executor, Optimizer};
+use neurox::Executor::{CPU, GPU};
+use ocl::flags::DeviceType;
+
+/// `samples` samples of `input_size` inputs between -1 and 1, with the single target `target` gives
+/// the inputs of every sample
+pub fn dataset(samples: usize, input_size: usize, target: impl Fn(&[f32]) -> f32) -> (DualVec, DualVec) {
+    let inputs: Vec<f32> = (0..samples * input_size).map(|i| ((i * 7 % 13) as f32 - 6.) / 6.).collect();
+    let targets: Vec<f32> = inputs.chunks(input_size).map(target).collect();
+    (DualVec::from_vec((&CPU, &CPU), inputs), DualVec::from_vec((&CPU, &CPU), targets))
+}
+
+pub fn predictions(network: &mut Network, inputs: &mut DualVec) -> Vec<f32> {
+    network.predict(inputs).unwrap().cpu_borrow().unwrap().clone()
+}
+
+/// Checks the values are within `tolerance` of each other, relative to the expected values above 1
+pub fn assert_close(actual: &[f32], expected: &[f32], tolerance: f32) {
+    assert_eq!(actual.len(), expected.len(), "{:?} != {:?}", actual, expected);
+    for (a, e) in actual.iter().zip(expected) {
+        assert!((a - e).abs() <= tolerance * e.abs().max(1.), "{:?} != {:?}", actual, expected);
+    }
+}
+
+/// The loss `sum(outputs * coefficients)` of the outputs of `layer` for `inputs`
+fn weighted_sum(layer: &mut dyn Layer, inputs: &[f32], coefficients: &[f32]) -> f64 {
+    let input_size = layer.input_size();
+    layer.forward(&mut Tensor::batch(DualVec::from_vec((&CPU, &CPU), inputs.to_vec()), input_size));
+    layer.activated_output().cpu_borrow().unwrap().iter().zip(coefficients).map(|(o, c)| *o as f64 * *c as f64).sum()
+}
+
+/// The largest difference between the gradients of the values and inputs of `layer` from its
+/// backward pass and central finite differences, relative to the gradients above 1
+pub fn gradient_error(layer: &mut dyn Layer, inputs: &[f32]) -> f32 {
+    let epsilon = 1e-2;
+    let samples = inputs.len() / layer.input_size();
+    let coefficients: Vec<f32> = (0..samples * layer.output_size()).map(|i| ((i * 3 % 7) as f32 - 3.) / 3.).collect();
+    let mut error: f32 = 0.;
+    let mut compare = |analytic: f32, numeric: f64| error = error.max((analytic - numeric as f32).abs() / analytic.abs().max(1.));
+
+    weighted_sum(layer, inputs, &coefficients);
+    let input_size = layer.input_size();
+    let mut gradients = DualVec::from_vec((&CPU, &CPU), coefficients.clone());
+    layer.backward(&mut Tensor::batch(DualVec::from_vec((&CPU, &CPU), inputs.to_vec()), input_size), &mut gradients, &Optimizer::GradientDecent(1.));
+
+    // the modifications are the gradients scaled by the negative learn rate
+    let mods: Vec<Vec<f32>> = layer.mods().unwrap().into_iter().map(|m| m.cpu_borrow().unwrap().clone()).collect();
+    let input_gradients = layer.sensitivities().cpu_borrow().unwrap().clone();
+
+    let values: Vec<DualVec> = layer.values().into_iter().cloned().collect();
+    for (mut value, mods) in values.into_iter().zip(mods) {
+        for (i, m) in mods.iter().enumerate() {
+            let original = value.cpu_borrow().unwrap()[i];
+            let mut loss_at = |v: f32| {
+                value.cpu_borrow().unwrap()[i] = v;
+                value.updated_cpu();
+                weighted_sum(layer, inputs, &coefficients)
+            };
+            let numeric = (loss_at(original + epsilon) - loss_at(original - epsilon)) / (2. * epsilon as f64);
+            loss_at(original);
+            compare(-m, numeric);
+        }
+    }
+
+    for i in 0..inputs.len() {
+        let mut shifted = inputs.to_vec();
+        shifted[i] += epsilon;
+        let above = weighted_sum(layer, &shifted, &coefficients);
+        shifted[i] -= 2. * epsilon;
+        let below = weighted_sum(layer, &shifted, &coefficients);
+        compare(input_gradients[i], (above - below) / (2. * epsilon as f64));
+    }
+    error
+}
+
+/// A GPU built by `builder`, for the ignored tests which need an OpenCL device
+pub fn gpu_with(builder: GpuBuilder) -> Arc<Executor> {
+    match builder.build() {
+        Ok(gpu) => Arc::new(GPU(gpu)),
+        Err(err) => panic!("No OpenCL device is available: {}", err),
+    }
+}
+
+pub fn gpu() -> Arc<Executor> {
+    gpu_with(Gpu::builder())
+}
+
+/// A CPU OpenCL device, whose results can be compared with the host ones
+pub fn cpu_device() -> Arc<Executor> {
+    gpu_with(Gpu::builder().device(DeviceType::CPU))
+}
@@ -0,0 +1,136 @@
+mod common;
+
+use std::rc::Rc;
+use std::sync::Arc;
+
+use neurox::activation::Activation::{Linear, TanH};
+use neurox::autograd::{Tape, Var};
+use neurox::builder::NetworkBuilder;
+use neurox::data_parallel::DataParallel;
+use neurox::dual_vec::DualVec;
+use neurox::error::{Error, NetworkError};
+use neurox::loss::Loss;
+use neurox::network::Network;
+use neurox::tensor::DType;
+use neurox::Executor;
+use neurox::Executor::CPU;
+use neurox::Optimizer;
+use common::{assert_close, gpu, predictions};
+
+fn network() -> Network {
+    NetworkBuilder::new(2)
+        .dense(16, TanH)
+        .layer_norm()
+        .dense(1, Linear)
+        .build()
+        .unwrap()
+}
+
+/// 64 samples of 2 inputs
+fn dataset() -> (DualVec, DualVec) {
+    common::dataset(64, 2, |p| (p[0] * 2.).sin() * 0.5 + p[1] * 0.25)
+}
+
+/// Trains replicas on `execs`, checking the loss decreases and the replicas agree within `tolerance`
+fn train_replicas(execs: &[Arc<Executor>], tolerance: f32) {
+    let (mut inputs, mut targets) = dataset();
+    let mut parallel = DataParallel::new(&mut network(), execs).unwrap();
+    assert_eq!(parallel.replicas(), execs.len());
+
+    // batches of 10 are split unevenly between 3 replicas
+    let first = parallel.train(&mut inputs, &mut targets, Optimizer::GradientDecent(0.05), Loss::MeanSquared, 1, 10).unwrap();
+    let trained = parallel.train(&mut inputs, &mut targets, Optimizer::GradientDecent(0.05), Loss::MeanSquared, 100, 10).unwrap();
+    assert!(trained < first, "The loss didn't decrease from {} to {}", first, trained);
+
+    let expected = predictions(parallel.network(), &mut inputs);
+    for r in 1..parallel.replicas() {
+        assert_close(&predictions(parallel.replica(r), &mut inputs), &expected, tolerance);
+    }
+
+    parallel.sync().unwrap();
+    assert_eq!(predictions(parallel.replica(execs.len() - 1), &mut inputs), expected);
+}
+
+/// Trains one and three replicas on a single batch of all 64 samples, which is split unevenly between
+/// the 3 replicas, checking both take the same step and report the loss of a network
+fn assert_same_step(loss: impl Fn() -> Loss) {
+    let (mut inputs, mut targets) = dataset();
+    let cpu = Arc::new(CPU);
+    let mut initial = network();
+
+    let mut single = DataParallel::new(&mut initial, std::slice::from_ref(&cpu)).unwrap();
+    let single_loss = single.train(&mut inputs, &mut targets, Optimizer::GradientDecent(0.05), loss(), 1, 64).unwrap();
+    let mut parallel = DataParallel::new(&mut initial, &[cpu.clone(), cpu.clone(), cpu]).unwrap();
+    let parallel_loss = parallel.train(&mut inputs, &mut targets, Optimizer::GradientDecent(0.05), loss(), 1, 64).unwrap();
+    let untrained = predictions(&mut initial, &mut inputs);
+    let network_loss = initial.train(&mut inputs, &mut targets, Optimizer::GradientDecent(0.05), loss(), 1, 64).unwrap();
+    assert_close(&[single_loss, parallel_loss], &[network_loss, network_loss], 1e-6);
+
+    let expected = predictions(single.network(), &mut inputs);
+    assert_ne!(untrained, expected);
+    assert_close(&predictions(&mut initial, &mut inputs), &expected, 1e-6);
+    // the replicas sum their modifications in a different order than a single network
+    assert_close(&predictions(parallel.network(), &mut inputs), &expected, 1e-6);
+}
+
+#[test]
+fn replicas_take_the_step_of_a_single_network() {
+    assert_same_step(|| Loss::MeanSquared);
+    // the gradients of custom losses are those of the sum of the losses of every sample
+    assert_same_step(|| Loss::Custom(Rc::new(|tape: &mut Tape, actual: Var, target: Var| {
+        let difference = tape.sub(actual, target)?;
+        tape.mul(difference, difference)
+    })));
+}
+
+#[test]
+fn replicas_only_update_the_embedded_rows() {
+    let cpu = Arc::new(CPU);
+    let mut initial = NetworkBuilder::new(1).embedding(5, 2).build().unwrap();
+    let mut vocabulary = DualVec::from_vec((&CPU, &CPU), vec![0., 1., 2., 3., 4.]);
+    let untrained = predictions(&mut initial, &mut vocabulary);
+
+    // every replica embeds a different token
+    let mut inputs = DualVec::from_vec((&CPU, &CPU), vec![1., 3.]);
+    let mut targets = DualVec::from_vec((&CPU, &CPU), vec![1., 1., -1., -1.]);
+    let mut parallel = DataParallel::new(&mut initial, &[cpu.clone(), cpu]).unwrap();
+    parallel.train(&mut inputs, &mut targets, Optimizer::GradientDecent(0.5), Loss::MeanSquared, 1, 2).unwrap();
+    initial.train(&mut inputs, &mut targets, Optimizer::GradientDecent(0.5), Loss::MeanSquared, 1, 2).unwrap();
+
+    let expected = predictions(&mut initial, &mut vocabulary);
+    for r in 0..parallel.replicas() {
+        let rows = predictions(parallel.replica(r), &mut vocabulary);
+        assert_close(&rows, &expected, 1e-6);
+        for (row, (trained, untrained)) in rows.chunks(2).zip(untrained.chunks(2)).enumerate() {
+            assert_eq!(trained == untrained, row != 1 && row != 3, "Row {} of replica {} was updated wrongly", row, r);
+        }
+    }
+}
+
+#[test]
+fn cpu_replicas_stay_in_sync() {
+    let cpu = Arc::new(CPU);
+    train_replicas(&[cpu.clone(), cpu.clone(), cpu], 0.);
+}
+
+#[test]
+#[ignore = "needs an OpenCL device"]
+fn gpu_replicas_stay_in_sync() {
+    train_replicas(&[gpu(), gpu(), gpu()], 1e-5);
+}
+
+#[test]
+fn replication_errors() {
+    let (mut inputs, mut targets) = dataset();
+    let cpu = Arc::new(CPU);
+
+    assert!(matches!(DataParallel::new(&mut network(), &[]), Err(Error::Network(NetworkError::ZeroReplicas))));
+
+    let mut parallel = DataParallel::new(&mut network(), &[cpu.clone(), cpu.clone(), cpu.clone()]).unwrap();
+    let trained = parallel.train(&mut inputs, &mut targets, Optimizer::GradientDecent(0.05), Loss::MeanSquared, 1, 2);
+    assert!(matches!(trained, Err(Error::Network(NetworkError::Replicas(2, 3)))));
+
+    // f64 modifications aren't kept in the buffers shared between replicas
+    let mut double = NetworkBuilder::new(2).dense(4, TanH).precision(DType::F64).dense(1, Linear).build().unwrap();
+    assert!(matches!(DataParallel::new(&mut double, &[cpu]), Err(Error::Network(NetworkError::Precision(0, DType::F64)))));
+}
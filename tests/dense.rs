@@ -0,0 +1,54 @@
+mod common;
+
+use std::sync::Arc;
+
+use neurox::activation::Activation::TanH;
+use neurox::dual_vec::DualVec;
+use neurox::layer::dense::Dense;
+use neurox::layer::Layer;
+use neurox::tensor::Tensor;
+use neurox::Executor::{CPU, Threaded};
+use neurox::{Execs, Executor, Optimizer};
+
+const INPUTS: usize = 5;
+const SIZE: usize = 6;
+const SAMPLES: usize = 7;
+
+/// The outputs, sensitivities and modifications of a forward and backward pass of `layer`
+fn pass(layer: &mut Dense) -> Vec<Vec<f32>> {
+    let inputs: Vec<f32> = (0..SAMPLES * INPUTS).map(|i| ((i * 5 % 7) as f32 - 3.) / 3.).collect();
+    let gradients: Vec<f32> = (0..SAMPLES * SIZE).map(|i| ((i * 3 % 7) as f32 - 3.) / 3.).collect();
+    layer.forward(&mut Tensor::batch(DualVec::from_vec((&CPU, &CPU), inputs.clone()), INPUTS));
+    let outputs = layer.activated_output().cpu_borrow().unwrap().clone();
+
+    let mut gradients = DualVec::from_vec((&CPU, &CPU), gradients);
+    layer.backward(&mut Tensor::batch(DualVec::from_vec((&CPU, &CPU), inputs), INPUTS), &mut gradients, &Optimizer::GradientDecent(0.1));
+    let sensitivities = layer.sensitivities().cpu_borrow().unwrap()[..SAMPLES * INPUTS].to_vec();
+
+    let mut results = vec![outputs, sensitivities];
+    results.extend(layer.mods().unwrap().into_iter().map(|m| m.cpu_borrow().unwrap().clone()));
+    results
+}
+
+/// A layer on `exec` with the values of `reference`
+fn copy(reference: &Dense, exec: Executor) -> Dense {
+    let layer = Dense::new(&Execs::all(&Arc::new(exec)), INPUTS, SIZE, TanH);
+    for (value, mut copied) in reference.values().into_iter().zip(layer.values().into_iter().cloned()) {
+        let mut value = value.clone();
+        copied.cpu_borrow().unwrap().copy_from_slice(&value.cpu_borrow().unwrap());
+        copied.updated_cpu();
+    }
+    layer
+}
+
+#[test]
+fn threads_match_the_cpu() {
+    let mut cpu = Dense::new(&Execs::all(&Arc::new(CPU)), INPUTS, SIZE, TanH);
+    let mut threaded: Vec<Dense> = [1, 2, 5].into_iter().map(|threads| copy(&cpu, Threaded(threads))).collect();
+
+    // the reductions are summed in the same order for any amount of threads, so the results are equal
+    let expected = pass(&mut cpu);
+    for layer in &mut threaded {
+        assert_eq!(pass(layer), expected, "The results on {:?} differ from those on the CPU", layer.exec());
+    }
+}
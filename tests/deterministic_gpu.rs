@@ -0,0 +1,56 @@
+mod common;
+
+use std::sync::Arc;
+
+use neurox::activation::Activation::{Linear, TanH};
+use neurox::builder::NetworkBuilder;
+use neurox::dual_vec::DualVec;
+use neurox::gpu::Gpu;
+use neurox::loss::Loss;
+use neurox::network::Network;
+use neurox::Executor::CPU;
+use neurox::{Executor, Optimizer};
+use ocl::flags::DeviceType;
+use common::gpu_with;
+
+/// Trains a copy of the network and returns its values afterwards
+fn train(exec: &Arc<Executor>, initial: &[u8]) -> Vec<u8> {
+    let mut network = Network::from_bytes(Some(exec.clone()), initial.to_vec()).unwrap();
+
+    let sequence = 4;
+    let samples = 64;
+    let mut inputs = Vec::new();
+    let mut targets = Vec::new();
+    for i in 0..samples {
+        for t in 0..sequence {
+            // few tokens, so every row is used by many tokens of a batch
+            inputs.push(((i * 7 + t * 3) % 5) as f32);
+        }
+        targets.push((i as f32 / samples as f32).sin());
+    }
+    let mut inputs = DualVec::from_vec((&CPU, exec), inputs);
+    let mut targets = DualVec::from_vec((&CPU, exec), targets);
+
+    network.train(&mut inputs, &mut targets, Optimizer::GradientDecent(0.05), Loss::MeanSquared, 5, 16).unwrap();
+    network.as_bytes()
+}
+
+#[test]
+#[ignore = "needs a CPU OpenCL device"]
+fn deterministic_training_is_reproducible() {
+    // runs on a CPU OpenCL device, such as pocl
+    let exec = gpu_with(Gpu::builder().device(DeviceType::CPU).deterministic(true));
+
+    let mut network = NetworkBuilder::new(4)
+        .embedding(5, 8).on(&exec)
+        .dense(16, TanH)
+        .dense(1, Linear)
+        .build()
+        .unwrap();
+    let initial = network.as_bytes();
+
+    let first = train(&exec, &initial);
+    for _ in 0..3 {
+        assert_eq!(first, train(&exec, &initial), "Deterministic training gave different values between runs");
+    }
+}
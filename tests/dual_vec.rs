@@ -0,0 +1,91 @@
+//! The copies started by the prefetches run on the transfer queue of a CPU OpenCL device, such
+//! as pocl, so the kernels and reads have to wait for them.
+
+mod common;
+
+use std::sync::Arc;
+
+use neurox::dual_vec::DualVec;
+use neurox::gpu::Gpu;
+use neurox::ops;
+use neurox::tensor::Tensor;
+use neurox::Executor;
+use neurox::Executor::CPU;
+use ocl::flags::DeviceType;
+use common::{assert_close, gpu_with};
+
+/// Large enough for a copy to still be running when the next command is enqueued
+const LEN: usize = 1 << 20;
+
+fn transfer_gpu() -> Arc<Executor> {
+    gpu_with(Gpu::builder().device(DeviceType::CPU).transfer_queue(true))
+}
+
+fn values(round: usize) -> Vec<f32> {
+    (0..LEN).map(|i| ((i * 7 + round) % 13) as f32 - 6.).collect()
+}
+
+/// Writes `values` to the host side, leaving it newer than the device
+fn write(vec: &mut DualVec, values: &[f32]) {
+    vec.cpu_borrow().unwrap().copy_from_slice(values);
+    vec.updated_cpu();
+}
+
+#[test]
+#[ignore = "needs a CPU OpenCL device"]
+fn kernels_wait_for_prefetched_writes() {
+    let gpu = transfer_gpu();
+    let mut vec = DualVec::from_vec((&CPU, &gpu), vec![0.; LEN]);
+
+    // every round overwrites the values the last kernel read, so a kernel which doesn't wait
+    // for the copy sees the values of the last round
+    for round in 0..4 {
+        let values = values(round);
+        write(&mut vec, &values);
+        vec.prefetch_gpu(&gpu);
+
+        let mut doubled = ops::scale(&gpu, &mut Tensor::new(vec.clone(), &[LEN]).unwrap(), 2.).unwrap();
+        let expected: Vec<f32> = values.iter().map(|v| v * 2.).collect();
+        assert_close(&doubled.to_vec(), &expected, 0.);
+    }
+}
+
+#[test]
+#[ignore = "needs a CPU OpenCL device"]
+fn reads_wait_for_prefetched_reads() {
+    let gpu = transfer_gpu();
+    let values = values(0);
+    let mut vec = DualVec::from_vec((&CPU, &gpu), values.clone());
+
+    for round in 1..4 {
+        // the device side is newer after the kernel, and is copied back before it is read
+        let mut negated = ops::scale(&gpu, &mut Tensor::new(vec.clone(), &[LEN]).unwrap(), -1.).unwrap();
+        negated.data_mut().prefetch_cpu(&gpu);
+        let expected: Vec<f32> = values.iter().map(|v| if round % 2 == 1 { -v } else { *v }).collect();
+        assert_close(&negated.data_mut().cpu_borrow().unwrap(), &expected, 0.);
+        vec = negated.into_data();
+    }
+}
+
+#[test]
+#[ignore = "needs a CPU OpenCL device"]
+fn dropping_waits_for_the_copies_in_flight() {
+    let gpu = transfer_gpu();
+
+    for round in 0..4 {
+        let mut to_device = DualVec::from_vec((&CPU, &gpu), vec![0.; LEN]);
+        write(&mut to_device, &values(round));
+        to_device.prefetch_gpu(&gpu);
+        drop(to_device);
+
+        let mut to_host = ops::scale(&gpu, &mut Tensor::new(DualVec::from_vec((&CPU, &gpu), values(round)), &[LEN]).unwrap(), 1.).unwrap();
+        to_host.data_mut().prefetch_cpu(&gpu);
+        drop(to_host);
+
+        // the buffers and vectors of both are recycled, and cleared after their copies are done
+        let mut recycled = DualVec::from_execs((&CPU, &gpu), LEN);
+        assert!(recycled.cpu_borrow().unwrap().iter().all(|v| *v == 0.));
+        let mut zeroes = ops::scale(&gpu, &mut Tensor::new(recycled, &[LEN]).unwrap(), 1.).unwrap();
+        assert!(zeroes.to_vec().iter().all(|v| *v == 0.));
+    }
+}
@@ -0,0 +1,64 @@
+mod common;
+
+use std::sync::Arc;
+
+use neurox::activation::Activation::Linear;
+use neurox::builder::NetworkBuilder;
+use neurox::dual_vec::DualVec;
+use neurox::error::{Error, NetworkError};
+use neurox::graph::{GraphNetwork, GraphNode};
+use neurox::layer::LayerType;
+use neurox::loss::Loss;
+use neurox::network::Network;
+use neurox::Executor::CPU;
+use neurox::Optimizer;
+use common::predictions;
+
+/// Two tokens of a vocabulary of 5, embedded into 3 values each
+fn network() -> Network {
+    NetworkBuilder::new(2).embedding(5, 3).dense(1, Linear).build().unwrap()
+}
+
+fn tokens(ids: Vec<f32>) -> DualVec {
+    DualVec::from_vec((&CPU, &CPU), ids)
+}
+
+fn is_token_error(result: Result<impl std::fmt::Debug, Error>, id: f32) -> bool {
+    match result {
+        Err(Error::Network(NetworkError::Token(token, 5))) => token == id || (token.is_nan() && id.is_nan()),
+        _ => false,
+    }
+}
+
+#[test]
+fn ids_outside_the_vocabulary_are_rejected() {
+    let mut network = network();
+    let frozen = network.freeze().unwrap();
+    let cpu = Arc::new(CPU);
+    let mut graph = GraphNetwork::new(2, &[
+        GraphNode::layer("embedding", &cpu, LayerType::Embedding(5, 3), "input"),
+        GraphNode::layer("out", &cpu, LayerType::Dense(1, Linear), "embedding"),
+    ], "out").unwrap();
+
+    for id in [5., 17., -1., 1.5, f32::NAN, f32::INFINITY] {
+        let ids = vec![0., 1., 4., id];
+
+        assert!(is_token_error(network.predict(&mut tokens(ids.clone())), id), "{} was accepted", id);
+        let trained = network.train(&mut tokens(ids.clone()), &mut tokens(vec![1., -1.]), Optimizer::GradientDecent(0.1), Loss::MeanSquared, 1, 2);
+        assert!(is_token_error(trained, id), "{} was trained on", id);
+        assert!(is_token_error(network.step(&mut network.incremental_state(), &mut tokens(vec![1., id])), id));
+        assert!(is_token_error(frozen.predict(&ids), id));
+        assert!(is_token_error(graph.predict(&mut tokens(ids)), id));
+    }
+}
+
+#[test]
+fn ids_within_the_vocabulary_are_embedded() {
+    let mut network = network();
+    let mut inputs = tokens(vec![0., 4., 4., 0.]);
+    let outputs = predictions(&mut network, &mut inputs);
+
+    assert_eq!(outputs.len(), 2);
+    assert_eq!(network.freeze().unwrap().predict(&[0., 4., 4., 0.]).unwrap(), outputs);
+    network.train(&mut inputs, &mut tokens(vec![1., -1.]), Optimizer::GradientDecent(0.1), Loss::MeanSquared, 1, 2).unwrap();
+}
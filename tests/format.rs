@@ -0,0 +1,201 @@
+mod common;
+
+use std::sync::Arc;
+
+use neurox::activation::Activation::{Linear, TanH};
+use neurox::builder::NetworkBuilder;
+use neurox::dual_vec::DualVec;
+use neurox::error::{DecodeError, Error};
+use neurox::format::{crc32, Kind, LayerSection, ModelFile, MAGIC, VERSION};
+use neurox::graph::{GraphNetwork, GraphNode, Merge};
+use neurox::layer::LayerType;
+use neurox::network::Network;
+use neurox::tensor::DType;
+use neurox::utils::vec_utils::VecWriter;
+use neurox::Executor::CPU;
+use common::{assert_close, predictions};
+
+/// Written by the first release, with the executor of every layer and no precisions, with a dense
+/// layer of 3 values and a dense layer of 1
+const BASELINE_NETWORK: &[u8] = include_bytes!("data/baseline_network.bin");
+
+fn inputs() -> DualVec {
+    DualVec::from_vec((&CPU, &CPU), vec![0.25, -0.5, 1., 0.75])
+}
+
+#[test]
+fn checksums_match_crc32() {
+    assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    assert_eq!(crc32(b""), 0);
+}
+
+#[test]
+fn metadata_and_values_round_trip() {
+    let mut network = NetworkBuilder::new(2)
+        .dense(4, TanH).precision(DType::F64)
+        .dense(1, Linear)
+        .build()
+        .unwrap();
+    network.metadata_mut().insert("author".to_string(), "tests".to_string());
+    network.metadata_mut().insert("dataset".to_string(), "4f2a".to_string());
+
+    let bytes = network.as_bytes();
+    assert!(bytes.starts_with(&MAGIC));
+
+    let file = ModelFile::from_bytes(&bytes, Kind::Sequential).unwrap();
+    assert_eq!(file.version, VERSION);
+    assert_eq!(file.layers.iter().map(|s| (s.id, s.precision)).collect::<Vec<_>>(), vec![(0, DType::F64), (0, DType::F32)]);
+
+    let mut restored = Network::from_bytes(None, bytes).unwrap();
+    assert_eq!(restored.metadata(), network.metadata());
+    assert_eq!(restored.precision(0), DType::F64);
+    assert_eq!(predictions(&mut restored, &mut inputs()), predictions(&mut network, &mut inputs()));
+}
+
+#[test]
+fn damaged_files_are_rejected() {
+    let mut network = NetworkBuilder::new(2).dense(2, TanH).build().unwrap();
+    let bytes = network.as_bytes();
+
+    let mut flipped = bytes.clone();
+    flipped[20] ^= 1;
+    assert!(matches!(Network::from_bytes(None, flipped), Err(Error::Decode(DecodeError::Checksum(..)))));
+
+    let truncated = bytes[..bytes.len() / 2].to_vec();
+    assert!(matches!(Network::from_bytes(None, truncated), Err(Error::Decode(DecodeError::Checksum(..)))));
+    assert!(matches!(Network::from_bytes(None, b"not a model".to_vec()), Err(Error::Decode(DecodeError::Format))));
+    assert!(matches!(GraphNetwork::from_bytes(None, bytes), Err(Error::Decode(DecodeError::Kind(Kind::Sequential, Kind::Graph)))));
+}
+
+#[test]
+fn unknown_layers_can_be_skipped() {
+    let mut network = NetworkBuilder::new(2).dense(2, TanH).build().unwrap();
+    let mut file = ModelFile::from_bytes(&network.as_bytes(), Kind::Sequential).unwrap();
+    file.layers.push(LayerSection { id: 99, exec: 0, threads: 1, precision: DType::F32, bytes: vec![1, 2, 3] });
+    file.metadata.insert("epochs".to_string(), "10".to_string());
+    let bytes = file.as_bytes();
+
+    // the file can still be read, just not as a network
+    let read = ModelFile::from_bytes(&bytes, Kind::Sequential).unwrap();
+    assert_eq!(read.metadata["epochs"], "10");
+    assert_eq!(read.layers[1].bytes, vec![1, 2, 3]);
+    assert!(matches!(Network::from_bytes(None, bytes), Err(Error::Decode(DecodeError::InvalidLayerType(99)))));
+
+    // a layer reading more or less than its section is caught
+    file.layers.pop();
+    file.layers[0].bytes.push(0);
+    assert!(matches!(Network::from_bytes(None, file.as_bytes()), Err(Error::Decode(DecodeError::Section(0, ..)))));
+}
+
+#[test]
+fn unknown_kinds_are_rejected() {
+    let mut network = NetworkBuilder::new(2).dense(2, TanH).build().unwrap();
+    let bytes = network.as_bytes();
+
+    // the kind follows the magic and the version, and the checksum is made to match
+    let mut contents = bytes[..bytes.len() - 4].to_vec();
+    contents[19] = 7;
+    contents.extend_from_slice(&crc32(&contents).to_be_bytes());
+    assert!(matches!(Network::from_bytes(None, contents), Err(Error::Decode(DecodeError::InvalidKind(7)))));
+}
+
+#[test]
+fn damaged_lengths_are_rejected() {
+    let mut network = NetworkBuilder::new(2).dense(2, TanH).build().unwrap();
+    network.metadata_mut().insert("author".to_string(), "tests".to_string());
+    let bytes = network.as_bytes();
+
+    // the length of the first metadata key follows the magic, the version, the kind and the count
+    let mut contents = bytes[..bytes.len() - 4].to_vec();
+    contents[28..36].fill(0xff);
+    contents.extend_from_slice(&crc32(&contents).to_be_bytes());
+    assert!(matches!(Network::from_bytes(None, contents), Err(Error::Decode(DecodeError::Truncated))));
+}
+
+#[test]
+fn baseline_networks_are_migrated() {
+    let file = ModelFile::from_bytes(BASELINE_NETWORK, Kind::Sequential).unwrap();
+    assert_eq!(file.version, 0);
+    assert_eq!(file.layers.iter().map(|s| (s.id, s.exec, s.precision)).collect::<Vec<_>>(), vec![(0, 0, DType::F32), (0, 0, DType::F32)]);
+
+    let mut network = Network::from_bytes(None, BASELINE_NETWORK.to_vec()).unwrap();
+    let expected = [0.5664408, 0.80902874];
+    assert_close(&predictions(&mut network, &mut inputs()), &expected, 1e-6);
+
+    let mut migrated = Network::from_bytes(None, network.as_bytes()).unwrap();
+    assert_close(&predictions(&mut migrated, &mut inputs()), &expected, 1e-6);
+}
+
+#[test]
+fn unversioned_bytes_are_only_read_as_baseline_networks() {
+    assert!(matches!(GraphNetwork::from_bytes(None, BASELINE_NETWORK.to_vec()), Err(Error::Decode(DecodeError::Format))));
+
+    // a damaged layer count ends at the end of the bytes rather than running on
+    let count = vec![0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff];
+    assert!(matches!(Network::from_bytes(None, count), Err(Error::Decode(DecodeError::Truncated))));
+}
+
+#[test]
+fn merged_nodes_round_trip() {
+    let cpu = Arc::new(CPU);
+    let mut built = GraphNetwork::new(2, &[
+        GraphNode::layer("a", &cpu, LayerType::Dense(3, TanH), "input"),
+        GraphNode::merge("twice", Merge::Add, &["a", "a"]),
+        GraphNode::layer("out", &cpu, LayerType::Dense(1, Linear), "twice"),
+    ], "out").unwrap();
+    let mut restored = GraphNetwork::from_bytes(None, built.as_bytes()).unwrap();
+    assert_eq!(restored.predict(&mut inputs()).unwrap().cpu_borrow().unwrap().clone(), built.predict(&mut inputs()).unwrap().cpu_borrow().unwrap().clone());
+}
+
+/// The topology of a graph with an input of 2 values and two dense layer nodes, with the sources
+/// of every node as (0 for an input or 1 for a node, index) and the outputs
+fn topology(sources: [&[(usize, usize)]; 2], outputs: &[usize]) -> Vec<u8> {
+    let mut writer = VecWriter::new();
+    writer.usize(1);
+    writer.string("input");
+    writer.usize(2);
+    writer.usize(outputs.len());
+    for o in outputs {
+        writer.usize(*o);
+    }
+    writer.usize(2);
+    for (n, node_sources) in sources.iter().enumerate() {
+        writer.string(&format!("dense {}", n));
+        writer.usize(0);
+        writer.usize(0);
+        writer.usize(0);
+        writer.usize(node_sources.len());
+        for (kind, index) in *node_sources {
+            writer.usize(*kind);
+            writer.usize(*index);
+        }
+    }
+    writer.vec()
+}
+
+#[test]
+fn malformed_topologies_are_rejected() {
+    let cpu = Arc::new(CPU);
+    let mut graph = GraphNetwork::new(2, &[
+        GraphNode::layer("dense 0", &cpu, LayerType::Dense(2, TanH), "input"),
+        GraphNode::layer("dense 1", &cpu, LayerType::Dense(2, TanH), "dense 0"),
+    ], "dense 1").unwrap();
+    let mut file = ModelFile::from_bytes(&graph.as_bytes(), Kind::Graph).unwrap();
+    let mut read = |topology: Vec<u8>| {
+        file.topology = topology;
+        GraphNetwork::from_bytes(None, file.as_bytes())
+    };
+
+    assert!(read(topology([&[(0, 0)], &[(1, 0)]], &[1])).is_ok());
+    assert!(matches!(read(topology([&[(0, 0)], &[(1, 2)]], &[1])), Err(Error::Decode(DecodeError::InvalidSource(1)))));
+    // nodes only use the nodes before them, so there are no cycles
+    assert!(matches!(read(topology([&[(1, 1)], &[(1, 0)]], &[1])), Err(Error::Decode(DecodeError::InvalidSource(0)))));
+    assert!(matches!(read(topology([&[(0, 1)], &[(1, 0)]], &[1])), Err(Error::Decode(DecodeError::InvalidSource(0)))));
+    assert!(matches!(read(topology([&[(0, 0)], &[]], &[1])), Err(Error::Decode(DecodeError::Sources(1, 0)))));
+    assert!(matches!(read(topology([&[(0, 0)], &[(1, 0), (0, 0)]], &[1])), Err(Error::Decode(DecodeError::Sources(1, 2)))));
+    assert!(matches!(read(topology([&[(0, 0)], &[(1, 0)]], &[2])), Err(Error::Decode(DecodeError::InvalidOutput(2)))));
+
+    let mut truncated = topology([&[(0, 0)], &[(1, 0)]], &[1]);
+    truncated[7] = 0xff;
+    assert!(matches!(read(truncated), Err(Error::Decode(DecodeError::Truncated))));
+}
@@ -0,0 +1,39 @@
+mod common;
+
+use std::sync::Arc;
+use std::thread;
+
+use neurox::activation::Activation::{Linear, TanH};
+use neurox::builder::NetworkBuilder;
+use neurox::error::{Error, MismatchError};
+use neurox::frozen::Scratch;
+use common::{assert_close, dataset, predictions};
+
+#[test]
+fn threads_predict_like_the_network() {
+    let mut network = NetworkBuilder::new(3).dense(5, TanH).dense(2, Linear).build().unwrap();
+    let (mut inputs, _) = dataset(8, 3, |x| x[0]);
+    let expected = predictions(&mut network, &mut inputs);
+    let inputs = inputs.cpu_borrow().unwrap().clone();
+
+    let frozen = Arc::new(network.freeze().unwrap());
+    thread::scope(|scope| {
+        for _ in 0..4 {
+            let (frozen, inputs, expected) = (frozen.clone(), &inputs, &expected);
+            scope.spawn(move || {
+                let mut scratch = Scratch::new();
+                for _ in 0..3 {
+                    assert_close(&frozen.predict(inputs).unwrap(), expected, 1e-6);
+                    assert_close(&frozen.predict_with(&mut scratch, inputs).unwrap(), expected, 1e-6);
+                }
+            });
+        }
+    });
+}
+
+#[test]
+fn partial_samples_are_rejected() {
+    let network = NetworkBuilder::new(3).dense(2, Linear).build().unwrap();
+    let predicted = network.freeze().unwrap().predict(&[0.; 7]);
+    assert!(matches!(predicted, Err(Error::Mismatch(MismatchError::Input(7, 3)))));
+}
@@ -0,0 +1,78 @@
+mod common;
+
+use neurox::utils::gemm::{gemm, gemm_with, Kernel, Matrix};
+use common::assert_close;
+
+/// Values between -1 and 1 which don't repeat within the sizes of the tests
+fn values(len: usize, seed: usize) -> Vec<f32> {
+    (0..len).map(|i| (((i * 37 + seed * 11) % 101) as f32 - 50.) / 50.).collect()
+}
+
+/// `c = alpha * a * b + beta * c` as a triple loop, where `a(i, p)` and `b(p, j)` give the values
+fn naive((m, n, k): (usize, usize, usize), alpha: f32, a: impl Fn(usize, usize) -> f32, b: impl Fn(usize, usize) -> f32, beta: f32, c: &[f32]) -> Vec<f32> {
+    let mut expected = vec![0.; m * n];
+    for i in 0..m {
+        for j in 0..n {
+            let mut sum = 0.;
+            for p in 0..k {
+                sum += a(i, p) * b(p, j);
+            }
+            expected[i * n + j] = alpha * sum + beta * c[i * n + j];
+        }
+    }
+    expected
+}
+
+/// Checks every kernel against the triple loop for a `[m, k]` by `[k, n]` product, with `a` and
+/// `b` stored row major, transposed and as views into larger matrices
+fn assert_matches_naive(m: usize, n: usize, k: usize) {
+    // the row and column strides of a view of `rows` rows and `cols` columns in every layout
+    let layouts = |rows: usize, cols: usize| [(cols, 1), (1, rows), (cols + 3, 1), (2, 2 * rows + 1)];
+
+    let a_values = values(m * k * 4 + 8, 1);
+    let b_values = values(k * n * 4 + 8, 2);
+    for ((ar, ac), (br, bc)) in layouts(m, k).into_iter().zip(layouts(k, n).into_iter().rev()) {
+        let a = Matrix::strided(&a_values, m, k, ar, ac);
+        let b = Matrix::strided(&b_values, k, n, br, bc);
+
+        for (alpha, beta) in [(1., 0.), (-0.5, 1.), (0.75, 0.25)] {
+            let c = values(m * n, 3);
+            let expected = naive((m, n, k), alpha, |i, p| a_values[i * ar + p * ac], |p, j| b_values[p * br + j * bc], beta, &c);
+
+            for kernel in Kernel::available() {
+                for threads in [1, 3] {
+                    let mut actual = c.clone();
+                    gemm_with(kernel, threads, alpha, a, b, beta, &mut actual);
+                    assert_close(&actual, &expected, 1e-4);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn products_match_the_triple_loop() {
+    assert_matches_naive(7, 13, 5);
+    assert_matches_naive(1, 17, 3);
+    assert_matches_naive(5, 1, 9);
+}
+
+#[test]
+fn products_beyond_a_block_match_the_triple_loop() {
+    // more rows than a block of `a`, more columns than a panel of `b` and deeper than a panel
+    assert_matches_naive(67, 35, 263);
+}
+
+#[test]
+fn transposed_views_match_copies() {
+    let (m, n, k) = (9, 11, 7);
+    let a = values(m * k, 4);
+    let b = values(n * k, 5);
+    let b_copy: Vec<f32> = (0..k * n).map(|i| b[(i % n) * k + i / n]).collect();
+
+    let mut viewed = vec![0.; m * n];
+    gemm(1, 1., Matrix::new(&a, m, k), Matrix::new(&b, n, k).t(), 0., &mut viewed);
+    let mut copied = vec![0.; m * n];
+    gemm(1, 1., Matrix::new(&a, m, k), Matrix::new(&b_copy, k, n), 0., &mut copied);
+    assert_close(&viewed, &copied, 1e-6);
+}
@@ -0,0 +1,91 @@
+//! Compares the layers on a CPU OpenCL device, such as pocl, with the same layers on the host.
+//! The sizes aren't multiples of the tile sizes of the kernels, so the edges of the tiles are covered.
+
+mod common;
+
+use std::sync::Arc;
+
+use neurox::activation::Activation::{Linear, TanH};
+use neurox::builder::NetworkBuilder;
+use neurox::dual_vec::DualVec;
+use neurox::loss::Loss;
+use neurox::network::Network;
+use neurox::Executor::CPU;
+use neurox::Optimizer;
+use common::{assert_close, cpu_device, dataset, predictions};
+
+/// Token ids of `samples` samples of `sequence` tokens of a vocabulary of `vocab`
+fn tokens(samples: usize, sequence: usize, vocab: usize) -> Vec<f32> {
+    (0..samples * sequence).map(|i| ((i * 7 + 3) % vocab) as f32).collect()
+}
+
+/// Checks `network` predicts the same as a copy of it on the host, before and after a single
+/// training step over all `samples` samples
+fn assert_matches_host(mut network: Network, samples: usize, inputs: Vec<f32>, targets: &mut DualVec) {
+    let mut host = network.replicate(&Arc::new(CPU)).unwrap();
+    let inputs = &mut DualVec::from_vec((&CPU, network.executor(0)), inputs);
+    let targets = &mut DualVec::from_vec((&CPU, network.executor(0)), targets.cpu_borrow().unwrap().clone());
+    assert_close(&predictions(&mut network, inputs), &predictions(&mut host, inputs), 1e-4);
+
+    for n in [&mut network, &mut host] {
+        n.train(inputs, targets, Optimizer::GradientDecent(0.1), Loss::MeanSquared, 1, samples).unwrap();
+    }
+    assert_close(&predictions(&mut network, inputs), &predictions(&mut host, inputs), 1e-4);
+}
+
+#[test]
+#[ignore = "needs a CPU OpenCL device"]
+fn dense_layers_match_the_host() {
+    let exec = cpu_device();
+    let samples = 37;
+    let (mut inputs, mut targets) = dataset(samples, 19, |x| x.iter().sum::<f32>().sin());
+    let inputs = inputs.cpu_borrow().unwrap().clone();
+
+    let network = NetworkBuilder::new(19).on(&exec)
+        .dense(23, TanH)
+        .dense(1, Linear)
+        .build()
+        .unwrap();
+    assert_matches_host(network, samples, inputs, &mut targets);
+}
+
+#[test]
+#[ignore = "needs a CPU OpenCL device"]
+fn embedding_layers_match_the_host() {
+    let exec = cpu_device();
+    let samples = 21;
+    let inputs = tokens(samples, 5, 11);
+    let (_, mut targets) = dataset(samples, 1, |x| x[0]);
+
+    let network = NetworkBuilder::new(5).on(&exec)
+        .embedding(11, 7)
+        .dense(1, Linear)
+        .build()
+        .unwrap();
+    assert_matches_host(network, samples, inputs, &mut targets);
+}
+
+#[test]
+#[ignore = "needs a CPU OpenCL device"]
+fn attention_masks_match_the_host() {
+    let exec = cpu_device();
+    let (sequence, characteristics) = (5, 3);
+    let lengths = vec![5, 2, 1, 4];
+    let inputs: Vec<f32> = (0..lengths.len() * sequence * characteristics).map(|i| ((i * 7 % 13) as f32 - 6.) / 6.).collect();
+
+    for causal in [false, true] {
+        let mut network = NetworkBuilder::new([sequence, characteristics]).on(&exec)
+            .attention(2, 3, 7, causal)
+            .build()
+            .unwrap();
+        let mut host = network.replicate(&Arc::new(CPU)).unwrap();
+
+        let mut inputs = DualVec::from_vec((&CPU, &exec), inputs.clone());
+        // twice, so the second batch runs the kernels kept from the first one
+        for _ in 0..2 {
+            let actual = network.predict_padded(&mut inputs, &lengths).unwrap().cpu_borrow().unwrap().clone();
+            let expected = host.predict_padded(&mut inputs, &lengths).unwrap().cpu_borrow().unwrap().clone();
+            assert_close(&actual, &expected, 1e-4);
+        }
+    }
+}
@@ -0,0 +1,105 @@
+mod common;
+
+use std::rc::Rc;
+use std::sync::Arc;
+
+use neurox::activation::Activation::{Linear, TanH};
+use neurox::autograd::{Tape, Var};
+use neurox::dual_vec::DualVec;
+use neurox::error::{Error, NetworkError};
+use neurox::graph::{GraphNetwork, GraphNode, Merge};
+use neurox::layer::LayerType;
+use neurox::loss::Loss;
+use neurox::Executor::CPU;
+use neurox::Optimizer;
+
+const INPUTS: usize = 2;
+const OUTPUTS: usize = 2;
+const SAMPLES: usize = 3;
+
+fn inputs() -> Vec<f32> {
+    (0..SAMPLES * INPUTS).map(|i| ((i * 5 % 7) as f32 - 3.) / 3.).collect()
+}
+
+/// `x` and `y` both read the input, and are merged before the output layer
+fn merged(merge: Merge) -> GraphNetwork {
+    let cpu = Arc::new(CPU);
+    GraphNetwork::new(INPUTS, &[
+        GraphNode::layer("x", &cpu, LayerType::Dense(3, TanH), "input"),
+        GraphNode::layer("y", &cpu, LayerType::Dense(3, TanH), "input"),
+        GraphNode::merge("m", merge, &["x", "y"]),
+        GraphNode::layer("out", &cpu, LayerType::Dense(OUTPUTS, Linear), "m"),
+    ], "out").unwrap()
+}
+
+/// The loss `sum(outputs * coefficients)` of the predictions of `graph`
+fn weighted_sum(graph: &mut GraphNetwork, coefficients: &[f32]) -> f64 {
+    let outputs = graph.predict(&mut DualVec::from_vec((&CPU, &CPU), inputs())).unwrap();
+    outputs.clone().cpu_borrow().unwrap().iter().zip(coefficients).map(|(o, c)| *o as f64 * *c as f64).sum()
+}
+
+/// The largest difference between the gradients of the values of the layer node `name` from a
+/// training step and central finite differences, relative to the gradients above 1
+fn gradient_error(graph: &mut GraphNetwork, name: &str) -> f32 {
+    let epsilon = 1e-2;
+    let coefficients: Vec<f32> = (0..SAMPLES * OUTPUTS).map(|i| ((i * 3 % 7) as f32 - 3.) / 3.).collect();
+
+    // a step of a single batch moves the values by the gradients of the summed loss over the batch
+    // size, which is taken on a copy so that the finite differences are those of the original values
+    let mut trained = GraphNetwork::from_bytes(None, graph.as_bytes()).unwrap();
+    let weighted = Loss::Custom(Rc::new(|tape: &mut Tape, actual: Var, target: Var| tape.mul(actual, target)));
+    let mut targets = DualVec::from_vec((&CPU, &CPU), coefficients.clone());
+    trained.train(&mut DualVec::from_vec((&CPU, &CPU), inputs()), &mut targets, Optimizer::GradientDecent(1.), weighted, 1, SAMPLES).unwrap();
+
+    let values: Vec<DualVec> = graph.layer(name).unwrap().borrow().values().into_iter().cloned().collect();
+    let stepped: Vec<DualVec> = trained.layer(name).unwrap().borrow().values().into_iter().cloned().collect();
+    let mut error: f32 = 0.;
+    for (mut value, mut stepped) in values.into_iter().zip(stepped) {
+        let (before, after) = (value.cpu_borrow().unwrap().clone(), stepped.cpu_borrow().unwrap().clone());
+        for i in 0..before.len() {
+            let mut loss_at = |v: f32| {
+                value.cpu_borrow().unwrap()[i] = v;
+                value.updated_cpu();
+                weighted_sum(graph, &coefficients)
+            };
+            let numeric = (loss_at(before[i] + epsilon) - loss_at(before[i] - epsilon)) / (2. * epsilon as f64);
+            loss_at(before[i]);
+
+            let analytic = (before[i] - after[i]) * SAMPLES as f32;
+            error = error.max((analytic - numeric as f32).abs() / analytic.abs().max(1.));
+        }
+    }
+    error
+}
+
+#[test]
+fn merge_gradients_match_finite_differences() {
+    for merge in [Merge::Add, Merge::Concat, Merge::Multiply] {
+        let mut graph = merged(merge.clone());
+        for name in ["x", "y"] {
+            let error = gradient_error(&mut graph, name);
+            assert!(error < 1e-2, "The gradients of {} through {:?} differ from finite differences by {}", name, merge, error);
+        }
+    }
+}
+
+#[test]
+fn gradients_of_nodes_with_several_consumers_accumulate() {
+    let cpu = Arc::new(CPU);
+    let mut graph = GraphNetwork::new(INPUTS, &[
+        GraphNode::layer("x", &cpu, LayerType::Dense(3, TanH), "input"),
+        GraphNode::layer("h", &cpu, LayerType::Dense(3, TanH), "x"),
+        GraphNode::merge("m", Merge::Add, &["x", "h"]),
+        GraphNode::layer("out", &cpu, LayerType::Dense(OUTPUTS, Linear), "m"),
+    ], "out").unwrap();
+
+    let error = gradient_error(&mut graph, "x");
+    assert!(error < 1e-2, "The gradients of a node used twice differ from finite differences by {}", error);
+}
+
+#[test]
+fn merges_need_an_input() {
+    let merged = GraphNetwork::new(INPUTS, &[GraphNode::merge("m", Merge::Add, &[])], "m");
+    assert!(matches!(merged, Err(Error::Network(NetworkError::MergeInputs(name))) if name == "m"));
+}
+
@@ -0,0 +1,96 @@
+mod common;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use neurox::gpu::{cache_key, cache_path, define_options, write_cached, Gpu};
+use neurox::Executor::GPU;
+use common::gpu_with;
+
+fn device() -> Vec<String> {
+    ["Device", "Vendor", "OpenCL 3.0", "1.0"].map(String::from).to_vec()
+}
+
+/// A directory for the test `name`, which doesn't exist yet
+fn directory(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("neurox-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn files(dir: &Path) -> Vec<String> {
+    let mut files: Vec<String> = fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap()).collect();
+    files.sort();
+    files
+}
+
+#[test]
+fn keys_change_with_everything_that_decides_the_binary() {
+    let key = cache_key("-D SIZE=4", &device());
+    assert_eq!(cache_key("-D SIZE=4", &device()), key);
+    assert_ne!(cache_key("-D SIZE=5", &device()), key);
+    assert_ne!(cache_key("", &device()), key);
+    for i in 0..device().len() {
+        let mut other = device();
+        other[i].push('2');
+        assert_ne!(cache_key("-D SIZE=4", &other), key, "The key doesn't depend on the device info {}", i);
+    }
+
+    // moving bytes from one part to the next gives another key
+    let moved = ["Devic", "eVendor", "OpenCL 3.0", "1.0"].map(String::from);
+    assert_ne!(cache_key("-D SIZE=4", &moved), key);
+}
+
+#[test]
+fn specializations_have_their_own_keys() {
+    assert_eq!(define_options(&[]), "");
+    assert_eq!(define_options(&[("TILE", 16), ("SIZE", 4)]), "-D TILE=16 -D SIZE=4");
+
+    let keys: Vec<u64> = [[("SIZE", 4)], [("SIZE", 5)], [("TILE", 4)]].iter()
+        .map(|defines| cache_key(&define_options(defines), &device()))
+        .collect();
+    assert!(keys[0] != keys[1] && keys[0] != keys[2] && keys[1] != keys[2]);
+}
+
+#[test]
+fn binaries_are_cached_in_private_directories() {
+    let root = directory("cache");
+    let dir = root.join("kernels");
+    let key = cache_key("", &device());
+    let path = cache_path(&dir, key);
+    assert_eq!(path, dir.join(format!("{:016x}.bin", key)));
+
+    write_cached(&path, b"first").unwrap();
+    write_cached(&path, b"second").unwrap();
+    assert_eq!(fs::read(&path).unwrap(), b"second");
+    // nothing but the binary is left behind
+    assert_eq!(files(&dir), vec![format!("{:016x}.bin", key)]);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(fs::metadata(&dir).unwrap().permissions().mode() & 0o777, 0o700);
+    }
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+#[ignore = "needs an OpenCL device"]
+fn programs_are_cached_once_per_specialization() {
+    let dir = directory("programs");
+    let built = |dir: &Path| match &*gpu_with(Gpu::builder().cache_dir(Some(dir))) {
+        GPU(gpu) => {
+            for defines in [[("SIZE", 4)], [("SIZE", 5)], [("SIZE", 4)]] {
+                gpu.program(&defines).unwrap();
+            }
+            files(dir)
+        }
+        _ => unreachable!(),
+    };
+
+    // the base program and the two specializations, which are loaded again on the next run
+    let cached = built(&dir);
+    assert_eq!(cached.len(), 3);
+    assert_eq!(built(&dir), cached);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,29 @@
+mod common;
+
+use std::sync::Arc;
+
+use neurox::builder::NetworkBuilder;
+use neurox::dual_vec::DualVec;
+use neurox::layer::norm::LayerNorm;
+use neurox::Executor::CPU;
+use neurox::Execs;
+use common::{assert_close, gradient_error, predictions};
+
+#[test]
+fn rows_are_normalized() {
+    let mut network = NetworkBuilder::new([2, 4]).layer_norm().build().unwrap();
+    let inputs = vec![1., 2., 3., 4., -2., -2., 2., 2.];
+    let outputs = predictions(&mut network, &mut DualVec::from_vec((&CPU, &CPU), inputs));
+
+    // every row of 4 values has a mean of 0 and a variance of 1
+    let step = 1. / 5f32.sqrt();
+    assert_close(&outputs, &[-3. * step, -step, step, 3. * step, -1., -1., 1., 1.], 1e-4);
+}
+
+#[test]
+fn gradients_match_finite_differences() {
+    let inputs: Vec<f32> = (0..16).map(|i| ((i * 5 % 7) as f32 - 3.) / 3.).collect();
+    let mut layer = LayerNorm::new(&Execs::all(&Arc::new(CPU)), 8, 4);
+    let error = gradient_error(&mut layer, &inputs);
+    assert!(error < 1e-2, "The gradients of layer norm differ from finite differences by {}", error);
+}
@@ -0,0 +1,113 @@
+mod common;
+
+use neurox::dual_vec::DualVec;
+use neurox::error::Error;
+use neurox::ops;
+use neurox::tensor::Tensor;
+use neurox::Executor;
+use neurox::Executor::{CPU, Threaded};
+use common::{assert_close, gpu};
+
+fn tensor(exec: &Executor, values: &[f32], shape: &[usize]) -> Tensor {
+    Tensor::new(DualVec::from_vec((&CPU, exec), values.to_vec()), shape).unwrap()
+}
+
+/// Values of both signs, the same for every executor
+fn values(len: usize) -> Vec<f32> {
+    (0..len).map(|i| ((i * 7 % 11) as f32 - 5.) / 4.).collect()
+}
+
+/// Runs every op on `exec` with the same inputs, returning the shape and values of every result
+fn run_all(exec: &Executor) -> Vec<(Vec<usize>, Vec<f32>)> {
+    let a_values = values(12);
+    let positive: Vec<f32> = a_values.iter().map(|v| v.abs() + 0.5).collect();
+    let b_values: Vec<f32> = a_values.iter().rev().map(|v| v * 2. + 3.).collect();
+    let row = [1., -2., 0.5];
+
+    let mut a = tensor(exec, &a_values, &[4, 3]);
+    let mut b = tensor(exec, &b_values, &[4, 3]);
+    let mut p = tensor(exec, &positive, &[4, 3]);
+    let mut r = tensor(exec, &row, &[3]);
+    let mut column = tensor(exec, &row[..1], &[1, 1]);
+    let mut c = tensor(exec, &b_values, &[3, 4]);
+
+    let mut results = vec![
+        ops::add(exec, &mut a, &mut b),
+        ops::sub(exec, &mut a, &mut r),
+        ops::mul(exec, &mut a, &mut column),
+        ops::div(exec, &mut a, &mut p),
+        ops::scale(exec, &mut a, -1.5),
+        ops::exp(exec, &mut a),
+        ops::log(exec, &mut p),
+        ops::sum(exec, &mut a, 0),
+        ops::sum(exec, &mut a, 1),
+        ops::mean(exec, &mut a, 1),
+        ops::max(exec, &mut a, 0),
+        ops::argmax(exec, &mut a, 1),
+        ops::transpose(exec, &mut a, 0, 1),
+        ops::matmul(exec, &mut a, &mut c),
+        ops::matmul(exec, &mut a.transpose(0, 1).unwrap(), &mut b),
+        ops::add(exec, &mut a.gather(&[3, 0, 0]).unwrap(), &mut r),
+        ops::sum(exec, &mut a.slice_rows(1..3).unwrap(), 0),
+    ];
+
+    results.iter_mut()
+        .map(|result| {
+            let result = result.as_mut().unwrap();
+            (result.shape().to_vec(), result.to_vec())
+        })
+        .collect()
+}
+
+#[test]
+fn ops_compute_the_expected_values() {
+    let mut a = tensor(&CPU, &[1., 2., 3., 4., 5., 6.], &[2, 3]);
+    let mut b = tensor(&CPU, &[10., 20., 30.], &[3]);
+    let mut c = tensor(&CPU, &[1., 0., 0., 1., 1., 1.], &[3, 2]);
+
+    let mut sum = ops::add(&CPU, &mut a, &mut b).unwrap();
+    assert_eq!(sum.shape(), &[2, 3]);
+    assert_close(&sum.to_vec(), &[11., 22., 33., 14., 25., 36.], 1e-4);
+
+    assert_close(&ops::sum(&CPU, &mut a, 0).unwrap().to_vec(), &[5., 7., 9.], 1e-4);
+    assert_close(&ops::mean(&CPU, &mut a, 1).unwrap().to_vec(), &[2., 5.], 1e-4);
+    assert_close(&ops::max(&CPU, &mut a, 1).unwrap().to_vec(), &[3., 6.], 1e-4);
+    assert_close(&ops::argmax(&CPU, &mut b, 0).unwrap().to_vec(), &[2.], 1e-4);
+    assert_close(&ops::transpose(&CPU, &mut a, 0, 1).unwrap().to_vec(), &[1., 4., 2., 5., 3., 6.], 1e-4);
+
+    let mut product = ops::matmul(&CPU, &mut a, &mut c).unwrap();
+    assert_eq!(product.shape(), &[2, 2]);
+    assert_close(&product.to_vec(), &[4., 5., 10., 11.], 1e-4);
+
+    let mut gathered = a.gather(&[1, 1]).unwrap();
+    assert_close(&ops::scale(&CPU, &mut gathered, 2.).unwrap().to_vec(), &[8., 10., 12., 8., 10., 12.], 1e-4);
+}
+
+#[test]
+fn ops_reject_mismatched_shapes() {
+    let mut a = tensor(&CPU, &[0.; 6], &[2, 3]);
+    let mut b = tensor(&CPU, &[0.; 2], &[2]);
+
+    assert!(matches!(ops::add(&CPU, &mut a, &mut b), Err(Error::Tensor(_))));
+    assert!(matches!(ops::matmul(&CPU, &mut a.clone(), &mut a), Err(Error::Tensor(_))));
+    assert!(matches!(ops::sum(&CPU, &mut a, 2), Err(Error::Tensor(_))));
+}
+
+#[test]
+fn threaded_ops_match_cpu() {
+    let expected = run_all(&CPU);
+    for ((shape, values), (expected_shape, expected)) in run_all(&Threaded(4)).iter().zip(&expected) {
+        assert_eq!(shape, expected_shape);
+        assert_close(values, expected, 1e-4);
+    }
+}
+
+#[test]
+#[ignore = "needs an OpenCL device"]
+fn gpu_ops_match_cpu() {
+    let expected = run_all(&CPU);
+    for ((shape, values), (expected_shape, expected)) in run_all(&gpu()).iter().zip(&expected) {
+        assert_eq!(shape, expected_shape);
+        assert_close(values, expected, 1e-4);
+    }
+}
@@ -0,0 +1,82 @@
+mod common;
+
+use std::sync::Arc;
+
+use neurox::activation::Activation::{Linear, TanH};
+use neurox::builder::NetworkBuilder;
+use neurox::loss::Loss;
+use neurox::network::Network;
+use neurox::Executor;
+use neurox::Executor::{CPU, Threaded};
+use neurox::Optimizer;
+use common::{assert_close, dataset, gpu, predictions};
+
+/// A network of three layers, the middle one on `middle` and the others on the CPU
+fn network(middle: &Arc<Executor>) -> Network {
+    NetworkBuilder::new(2)
+        .dense(8, TanH)
+        .dense(8, TanH).on(middle)
+        .dense(1, Linear).on(&Arc::new(CPU))
+        .build()
+        .unwrap()
+}
+
+fn linear(inputs: &[f32]) -> f32 {
+    inputs[0] * 0.5 - inputs[1] * 0.25
+}
+
+#[test]
+fn placements_round_trip() {
+    let mut threaded = network(&Arc::new(Threaded(2)));
+    let restored = Network::from_bytes(None, threaded.as_bytes()).unwrap();
+    assert!(matches!(**restored.executor(0), CPU));
+    assert!(matches!(**restored.executor(1), Threaded(2)));
+    assert!(matches!(**restored.executor(2), CPU));
+}
+
+#[test]
+#[ignore = "needs an OpenCL device"]
+fn gpu_placements_round_trip() {
+    let gpu = gpu();
+    let (mut inputs, _) = dataset(32, 2, linear);
+    let mut mixed = network(&gpu);
+    let mut restored = Network::from_bytes(Some(gpu.clone()), mixed.as_bytes()).unwrap();
+    let placements: Vec<bool> = (0..3).map(|i| restored.executor(i).is_gpu()).collect();
+    assert_eq!(placements, vec![false, true, false]);
+    assert_close(&predictions(&mut restored, &mut inputs), &predictions(&mut mixed, &mut inputs), 1e-4);
+}
+
+#[test]
+fn moved_layers_keep_their_values() {
+    let (mut inputs, mut targets) = dataset(32, 2, linear);
+    let mut network = network(&Arc::new(CPU));
+    let expected = predictions(&mut network, &mut inputs);
+
+    network.move_layer(1, &Arc::new(Threaded(2))).unwrap();
+    assert!(matches!(**network.executor(1), Threaded(2)));
+    assert_close(&predictions(&mut network, &mut inputs), &expected, 1e-4);
+    network.train(&mut inputs, &mut targets, Optimizer::GradientDecent(0.05), Loss::MeanSquared, 2, 8).unwrap();
+}
+
+#[test]
+#[ignore = "needs an OpenCL device"]
+fn layers_move_to_and_from_the_gpu() {
+    let gpu = gpu();
+    let (mut inputs, mut targets) = dataset(32, 2, linear);
+    let mut network = network(&Arc::new(CPU));
+
+    let expected = predictions(&mut network, &mut inputs);
+    network.move_layer(1, &gpu).unwrap();
+    assert_close(&predictions(&mut network, &mut inputs), &expected, 1e-4);
+
+    // the values pass between the host and the device on both sides of the GPU layer
+    gpu.enable_profiling();
+    network.train(&mut inputs, &mut targets, Optimizer::GradientDecent(0.05), Loss::MeanSquared, 1, 8).unwrap();
+    gpu.disable_profiling();
+    let reports = gpu.take_reports();
+    let boundaries: Vec<&str> = reports.last().unwrap().boundaries.iter().map(|b| b.name.as_str()).collect();
+    assert_eq!(boundaries, vec!["0 Dense -> 1 Dense", "1 Dense -> 2 Dense"]);
+
+    network.move_layer(1, &Arc::new(CPU)).unwrap();
+    assert!(!network.executor(1).is_gpu());
+}
@@ -0,0 +1,106 @@
+mod common;
+
+use neurox::activation::Activation::{Linear, TanH};
+use neurox::builder::NetworkBuilder;
+use neurox::dual_vec::DualVec;
+use neurox::loss::Loss;
+use neurox::pool::{size_class, MemoryUsage};
+use neurox::Executor::CPU;
+use neurox::Optimizer;
+use common::{cpu_device, dataset};
+
+// The CPU executors share a pool, so every test allocates sizes of its own classes
+
+#[test]
+fn sizes_round_up_to_their_class() {
+    assert_eq!(size_class(0), 1);
+    assert_eq!(size_class(7), 7);
+    assert_eq!(size_class(100), 112);
+    assert_eq!(size_class(112), 112);
+    assert_eq!(size_class(113), 128);
+    for len in 1..5000 {
+        let class = size_class(len);
+        assert!(class >= len && class * 4 <= len * 5, "{} is rounded to {}", len, class);
+    }
+}
+
+#[test]
+fn allocations_of_a_class_are_reused() {
+    let mut first = DualVec::from_exec(&CPU, 2900);
+    let ptr = first.cpu_borrow().unwrap().as_ptr();
+    drop(first);
+
+    // 3000 is of the same class as 2900, 3100 of the next one
+    let mut larger = DualVec::from_exec(&CPU, 3100);
+    assert_ne!(larger.cpu_borrow().unwrap().as_ptr(), ptr);
+    let mut same = DualVec::from_exec(&CPU, 3000);
+    assert_eq!(same.cpu_borrow().unwrap().as_ptr(), ptr);
+    assert_eq!(same.len(), 3000);
+}
+
+#[test]
+fn recycled_vectors_are_cleared() {
+    let mut first = DualVec::from_exec(&CPU, 5000);
+    let ptr = {
+        let mut values = first.cpu_borrow().unwrap();
+        values.fill(1.);
+        values.as_ptr()
+    };
+    drop(first);
+
+    let mut recycled = DualVec::from_exec(&CPU, 5100);
+    let values = recycled.cpu_borrow().unwrap();
+    assert_eq!(values.as_ptr(), ptr);
+    assert_eq!(values.len(), 5100);
+    assert!(values.iter().all(|v| *v == 0.));
+}
+
+#[test]
+fn usage_is_recorded_by_layer() {
+    let layers = |usage: Vec<MemoryUsage>| -> Vec<MemoryUsage> {
+        usage.into_iter().filter(|u| u.owner.ends_with("Dense")).collect()
+    };
+    let pool = CPU.pool();
+    let (mut inputs, mut targets) = dataset(32, 2, |x| x[0]);
+
+    let mut network = NetworkBuilder::new(2)
+        .dense(8, TanH)
+        .dense(1, Linear)
+        .build()
+        .unwrap();
+    let built = layers(pool.usage());
+    assert_eq!(built.iter().map(|u| u.owner.as_str()).collect::<Vec<_>>(), vec!["0 Dense", "1 Dense"]);
+    // the weights and biases of the first layer are larger than the whole second layer
+    assert!(built[0].current > built[1].current && built[1].current > 0);
+
+    // the outputs of the batches are allocated while training, and freed with the network
+    network.train(&mut inputs, &mut targets, Optimizer::GradientDecent(0.1), Loss::MeanSquared, 1, 32).unwrap();
+    let trained = layers(pool.usage());
+    drop(network);
+    for ((built, trained), dropped) in built.iter().zip(&trained).zip(layers(pool.usage())) {
+        assert!(trained.current > built.current, "{}", built.owner);
+        assert_eq!(dropped.current, 0, "{}", built.owner);
+        assert_eq!(dropped.peak, trained.peak, "{}", built.owner);
+        assert!(dropped.peak >= trained.current, "{}", built.owner);
+    }
+}
+
+#[test]
+#[ignore = "needs a CPU OpenCL device"]
+fn recycled_buffers_are_cleared() {
+    // a GPU has a pool of its own, so no other test allocates from it
+    let gpu = cpu_device();
+    let pool = gpu.pool();
+
+    let mut first = DualVec::from_vec((&CPU, &gpu), vec![1.; 300]);
+    first.gpu();
+    drop(first);
+    // only the buffer is kept, the vector given to the pool isn't of a size class
+    assert_eq!(pool.free(), size_class(300) * size_of::<f32>());
+
+    let mut recycled = DualVec::from_exec(&gpu, 300);
+    assert!(recycled.cpu_borrow().unwrap().iter().all(|v| *v == 0.));
+
+    pool.trim();
+    assert_eq!(pool.free(), 0);
+}
@@ -0,0 +1,34 @@
+mod common;
+
+use std::sync::Arc;
+
+use neurox::builder::NetworkBuilder;
+use neurox::dual_vec::DualVec;
+use neurox::layer::positional::{Encoding, PositionalEncoding};
+use neurox::Executor::CPU;
+use neurox::Execs;
+use common::{assert_close, gradient_error, predictions};
+
+const SEQUENCE: usize = 2;
+const CHARACTERISTICS: usize = 4;
+
+#[test]
+fn sinusoids_are_added_to_every_position() {
+    let mut network = NetworkBuilder::new([SEQUENCE, CHARACTERISTICS]).positional_encoding(Encoding::Sinusoidal).build().unwrap();
+    let inputs = vec![1., 0., -1., 0.5, 0., 0., 0., 0.];
+    let outputs = predictions(&mut network, &mut DualVec::from_vec((&CPU, &CPU), inputs));
+
+    // the second pair of characteristics changes 100 times slower than the first
+    let expected = [1., 1., -1., 1.5, 1f32.sin(), 1f32.cos(), 0.01f32.sin(), 0.01f32.cos()];
+    assert_close(&outputs, &expected, 1e-6);
+}
+
+#[test]
+fn gradients_match_finite_differences() {
+    let inputs: Vec<f32> = (0..2 * SEQUENCE * CHARACTERISTICS).map(|i| ((i * 5 % 7) as f32 - 3.) / 3.).collect();
+    for encoding in [Encoding::Sinusoidal, Encoding::Learned] {
+        let mut layer = PositionalEncoding::new(&Execs::all(&Arc::new(CPU)), SEQUENCE * CHARACTERISTICS, encoding.clone(), CHARACTERISTICS);
+        let error = gradient_error(&mut layer, &inputs);
+        assert!(error < 1e-2, "The gradients of the {:?} encoding differ from finite differences by {}", encoding, error);
+    }
+}
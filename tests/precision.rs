@@ -0,0 +1,114 @@
+mod common;
+
+use neurox::activation::Activation::{Linear, TanH};
+use neurox::builder::NetworkBuilder;
+use neurox::dual_vec::DualVec;
+use neurox::error::{Error, NetworkError};
+use neurox::loss::Loss;
+use neurox::network::Network;
+use neurox::precision::{from_half, round, to_half, LossScale};
+use neurox::tensor::DType;
+use neurox::Executor::CPU;
+use neurox::Optimizer;
+use common::{assert_close, dataset, gpu, predictions};
+
+fn sine(inputs: &[f32]) -> f32 {
+    (inputs[0] * 3.).sin() * 0.5
+}
+
+#[test]
+fn half_conversions_round_to_nearest_even() {
+    assert_eq!(to_half(DType::F16, &[1., -2., 65504., 65520., 2f32.powi(-24), 0.]), vec![0x3c00, 0xc000, 0x7bff, 0x7c00, 0x0001, 0]);
+    assert_eq!(to_half(DType::BF16, &[1., -2., 1. + 2f32.powi(-8)]), vec![0x3f80, 0xc000, 0x3f80]);
+    assert_eq!(from_half(DType::F16, &[0x3c00, 0x0001, 0xfc00]), vec![1., 2f32.powi(-24), f32::NEG_INFINITY]);
+    assert!(from_half(DType::BF16, &to_half(DType::BF16, &[f32::NAN]))[0].is_nan());
+
+    for dtype in [DType::F16, DType::BF16] {
+        for v in [0.1f32, -3.7, 1234.5, 6e-5] {
+            let rounded = round(dtype, v);
+            assert!((rounded - v).abs() <= v.abs() / 256., "{:?} rounded {} to {}", dtype, v, rounded);
+            assert_eq!(round(dtype, rounded), rounded);
+        }
+    }
+}
+
+#[test]
+fn loss_scale_backs_off_and_grows() {
+    let mut scale = LossScale::new(1024., 2);
+    scale.update(true);
+    assert_eq!(scale.scale(), 512.);
+    scale.update(false);
+    assert_eq!(scale.scale(), 512.);
+    scale.update(false);
+    assert_eq!(scale.scale(), 1024.);
+}
+
+#[test]
+fn double_precision_layers_train_on_the_cpu() {
+    let (mut inputs, mut targets) = dataset(64, 1, sine);
+    let mut network = NetworkBuilder::new(1)
+        .dense(16, TanH)
+        .dense(1, Linear)
+        .build()
+        .unwrap();
+
+    // the values start from the same f32 values, so the outputs only differ by rounding
+    let single = predictions(&mut network, &mut inputs);
+    network.set_precision(0, DType::F64).unwrap();
+    network.set_precision(1, DType::F64).unwrap();
+    assert_close(&predictions(&mut network, &mut inputs), &single, 1e-5);
+
+    let first = network.train(&mut inputs, &mut targets, Optimizer::GradientDecent(0.05), Loss::MeanSquared, 1, 8).unwrap();
+    let trained = network.train(&mut inputs, &mut targets, Optimizer::GradientDecent(0.05), Loss::MeanSquared, 100, 8).unwrap();
+    assert!(trained < first, "The loss didn't decrease from {} to {}", first, trained);
+}
+
+#[test]
+fn precisions_are_saved_and_checked() {
+    let mut network = NetworkBuilder::new(2)
+        .dense(4, TanH).precision(DType::F64)
+        .dense(1, Linear)
+        .build()
+        .unwrap();
+
+    let mut restored = Network::from_bytes(None, network.as_bytes()).unwrap();
+    assert_eq!(restored.precision(0), DType::F64);
+    assert_eq!(restored.precision(1), DType::F32);
+
+    let mut inputs = DualVec::from_vec((&CPU, &CPU), vec![0.25, -0.5, 1., 0.75]);
+    assert_eq!(predictions(&mut network, &mut inputs), predictions(&mut restored, &mut inputs));
+
+    // half precision is only stored on GPUs, and only dense layers support other precisions
+    assert!(matches!(network.set_precision(1, DType::F16), Err(Error::Network(NetworkError::Precision(0, DType::F16)))));
+    let built = NetworkBuilder::new(2).dense(2, TanH).layer_norm().precision(DType::F64).build();
+    assert!(matches!(built, Err(Error::Network(NetworkError::Precision(5, DType::F64)))));
+}
+
+#[test]
+#[ignore = "needs an OpenCL device"]
+fn half_precision_layers_train_on_the_gpu() {
+    let gpu = gpu();
+
+    for dtype in [DType::F16, DType::BF16] {
+        let (mut inputs, mut targets) = dataset(64, 1, sine);
+        let mut network = NetworkBuilder::new(1)
+            .dense(16, TanH).on(&gpu)
+            .dense(1, Linear)
+            .build()
+            .unwrap();
+        let single = predictions(&mut network, &mut inputs);
+
+        match network.set_precision(0, dtype) {
+            Ok(()) => {}
+            Err(err) => {
+                eprintln!("Skipping {:?}: {}", dtype, err);
+                continue;
+            }
+        }
+        assert_close(&predictions(&mut network, &mut inputs), &single, 5e-2);
+
+        let first = network.train(&mut inputs, &mut targets, Optimizer::GradientDecent(0.05), Loss::MeanSquared, 1, 8).unwrap();
+        let trained = network.train(&mut inputs, &mut targets, Optimizer::GradientDecent(0.05), Loss::MeanSquared, 100, 8).unwrap();
+        assert!(trained < first, "The {:?} loss didn't decrease from {} to {}", dtype, first, trained);
+    }
+}
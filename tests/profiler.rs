@@ -0,0 +1,122 @@
+mod common;
+
+use std::sync::Arc;
+use std::thread;
+
+use neurox::activation::Activation::{Linear, TanH};
+use neurox::builder::NetworkBuilder;
+use neurox::gpu::Gpu;
+use neurox::loss::Loss;
+use neurox::network::Network;
+use neurox::profiler::{Report, Timing};
+use neurox::Executor;
+use neurox::Executor::CPU;
+use neurox::Optimizer;
+use ocl::flags::DeviceType;
+use common::{dataset, gpu_with};
+
+/// A network of three layers, the middle one on `middle` and the others on the CPU
+fn network(middle: &Arc<Executor>) -> Network {
+    NetworkBuilder::new(2)
+        .dense(4, TanH)
+        .dense(4, TanH).on(middle)
+        .dense(1, Linear).on(&Arc::new(CPU))
+        .build()
+        .unwrap()
+}
+
+/// Trains `network` for `epochs` epochs of 4 batches of 8 samples
+fn train(network: &mut Network, epochs: u32) {
+    let (mut inputs, mut targets) = dataset(32, 2, |x| x[0] - x[1]);
+    network.train(&mut inputs, &mut targets, Optimizer::GradientDecent(0.05), Loss::MeanSquared, epochs, 8).unwrap();
+}
+
+fn calls(timings: &[Timing], name: &str) -> usize {
+    timings.iter().find(|t| t.name == name).map_or(0, |t| t.calls)
+}
+
+/// Checks every layer of `layers` ran every pass once per batch
+fn assert_layer_calls(report: &Report, layers: &[usize]) {
+    let mut names: Vec<&str> = report.layers.iter().map(|t| t.name.as_str()).collect();
+    names.sort();
+    let mut expected: Vec<String> = layers.iter()
+        .flat_map(|i| ["apply", "backward", "forward"].map(|pass| format!("{} Dense {}", i, pass)))
+        .collect();
+    expected.sort();
+    assert_eq!(names, expected);
+    for timing in &report.layers {
+        assert_eq!(timing.calls, 4, "{}", timing.name);
+    }
+}
+
+#[test]
+fn layers_are_timed_once_per_batch() {
+    let cpu = Arc::new(CPU);
+    let mut network = network(&cpu);
+
+    cpu.enable_profiling();
+    train(&mut network, 2);
+    cpu.disable_profiling();
+    let reports = cpu.take_reports();
+
+    assert_eq!(reports.iter().map(|r| r.epoch).collect::<Vec<_>>(), vec![0, 1]);
+    for report in &reports {
+        assert_layer_calls(report, &[0, 1, 2]);
+        assert!(report.kernels.is_empty());
+        assert_eq!((report.to_device.count, report.to_host.count), (0, 0));
+        assert!(report.boundaries.is_empty());
+    }
+    assert!(cpu.take_reports().is_empty());
+}
+
+#[test]
+fn concurrent_runs_keep_their_reports() {
+    let profiled = thread::spawn(|| {
+        let cpu = Arc::new(CPU);
+        cpu.enable_profiling();
+        train(&mut network(&cpu), 3);
+        cpu.take_reports()
+    });
+    let unprofiled = thread::spawn(|| {
+        let cpu = Arc::new(CPU);
+        train(&mut network(&cpu), 2);
+        cpu.take_reports()
+    });
+
+    let reports = profiled.join().unwrap();
+    assert_eq!(reports.len(), 3);
+    for report in &reports {
+        assert_layer_calls(report, &[0, 1, 2]);
+    }
+    assert!(unprofiled.join().unwrap().is_empty());
+}
+
+#[test]
+#[ignore = "needs a CPU OpenCL device"]
+fn kernels_and_copies_of_a_gpu_layer_are_counted() {
+    let gpu = gpu_with(Gpu::builder().device(DeviceType::CPU).profiling(true));
+    let cpu = Arc::new(CPU);
+    let mut network = network(&gpu);
+
+    gpu.enable_profiling();
+    train(&mut network, 1);
+    let reports = gpu.take_reports();
+    assert!(cpu.take_reports().is_empty());
+
+    let report = &reports[0];
+    assert_layer_calls(report, &[1]);
+    for kernel in ["dense_forward", "dense_gradients", "dense_weight_mods", "dense_sensitivities"] {
+        assert_eq!(calls(&report.kernels, kernel), 4, "{}", kernel);
+    }
+
+    // every batch passes the values to the device and back in the forward pass and the
+    // gradients back and forth in the backward pass, 4 values of 8 samples at a time
+    let boundaries: Vec<(&str, usize, usize)> = report.boundaries.iter()
+        .map(|b| (b.name.as_str(), b.to_device.count, b.to_host.count))
+        .collect();
+    assert_eq!(boundaries, vec![("0 Dense -> 1 Dense", 4, 4), ("1 Dense -> 2 Dense", 4, 4)]);
+    for boundary in &report.boundaries {
+        assert_eq!(boundary.to_device.bytes, 4 * 8 * 4 * size_of::<f32>());
+    }
+    assert!(report.to_device.count >= 8 && report.to_host.count >= 8);
+}
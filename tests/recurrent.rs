@@ -0,0 +1,48 @@
+mod common;
+
+use std::sync::Arc;
+
+use neurox::error::{Error, NetworkError};
+use neurox::layer::recurrent::{Bidirectional, Cell, Recurrent};
+use neurox::layer::LayerType;
+use neurox::network::Network;
+use neurox::Executor::CPU;
+use neurox::Execs;
+use common::gradient_error;
+
+const TIMESTEPS: usize = 3;
+const FEATURES: usize = 2;
+
+/// Two samples of `[TIMESTEPS, FEATURES]`
+fn inputs() -> Vec<f32> {
+    (0..2 * TIMESTEPS * FEATURES).map(|i| ((i * 5 % 11) as f32 - 5.) / 5.).collect()
+}
+
+#[test]
+fn gradients_match_finite_differences() {
+    let execs = Execs::all(&Arc::new(CPU));
+    for cell in [Cell::SimpleRNN, Cell::LSTM, Cell::GRU] {
+        for return_sequences in [false, true] {
+            let mut layer = Recurrent::new(&execs, TIMESTEPS * FEATURES, cell.clone(), 3, TIMESTEPS, return_sequences, false);
+            let error = gradient_error(&mut layer, &inputs());
+            assert!(error < 1e-2, "The gradients of {:?} differ from finite differences by {}", cell, error);
+        }
+
+        let mut layer = Bidirectional::new(&execs, TIMESTEPS * FEATURES, cell.clone(), 3, TIMESTEPS, true);
+        let error = gradient_error(&mut layer, &inputs());
+        assert!(error < 1e-2, "The gradients of bidirectional {:?} differ from finite differences by {}", cell, error);
+    }
+}
+
+#[test]
+fn inputs_which_dont_split_into_timesteps_are_rejected() {
+    let cpu = Arc::new(CPU);
+    for (inputs, timesteps) in [(6, 0), (7, 3)] {
+        let recurrent = LayerType::Recurrent { cell: Cell::GRU, units: 2, timesteps, return_sequences: false, stateful: false };
+        let bidirectional = LayerType::Bidirectional { cell: Cell::GRU, units: 2, timesteps, return_sequences: false };
+        for layer in [recurrent, bidirectional] {
+            let network = Network::new(inputs, &[(cpu.clone(), layer)]);
+            assert!(matches!(network, Err(Error::Network(NetworkError::Timesteps(i, t))) if i == inputs && t == timesteps));
+        }
+    }
+}
@@ -0,0 +1,74 @@
+mod common;
+
+use std::cell::Cell;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use neurox::activation::Activation::Linear;
+use neurox::autograd::{Tape, Var};
+use neurox::builder::NetworkBuilder;
+use neurox::dual_vec::DualVec;
+use neurox::error::{Error, MismatchError};
+use neurox::graph::{GraphNetwork, GraphNode, Head};
+use neurox::layer::LayerType;
+use neurox::loss::Loss;
+use neurox::Executor::CPU;
+use neurox::Optimizer;
+use common::{assert_close, predictions};
+
+/// Two samples which need different outputs, so a network only trained on one of them can't fit both
+fn dataset() -> (DualVec, DualVec) {
+    (DualVec::from_vec((&CPU, &CPU), vec![1., 0., 0., 1.]), DualVec::from_vec((&CPU, &CPU), vec![1., -1.]))
+}
+
+#[test]
+fn batches_of_every_sample_train_on_every_sample() {
+    let (mut inputs, mut targets) = dataset();
+
+    let mut network = NetworkBuilder::new(2).dense(2, Linear).dense(1, Linear).build().unwrap();
+    network.train(&mut inputs, &mut targets, Optimizer::GradientDecent(0.1), Loss::MeanSquared, 300, 2).unwrap();
+    assert_close(&predictions(&mut network, &mut inputs), &[1., -1.], 1e-2);
+
+    let cpu = Arc::new(CPU);
+    let mut graph = GraphNetwork::new(2, &[GraphNode::layer("out", &cpu, LayerType::Dense(1, Linear), "input")], "out").unwrap();
+    graph.train(&mut inputs, &mut targets, Optimizer::GradientDecent(0.1), Loss::MeanSquared, 300, 2).unwrap();
+    assert_close(&graph.predict(&mut inputs).unwrap().cpu_borrow().unwrap(), &[1., -1.], 1e-2);
+}
+
+#[test]
+fn networks_of_a_single_layer_train() {
+    let (mut inputs, mut targets) = dataset();
+
+    let mut network = NetworkBuilder::new(2).dense(1, Linear).build().unwrap();
+    network.train(&mut inputs, &mut targets, Optimizer::GradientDecent(0.1), Loss::MeanSquared, 300, 2).unwrap();
+    assert_close(&predictions(&mut network, &mut inputs), &[1., -1.], 1e-2);
+}
+
+#[test]
+fn loss_errors_are_returned() {
+    let (mut inputs, mut targets) = dataset();
+    let cpu = Arc::new(CPU);
+    let mut graph = GraphNetwork::new(2, &[GraphNode::layer("out", &cpu, LayerType::Dense(1, Linear), "input")], "out").unwrap();
+
+    let failing = Loss::Custom(Rc::new(|_, _, _| Err(Error::Mismatch(MismatchError::Sample(1, 2)))));
+    let trained = graph.train_multi(&mut [("input", &mut inputs)], vec![Head::new("out", &mut targets, failing)], Optimizer::GradientDecent(0.1), 1, 2);
+    assert!(matches!(trained, Err(Error::Mismatch(MismatchError::Sample(1, 2)))));
+}
+
+#[test]
+fn loss_errors_of_the_gradients_are_returned() {
+    let (mut inputs, mut targets) = dataset();
+    let mut network = NetworkBuilder::new(2).dense(1, Linear).build().unwrap();
+
+    // the loss is recorded again for its gradients, which fails
+    let recorded = Cell::new(0);
+    let failing = Loss::Custom(Rc::new(move |tape: &mut Tape, actual: Var, target: Var| {
+        recorded.set(recorded.get() + 1);
+        match recorded.get() {
+            1 => tape.sub(actual, target),
+            _ => Err(Error::Mismatch(MismatchError::Sample(1, 2))),
+        }
+    }));
+    let trained = network.train(&mut inputs, &mut targets, Optimizer::GradientDecent(0.1), failing, 1, 2);
+    assert!(matches!(trained, Err(Error::Mismatch(MismatchError::Sample(1, 2)))));
+}
@@ -0,0 +1,84 @@
+mod common;
+
+use std::sync::Arc;
+
+use neurox::builder::NetworkBuilder;
+use neurox::dual_vec::DualVec;
+use neurox::layer::transformer::TransformerEncoder;
+use neurox::layer::Layer;
+use neurox::network::Network;
+use neurox::Executor::CPU;
+use neurox::Execs;
+use common::{assert_close, gradient_error, predictions};
+
+const SEQUENCE: usize = 3;
+const CHARACTERISTICS: usize = 4;
+
+fn encoder(causal: bool) -> Network {
+    NetworkBuilder::new([SEQUENCE, CHARACTERISTICS]).transformer_encoder(2, 6, 0.1, causal).build().unwrap()
+}
+
+fn sequence() -> Vec<f32> {
+    (0..SEQUENCE * CHARACTERISTICS).map(|i| ((i * 5 % 7) as f32 - 3.) / 3.).collect()
+}
+
+#[test]
+fn outputs_are_normalized_positions() {
+    let mut network = encoder(false);
+    let outputs = predictions(&mut network, &mut DualVec::from_vec((&CPU, &CPU), sequence()));
+    assert_eq!(outputs.len(), SEQUENCE * CHARACTERISTICS);
+
+    // the last layer norm starts without gains or biases, so every position has a mean of 0 and a variance of 1
+    for position in outputs.chunks(CHARACTERISTICS) {
+        let mean = position.iter().sum::<f32>() / CHARACTERISTICS as f32;
+        let variance = position.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / CHARACTERISTICS as f32;
+        assert_close(&[mean, variance], &[0., 1.], 1e-3);
+    }
+}
+
+#[test]
+fn causal_positions_only_depend_on_earlier_ones() {
+    let mut network = encoder(true);
+    let mut changed = sequence();
+    changed[(SEQUENCE - 1) * CHARACTERISTICS] += 1.;
+
+    let outputs = predictions(&mut network, &mut DualVec::from_vec((&CPU, &CPU), sequence()));
+    let changed = predictions(&mut network, &mut DualVec::from_vec((&CPU, &CPU), changed));
+    let earlier = (SEQUENCE - 1) * CHARACTERISTICS;
+    assert_eq!(changed[..earlier], outputs[..earlier]);
+    assert_ne!(changed[earlier..], outputs[earlier..]);
+}
+
+#[test]
+fn gradients_match_finite_differences() {
+    let mut inputs = sequence();
+    inputs.extend(sequence().iter().map(|v| v * -0.5));
+    for causal in [false, true] {
+        let mut layer = TransformerEncoder::new(&Execs::all(&Arc::new(CPU)), SEQUENCE * CHARACTERISTICS, 2, CHARACTERISTICS, 6, 0., causal);
+        // fixed values, as finite differences across the kink of ReLU at random values can differ a lot
+        for (k, value) in layer.values().into_iter().enumerate() {
+            let mut value = value.clone();
+            for (i, v) in value.cpu_borrow().unwrap().iter_mut().enumerate() {
+                *v = ((i * 7 + k * 3) % 11) as f32 / 10. - 0.5;
+            }
+            value.updated_cpu();
+        }
+        let error = gradient_error(&mut layer, &inputs);
+        assert!(error < 1e-2, "The gradients of a causal ({}) encoder differ from finite differences by {}", causal, error);
+    }
+}
+
+#[test]
+fn steps_match_the_causal_sequence() {
+    let mut network = encoder(true);
+    let inputs = sequence();
+    let expected = predictions(&mut network, &mut DualVec::from_vec((&CPU, &CPU), inputs.clone()));
+
+    let mut state = network.incremental_state();
+    let mut stepped = vec![];
+    for position in inputs.chunks(CHARACTERISTICS) {
+        let mut outputs = network.step(&mut state, &mut DualVec::from_vec((&CPU, &CPU), position.to_vec())).unwrap();
+        stepped.extend(outputs.cpu_borrow().unwrap().iter());
+    }
+    assert_close(&stepped, &expected, 1e-5);
+}
@@ -0,0 +1,61 @@
+use neurox::utils::cl_utils::DeviceLimits;
+
+/// Limits which allow the largest tiles and reductions
+const LARGE: DeviceLimits = DeviceLimits { max_wg_size: 4096, max_item_sizes: [4096, 4096], local_mem_size: 1 << 20 };
+
+/// The bytes of the two local tiles of a matrix product
+fn tile_bytes(tile: usize) -> usize {
+    2 * tile * (tile + 1) * 4
+}
+
+#[test]
+fn tiles_fit_the_device() {
+    assert_eq!(LARGE.tile(), 32);
+    let tile = |limits: DeviceLimits| limits.tile();
+
+    assert_eq!(tile(DeviceLimits { max_wg_size: 32 * 32, ..LARGE }), 32);
+    assert_eq!(tile(DeviceLimits { max_wg_size: 32 * 32 - 1, ..LARGE }), 16);
+    assert_eq!(tile(DeviceLimits { max_wg_size: 0, ..LARGE }), 1);
+
+    assert_eq!(tile(DeviceLimits { max_item_sizes: [4096, 32], ..LARGE }), 32);
+    assert_eq!(tile(DeviceLimits { max_item_sizes: [31, 4096], ..LARGE }), 16);
+    assert_eq!(tile(DeviceLimits { max_item_sizes: [0, 0], ..LARGE }), 1);
+
+    assert_eq!(tile(DeviceLimits { local_mem_size: tile_bytes(32), ..LARGE }), 32);
+    assert_eq!(tile(DeviceLimits { local_mem_size: tile_bytes(32) - 1, ..LARGE }), 16);
+    assert_eq!(tile(DeviceLimits { local_mem_size: 0, ..LARGE }), 1);
+}
+
+#[test]
+fn reductions_fit_the_device() {
+    assert_eq!(LARGE.reduction(), 256);
+    let reduction = |limits: DeviceLimits| limits.reduction();
+
+    assert_eq!(reduction(DeviceLimits { max_wg_size: 256, ..LARGE }), 256);
+    assert_eq!(reduction(DeviceLimits { max_wg_size: 255, ..LARGE }), 128);
+    assert_eq!(reduction(DeviceLimits { max_wg_size: 0, ..LARGE }), 1);
+
+    assert_eq!(reduction(DeviceLimits { max_item_sizes: [256, 1], ..LARGE }), 256);
+    assert_eq!(reduction(DeviceLimits { max_item_sizes: [255, 1], ..LARGE }), 128);
+    assert_eq!(reduction(DeviceLimits { max_item_sizes: [0, 0], ..LARGE }), 1);
+
+    assert_eq!(reduction(DeviceLimits { local_mem_size: 256 * 4, ..LARGE }), 256);
+    assert_eq!(reduction(DeviceLimits { local_mem_size: 256 * 4 - 1, ..LARGE }), 128);
+    assert_eq!(reduction(DeviceLimits { local_mem_size: 0, ..LARGE }), 1);
+}
+
+#[test]
+fn work_groups_fit_the_kernel() {
+    assert_eq!(LARGE.kernel_tile(32 * 32), 32);
+    assert_eq!(LARGE.kernel_tile(32 * 32 - 1), 16);
+    assert_eq!(LARGE.kernel_tile(0), 1);
+
+    assert_eq!(LARGE.kernel_reduction(256), 256);
+    assert_eq!(LARGE.kernel_reduction(255), 128);
+    assert_eq!(LARGE.kernel_reduction(0), 1);
+
+    // the kernel never gets more than the device allows
+    let small = DeviceLimits { max_wg_size: 64, ..LARGE };
+    assert_eq!(small.kernel_tile(4096), 8);
+    assert_eq!(small.kernel_reduction(4096), 64);
+}